@@ -0,0 +1,219 @@
+//! Lock latency and throughput
+//!
+//! Run with `cargo bench`. The in-process `std::sync::Mutex` and `parking_lot::Mutex` give a
+//! baseline for the cost of keeping the lock in shared memory. The semaphore group measures a
+//! permit taken and given back, alone and with more threads than permits.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rufutex::rufutex::SharedFutex;
+use rufutex::semaphore::{Semaphore, SharedSemaphore};
+use rushm::posixaccessor::POSIXShm;
+
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREADS: [usize; 3] = [2, 4, 8];
+/// Reads of the word before lock_ttas() sleeps
+const TTAS_SPINS: u32 = 100;
+/// Permits of the contended semaphore, fewer than the threads taking them
+const PERMITS: u32 = 2;
+
+/// Length of a critical section, in iterations of a busy loop
+#[derive(Clone, Copy)]
+enum Section {
+    Short,
+    Long,
+}
+
+impl Section {
+    fn name(self) -> &'static str {
+        match self {
+            Section::Short => "short",
+            Section::Long => "long",
+        }
+    }
+
+    fn run(self) {
+        let spins = match self {
+            Section::Short => 1,
+            Section::Long => 200,
+        };
+        for spin in 0..spins {
+            black_box(spin);
+        }
+    }
+}
+
+/// A POSIX shared memory segment unlinked on drop
+/// Names carry the pid and a counter so concurrent or repeated runs never collide, and the
+/// segment is removed even when a benchmark panics.
+struct Segment {
+    shm: POSIXShm<i32>,
+}
+
+impl Segment {
+    fn new(len: usize) -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let name = format!(
+            "rufutex_bench_{}_{}",
+            process::id(),
+            NEXT.fetch_add(1, Relaxed)
+        );
+        let mut shm = POSIXShm::<i32>::new(name, len);
+        unsafe {
+            let ret = shm.open();
+            assert!(ret.is_ok());
+        }
+        Self { shm }
+    }
+
+    fn futex(&mut self) -> SharedFutex {
+        SharedFutex::new(self.shm.get_cptr_mut())
+    }
+
+    fn semaphore(&mut self, permits: u32) -> &SharedSemaphore {
+        let semaphore = self.shm.get_cptr_mut() as *mut SharedSemaphore;
+        unsafe {
+            semaphore.write(SharedSemaphore::new(permits));
+            &*semaphore
+        }
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = self.shm.close(true);
+        }
+    }
+}
+
+/// Runs `iters` lock/unlock pairs split over `threads` threads
+/// # Returns
+/// The time for all threads to finish
+fn contend(threads: usize, iters: u64, lock_unlock: impl Fn() + Sync) -> Duration {
+    let per_thread = iters.div_ceil(threads as u64);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..per_thread {
+                    lock_unlock();
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn uncontended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("uncontended");
+    let mut segment = Segment::new(8);
+    let futex = segment.futex();
+    group.bench_function("rufutex", |b| {
+        b.iter(|| {
+            futex.lock();
+            futex.unlock();
+        })
+    });
+    let mutex = std::sync::Mutex::new(());
+    group.bench_function("std", |b| b.iter(|| drop(mutex.lock().unwrap())));
+    let mutex = parking_lot::Mutex::new(());
+    group.bench_function("parking_lot", |b| b.iter(|| drop(mutex.lock())));
+    group.finish();
+}
+
+fn contended(c: &mut Criterion) {
+    for section in [Section::Short, Section::Long] {
+        let mut group = c.benchmark_group(format!("contended_{}", section.name()));
+        group.throughput(Throughput::Elements(1));
+        for threads in THREADS {
+            let mut segment = Segment::new(8);
+            let futex = segment.futex();
+            group.bench_with_input(
+                BenchmarkId::new("rufutex", threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_custom(|iters| {
+                        contend(threads, iters, || {
+                            futex.lock();
+                            section.run();
+                            futex.unlock();
+                        })
+                    })
+                },
+            );
+            group.bench_with_input(
+                BenchmarkId::new("rufutex_ttas", threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_custom(|iters| {
+                        contend(threads, iters, || {
+                            futex.lock_ttas(TTAS_SPINS);
+                            section.run();
+                            futex.unlock();
+                        })
+                    })
+                },
+            );
+
+            let mutex = std::sync::Mutex::new(());
+            group.bench_with_input(BenchmarkId::new("std", threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| {
+                    contend(threads, iters, || {
+                        let _guard = mutex.lock().unwrap();
+                        section.run();
+                    })
+                })
+            });
+
+            let mutex = parking_lot::Mutex::new(());
+            group.bench_with_input(
+                BenchmarkId::new("parking_lot", threads),
+                &threads,
+                |b, &threads| {
+                    b.iter_custom(|iters| {
+                        contend(threads, iters, || {
+                            let _guard = mutex.lock();
+                            section.run();
+                        })
+                    })
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+fn semaphore(c: &mut Criterion) {
+    let mut group = c.benchmark_group("semaphore");
+    let mut segment = Segment::new(8);
+    let semaphore = segment.semaphore(1);
+    group.bench_function("uncontended", |b| {
+        b.iter(|| drop(semaphore.acquire().unwrap()))
+    });
+
+    group.throughput(Throughput::Elements(1));
+    for threads in THREADS {
+        let mut segment = Segment::new(8);
+        let semaphore = segment.semaphore(PERMITS);
+        group.bench_with_input(
+            BenchmarkId::new("contended", threads),
+            &threads,
+            |b, &threads| {
+                b.iter_custom(|iters| {
+                    contend(threads, iters, || {
+                        let _permit = semaphore.acquire().unwrap();
+                        Section::Short.run();
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, uncontended, contended, semaphore);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+use rufutex::rufutex::SharedFutex;
+
+fn main() {
+    // The page is inherited by the forked children, no name or descriptor needed
+    let shared_futex = SharedFutex::new_anonymous().expect("mmap failed");
+    let counter = unsafe { (shared_futex.futex as *mut u32).add(1) };
+
+    let children: Vec<_> = (0..4)
+        .map(|_| {
+            let pid = unsafe { libc::fork() };
+            assert!(pid >= 0);
+            if pid == 0 {
+                for _ in 0..1000 {
+                    shared_futex.lock();
+                    unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                    shared_futex.unlock();
+                }
+                unsafe { libc::_exit(0) };
+            }
+            pid
+        })
+        .collect();
+
+    for pid in children {
+        let mut status = 0;
+        unsafe { libc::waitpid(pid, &mut status, 0) };
+    }
+    println!("Counter {}", unsafe { counter.read_volatile() });
+}
@@ -0,0 +1,37 @@
+use rufutex::rufutex::SharedFutex;
+
+const ITEMS: u32 = 10;
+
+fn main() {
+    // The page is inherited by the forked consumer: two futex words, then the slot
+    let page = SharedFutex::new_anonymous().expect("mmap failed");
+    let (ready, free) = SharedFutex::futex_pair(page.futex);
+    let slot = unsafe { (page.futex as *mut u32).add(2) };
+    free.set_futex_value(1);
+
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        for _ in 0..ITEMS {
+            while ready.swap(0) == 0 {
+                let _ = ready.wait(0);
+            }
+            println!("Consumer got {}", unsafe { slot.read_volatile() });
+            free.set_futex_value(1);
+            let _ = free.post(1);
+        }
+        unsafe { libc::_exit(0) };
+    }
+
+    for item in 0..ITEMS {
+        while free.swap(0) == 0 {
+            let _ = free.wait(0);
+        }
+        unsafe { slot.write_volatile(item * item) };
+        ready.set_futex_value(1);
+        let _ = ready.post(1);
+    }
+
+    let mut status = 0;
+    unsafe { libc::waitpid(pid, &mut status, 0) };
+}
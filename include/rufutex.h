@@ -0,0 +1,52 @@
+/*
+ * C interface of rufutex, built with the `ffi` feature.
+ *
+ * A rufutex_t is a per-process handle over a 4 bytes aligned futex word, usually placed in
+ * shared memory. Every process attaching to the segment creates its own handle over the same
+ * word and they all follow the same locking protocol.
+ */
+#ifndef RUFUTEX_H
+#define RUFUTEX_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define RUFUTEX_OK 0
+#define RUFUTEX_ERR_NULL (-1)
+#define RUFUTEX_ERR_MISALIGNED (-2)
+#define RUFUTEX_ERR_WOULD_BLOCK (-3)
+#define RUFUTEX_ERR_TIMED_OUT (-4)
+#define RUFUTEX_ERR_INTERRUPTED (-5)
+#define RUFUTEX_ERR_FAULT (-6)
+#define RUFUTEX_ERR_INVALID (-7)
+#define RUFUTEX_ERR_NO_SYS (-8)
+#define RUFUTEX_ERR_OS (-9)
+#define RUFUTEX_ERR_PANIC (-10)
+#define RUFUTEX_ERR_NOT_SUPPORTED (-11)
+
+typedef struct rufutex rufutex_t;
+
+/* Returns NULL if word is NULL or not 4 bytes aligned. The word must outlive the handle. */
+rufutex_t *rufutex_new(void *word);
+void rufutex_free(rufutex_t *handle);
+
+int rufutex_lock(const rufutex_t *handle);
+/* RUFUTEX_ERR_WOULD_BLOCK if the lock is held */
+int rufutex_try_lock(const rufutex_t *handle);
+int rufutex_unlock(const rufutex_t *handle);
+
+/* RUFUTEX_ERR_WOULD_BLOCK if the word does not hold expected */
+int rufutex_wait(const rufutex_t *handle, uint32_t expected);
+int rufutex_wait_timeout(const rufutex_t *handle, uint32_t expected, uint64_t timeout_ns);
+/* Returns the number of woken waiters or a negative error code */
+int rufutex_wake(const rufutex_t *handle, uint32_t count);
+uint32_t rufutex_value(const rufutex_t *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUFUTEX_H */
@@ -0,0 +1,88 @@
+//! Futex in anonymous shared memory, without a shared memory crate
+//!
+//! The page is mapped with `mmap(MAP_SHARED | MAP_ANONYMOUS)` and reaches other processes by
+//! being inherited across fork(). Unlike [`SharedFutex::new_anonymous`] the mapping is returned
+//! to the caller, who decides how long it lives.
+
+use crate::error::FutexError;
+use crate::mapping::Mapping;
+use crate::rufutex::SharedFutex;
+use crate::UNLOCKED;
+use libc::c_void;
+
+/// An anonymous MAP_SHARED mapping, unmapped with munmap when dropped
+pub struct MmapRegion {
+    mapping: Mapping,
+}
+
+impl MmapRegion {
+    /// Returns the start of the mapping
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.mapping.as_ptr()
+    }
+
+    /// Returns the size of the mapping in bytes
+    pub fn len(&self) -> usize {
+        self.mapping.len()
+    }
+
+    /// Returns true if the mapping is empty, which never happens
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Creates futexes in their own anonymous page
+pub struct AnonymousFutex;
+
+impl AnonymousFutex {
+    /// Maps a page of anonymous shared memory and puts an unlocked futex at its start
+    /// The rest of the page is free for the data the futex protects. The futex points into
+    /// the region and must not be used after the region is dropped.
+    /// # Returns
+    /// The SharedFutex and the region holding it, or the error of mmap
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> Result<(SharedFutex, MmapRegion), FutexError> {
+        let mapping = Mapping::anonymous(Mapping::page_size())
+            .map_err(|err| FutexError::from_errno(err.raw_os_error().unwrap_or(0)))?;
+        let region = MmapRegion { mapping };
+        let futex = SharedFutex::new(region.as_ptr());
+        futex.set_futex_value(UNLOCKED);
+        Ok((futex, region))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "forks a child process")]
+    fn test_shared_with_child() {
+        let (futex, region) = AnonymousFutex::new().unwrap();
+        assert!(region.len() >= 4096);
+        assert_eq!(futex.futex, region.as_ptr());
+        let counter = unsafe { (region.as_ptr() as *mut u32).add(1) };
+        futex.lock();
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Child: only async-signal-safe work, the lock is released by the parent
+            futex.lock();
+            unsafe { counter.write_volatile(counter.read_volatile() + 41) };
+            futex.unlock();
+            unsafe { libc::_exit(0) };
+        }
+
+        unsafe { counter.write_volatile(1) };
+        futex.unlock();
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+        futex.lock();
+        assert_eq!(unsafe { counter.read_volatile() }, 42);
+        futex.unlock();
+    }
+}
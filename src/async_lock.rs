@@ -0,0 +1,287 @@
+//! Locking from async code
+//!
+//! [`SharedFutex::lock_async`] takes the lock at once when it is free. Otherwise the blocking
+//! lock() runs on a helper thread, so no runtime worker sleeps in FUTEX_WAIT, and the task is
+//! woken once the helper holds the lock on its behalf. The mutex has no owner, the lock taken
+//! by the helper simply becomes the lock of the task.
+//!
+//! A future dropped while its helper still waits cancels the helper's sleep and waits for the
+//! helper to let go of the word, so the word may be freed as soon as the drop returns. This
+//! blocks the dropping thread for one futex_waitv(2) wake up, or up to 10ms on kernels older
+//! than 5.16. When the helper got the lock in the meantime, or before the future was polled
+//! again, the dropped future unlocks it itself. Either way no lock or wake up is lost.
+//!
+//! Only that drop waits for the helper, and a leaked future is never dropped, so nothing in the
+//! types keeps a borrowed word alive for the helper. This is why `lock_async` is unsafe: a
+//! SharedFutex owning its mapping is kept mapped by the clone the helper holds, any other word
+//! must stay mapped for as long as a leaked future's helper may use it.
+//!
+//! Helpers are spawned when every existing one is busy, one per pending future at most, so a
+//! task holding one lock while waiting for another cannot starve the helper it needs. Idle
+//! helpers exit after a few seconds.
+
+use crate::backend::FutexBackend;
+use crate::cancel::CancelToken;
+use crate::rufutex::{SharedFutex, SharedFutexGuard};
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+/// How long an idle helper waits for a request before exiting
+const HELPER_IDLE: Duration = Duration::from_secs(5);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The requests waiting for a helper
+struct Helpers {
+    jobs: Mutex<HelperQueue>,
+    available: Condvar,
+}
+
+struct HelperQueue {
+    jobs: VecDeque<Job>,
+    idle: usize,
+}
+
+static HELPERS: Helpers = Helpers {
+    jobs: Mutex::new(HelperQueue {
+        jobs: VecDeque::new(),
+        idle: 0,
+    }),
+    available: Condvar::new(),
+};
+
+impl Helpers {
+    /// Runs `job` on an idle helper, or on a new one if every helper is busy
+    fn run(&'static self, job: Job) {
+        let mut queue = self.jobs.lock().unwrap();
+        queue.jobs.push_back(job);
+        if queue.idle >= queue.jobs.len() {
+            self.available.notify_one();
+            return;
+        }
+        drop(queue);
+        thread::Builder::new()
+            .name("rufutex-async".to_string())
+            .spawn(move || self.work())
+            .expect("failed to spawn a rufutex async helper thread");
+    }
+
+    fn work(&self) {
+        let mut queue = self.jobs.lock().unwrap();
+        loop {
+            if let Some(job) = queue.jobs.pop_front() {
+                drop(queue);
+                job();
+                queue = self.jobs.lock().unwrap();
+                continue;
+            }
+            queue.idle += 1;
+            let (next, timeout) = self.available.wait_timeout(queue, HELPER_IDLE).unwrap();
+            queue = next;
+            queue.idle -= 1;
+            if timeout.timed_out() && queue.jobs.is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+/// Runs a blocking `job` on a helper thread
+/// # Arguments
+/// * `job` - The blocking work, typically a futex wait followed by waking a task
+pub(crate) fn run_on_helper(job: impl FnOnce() + Send + 'static) {
+    HELPERS.run(Box::new(job));
+}
+
+/// Where a request handed to a helper stands
+enum Request {
+    /// The helper waits for the lock, the waker is the task's
+    Waiting(Option<Waker>),
+    /// The helper holds the lock for the future and is done with the word
+    Locked,
+    /// The future took the lock
+    Taken,
+    /// The helper gave up waiting after the future was dropped and is done with the word
+    Abandoned,
+}
+
+/// The lock a helper takes on behalf of a [`LockFuture`]
+struct LockRequest {
+    /// Cancelled by a dropped future, ending the helper's sleep
+    cancel: CancelToken,
+    state: Mutex<Request>,
+    /// Signalled once the helper is done with the word
+    finished: Condvar,
+}
+
+/// Future of [`SharedFutex::lock_async`]
+#[must_use = "futures do nothing unless polled"]
+pub struct LockFuture<'a, B: FutexBackend = crate::backend::SyscallBackend> {
+    futex: &'a SharedFutex<B>,
+    request: Option<Arc<LockRequest>>,
+}
+
+impl<'a, B> LockFuture<'a, B>
+where
+    B: FutexBackend + Clone + Send + Sync + 'static,
+{
+    pub(crate) fn new(futex: &'a SharedFutex<B>) -> Self {
+        Self {
+            futex,
+            request: None,
+        }
+    }
+}
+
+impl<'a, B> Future for LockFuture<'a, B>
+where
+    B: FutexBackend + Clone + Send + Sync + 'static,
+{
+    type Output = SharedFutexGuard<'a, B>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let request = match &self.request {
+            Some(request) => request.clone(),
+            None => {
+                if self.futex.try_lock() {
+                    return Poll::Ready(SharedFutexGuard::new(self.futex));
+                }
+                let request = Arc::new(LockRequest {
+                    cancel: CancelToken::new(),
+                    state: Mutex::new(Request::Waiting(Some(cx.waker().clone()))),
+                    finished: Condvar::new(),
+                });
+                // Keeps an owned mapping alive for the helper, a borrowed word is kept mapped
+                // by the caller of lock_async()
+                let futex = self.futex.clone();
+                let helper = request.clone();
+                run_on_helper(move || {
+                    let locked = futex.lock_cancellable(&helper.cancel).is_ok();
+                    let mut state = helper.state.lock().unwrap();
+                    let next = if locked {
+                        Request::Locked
+                    } else {
+                        Request::Abandoned
+                    };
+                    let waker = match mem::replace(&mut *state, next) {
+                        Request::Waiting(waker) => waker,
+                        _ => None,
+                    };
+                    helper.finished.notify_all();
+                    drop(state);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                });
+                self.request = Some(request);
+                return Poll::Pending;
+            }
+        };
+        let mut state = request.state.lock().unwrap();
+        match &mut *state {
+            Request::Locked => {
+                *state = Request::Taken;
+                Poll::Ready(SharedFutexGuard::new(self.futex))
+            }
+            Request::Waiting(waker) => {
+                if !waker
+                    .as_ref()
+                    .is_some_and(|waker| waker.will_wake(cx.waker()))
+                {
+                    *waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            Request::Taken | Request::Abandoned => panic!("LockFuture polled after completion"),
+        }
+    }
+}
+
+impl<B: FutexBackend> Drop for LockFuture<'_, B> {
+    fn drop(&mut self) {
+        let Some(request) = &self.request else {
+            return;
+        };
+        let mut state = request.state.lock().unwrap();
+        if let Request::Waiting(_) = *state {
+            drop(state);
+            request.cancel.cancel();
+            state = request.state.lock().unwrap();
+            while let Request::Waiting(_) = *state {
+                state = request.finished.wait(state).unwrap();
+            }
+        }
+        if let Request::Locked = *state {
+            // Got before the cancellation landed, passed on like any unlock
+            *state = Request::Taken;
+            drop(state);
+            self.futex.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_free_lock_is_ready_at_once() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut future = unsafe { futex.lock_async() };
+        let guard = match Pin::new(&mut future).poll(&mut cx) {
+            Poll::Ready(guard) => guard,
+            Poll::Pending => panic!("free lock not taken"),
+        };
+        assert!(future.request.is_none());
+        assert!(!futex.try_lock());
+        drop(guard);
+        assert!(futex.try_lock());
+        futex.unlock();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_dropped_future_passes_the_lock_on() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+        futex.lock();
+        let mut future = unsafe { futex.lock_async() };
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        drop(future);
+        futex.unlock();
+        // The helper takes the lock for the dropped future and gives it back
+        futex.lock();
+        futex.unlock();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_dropped_future_lets_go_of_the_word() {
+        let word = Box::new(AtomicU32::new(0));
+        let futex = SharedFutex::from_atomic(&word);
+        let mut cx = Context::from_waker(Waker::noop());
+        futex.lock();
+        let mut future = unsafe { futex.lock_async() };
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        // Lets the helper fall asleep on the word
+        thread::sleep(Duration::from_millis(100));
+        drop(future);
+        // Nobody sleeps on the word anymore and nobody takes it after the unlock
+        assert_eq!(futex.wake_n(i32::MAX as u32), Ok(0));
+        futex.unlock();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(futex.get_futex_value(), 0);
+        drop(futex);
+        drop(word);
+    }
+}
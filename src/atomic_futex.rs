@@ -0,0 +1,185 @@
+use crate::sys;
+use libc::c_void;
+
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// A single `AtomicU32` in shared memory that is at the same time an atomic value and a futex
+/// wait target. It exposes the usual atomic operations together with wait/wake, which is the
+/// way the kernel futex word (`uval`) is used in most protocols.
+pub struct AtomicFutex {
+    pub futex: *mut c_void,
+    atom: *mut AtomicU32,
+}
+
+impl AtomicFutex {
+    /// Create a new AtomicFutex
+    /// # Arguments
+    /// * `futex` - A mutable pointer to a c_void pointing to a 4 bytes aligned word
+    /// # Returns
+    /// A new AtomicFutex
+    pub fn new(futex: *mut c_void) -> Self {
+        let atom: *mut AtomicU32 = futex as *mut AtomicU32;
+        Self { futex, atom }
+    }
+
+    /// Loads the value of the futex word
+    /// # Arguments
+    /// * `order` - The memory ordering of the load
+    /// # Returns
+    /// The current value
+    pub fn load(&self, order: Ordering) -> u32 {
+        unsafe { (*self.atom).load(order) }
+    }
+
+    /// Stores a value into the futex word
+    /// # Arguments
+    /// * `value` - The value to store
+    /// * `order` - The memory ordering of the store
+    pub fn store(&self, value: u32, order: Ordering) {
+        unsafe { (*self.atom).store(value, order) }
+    }
+
+    /// Adds to the futex word
+    /// # Arguments
+    /// * `value` - The value to add
+    /// * `order` - The memory ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn fetch_add(&self, value: u32, order: Ordering) -> u32 {
+        unsafe { (*self.atom).fetch_add(value, order) }
+    }
+
+    /// Subtracts from the futex word
+    /// # Arguments
+    /// * `value` - The value to subtract
+    /// * `order` - The memory ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn fetch_sub(&self, value: u32, order: Ordering) -> u32 {
+        unsafe { (*self.atom).fetch_sub(value, order) }
+    }
+
+    /// Stores `new` into the futex word if the current value is `current`
+    /// # Arguments
+    /// * `current` - The expected value
+    /// * `new` - The value to store if the current value matches
+    /// * `success` - The memory ordering if the operation succeeds
+    /// * `failure` - The memory ordering if the operation fails
+    /// # Returns
+    /// `Ok` with the previous value on success, `Err` with the observed value on failure
+    pub fn compare_exchange(
+        &self,
+        current: u32,
+        new: u32,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<u32, u32> {
+        unsafe { (*self.atom).compare_exchange(current, new, success, failure) }
+    }
+
+    /// Sleeps while the futex word holds `val`
+    /// The kernel checks the value atomically, so if the word no longer holds `val` the call
+    /// returns immediately with EAGAIN.
+    /// # Arguments
+    /// * `val` - The value to wait on
+    /// # Returns
+    /// the ret value of the syscall
+    #[must_use = "check the return value for errors"]
+    pub fn wait_if_eq(&self, val: u32) -> i64 {
+        unsafe { sys::futex(self.futex, sys::FUTEX_WAIT, val, 0, ptr::null_mut(), 0) }.unwrap_or(-1)
+    }
+
+    /// Wakes one waiter
+    /// # Returns
+    /// the number of woken waiters or -1 on error
+    #[must_use = "check the return value for errors"]
+    pub fn wake_one(&self) -> i64 {
+        self.wake(1)
+    }
+
+    /// Wakes every waiter
+    /// # Returns
+    /// the number of woken waiters or -1 on error
+    #[must_use = "check the return value for errors"]
+    pub fn wake_all(&self) -> i64 {
+        self.wake(i32::MAX as u32)
+    }
+
+    fn wake(&self, number_of_waiters: u32) -> i64 {
+        unsafe {
+            sys::futex(
+                self.futex,
+                sys::FUTEX_WAKE,
+                number_of_waiters,
+                0,
+                ptr::null_mut(),
+                0,
+            )
+        }
+        .unwrap_or(-1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::mpsc;
+    use std::{thread, time};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_atomic_operations() {
+        let segment = ShmSegment::create("test_atomic_futex_ops", 8);
+        let futex = AtomicFutex::new(segment.as_ptr());
+
+        futex.store(5, SeqCst);
+        assert_eq!(futex.load(SeqCst), 5);
+        assert_eq!(futex.fetch_add(3, SeqCst), 5);
+        assert_eq!(futex.fetch_sub(1, SeqCst), 8);
+        assert_eq!(futex.load(SeqCst), 7);
+        assert_eq!(futex.compare_exchange(7, 1, SeqCst, SeqCst), Ok(7));
+        assert_eq!(futex.compare_exchange(7, 2, SeqCst, SeqCst), Err(1));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_wait_if_eq_value_mismatch() {
+        let segment = ShmSegment::create("test_atomic_futex_mismatch", 8);
+        let futex = AtomicFutex::new(segment.as_ptr());
+        futex.store(1, SeqCst);
+
+        // The word does not hold 0 so the kernel refuses to sleep
+        assert_eq!(futex.wait_if_eq(0), -1);
+        assert_eq!(futex.wake_all(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_counter_wait_wake() {
+        let (tx, rx) = mpsc::channel();
+        let segment = ShmSegment::create("test_atomic_futex_wake", 8);
+        let futex = AtomicFutex::new(segment.as_ptr());
+        futex.store(0, SeqCst);
+
+        let mapping = segment.map_again();
+
+        let handle = thread::spawn(move || {
+            let futex = AtomicFutex::new(mapping.as_ptr());
+            tx.send(true).unwrap();
+            while futex.load(SeqCst) == 0 {
+                let _ = futex.wait_if_eq(0);
+            }
+            assert_eq!(futex.load(SeqCst), 1);
+        });
+
+        let _ = rx.recv().unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+        futex.fetch_add(1, SeqCst);
+        let _ = futex.wake_one();
+
+        handle.join().unwrap();
+    }
+}
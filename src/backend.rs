@@ -0,0 +1,386 @@
+//! Pluggable implementation of the futex syscalls
+//!
+//! [`SharedFutex`](crate::rufutex::SharedFutex) issues its waits and wakes through a
+//! [`FutexBackend`]. The default [`SyscallBackend`] is a zero sized type calling futex(2), so it
+//! costs nothing; tests can swap in the scripted
+//! [`MockBackend`](crate::test_support::MockBackend) to drive the lock state machine through
+//! spurious wake ups, EINTR and timeouts deterministically. Under `--cfg loom` the
+//! [`LoomBackend`] shim lets loom explore the interleavings of the lock protocol.
+//!
+//! [`CondvarBackend`] emulates FUTEX_WAIT and FUTEX_WAKE inside the process with a std Mutex and
+//! Condvar. Miri cannot issue system calls, so under `cfg(miri)` the futex calls of
+//! [`SyscallBackend`] go to it instead.
+
+use crate::error::FutexError;
+use crate::sys;
+use libc::c_void;
+
+use crate::sync::AtomicU32;
+use std::ptr;
+use std::sync::atomic::{
+    self, AtomicU64,
+    Ordering::{Relaxed, SeqCst},
+};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait FutexBackend {
+    /// Performs a raw futex operation
+    /// # Arguments
+    /// * `uaddr` - The futex word
+    /// * `futex_op` - The operation, including flags
+    /// * `val` - The operation's val argument
+    /// * `timeout_or_val2` - The timeout pointer or the val2 number, depending on the operation
+    /// * `uaddr2` - The second futex word for requeue operations
+    /// * `val3` - The operation's val3 argument
+    /// # Returns
+    /// The non negative result of the operation or the error
+    /// # Safety
+    /// The pointers must be valid for the requested operation
+    unsafe fn futex(
+        &self,
+        uaddr: *mut c_void,
+        futex_op: i32,
+        val: u32,
+        timeout_or_val2: usize,
+        uaddr2: *mut c_void,
+        val3: u32,
+    ) -> Result<i64, FutexError>;
+
+    /// Sleeps while the word holds `expected`, FUTEX_WAIT
+    /// # Arguments
+    /// * `word` - The futex word
+    /// * `expected` - The value to sleep on
+    /// * `timeout` - The relative timeout, None sleeps until woken
+    /// * `private` - Whether to add FUTEX_PRIVATE_FLAG
+    /// # Returns
+    /// 0 when woken, or the error
+    fn wait(
+        &self,
+        word: &AtomicU32,
+        expected: u32,
+        timeout: Option<&libc::timespec>,
+        private: bool,
+    ) -> Result<i64, FutexError> {
+        let timeout = timeout.map_or(ptr::null(), |timeout| timeout as *const libc::timespec);
+        unsafe {
+            self.futex(
+                word as *const AtomicU32 as *mut c_void,
+                with_private(sys::FUTEX_WAIT, private),
+                expected,
+                timeout as usize,
+                ptr::null_mut(),
+                0,
+            )
+        }
+    }
+
+    /// Sleeps until one of the words is woken or no longer holds its value, futex_waitv(2)
+    /// Backends without it, and kernels older than 5.16, fail with NoSys; callers fall back to
+    /// FUTEX_WAIT on one of the words.
+    /// # Arguments
+    /// * `words` - The futex words with the value to sleep on and whether they are private
+    /// # Returns
+    /// The index of the woken word, or the error
+    fn wait_any(&self, words: &[(&AtomicU32, u32, bool)]) -> Result<i64, FutexError> {
+        let _ = words;
+        Err(FutexError::NoSys)
+    }
+
+    /// Wakes up to `count` waiters, FUTEX_WAKE
+    /// # Arguments
+    /// * `word` - The futex word
+    /// * `count` - The maximum number of waiters to wake
+    /// * `private` - Whether to add FUTEX_PRIVATE_FLAG
+    /// # Returns
+    /// The number of woken waiters, or the error
+    fn wake(&self, word: &AtomicU32, count: u32, private: bool) -> Result<i64, FutexError> {
+        unsafe {
+            self.futex(
+                word as *const AtomicU32 as *mut c_void,
+                with_private(sys::FUTEX_WAKE, private),
+                count,
+                0,
+                ptr::null_mut(),
+                0,
+            )
+        }
+    }
+}
+
+/// Calls futex(2)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallBackend;
+
+impl FutexBackend for SyscallBackend {
+    unsafe fn futex(
+        &self,
+        uaddr: *mut c_void,
+        futex_op: i32,
+        val: u32,
+        timeout_or_val2: usize,
+        uaddr2: *mut c_void,
+        val3: u32,
+    ) -> Result<i64, FutexError> {
+        sys::futex(uaddr, futex_op, val, timeout_or_val2, uaddr2, val3)
+    }
+
+    fn wait_any(&self, words: &[(&AtomicU32, u32, bool)]) -> Result<i64, FutexError> {
+        let words: Vec<_> = words
+            .iter()
+            .map(|&(word, expected, private)| {
+                (word as *const AtomicU32 as *mut c_void, expected, private)
+            })
+            .collect();
+        unsafe { sys::futex_waitv(&words) }
+    }
+}
+
+/// Emulates futex(2) within the process with a std Mutex and Condvar
+/// Sleepers queue by address in a table shared by every CondvarBackend, so handles over the same
+/// word see each other. Only FUTEX_WAIT and FUTEX_WAKE are emulated, the other operations fail
+/// with NoSys. Memory shared with another process is not supported.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CondvarBackend;
+
+/// Sleepers as (address, ticket), a wake removes the entries it wakes
+static SLEEPERS: Mutex<Vec<(usize, u64)>> = Mutex::new(Vec::new());
+static WAKE_UP: Condvar = Condvar::new();
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+
+impl FutexBackend for CondvarBackend {
+    /// The value is checked with the table locked and the wakers lock it before waking, so a
+    /// wake up cannot fall between the check and the sleep, like with the kernel's check.
+    unsafe fn futex(
+        &self,
+        uaddr: *mut c_void,
+        futex_op: i32,
+        val: u32,
+        timeout_or_val2: usize,
+        _uaddr2: *mut c_void,
+        _val3: u32,
+    ) -> Result<i64, FutexError> {
+        let address = uaddr as usize;
+        let mut sleepers = SLEEPERS.lock().unwrap_or_else(|err| err.into_inner());
+        match futex_op & !sys::FUTEX_PRIVATE_FLAG {
+            sys::FUTEX_WAIT => {
+                let word = &*(uaddr as *const atomic::AtomicU32);
+                if word.load(SeqCst) != val {
+                    return Err(FutexError::WouldBlock);
+                }
+                let deadline = (timeout_or_val2 as *const libc::timespec)
+                    .as_ref()
+                    .map(|timeout| {
+                        Instant::now()
+                            + Duration::new(timeout.tv_sec as u64, timeout.tv_nsec as u32)
+                    });
+                let ticket = NEXT_TICKET.fetch_add(1, Relaxed);
+                sleepers.push((address, ticket));
+                loop {
+                    let Some(index) = sleepers.iter().position(|sleeper| sleeper.1 == ticket)
+                    else {
+                        return Ok(0);
+                    };
+                    sleepers = match deadline {
+                        None => WAKE_UP
+                            .wait(sleepers)
+                            .unwrap_or_else(|err| err.into_inner()),
+                        Some(deadline) => {
+                            let now = Instant::now();
+                            if now >= deadline {
+                                sleepers.remove(index);
+                                return Err(FutexError::TimedOut);
+                            }
+                            WAKE_UP
+                                .wait_timeout(sleepers, deadline - now)
+                                .unwrap_or_else(|err| err.into_inner())
+                                .0
+                        }
+                    };
+                }
+            }
+            sys::FUTEX_WAKE => {
+                let mut woken = 0;
+                sleepers.retain(|sleeper| {
+                    let wake = sleeper.0 == address && woken < val;
+                    woken += wake as u32;
+                    !wake
+                });
+                if woken > 0 {
+                    WAKE_UP.notify_all();
+                }
+                Ok(woken as i64)
+            }
+            _ => Err(FutexError::NoSys),
+        }
+    }
+}
+
+/// Waits and wakes by parking loom threads
+/// Sleepers queue by address with their thread, a wake unparks up to `count` of the word's
+/// sleepers and removes them, like the kernel's hash bucket. Every SharedFutex of a word must
+/// share the same LoomBackend, clones do.
+#[cfg(loom)]
+#[derive(Clone, Default)]
+pub struct LoomBackend {
+    sleepers: loom::sync::Arc<loom::sync::Mutex<LoomSleepers>>,
+}
+
+/// Sleepers as (address, ticket, thread), and the ticket of the next one
+#[cfg(loom)]
+#[derive(Default)]
+struct LoomSleepers {
+    queue: Vec<(usize, u64, loom::thread::Thread)>,
+    next_ticket: u64,
+}
+
+#[cfg(loom)]
+impl LoomBackend {
+    /// Create a new LoomBackend
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(loom)]
+impl FutexBackend for LoomBackend {
+    unsafe fn futex(
+        &self,
+        _uaddr: *mut c_void,
+        _futex_op: i32,
+        _val: u32,
+        _timeout_or_val2: usize,
+        _uaddr2: *mut c_void,
+        _val3: u32,
+    ) -> Result<i64, FutexError> {
+        Err(FutexError::NoSys)
+    }
+
+    /// The value is checked with the queue locked and the wakers lock it before unparking, so
+    /// a wake up cannot fall between the check and the sleep, like with the kernel's check.
+    /// Timeouts are ignored, loom has no clock.
+    fn wait(
+        &self,
+        word: &AtomicU32,
+        expected: u32,
+        _timeout: Option<&libc::timespec>,
+        _private: bool,
+    ) -> Result<i64, FutexError> {
+        let address = word as *const AtomicU32 as usize;
+        let ticket = {
+            let mut sleepers = self.sleepers.lock().unwrap();
+            if word.load(SeqCst) != expected {
+                return Err(FutexError::WouldBlock);
+            }
+            let ticket = sleepers.next_ticket;
+            sleepers.next_ticket += 1;
+            sleepers
+                .queue
+                .push((address, ticket, loom::thread::current()));
+            ticket
+        };
+        // An unpark given before the park is kept, the thread only sleeps while still queued
+        while self
+            .sleepers
+            .lock()
+            .unwrap()
+            .queue
+            .iter()
+            .any(|sleeper| sleeper.1 == ticket)
+        {
+            loom::thread::park();
+        }
+        Ok(0)
+    }
+
+    fn wake(&self, word: &AtomicU32, count: u32, _private: bool) -> Result<i64, FutexError> {
+        let address = word as *const AtomicU32 as usize;
+        let mut sleepers = self.sleepers.lock().unwrap();
+        let mut woken = 0;
+        sleepers.queue.retain(|sleeper| {
+            let wake = sleeper.0 == address && woken < count;
+            if wake {
+                sleeper.2.unpark();
+                woken += 1;
+            }
+            !wake
+        });
+        Ok(woken as i64)
+    }
+}
+
+fn with_private(futex_op: i32, private: bool) -> i32 {
+    if private {
+        futex_op | sys::FUTEX_PRIVATE_FLAG
+    } else {
+        futex_op
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rufutex::{FutexWakeReason, SharedFutex};
+    use std::thread;
+
+    #[test]
+    fn test_condvar_backend_wait_wake() {
+        let word = AtomicU32::new(0);
+        let futex =
+            SharedFutex::with_backend(&word as *const AtomicU32 as *mut c_void, CondvarBackend);
+        assert_eq!(futex.wait(1), Ok(FutexWakeReason::ValueMismatch));
+        assert_eq!(
+            futex.wait_with_timeout(
+                0,
+                libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: 10_000_000
+                }
+            ),
+            Ok(FutexWakeReason::TimedOut)
+        );
+        assert_eq!(futex.post(1).unwrap(), 0);
+
+        thread::scope(|s| {
+            let waiters: Vec<_> = (0..3).map(|_| s.spawn(|| futex.wait(0))).collect();
+            // The table is shared with every other test running on a CondvarBackend
+            let address = &word as *const AtomicU32 as usize;
+            let asleep = || {
+                let sleepers = SLEEPERS.lock().unwrap();
+                sleepers.iter().filter(|sleeper| sleeper.0 == address).count()
+            };
+            while asleep() < 3 {
+                thread::yield_now();
+            }
+            futex.set_futex_value(1);
+            assert_eq!(futex.post(2).unwrap(), 2);
+            assert_eq!(futex.post(i32::MAX as u32).unwrap(), 1);
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap(), Ok(FutexWakeReason::Woken));
+            }
+        });
+    }
+
+    #[test]
+    fn test_condvar_backend_lock() {
+        let word = AtomicU32::new(0);
+        let futex =
+            SharedFutex::with_backend(&word as *const AtomicU32 as *mut c_void, CondvarBackend);
+        let counter = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        futex.lock();
+                        // Not an atomic increment, only the lock keeps the updates
+                        let value = counter.load(Relaxed);
+                        counter.store(value + 1, Relaxed);
+                        futex.unlock();
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(Relaxed), 4000);
+    }
+}
@@ -0,0 +1,237 @@
+//! Construction of SharedFutex segments with optional extras
+//!
+//! A plain [`SharedFutex::new`] only needs the futex word. The builder lays out a versioned
+//! segment instead: the word, a layout header recording the options, and the extra blocks the
+//! options ask for. Every process attaching to the segment checks the header against its own
+//! options, so processes built with different configurations fail loudly instead of
+//! misreading each other's memory.
+
+use crate::rufutex::SharedFutex;
+use crate::shared_stats::{
+    check_layout_header, header_word, layout_header, stats_block, LayoutError, StatsBlock,
+    STATS_OFFSET,
+};
+use crate::UNLOCKED;
+use libc::c_void;
+
+use std::mem;
+use std::ptr::NonNull;
+use std::sync::atomic::Ordering::{Acquire, Release};
+
+/// Builds a SharedFutex over a segment laid out with a layout header
+pub struct SharedFutexBuilder {
+    segment: *mut c_void,
+    shared_stats: bool,
+}
+
+impl SharedFutexBuilder {
+    /// Create a new SharedFutexBuilder
+    /// # Arguments
+    /// * `segment` - A pointer to memory_requirements() bytes, 8 bytes aligned, starting with
+    ///   the futex word
+    /// # Returns
+    /// A builder without extras
+    pub fn new(segment: *mut c_void) -> Self {
+        Self {
+            segment,
+            shared_stats: false,
+        }
+    }
+
+    /// Keeps lock statistics in the segment, see [`crate::shared_stats`]
+    /// # Arguments
+    /// * `enabled` - Whether the segment carries the shared stats block
+    pub fn shared_stats(mut self, enabled: bool) -> Self {
+        self.shared_stats = enabled;
+        self
+    }
+
+    /// Returns the number of bytes of the segment with the selected options
+    pub fn memory_requirements(&self) -> usize {
+        if self.shared_stats {
+            STATS_OFFSET + mem::size_of::<StatsBlock>()
+        } else {
+            STATS_OFFSET
+        }
+    }
+
+    /// Lays out the segment and returns a SharedFutex over it
+    /// Only one process initializes the segment, while nobody else uses it; the others
+    /// [`SharedFutexBuilder::attach`] to it with the same options.
+    /// # Returns
+    /// An unlocked SharedFutex
+    pub fn init(self) -> SharedFutex {
+        self.check_alignment();
+        let futex = self.build();
+        futex.set_futex_value(UNLOCKED);
+        if let Some(stats) = futex.shared_stats_block() {
+            stats.reset();
+        }
+        // Publishes the zeroed counters to the processes checking the header
+        unsafe { (*header_word(self.segment)).store(layout_header(self.shared_stats), Release) };
+        futex
+    }
+
+    /// Returns a SharedFutex over a segment laid out by [`SharedFutexBuilder::init`]
+    /// # Returns
+    /// The SharedFutex, or a LayoutError if the segment was laid out with other options or by
+    /// another version of the crate
+    pub fn attach(self) -> Result<SharedFutex, LayoutError> {
+        self.check_alignment();
+        let found = check_layout_header(unsafe { (*header_word(self.segment)).load(Acquire) })?;
+        if found != self.shared_stats {
+            return Err(LayoutError::SharedStats {
+                expected: self.shared_stats,
+                found,
+            });
+        }
+        Ok(self.build())
+    }
+
+    fn check_alignment(&self) {
+        assert!(
+            (self.segment as usize).is_multiple_of(mem::align_of::<StatsBlock>()),
+            "futex segment is not 8 bytes aligned"
+        );
+    }
+
+    fn build(&self) -> SharedFutex {
+        let mut futex = SharedFutex::new(self.segment);
+        if self.shared_stats {
+            futex.stats = NonNull::new(stats_block(self.segment) as *mut StatsBlock);
+        }
+        futex
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared_stats::{read_shared_stats, SharedStats};
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_shared_stats_across_mappings() {
+        let len = SharedFutexBuilder::new(std::ptr::null_mut())
+            .shared_stats(true)
+            .memory_requirements();
+        let shm = ShmSegment::create("test_shared_stats", len);
+        let segment = shm.as_ptr();
+        SharedFutexBuilder::new(segment).shared_stats(true).init();
+        assert_eq!(read_shared_stats(segment), Ok(SharedStats::default()));
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            let workers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mapping = shm.map_again();
+                        let futex = SharedFutexBuilder::new(mapping.as_ptr())
+                            .shared_stats(true)
+                            .attach()
+                            .unwrap();
+                        for _ in 0..1000 {
+                            futex.lock();
+                            futex.unlock();
+                        }
+                    })
+                })
+                .collect();
+
+            s.spawn(|| {
+                let mapping = shm.map_again();
+                let mut last = SharedStats::default();
+                while !done.load(SeqCst) {
+                    let stats = read_shared_stats(mapping.as_ptr()).unwrap();
+                    assert!(stats.acquisitions >= last.acquisitions);
+                    assert!(stats.contentions >= last.contentions);
+                    assert!(stats.wait_ns >= last.wait_ns);
+                    last = stats;
+                }
+            });
+
+            for worker in workers {
+                worker.join().unwrap();
+            }
+            done.store(true, SeqCst);
+        });
+
+        let stats = read_shared_stats(segment).unwrap();
+        assert_eq!(stats.acquisitions, 2000);
+        assert!(stats.contentions <= stats.acquisitions);
+        assert_eq!(stats.holder_tid, None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs gettid")]
+    fn test_holder_tid() {
+        let mut segment = [0u64; 5];
+        let ptr = segment.as_mut_ptr() as *mut c_void;
+        let futex = SharedFutexBuilder::new(ptr).shared_stats(true).init();
+
+        futex.lock();
+        let tid = unsafe { libc::gettid() } as u32;
+        assert_eq!(read_shared_stats(ptr).unwrap().holder_tid, Some(tid));
+        futex.unlock();
+        assert!(futex.try_lock());
+        futex.unlock();
+
+        let stats = read_shared_stats(ptr).unwrap();
+        assert_eq!(stats.acquisitions, 2);
+        assert_eq!(stats.contentions, 0);
+        assert_eq!(stats.holder_tid, None);
+    }
+
+    #[test]
+    fn test_mixed_configurations_fail() {
+        let mut segment = [0u64; 5];
+        let ptr = segment.as_mut_ptr() as *mut c_void;
+        assert_eq!(
+            SharedFutexBuilder::new(ptr).attach().err(),
+            Some(LayoutError::NotInitialized)
+        );
+        assert_eq!(read_shared_stats(ptr), Err(LayoutError::NotInitialized));
+
+        SharedFutexBuilder::new(ptr).init();
+        assert!(SharedFutexBuilder::new(ptr).attach().is_ok());
+        assert_eq!(
+            SharedFutexBuilder::new(ptr)
+                .shared_stats(true)
+                .attach()
+                .err(),
+            Some(LayoutError::SharedStats {
+                expected: true,
+                found: false
+            })
+        );
+        assert_eq!(
+            read_shared_stats(ptr),
+            Err(LayoutError::SharedStats {
+                expected: true,
+                found: false
+            })
+        );
+
+        SharedFutexBuilder::new(ptr).shared_stats(true).init();
+        assert_eq!(
+            SharedFutexBuilder::new(ptr).attach().err(),
+            Some(LayoutError::SharedStats {
+                expected: false,
+                found: true
+            })
+        );
+
+        // A header written by a future layout version
+        unsafe { (*header_word(ptr)).store(0x5246_0201, Release) };
+        assert_eq!(
+            SharedFutexBuilder::new(ptr)
+                .shared_stats(true)
+                .attach()
+                .err(),
+            Some(LayoutError::Version(2))
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Cancellation of blocked waiters
+//!
+//! A [`CancelToken`] is a futex word of its own, zero while active and one once cancelled. The
+//! cancellable operations, [`SharedFutex::lock_cancellable`] and
+//! [`SharedFutex::wait_cancellable`], sleep on their futex word and on the token together with
+//! futex_waitv(2), so cancel() waking the token word ends their sleep at once. On kernels older
+//! than 5.16 they sleep in slices of 10ms and notice the cancellation at the end of a slice.
+//!
+//! Cancelling is one way: a cancelled token stays cancelled, and every later cancellable call
+//! fails at once. The other waiters of the futex are not disturbed.
+
+use crate::error::RufutexError;
+use crate::futex_word::FutexWord;
+#[cfg(doc)]
+use crate::rufutex::SharedFutex;
+
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+const CANCELLED: u32 = 1;
+
+/// Token telling every process waiting on it to stop waiting
+/// Zeroed memory is an active token, it can be embedded in a `#[repr(C)]` shared memory layout
+/// next to the futexes whose waits it cancels.
+#[repr(C)]
+pub struct CancelToken {
+    word: FutexWord,
+}
+
+const _: () = assert!(mem::size_of::<CancelToken>() == 4);
+
+impl CancelToken {
+    /// Create a new CancelToken
+    /// # Returns
+    /// A token that is not cancelled
+    pub const fn new() -> Self {
+        Self {
+            word: FutexWord::new(0),
+        }
+    }
+
+    /// Cancels the token and wakes every waiter sleeping on it
+    /// Cancelling an already cancelled token does nothing.
+    pub fn cancel(&self) {
+        if self.word.as_atomic().swap(CANCELLED, SeqCst) != CANCELLED {
+            let _ = self.word.as_futex().post(i32::MAX as u32);
+        }
+    }
+
+    /// Returns true once the token is cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.word.load(SeqCst) == CANCELLED
+    }
+
+    /// Returns Cancelled once the token is cancelled
+    pub(crate) fn check(&self) -> Result<(), RufutexError> {
+        if self.is_cancelled() {
+            return Err(RufutexError::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// Returns the token word, the waiters sleep on it while it holds 0
+    pub(crate) fn as_atomic(&self) -> &AtomicU32 {
+        self.word.as_atomic()
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::CondvarBackend;
+    use crate::rufutex::{FutexWakeReason, SharedFutex};
+    use crate::test_support::ShmSegment;
+    use libc::c_void;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[repr(C)]
+    struct Segment {
+        lock: FutexWord,
+        token: CancelToken,
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_cancel_blocked_waiters() {
+        let len = mem::size_of::<Segment>();
+        let shm = &ShmSegment::create("test_cancel_token", len);
+        unsafe {
+            (shm.as_ptr() as *mut Segment).write(Segment {
+                lock: FutexWord::unlocked(),
+                token: CancelToken::new(),
+            });
+        }
+        let segment = unsafe { shm.get::<Segment>() };
+        // Never released
+        segment.lock.lock();
+        let locked = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            let waiters: Vec<_> = (0..3)
+                .map(|waiter| {
+                    s.spawn(move || {
+                        let mapping = shm.map_again();
+                        let segment = unsafe { mapping.get::<Segment>() };
+                        let lock = segment.lock.as_futex();
+                        let ret = if waiter == 0 {
+                            // Sleeps on whatever the lockers leave in the word
+                            loop {
+                                let value = lock.get_futex_value();
+                                if let Err(err) = lock.wait_cancellable(value, &segment.token) {
+                                    break Err(err);
+                                }
+                            }
+                        } else {
+                            lock.lock_cancellable(&segment.token)
+                        };
+                        (ret, Instant::now())
+                    })
+                })
+                .collect();
+            // A waiter that is not cancellable stays asleep
+            let plain = s.spawn(|| {
+                segment.lock.lock();
+                locked.store(true, SeqCst);
+                segment.lock.unlock();
+            });
+
+            thread::sleep(Duration::from_millis(100));
+            let cancelled = Instant::now();
+            segment.token.cancel();
+            for waiter in waiters {
+                let (ret, returned) = waiter.join().unwrap();
+                assert!(matches!(ret, Err(RufutexError::Cancelled)));
+                assert!(returned - cancelled < Duration::from_millis(100));
+            }
+            thread::sleep(Duration::from_millis(50));
+            assert!(!locked.load(SeqCst));
+            segment.lock.unlock();
+            plain.join().unwrap();
+        });
+
+        assert!(locked.load(SeqCst));
+        // Late arrivals fail at once, even with the lock free
+        assert!(segment.token.is_cancelled());
+        let lock = segment.lock.as_futex();
+        assert!(matches!(
+            lock.lock_cancellable(&segment.token),
+            Err(RufutexError::Cancelled)
+        ));
+        assert!(matches!(
+            lock.wait_cancellable(0, &segment.token),
+            Err(RufutexError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_cancel_without_futex_waitv() {
+        // CondvarBackend has no futex_waitv, the waits are sliced
+        let mut words = [0u32; 2];
+        let lock = SharedFutex::with_backend(words.as_mut_ptr() as *mut c_void, CondvarBackend);
+        let token = unsafe { &*(words.as_mut_ptr().add(1) as *const CancelToken) };
+        lock.lock();
+        assert!(matches!(
+            lock.wait_cancellable(crate::UNLOCKED, token),
+            Ok(FutexWakeReason::ValueMismatch)
+        ));
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| lock.lock_cancellable(token));
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+            assert!(matches!(
+                waiter.join().unwrap(),
+                Err(RufutexError::Cancelled)
+            ));
+        });
+        lock.unlock();
+    }
+}
@@ -0,0 +1,266 @@
+//! Up to 32 conditions on the channels of one futex word
+//!
+//! Every condition owns one bit of the word. A notification sets the bit and wakes the waiters
+//! of that condition with FUTEX_WAKE_BITSET, the waiters sleep with FUTEX_WAIT_BITSET masked
+//! with the bits they wait for. The bit stays set until a waiter consumes it, so a waiter
+//! arriving after the notification finds it at once, and two notifications of a condition
+//! nobody consumed in between count as one. Notifying without sleepers costs no system call.
+
+use crate::deadline::Deadline;
+use crate::futex_op::FutexOp;
+use crate::futex_word::FutexWord;
+use crate::sleepers;
+
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::time::Duration;
+
+/// One of the 32 conditions of a [`ConditionSet`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConditionId(u8);
+
+impl ConditionId {
+    /// Create a new ConditionId, usable in constants naming the conditions of a protocol
+    /// # Arguments
+    /// * `index` - The bit of the condition, below 32
+    /// # Returns
+    /// The ConditionId, panicking if `index` is 32 or more
+    pub const fn new(index: u32) -> Self {
+        assert!(index < 32, "a ConditionSet has 32 conditions");
+        Self(index as u8)
+    }
+
+    /// Returns the bit of the condition
+    pub const fn index(self) -> u32 {
+        self.0 as u32
+    }
+
+    const fn bit(self) -> u32 {
+        1 << self.0
+    }
+}
+
+/// Returns the bits of `ids`
+fn mask_of(ids: &[ConditionId]) -> u32 {
+    ids.iter().fold(0, |mask, id| mask | id.bit())
+}
+
+/// Cross-process set of conditions sharing one futex word
+/// Zeroed memory is a valid ConditionSet with no condition signalled.
+#[repr(C)]
+pub struct ConditionSet {
+    signalled: FutexWord,
+    sleepers: AtomicU32,
+}
+
+const _: () = assert!(mem::size_of::<ConditionSet>() == 8);
+
+impl ConditionSet {
+    /// Create a new ConditionSet
+    /// # Returns
+    /// A ConditionSet with no condition signalled
+    pub const fn new() -> Self {
+        Self {
+            signalled: FutexWord::new(0),
+            sleepers: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns true if `id` is signalled and not consumed yet
+    pub fn is_signalled(&self, id: ConditionId) -> bool {
+        self.signalled.load(SeqCst) & id.bit() != 0
+    }
+
+    /// Signals `id`, waking its waiters
+    /// # Arguments
+    /// * `id` - The condition to signal
+    pub fn notify(&self, id: ConditionId) {
+        self.signalled.as_atomic().fetch_or(id.bit(), SeqCst);
+        if sleepers::any(&self.sleepers) {
+            let _ = self.signalled.as_futex().futex(FutexOp::WakeBitset {
+                count: i32::MAX as u32,
+                mask: id.bit(),
+            });
+        }
+    }
+
+    /// Sleeps until one of `ids` is signalled and consumes it
+    /// # Arguments
+    /// * `ids` - The conditions waited for
+    /// * `timeout` - The maximum time to sleep, None sleeps until signalled
+    /// # Returns
+    /// The condition consumed, the one with the lowest bit if several are signalled, or None
+    /// if the timeout expired or `ids` is empty
+    pub fn wait_any(&self, ids: &[ConditionId], timeout: Option<Duration>) -> Option<ConditionId> {
+        let mask = mask_of(ids);
+        let mut consumed = 0;
+        self.wait_until(mask, deadline_after(timeout), |signalled| {
+            let ready = signalled & mask;
+            (ready != 0).then(|| {
+                consumed = ready.trailing_zeros();
+                signalled & !(1 << consumed)
+            })
+        })
+        .then(|| ConditionId::new(consumed))
+    }
+
+    /// Sleeps until all of `ids` are signalled and consumes them together
+    /// The conditions signalled first stay signalled while the others are waited for, other
+    /// waiters may consume them meanwhile.
+    /// # Arguments
+    /// * `ids` - The conditions waited for
+    /// * `timeout` - The maximum time to sleep, None sleeps until signalled
+    /// # Returns
+    /// true once the conditions were consumed, false if the timeout expired
+    pub fn wait_all(&self, ids: &[ConditionId], timeout: Option<Duration>) -> bool {
+        let mask = mask_of(ids);
+        if mask == 0 {
+            return true;
+        }
+        self.wait_until(mask, deadline_after(timeout), |signalled| {
+            (signalled & mask == mask).then_some(signalled & !mask)
+        })
+    }
+
+    /// Sleeps on the bits of `mask` until `consume` accepts the word
+    /// # Arguments
+    /// * `consume` - Returns the word with the consumed bits cleared, None to keep waiting
+    /// # Returns
+    /// true once consumed, false if the deadline passed or the mask is empty
+    fn wait_until(
+        &self,
+        mask: u32,
+        deadline: Deadline,
+        mut consume: impl FnMut(u32) -> Option<u32>,
+    ) -> bool {
+        if mask == 0 {
+            return false;
+        }
+        let atom = self.signalled.as_atomic();
+        loop {
+            if atom.fetch_update(SeqCst, SeqCst, &mut consume).is_ok() {
+                return true;
+            }
+            if deadline.has_passed() {
+                return false;
+            }
+            sleepers::sleep(&self.sleepers, || {
+                // Loaded after registering, a notify() that did not see us set its bit before
+                let signalled = atom.load(SeqCst);
+                if consume(signalled).is_none() {
+                    let _ = self.signalled.as_futex().futex(FutexOp::WaitBitset {
+                        expected: signalled,
+                        mask,
+                        deadline: deadline.monotonic_timespec(),
+                    });
+                }
+            });
+        }
+    }
+}
+
+/// The deadline of a timeout, None never passes
+fn deadline_after(timeout: Option<Duration>) -> Deadline {
+    timeout.map_or(Deadline::never(), Deadline::after)
+}
+
+impl Default for ConditionSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ConditionSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConditionSet")
+            .field(
+                "signalled",
+                &format_args!("{:#034b}", self.signalled.load(SeqCst)),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::thread;
+    use std::time::Instant;
+
+    const READY: ConditionId = ConditionId::new(0);
+    const DATA: ConditionId = ConditionId::new(5);
+    const SHUTDOWN: ConditionId = ConditionId::new(31);
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_notify_before_wait() {
+        let set = ConditionSet::new();
+        set.notify(DATA);
+        set.notify(DATA);
+        assert!(set.is_signalled(DATA));
+        assert_eq!(set.wait_any(&[READY, DATA], None), Some(DATA));
+        // Consumed, the second notification counted as the first
+        assert!(!set.is_signalled(DATA));
+        assert_eq!(set.wait_any(&[DATA], Some(Duration::ZERO)), None);
+        assert_eq!(set.wait_any(&[], None), None);
+        assert!(set.wait_all(&[], None));
+
+        let start = Instant::now();
+        assert!(!set.wait_all(&[READY], Some(Duration::from_millis(50))));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_wait_any_returns_the_signalled_id() {
+        let set = ConditionSet::new();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| set.wait_any(&[READY, SHUTDOWN], None));
+            while set.sleepers.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            // Not a condition of the waiter, it keeps sleeping
+            set.notify(DATA);
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            set.notify(SHUTDOWN);
+            assert_eq!(waiter.join().unwrap(), Some(SHUTDOWN));
+        });
+        assert!(set.is_signalled(DATA));
+        assert!(!set.is_signalled(SHUTDOWN));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_wait_all_pending_while_two_are_signalled() {
+        let len = mem::size_of::<ConditionSet>();
+        let segment = &ShmSegment::create("test_condition_set", len);
+        unsafe {
+            (segment.as_ptr() as *mut ConditionSet).write(ConditionSet::new());
+        }
+        let set = unsafe { segment.get::<ConditionSet>() };
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| set.wait_all(&[READY, DATA], Some(Duration::from_secs(10))));
+            while set.sleepers.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            s.spawn(move || {
+                let mapping = segment.map_again();
+                let set = unsafe { mapping.get::<ConditionSet>() };
+                set.notify(DATA);
+                thread::sleep(Duration::from_millis(50));
+                set.notify(READY);
+            });
+            thread::sleep(Duration::from_millis(25));
+            // Half of the conditions are not enough
+            assert!(!waiter.is_finished());
+            assert!(waiter.join().unwrap());
+        });
+
+        assert!(!set.is_signalled(READY));
+        assert!(!set.is_signalled(DATA));
+    }
+}
@@ -0,0 +1,160 @@
+//! Condition variable shared between processes
+//!
+//! The condvar is a sequence word bumped by every notification and a count of the waiters. A
+//! waiter reads the sequence while it holds the mutex and sleeps on it after unlocking, so a
+//! notification in between changes the word and the wait returns at once. Notifying without
+//! waiters costs no system call.
+
+use crate::error::FutexError;
+use crate::futex_op::FutexOp;
+use crate::futex_word::FutexWord;
+use crate::rufutex::{duration_to_timespec, SharedFutex};
+use crate::sleepers;
+
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::time::Duration;
+
+/// Cross-process condition variable used with a SharedFutex mutex
+/// Zeroed memory is a valid SharedCondvar. Waits may return spuriously, callers check their
+/// condition in a loop as with `std::sync::Condvar`, and change it with the mutex held.
+#[repr(C)]
+pub struct SharedCondvar {
+    sequence: FutexWord,
+    waiters: AtomicU32,
+}
+
+const _: () = assert!(mem::size_of::<SharedCondvar>() == 8);
+
+impl SharedCondvar {
+    /// Create a new SharedCondvar
+    /// # Returns
+    /// A SharedCondvar without waiters
+    pub const fn new() -> Self {
+        Self {
+            sequence: FutexWord::new(0),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlocks `mutex`, sleeps until notified and locks `mutex` again
+    /// # Arguments
+    /// * `mutex` - The mutex guarding the condition, held by the caller
+    pub fn wait(&self, mutex: &SharedFutex) {
+        let _ = sleepers::sleep(&self.waiters, || {
+            let sequence = self.unlock(mutex);
+            self.sequence.as_futex().wait(sequence)
+        });
+        mutex.lock();
+    }
+
+    /// Unlocks `mutex`, sleeps until notified or until `timeout` expires and locks `mutex` again
+    /// # Arguments
+    /// * `mutex` - The mutex guarding the condition, held by the caller
+    /// * `timeout` - The maximum time to sleep
+    /// # Returns
+    /// false if the timeout expired, true otherwise
+    pub fn wait_timeout(&self, mutex: &SharedFutex, timeout: Duration) -> bool {
+        let ret = sleepers::sleep(&self.waiters, || {
+            let sequence = self.unlock(mutex);
+            self.sequence.as_futex().futex(FutexOp::Wait {
+                expected: sequence,
+                timeout: Some(duration_to_timespec(timeout)),
+            })
+        });
+        mutex.lock();
+        ret != Err(FutexError::TimedOut)
+    }
+
+    /// Wakes one waiter, if any
+    pub fn notify_one(&self) {
+        self.notify(1);
+    }
+
+    /// Wakes every waiter
+    pub fn notify_all(&self) {
+        self.notify(i32::MAX as u32);
+    }
+
+    /// Reads the sequence to sleep on while `mutex` is still held, then unlocks it
+    fn unlock(&self, mutex: &SharedFutex) -> u32 {
+        let sequence = self.sequence.load(SeqCst);
+        mutex.unlock();
+        sequence
+    }
+
+    fn notify(&self, count: u32) {
+        self.sequence.as_atomic().fetch_add(1, SeqCst);
+        if sleepers::any(&self.waiters) {
+            let _ = self.sequence.as_futex().post(count);
+        }
+    }
+}
+
+impl Default for SharedCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn test_wait_notify() {
+        let mutex = FutexWord::unlocked();
+        let condvar = SharedCondvar::new();
+        let ready = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mutex = mutex.as_futex();
+                mutex.lock();
+                while !ready.load(SeqCst) {
+                    condvar.wait(&mutex);
+                }
+                mutex.unlock();
+            });
+            thread::sleep(Duration::from_millis(50));
+            mutex.lock();
+            ready.store(true, SeqCst);
+            condvar.notify_one();
+            mutex.unlock();
+        });
+
+        let mutex = mutex.as_futex();
+        mutex.lock();
+        assert!(!condvar.wait_timeout(&mutex, Duration::from_millis(50)));
+        mutex.unlock();
+    }
+
+    #[test]
+    fn test_notify_all() {
+        let mutex = FutexWord::unlocked();
+        let condvar = SharedCondvar::new();
+        let ready = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mutex = mutex.as_futex();
+                    mutex.lock();
+                    while !ready.load(SeqCst) {
+                        condvar.wait(&mutex);
+                    }
+                    mutex.unlock();
+                });
+            }
+            while condvar.waiters.load(SeqCst) < 4 {
+                thread::yield_now();
+            }
+            mutex.lock();
+            ready.store(true, SeqCst);
+            condvar.notify_all();
+            mutex.unlock();
+        });
+    }
+}
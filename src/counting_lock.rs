@@ -0,0 +1,166 @@
+//! A lock held by up to N threads at a time
+//!
+//! Unlike a semaphore there are no permits to hand around: every unlock() pairs with a lock()
+//! of the same holder, and a holder does not lock again while it holds the lock.
+//!
+//! A locker sleeps on the holders word while it holds `limit`, counted in the word after it so
+//! an unlock without sleepers makes no system call.
+
+use crate::futex_word::FutexWord;
+use crate::sleepers;
+
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+/// Cross-process lock admitting up to `limit` holders
+/// It can be embedded in a `#[repr(C)]` shared memory layout. Like the mutex it has no owner
+/// tracking: a process dying with the lock held keeps its place.
+#[repr(C)]
+pub struct CountingLock {
+    holders: FutexWord,
+    sleepers: AtomicU32,
+    limit: u32,
+}
+
+const _: () = assert!(mem::size_of::<CountingLock>() == 12);
+
+impl CountingLock {
+    /// Create a new CountingLock
+    /// # Arguments
+    /// * `limit` - The number of threads that can hold the lock at the same time
+    /// # Returns
+    /// A CountingLock without holders
+    pub const fn new(limit: u32) -> Self {
+        Self {
+            holders: FutexWord::new(0),
+            sleepers: AtomicU32::new(0),
+            limit,
+        }
+    }
+
+    /// Returns the number of threads that can hold the lock at the same time
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// Returns the number of current holders
+    pub fn holders(&self) -> u32 {
+        self.holders.load(SeqCst)
+    }
+
+    /// Takes a place if fewer than `limit` threads hold the lock
+    /// # Returns
+    /// true if the lock is held by the caller
+    pub fn try_lock(&self) -> bool {
+        self.holders
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |holders| {
+                (holders < self.limit).then_some(holders + 1)
+            })
+            .is_ok()
+    }
+
+    /// Takes a place, sleeping while `limit` threads hold the lock
+    pub fn lock(&self) {
+        while !self.try_lock() {
+            // Returns at once when an unlock landed in between
+            sleepers::sleep(&self.sleepers, || {
+                let _ = self.holders.as_futex().wait(self.limit);
+            });
+        }
+    }
+
+    /// Gives back the place taken by lock() or try_lock() and wakes a sleeper
+    /// # Panics
+    /// If the lock has no holder
+    pub fn unlock(&self) {
+        let released = self
+            .holders
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |holders| holders.checked_sub(1));
+        assert!(released.is_ok(), "unlock of a CountingLock without holders");
+        if sleepers::any(&self.sleepers) {
+            let _ = self.holders.as_futex().post(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::AtomicBool;
+    use std::{thread, time};
+
+    #[test]
+    fn test_try_lock_limit() {
+        let lock = CountingLock::new(2);
+        assert!(lock.try_lock());
+        assert!(lock.try_lock());
+        assert!(!lock.try_lock());
+        assert_eq!(lock.holders(), 2);
+        lock.unlock();
+        assert!(lock.try_lock());
+        lock.unlock();
+        lock.unlock();
+        assert_eq!(lock.holders(), 0);
+        assert!(std::panic::catch_unwind(|| lock.unlock()).is_err());
+    }
+
+    #[test]
+    fn test_blocks_past_limit() {
+        let lock = CountingLock::new(2);
+        lock.lock();
+        lock.lock();
+        let acquired = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.lock();
+                acquired.store(true, SeqCst);
+                lock.unlock();
+            });
+            thread::sleep(time::Duration::from_millis(100));
+            assert!(!acquired.load(SeqCst));
+            lock.unlock();
+        });
+
+        assert!(acquired.load(SeqCst));
+        lock.unlock();
+        assert_eq!(lock.holders(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_never_exceeds_limit() {
+        const LIMIT: u32 = 3;
+        let len = mem::size_of::<CountingLock>();
+        let segment = ShmSegment::create("test_counting_lock", len);
+        unsafe {
+            (segment.as_ptr() as *mut CountingLock).write(CountingLock::new(LIMIT));
+        }
+        let inside = AtomicU32::new(0);
+        let peak = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let mapping = segment.map_again();
+                    let lock = unsafe { mapping.get::<CountingLock>() };
+                    for _ in 0..1000 {
+                        lock.lock();
+                        let now = inside.fetch_add(1, SeqCst) + 1;
+                        peak.fetch_max(now, SeqCst);
+                        thread::yield_now();
+                        inside.fetch_sub(1, SeqCst);
+                        lock.unlock();
+                    }
+                });
+            }
+        });
+
+        assert!(peak.load(SeqCst) <= LIMIT);
+        let lock = unsafe { segment.get::<CountingLock>() };
+        assert_eq!(lock.holders(), 0);
+    }
+}
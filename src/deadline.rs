@@ -0,0 +1,172 @@
+//! Deadlines of the timed operations
+//!
+//! A timed operation may sleep several times: after a spurious wake up, an EINTR or a lost
+//! race it goes back to sleep. Re-issuing the caller's relative timeout each time would let
+//! it wait far longer than asked under signal load, so the timeout becomes an absolute
+//! [`Deadline`] up front and every sleep gets the time left until it.
+
+use crate::backend::FutexBackend;
+use crate::rufutex::{duration_to_timespec, SharedFutex};
+
+use std::time::{Duration, Instant};
+
+/// The point in time at which a timed operation gives up
+/// A timeout too large for an Instant gives a deadline that never passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// A deadline `timeout` from now
+    pub fn after(timeout: Duration) -> Self {
+        Self::after_from(Instant::now(), timeout)
+    }
+
+    /// A deadline `timeout` after `now`
+    pub fn after_from(now: Instant, timeout: Duration) -> Self {
+        Self {
+            at: now.checked_add(timeout),
+        }
+    }
+
+    /// A deadline at `instant`
+    pub fn at(instant: Instant) -> Self {
+        Self { at: Some(instant) }
+    }
+
+    /// A deadline that never passes
+    pub const fn never() -> Self {
+        Self { at: None }
+    }
+
+    /// Returns the instant of the deadline, None if it never passes
+    pub fn instant(&self) -> Option<Instant> {
+        self.at
+    }
+
+    /// Returns the time left, zero once the deadline has passed, None if it never passes
+    pub fn remaining(&self) -> Option<Duration> {
+        self.remaining_from(Instant::now())
+    }
+
+    /// Returns the time left at `now`, zero once the deadline has passed, None if it never
+    /// passes
+    pub fn remaining_from(&self, now: Instant) -> Option<Duration> {
+        self.at.map(|at| at.saturating_duration_since(now))
+    }
+
+    /// Returns true once the deadline has passed
+    pub fn has_passed(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Sleeps on `futex` while it holds `value`, until woken or until the deadline
+    /// # Arguments
+    /// * `futex` - The futex to sleep on
+    /// * `value` - The value the word holds for the sleep to happen
+    /// # Returns
+    /// false without sleeping if the deadline has passed, true otherwise
+    pub(crate) fn wait<B: FutexBackend>(&self, futex: &SharedFutex<B>, value: u32) -> bool {
+        match self.remaining() {
+            None => {
+                let _ = futex.wait(value);
+                true
+            }
+            Some(Duration::ZERO) => false,
+            Some(left) => {
+                let _ = futex.wait_with_timeout(value, duration_to_timespec(left));
+                true
+            }
+        }
+    }
+
+    /// Converts the deadline into an absolute CLOCK_MONOTONIC timespec for FUTEX_WAIT_BITSET
+    /// Instant reads CLOCK_MONOTONIC on Linux; the time left is added to the clock read now.
+    pub(crate) fn monotonic_timespec(&self) -> Option<libc::timespec> {
+        self.clock_timespec(libc::CLOCK_MONOTONIC)
+    }
+
+    /// Converts the deadline into an absolute CLOCK_REALTIME timespec for the POSIX timed
+    /// operations, which leaves it at the mercy of clock changes
+    #[cfg(all(target_os = "linux", not(loom)))]
+    pub(crate) fn realtime_timespec(&self) -> Option<libc::timespec> {
+        self.clock_timespec(libc::CLOCK_REALTIME)
+    }
+
+    fn clock_timespec(&self, clock: libc::clockid_t) -> Option<libc::timespec> {
+        let left = duration_to_timespec(self.remaining()?);
+        let mut now = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(clock, &mut now) };
+        let mut nsec = now.tv_nsec + left.tv_nsec;
+        let mut sec = now.tv_sec.saturating_add(left.tv_sec);
+        if nsec >= 1_000_000_000 {
+            nsec -= 1_000_000_000;
+            sec = sec.saturating_add(1);
+        }
+        Some(libc::timespec {
+            tv_sec: sec,
+            tv_nsec: nsec,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_with_a_fake_clock() {
+        let start = Instant::now();
+        let deadline = Deadline::after_from(start, Duration::from_millis(100));
+        assert_eq!(deadline.instant(), Some(start + Duration::from_millis(100)));
+        assert_eq!(
+            deadline.remaining_from(start),
+            Some(Duration::from_millis(100))
+        );
+        // Every sleep gets what is left, however often the operation wakes up
+        let mut now = start;
+        for left in [70, 40, 10] {
+            now += Duration::from_millis(30);
+            assert_eq!(
+                deadline.remaining_from(now),
+                Some(Duration::from_millis(left))
+            );
+        }
+        // Clamped at zero once passed
+        for late in [100, 101, 10_000] {
+            assert_eq!(
+                deadline.remaining_from(start + Duration::from_millis(late)),
+                Some(Duration::ZERO)
+            );
+        }
+    }
+
+    #[test]
+    fn test_never() {
+        let start = Instant::now();
+        assert_eq!(
+            Deadline::after_from(start, Duration::MAX),
+            Deadline::never()
+        );
+        assert_eq!(Deadline::never().remaining_from(start), None);
+        assert!(!Deadline::never().has_passed());
+        assert!(Deadline::at(start).has_passed());
+        assert_eq!(Deadline::never().monotonic_timespec().map(|_| ()), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_wait() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(0);
+        assert!(!Deadline::at(Instant::now()).wait(&futex, 0));
+        let deadline = Deadline::after(Duration::from_millis(20));
+        let start = Instant::now();
+        while deadline.wait(&futex, 0) {}
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
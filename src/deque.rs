@@ -0,0 +1,439 @@
+//! Blocking double-ended queue in shared memory
+//!
+//! A ring of slots guarded by a mutex, with a [`SharedCondvar`] for the poppers and one for the
+//! pushers. A push only notifies the poppers and a pop only the pushers, and each notifies one
+//! sleeper since it makes room for exactly one more operation on the other side.
+
+use crate::condvar::SharedCondvar;
+use crate::deadline::Deadline;
+use crate::futex_word::FutexWord;
+use crate::rufutex::SharedFutex;
+use libc::c_void;
+
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::time::{Duration, Instant};
+
+#[repr(C)]
+struct Header {
+    mutex: FutexWord,
+    not_empty: SharedCondvar,
+    not_full: SharedCondvar,
+    capacity: u32,
+    head: UnsafeCell<u32>,
+    len: UnsafeCell<u32>,
+}
+
+/// Error returned when the deque has no free slot, it gives the value back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DequeFullError<T>(pub T);
+
+impl<T> fmt::Display for DequeFullError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shared deque is full")
+    }
+}
+
+impl<T: fmt::Debug> Error for DequeFullError<T> {}
+
+#[derive(Clone, Copy)]
+enum End {
+    Front,
+    Back,
+}
+
+/// A fixed capacity double-ended queue in shared memory
+/// Values are plain `Copy` data since they are visible from every process mapping the region
+/// and never dropped.
+pub struct SharedDeque<T: Copy> {
+    pub deque: *mut c_void,
+    header: *const Header,
+    slots: *mut MaybeUninit<T>,
+    _value: PhantomData<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SharedDeque<T> {}
+unsafe impl<T: Copy + Send> Sync for SharedDeque<T> {}
+
+impl<T: Copy> SharedDeque<T> {
+    /// Returns the number of bytes needed for a deque of `capacity` values
+    pub fn memory_requirements(capacity: usize) -> usize {
+        Self::slots_offset() + capacity * mem::size_of::<T>()
+    }
+
+    /// Lays out an empty deque in the memory
+    /// Only one process creates the deque, the others [`SharedDeque::attach`] to it.
+    /// # Arguments
+    /// * `deque` - A pointer to memory_requirements(capacity) bytes, 4 bytes aligned and aligned
+    ///   for T
+    /// * `capacity` - The number of values the deque holds, at least 1 and at most u32::MAX
+    /// # Returns
+    /// A new SharedDeque
+    pub fn create(deque: *mut c_void, capacity: usize) -> Self {
+        assert!(capacity > 0, "shared deque capacity is 0");
+        assert!(
+            capacity <= u32::MAX as usize,
+            "shared deque capacity does not fit the header"
+        );
+        let shared = Self::attach(deque);
+        unsafe {
+            (shared.header as *mut Header).write(Header {
+                mutex: FutexWord::unlocked(),
+                not_empty: SharedCondvar::new(),
+                not_full: SharedCondvar::new(),
+                capacity: capacity as u32,
+                head: UnsafeCell::new(0),
+                len: UnsafeCell::new(0),
+            });
+        }
+        shared
+    }
+
+    /// Uses a deque created by [`SharedDeque::create`], possibly in another process
+    /// # Arguments
+    /// * `deque` - A pointer to the start of the region
+    /// # Returns
+    /// A SharedDeque over the existing values
+    pub fn attach(deque: *mut c_void) -> Self {
+        assert!(
+            (deque as usize).is_multiple_of(mem::align_of::<Header>().max(mem::align_of::<T>())),
+            "deque memory is not aligned for its header and values"
+        );
+        Self {
+            deque,
+            header: deque as *const Header,
+            slots: unsafe { (deque as *mut u8).add(Self::slots_offset()) } as *mut MaybeUninit<T>,
+            _value: PhantomData,
+        }
+    }
+
+    /// The values follow the header, aligned for T
+    fn slots_offset() -> usize {
+        mem::size_of::<Header>().next_multiple_of(mem::align_of::<T>())
+    }
+
+    /// Returns the number of values the deque holds
+    pub fn capacity(&self) -> usize {
+        self.header().capacity as usize
+    }
+
+    /// Returns the number of values in the deque
+    pub fn len(&self) -> usize {
+        let header = self.header();
+        header.mutex.lock();
+        let len = unsafe { *header.len.get() };
+        header.mutex.unlock();
+        len as usize
+    }
+
+    /// Returns true if the deque holds no value
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes a value at the back, sleeping while the deque is full
+    /// # Arguments
+    /// * `value` - The value to push
+    pub fn push_back(&self, value: T) {
+        let pushed = self.push(End::Back, value, Deadline::never());
+        debug_assert!(pushed.is_ok());
+    }
+
+    /// Pushes a value at the back if a slot is free
+    /// # Arguments
+    /// * `value` - The value to push
+    /// # Returns
+    /// Ok, or DequeFullError with the value if the deque is full
+    pub fn try_push_back(&self, value: T) -> Result<(), DequeFullError<T>> {
+        self.push(End::Back, value, Deadline::at(Instant::now()))
+    }
+
+    /// Pushes a value at the back, sleeping at most `timeout` while the deque is full
+    /// # Arguments
+    /// * `value` - The value to push
+    /// * `timeout` - The maximum time to wait for a free slot
+    /// # Returns
+    /// Ok, or DequeFullError with the value if the timeout expired
+    pub fn push_back_timeout(&self, value: T, timeout: Duration) -> Result<(), DequeFullError<T>> {
+        self.push(End::Back, value, Deadline::after(timeout))
+    }
+
+    /// Pushes a value at the front, ahead of every queued value, sleeping while the deque is
+    /// full
+    /// # Arguments
+    /// * `value` - The value to push
+    pub fn push_front(&self, value: T) {
+        let pushed = self.push(End::Front, value, Deadline::never());
+        debug_assert!(pushed.is_ok());
+    }
+
+    /// Pushes a value at the front if a slot is free
+    /// # Arguments
+    /// * `value` - The value to push
+    /// # Returns
+    /// Ok, or DequeFullError with the value if the deque is full
+    pub fn try_push_front(&self, value: T) -> Result<(), DequeFullError<T>> {
+        self.push(End::Front, value, Deadline::at(Instant::now()))
+    }
+
+    /// Pushes a value at the front, sleeping at most `timeout` while the deque is full
+    /// # Arguments
+    /// * `value` - The value to push
+    /// * `timeout` - The maximum time to wait for a free slot
+    /// # Returns
+    /// Ok, or DequeFullError with the value if the timeout expired
+    pub fn push_front_timeout(&self, value: T, timeout: Duration) -> Result<(), DequeFullError<T>> {
+        self.push(End::Front, value, Deadline::after(timeout))
+    }
+
+    /// Pops the value at the front, sleeping while the deque is empty
+    pub fn pop_front(&self) -> T {
+        self.pop(End::Front, Deadline::never())
+            .expect("pop without deadline returned nothing")
+    }
+
+    /// Pops the value at the front if there is one
+    pub fn try_pop_front(&self) -> Option<T> {
+        self.pop(End::Front, Deadline::at(Instant::now()))
+    }
+
+    /// Pops the value at the front, sleeping at most `timeout` while the deque is empty
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for a value
+    /// # Returns
+    /// The value, or None if the timeout expired
+    pub fn pop_front_timeout(&self, timeout: Duration) -> Option<T> {
+        self.pop(End::Front, Deadline::after(timeout))
+    }
+
+    /// Pops the value at the back, sleeping while the deque is empty
+    pub fn pop_back(&self) -> T {
+        self.pop(End::Back, Deadline::never())
+            .expect("pop without deadline returned nothing")
+    }
+
+    /// Pops the value at the back if there is one
+    pub fn try_pop_back(&self) -> Option<T> {
+        self.pop(End::Back, Deadline::at(Instant::now()))
+    }
+
+    /// Pops the value at the back, sleeping at most `timeout` while the deque is empty
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for a value
+    /// # Returns
+    /// The value, or None if the timeout expired
+    pub fn pop_back_timeout(&self, timeout: Duration) -> Option<T> {
+        self.pop(End::Back, Deadline::after(timeout))
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    fn push(&self, end: End, value: T, deadline: Deadline) -> Result<(), DequeFullError<T>> {
+        let header = self.header();
+        let mutex = header.mutex.as_futex();
+        mutex.lock();
+        let capacity = header.capacity;
+        while unsafe { *header.len.get() } == capacity {
+            if !Self::sleep(&header.not_full, &mutex, deadline) {
+                mutex.unlock();
+                return Err(DequeFullError(value));
+            }
+        }
+        unsafe {
+            let head = &mut *header.head.get();
+            let len = &mut *header.len.get();
+            let index = match end {
+                End::Front => {
+                    *head = Self::wrap(*head, capacity - 1, capacity);
+                    *head
+                }
+                End::Back => Self::wrap(*head, *len, capacity),
+            };
+            (*self.slots.add(index as usize)).write(value);
+            *len += 1;
+        }
+        header.not_empty.notify_one();
+        mutex.unlock();
+        Ok(())
+    }
+
+    fn pop(&self, end: End, deadline: Deadline) -> Option<T> {
+        let header = self.header();
+        let mutex = header.mutex.as_futex();
+        mutex.lock();
+        let capacity = header.capacity;
+        while unsafe { *header.len.get() } == 0 {
+            if !Self::sleep(&header.not_empty, &mutex, deadline) {
+                mutex.unlock();
+                return None;
+            }
+        }
+        let value = unsafe {
+            let head = &mut *header.head.get();
+            let len = &mut *header.len.get();
+            *len -= 1;
+            let index = match end {
+                End::Front => {
+                    let index = *head;
+                    *head = Self::wrap(*head, 1, capacity);
+                    index
+                }
+                End::Back => Self::wrap(*head, *len, capacity),
+            };
+            (*self.slots.add(index as usize)).assume_init()
+        };
+        header.not_full.notify_one();
+        mutex.unlock();
+        Some(value)
+    }
+
+    /// Returns the slot `offset` places after `head`, without overflowing for capacities past
+    /// u32::MAX / 2
+    fn wrap(head: u32, offset: u32, capacity: u32) -> u32 {
+        ((head as u64 + offset as u64) % capacity as u64) as u32
+    }
+
+    /// Waits on `condvar` with the mutex held
+    /// # Returns
+    /// false once the deadline has passed
+    fn sleep(condvar: &SharedCondvar, mutex: &SharedFutex, deadline: Deadline) -> bool {
+        match deadline.remaining() {
+            None => {
+                condvar.wait(mutex);
+                true
+            }
+            Some(Duration::ZERO) => false,
+            Some(left) => {
+                // A timed out wait still rechecks the condition, it may have been notified
+                condvar.wait_timeout(mutex, left);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+    use std::thread;
+
+    fn deque<T: Copy>(memory: &mut Vec<u64>, capacity: usize) -> SharedDeque<T> {
+        memory.resize(
+            SharedDeque::<T>::memory_requirements(capacity).div_ceil(8),
+            0,
+        );
+        SharedDeque::create(memory.as_mut_ptr() as *mut c_void, capacity)
+    }
+
+    #[test]
+    fn test_both_ends() {
+        let mut memory = Vec::new();
+        let deque = deque::<u32>(&mut memory, 3);
+        assert_eq!(deque.try_pop_front(), None);
+        assert_eq!(deque.pop_back_timeout(Duration::from_millis(20)), None);
+
+        deque.push_back(1);
+        deque.push_back(2);
+        deque.push_front(0);
+        assert_eq!(deque.try_push_back(3), Err(DequeFullError(3)));
+        assert_eq!(
+            deque.push_front_timeout(3, Duration::from_millis(20)),
+            Err(DequeFullError(3))
+        );
+        assert_eq!(deque.len(), 3);
+        assert_eq!(deque.pop_back(), 2);
+        assert_eq!(deque.pop_front(), 0);
+        assert_eq!(deque.try_pop_back(), Some(1));
+        assert!(deque.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "shared deque capacity does not fit the header")]
+    fn test_capacity_past_u32() {
+        let mut memory = vec![0u64; 4];
+        // The largest capacity wraps around without overflowing
+        let last = u32::MAX - 1;
+        assert_eq!(SharedDeque::<u8>::wrap(last, last, u32::MAX), last - 1);
+        SharedDeque::<u8>::create(memory.as_mut_ptr() as *mut c_void, u32::MAX as usize + 1);
+    }
+
+    #[test]
+    fn test_capacity_one() {
+        let mut memory = Vec::new();
+        let deque = deque::<u64>(&mut memory, 1);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                for value in 0..10_000 {
+                    if value % 2 == 0 {
+                        deque.push_back(value);
+                    } else {
+                        deque.push_front(value);
+                    }
+                }
+            });
+            for value in 0..10_000 {
+                let popped = if value % 3 == 0 {
+                    deque.pop_back()
+                } else {
+                    deque.pop_front()
+                };
+                assert_eq!(popped, value);
+            }
+        });
+        assert!(deque.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_fifo_and_overtaking() {
+        const ITEMS: u32 = 10_000;
+        let len = SharedDeque::<u32>::memory_requirements(16);
+        let segment = ShmSegment::create("test_shared_deque", len);
+        let deque = SharedDeque::<u32>::create(segment.as_ptr(), 16);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mapping = segment.map_again();
+                let deque = SharedDeque::<u32>::attach(mapping.as_ptr());
+                let mut next = 0;
+                while next < ITEMS {
+                    let value = deque.pop_front();
+                    // Back pushed jobs come out in order
+                    assert_eq!(value, next);
+                    next += 1;
+                }
+                done.store(true, SeqCst);
+            });
+            for value in 0..ITEMS {
+                deque.push_back(value);
+            }
+        });
+        assert!(done.load(SeqCst));
+
+        // A front pushed job overtakes the queued ones
+        let start = AtomicBool::new(false);
+        thread::scope(|s| {
+            let worker = s.spawn(|| {
+                while !start.load(SeqCst) {
+                    thread::yield_now();
+                }
+                (0..5).map(|_| deque.pop_front()).collect::<Vec<_>>()
+            });
+            for value in 0..4 {
+                deque.push_back(value);
+            }
+            deque.push_front(99);
+            start.store(true, SeqCst);
+            assert_eq!(worker.join().unwrap(), [99, 0, 1, 2, 3]);
+        });
+    }
+}
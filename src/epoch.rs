@@ -0,0 +1,282 @@
+//! Epoch based synchronization between processes
+//!
+//! The region holds a global epoch counter followed by one word per participant. A participant
+//! announces the epoch it reads in with [`Epoch::read`] and leaves the read side with
+//! [`Epoch::quiesce`]. [`Epoch::advance`] moves the global epoch forward once every participant
+//! is either quiescent or reading in the current epoch, sleeping on the participant words with
+//! FUTEX_WAIT until the stragglers quiesce.
+//!
+//! This is the scheme crossbeam uses for deferred reclamation: an object unlinked while the
+//! global epoch is `e` can no longer be reached by any reader once the global epoch is `e + 2`.
+
+use crate::futex_word::FutexWord;
+use libc::c_void;
+
+use std::mem;
+use std::sync::atomic::Ordering::SeqCst;
+
+/// The participant slot is free, zeroed memory starts here
+const FREE: u32 = 0;
+/// The slot belongs to a participant
+const CLAIMED: u32 = 1;
+/// The participant is reading in the epoch stored in the upper bits
+const ACTIVE: u32 = 2;
+/// An advancer sleeps on the word until the participant quiesces
+const WAITING: u32 = 4;
+const EPOCH_SHIFT: u32 = 3;
+
+#[repr(C)]
+struct Header {
+    global: FutexWord,
+    participants: u32,
+}
+
+/// A participant in an epoch region
+/// Every process, or thread, using the region joins it and gets its own participant slot. The
+/// slot is given back when the Epoch is dropped.
+pub struct Epoch {
+    pub epoch: *mut c_void,
+    header: *const Header,
+    local: *const FutexWord,
+}
+
+unsafe impl Send for Epoch {}
+
+impl Epoch {
+    /// Returns the number of bytes needed for `participants` participants
+    pub fn memory_requirements(participants: usize) -> usize {
+        mem::size_of::<Header>() + participants * mem::size_of::<FutexWord>()
+    }
+
+    /// Lays out an epoch region at epoch 0 and joins it
+    /// Only one process initializes the region, the others [`Epoch::join`] it.
+    /// # Arguments
+    /// * `epoch` - A pointer to memory_requirements(participants) bytes, 4 bytes aligned
+    /// * `participants` - The number of participant slots
+    /// # Returns
+    /// The Epoch of the first participant, or None if there are no slots
+    pub fn init(epoch: *mut c_void, participants: usize) -> Option<Self> {
+        let header = epoch as *mut Header;
+        unsafe {
+            header.write(Header {
+                global: FutexWord::new(0),
+                participants: participants as u32,
+            });
+            let slots = header.add(1) as *mut FutexWord;
+            for index in 0..participants {
+                slots.add(index).write(FutexWord::new(FREE));
+            }
+        }
+        Self::join(epoch)
+    }
+
+    /// Joins an epoch region laid out by [`Epoch::init`]
+    /// # Arguments
+    /// * `epoch` - A pointer to the start of the region
+    /// # Returns
+    /// The Epoch, quiescent, or None if every participant slot is taken
+    pub fn join(epoch: *mut c_void) -> Option<Self> {
+        assert!(
+            (epoch as usize).is_multiple_of(mem::align_of::<Header>()),
+            "epoch memory is not 4 bytes aligned"
+        );
+        let header = epoch as *const Header;
+        let slots = unsafe { header.add(1) } as *const FutexWord;
+        let participants = unsafe { (*header).participants } as usize;
+        (0..participants)
+            .map(|index| unsafe { slots.add(index) })
+            .find(|slot| {
+                unsafe { &**slot }
+                    .as_atomic()
+                    .compare_exchange(FREE, CLAIMED, SeqCst, SeqCst)
+                    .is_ok()
+            })
+            .map(|local| Self {
+                epoch,
+                header,
+                local,
+            })
+    }
+
+    /// Returns the global epoch
+    pub fn current(&self) -> u32 {
+        self.global().load(SeqCst)
+    }
+
+    /// Enters the read side in the current global epoch
+    /// Objects reachable now stay valid until the matching [`Epoch::quiesce`]. Reading again
+    /// without quiescing moves the participant to the current epoch, waking an advancer that
+    /// waited for the old one.
+    /// # Returns
+    /// The epoch the participant reads in
+    pub fn read(&self) -> u32 {
+        let local = self.local().as_atomic();
+        loop {
+            let epoch = self.current();
+            let previous = local.swap((epoch << EPOCH_SHIFT) | ACTIVE | CLAIMED, SeqCst);
+            // The new word has no WAITING bit, the advancer would not hear of the change
+            if previous & WAITING != 0 {
+                let _ = self.local().as_futex().post(i32::MAX as u32);
+            }
+            // An advance in between would not have waited for us, announce the new epoch
+            if self.current() == epoch {
+                return epoch;
+            }
+        }
+    }
+
+    /// Leaves the read side and wakes an advancer waiting for this participant
+    pub fn quiesce(&self) {
+        let previous = self.local().as_atomic().swap(CLAIMED, SeqCst);
+        if previous & WAITING != 0 {
+            let _ = self.local().as_futex().post(i32::MAX as u32);
+        }
+    }
+
+    /// Returns true if the participant is on the read side
+    pub fn is_reading(&self) -> bool {
+        self.local().load(SeqCst) & ACTIVE != 0
+    }
+
+    /// Moves the global epoch forward by one
+    /// Sleeps until every participant reading in an older epoch has quiesced. A participant
+    /// must not advance while it reads itself in an older epoch, it would wait for itself.
+    /// # Returns
+    /// The new global epoch
+    pub fn advance(&self) -> u32 {
+        let epoch = self.current();
+        for slot in self.slots() {
+            let word = slot.as_atomic();
+            loop {
+                let value = word.load(SeqCst);
+                if value & ACTIVE == 0 || epoch_of(value) == epoch_of(epoch << EPOCH_SHIFT) {
+                    break;
+                }
+                if value & WAITING == 0
+                    && word
+                        .compare_exchange(value, value | WAITING, SeqCst, SeqCst)
+                        .is_err()
+                {
+                    continue;
+                }
+                let _ = slot.as_futex().wait(value | WAITING);
+            }
+        }
+        // Another advancer may have moved it already, both wanted the same epoch
+        let _ = self.global().as_atomic().compare_exchange(
+            epoch,
+            epoch.wrapping_add(1),
+            SeqCst,
+            SeqCst,
+        );
+        self.current()
+    }
+
+    fn global(&self) -> &FutexWord {
+        unsafe { &(*self.header).global }
+    }
+
+    fn local(&self) -> &FutexWord {
+        unsafe { &*self.local }
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &FutexWord> {
+        let slots = unsafe { self.header.add(1) } as *const FutexWord;
+        let participants = unsafe { (*self.header).participants } as usize;
+        (0..participants).map(move |index| unsafe { &*slots.add(index) })
+    }
+}
+
+impl Drop for Epoch {
+    fn drop(&mut self) {
+        self.quiesce();
+        self.local().as_atomic().store(FREE, SeqCst);
+    }
+}
+
+/// The epoch bits of a participant word
+fn epoch_of(value: u32) -> u32 {
+    value >> EPOCH_SHIFT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::AtomicBool;
+    use std::{thread, time};
+
+    #[test]
+    fn test_advance_without_readers() {
+        let mut memory = vec![0u32; Epoch::memory_requirements(2) / 4];
+        let ptr = memory.as_mut_ptr() as *mut c_void;
+        let first = Epoch::init(ptr, 2).unwrap();
+        let second = Epoch::join(ptr).unwrap();
+        assert!(Epoch::join(ptr).is_none());
+
+        assert_eq!(first.current(), 0);
+        assert_eq!(first.advance(), 1);
+        // Reading in the current epoch does not hold the advance back
+        assert_eq!(second.read(), 1);
+        assert!(second.is_reading());
+        assert_eq!(first.advance(), 2);
+        second.quiesce();
+        assert!(!second.is_reading());
+
+        // Dropping gives the slot back
+        drop(second);
+        assert!(Epoch::join(ptr).is_some());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_advance_waits_for_quiescence() {
+        let len = Epoch::memory_requirements(4);
+        let segment = ShmSegment::create("test_epoch_quiescence", len);
+        let reader = Epoch::init(segment.as_ptr(), 4).unwrap();
+        assert_eq!(reader.read(), 0);
+        reader.advance();
+        let advanced = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mapping = segment.map_again();
+                let writer = Epoch::join(mapping.as_ptr()).unwrap();
+                // The reader is still in epoch 0, this sleeps until it quiesces
+                assert_eq!(writer.advance(), 2);
+                advanced.store(true, SeqCst);
+            });
+            thread::sleep(time::Duration::from_millis(100));
+            assert!(!advanced.load(SeqCst));
+            reader.quiesce();
+        });
+
+        assert!(advanced.load(SeqCst));
+        assert_eq!(reader.current(), 2);
+        drop(reader);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_reading_again_wakes_a_waiting_advancer() {
+        let mut memory = vec![0u32; Epoch::memory_requirements(2) / 4];
+        let ptr = memory.as_mut_ptr() as *mut c_void;
+        let reader = Epoch::init(ptr, 2).unwrap();
+        let writer = Epoch::join(ptr).unwrap();
+        assert_eq!(reader.read(), 0);
+        assert_eq!(reader.advance(), 1);
+
+        thread::scope(|s| {
+            let advancer = s.spawn(move || writer.advance());
+            while reader.local().load(SeqCst) & WAITING == 0 {
+                thread::yield_now();
+            }
+            // Pins the reader again without quiescing, in epoch 2 if the woken advancer
+            // moved on before read() checked the epoch again
+            assert!(reader.read() >= 1);
+            assert_eq!(advancer.join().unwrap(), 2);
+        });
+        assert!(reader.is_reading());
+        reader.quiesce();
+    }
+}
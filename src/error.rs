@@ -0,0 +1,272 @@
+//! Errors returned by the fallible futex operations
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    /// The futex word is not aligned to 4 bytes, the kernel would reject it with EINVAL
+    Misaligned,
+    /// EAGAIN: the futex word did not hold the expected value
+    WouldBlock,
+    /// ETIMEDOUT: the timeout expired before the operation completed
+    TimedOut,
+    /// EINTR: a signal interrupted the wait
+    Interrupted,
+    /// EFAULT: the futex word or the timeout points outside the address space
+    Fault,
+    /// EINVAL: the kernel rejected the arguments or the operation
+    Invalid,
+    /// ENOSYS: the operation is not implemented by the running kernel
+    NoSys,
+    /// The running kernel does not provide a deprecated or optional operation
+    NotSupported,
+    /// Any other errno
+    Os(i32),
+}
+
+impl FutexError {
+    /// Maps an errno returned by futex(2) to a FutexError
+    /// # Arguments
+    /// * `errno` - The errno value
+    /// # Returns
+    /// The matching variant, Os for the uncommon ones
+    pub fn from_errno(errno: i32) -> Self {
+        match errno {
+            libc::EAGAIN => FutexError::WouldBlock,
+            libc::ETIMEDOUT => FutexError::TimedOut,
+            libc::EINTR => FutexError::Interrupted,
+            libc::EFAULT => FutexError::Fault,
+            libc::EINVAL => FutexError::Invalid,
+            libc::ENOSYS => FutexError::NoSys,
+            errno => FutexError::Os(errno),
+        }
+    }
+
+    /// Captures the current errno, must be called right after the failing syscall
+    #[cfg_attr(miri, allow(dead_code))]
+    pub(crate) fn last_os_error() -> Self {
+        Self::from_errno(io::Error::last_os_error().raw_os_error().unwrap_or(0))
+    }
+}
+
+impl fmt::Display for FutexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FutexError::Misaligned => write!(f, "futex word is not 4 bytes aligned"),
+            FutexError::WouldBlock => write!(f, "futex word does not hold the expected value"),
+            FutexError::TimedOut => write!(f, "futex operation timed out"),
+            FutexError::Interrupted => write!(f, "futex wait interrupted by a signal"),
+            FutexError::Fault => write!(f, "futex address is not accessible"),
+            FutexError::Invalid => write!(f, "invalid futex operation or argument"),
+            FutexError::NoSys => write!(f, "futex operation not supported by the kernel"),
+            FutexError::NotSupported => write!(f, "futex operation not available on this kernel"),
+            FutexError::Os(errno) => write!(f, "futex syscall failed with errno {}", errno),
+        }
+    }
+}
+
+impl std::error::Error for FutexError {}
+
+/// Error of the higher level operations: locks, channels and handles
+/// A FutexError converts into it with `?`, and it converts into an io::Error that keeps the
+/// errno where there is one.
+#[derive(Debug)]
+pub enum RufutexError {
+    /// The operation would have to block
+    WouldBlock,
+    /// The timeout expired before the operation completed
+    TimedOut,
+    /// A signal interrupted the wait
+    Interrupted,
+    /// The pointer is null, misaligned or outside the address space
+    InvalidPointer,
+    /// The calling thread does not own the lock
+    NotOwner,
+    /// A holder panicked with the lock held
+    Poisoned,
+    /// The owner of the lock died with the lock held
+    OwnerDied,
+    /// The other side is gone
+    Closed,
+    /// A [`CancelToken`](crate::cancel::CancelToken) was cancelled while waiting
+    Cancelled,
+    /// A system call failed
+    Syscall {
+        /// The operation that failed, like "futex" or "mmap"
+        operation: &'static str,
+        /// The error of the system call, with its errno
+        error: io::Error,
+    },
+}
+
+impl RufutexError {
+    /// Captures the current errno as the error of `operation`
+    /// # Arguments
+    /// * `operation` - The system call that failed
+    /// # Returns
+    /// A Syscall error, must be built right after the failing call
+    pub fn last_os_error(operation: &'static str) -> Self {
+        RufutexError::Syscall {
+            operation,
+            error: io::Error::last_os_error(),
+        }
+    }
+
+    /// Returns the errno behind the error, if any
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            RufutexError::WouldBlock => Some(libc::EAGAIN),
+            RufutexError::TimedOut => Some(libc::ETIMEDOUT),
+            RufutexError::Interrupted => Some(libc::EINTR),
+            RufutexError::NotOwner => Some(libc::EPERM),
+            RufutexError::OwnerDied => Some(libc::EOWNERDEAD),
+            RufutexError::Cancelled => Some(libc::ECANCELED),
+            RufutexError::Syscall { error, .. } => error.raw_os_error(),
+            RufutexError::InvalidPointer | RufutexError::Poisoned | RufutexError::Closed => None,
+        }
+    }
+}
+
+impl fmt::Display for RufutexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RufutexError::WouldBlock => write!(f, "operation would block"),
+            RufutexError::TimedOut => write!(f, "operation timed out"),
+            RufutexError::Interrupted => write!(f, "wait interrupted by a signal"),
+            RufutexError::InvalidPointer => write!(f, "invalid futex pointer"),
+            RufutexError::NotOwner => write!(f, "lock not owned by the calling thread"),
+            RufutexError::Poisoned => write!(f, "lock poisoned by a panicking holder"),
+            RufutexError::OwnerDied => write!(f, "lock owner died with the lock held"),
+            RufutexError::Closed => write!(f, "peer closed"),
+            RufutexError::Cancelled => write!(f, "wait cancelled"),
+            RufutexError::Syscall { operation, error } => {
+                write!(f, "{} failed: {}", operation, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RufutexError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RufutexError::Syscall { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<FutexError> for RufutexError {
+    fn from(err: FutexError) -> Self {
+        let errno = match err {
+            FutexError::WouldBlock => return RufutexError::WouldBlock,
+            FutexError::TimedOut => return RufutexError::TimedOut,
+            FutexError::Interrupted => return RufutexError::Interrupted,
+            FutexError::Misaligned | FutexError::Fault => return RufutexError::InvalidPointer,
+            FutexError::Invalid => libc::EINVAL,
+            FutexError::NoSys | FutexError::NotSupported => libc::ENOSYS,
+            FutexError::Os(errno) => errno,
+        };
+        RufutexError::Syscall {
+            operation: "futex",
+            error: io::Error::from_raw_os_error(errno),
+        }
+    }
+}
+
+impl From<RufutexError> for io::Error {
+    fn from(err: RufutexError) -> Self {
+        if let Some(errno) = err.raw_os_error() {
+            // Keeps the errno, the operation is lost
+            return io::Error::from_raw_os_error(errno);
+        }
+        let kind = match &err {
+            RufutexError::Syscall { error, .. } => error.kind(),
+            RufutexError::InvalidPointer => io::ErrorKind::InvalidInput,
+            RufutexError::Closed => io::ErrorKind::BrokenPipe,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_errno() {
+        assert_eq!(FutexError::from_errno(libc::EAGAIN), FutexError::WouldBlock);
+        assert_eq!(
+            FutexError::from_errno(libc::ETIMEDOUT),
+            FutexError::TimedOut
+        );
+        assert_eq!(FutexError::from_errno(libc::EINTR), FutexError::Interrupted);
+        assert_eq!(FutexError::from_errno(libc::EFAULT), FutexError::Fault);
+        assert_eq!(FutexError::from_errno(libc::EINVAL), FutexError::Invalid);
+        assert_eq!(FutexError::from_errno(libc::ENOSYS), FutexError::NoSys);
+        assert_eq!(
+            FutexError::from_errno(libc::EPERM),
+            FutexError::Os(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn test_rufutex_error_display() {
+        assert_eq!(RufutexError::TimedOut.to_string(), "operation timed out");
+        assert_eq!(
+            RufutexError::OwnerDied.to_string(),
+            "lock owner died with the lock held"
+        );
+        let err = RufutexError::Syscall {
+            operation: "mmap",
+            error: io::Error::from_raw_os_error(libc::ENOMEM),
+        };
+        assert!(err.to_string().starts_with("mmap failed: "));
+        assert!(err
+            .to_string()
+            .contains(&format!("os error {}", libc::ENOMEM)));
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn test_rufutex_error_conversions() {
+        let err = RufutexError::from(FutexError::Os(libc::EPERM));
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert!(err.to_string().starts_with("futex failed: "));
+        assert!(matches!(
+            RufutexError::from(FutexError::Misaligned),
+            RufutexError::InvalidPointer
+        ));
+
+        let io_err = io::Error::from(RufutexError::TimedOut);
+        assert_eq!(io_err.raw_os_error(), Some(libc::ETIMEDOUT));
+        assert_eq!(io_err.kind(), io::ErrorKind::TimedOut);
+        let io_err = io::Error::from(RufutexError::from(FutexError::Invalid));
+        assert_eq!(io_err.raw_os_error(), Some(libc::EINVAL));
+        let io_err = io::Error::from(RufutexError::Closed);
+        assert_eq!(io_err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(io_err.to_string(), "peer closed");
+    }
+
+    #[test]
+    fn test_question_mark() {
+        fn futex_op() -> Result<(), FutexError> {
+            Err(FutexError::WouldBlock)
+        }
+        fn high_level() -> Result<(), RufutexError> {
+            futex_op()?;
+            Ok(())
+        }
+        fn user_code() -> io::Result<()> {
+            high_level()?;
+            Ok(())
+        }
+        fn boxed() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            high_level()?;
+            Ok(())
+        }
+        assert_eq!(user_code().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(boxed().unwrap_err().to_string(), "operation would block");
+    }
+}
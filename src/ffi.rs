@@ -0,0 +1,225 @@
+//! C interface, enabled by the `ffi` feature
+//!
+//! The functions are exported unmangled from the cdylib and declared in `include/rufutex.h`, so
+//! C and C++ processes take part in the same locking protocol as Rust ones. A handle is created
+//! once per process with [`rufutex_new`]; lock, unlock, wait and wake never allocate. Panics are
+//! caught at the boundary and reported as [`RUFUTEX_ERR_PANIC`].
+
+use crate::error::FutexError;
+use crate::futex_op::FutexOp;
+use crate::rufutex::{validate_alignment, SharedFutex};
+use libc::{c_int, c_void};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+pub const RUFUTEX_OK: c_int = 0;
+pub const RUFUTEX_ERR_NULL: c_int = -1;
+pub const RUFUTEX_ERR_MISALIGNED: c_int = -2;
+pub const RUFUTEX_ERR_WOULD_BLOCK: c_int = -3;
+pub const RUFUTEX_ERR_TIMED_OUT: c_int = -4;
+pub const RUFUTEX_ERR_INTERRUPTED: c_int = -5;
+pub const RUFUTEX_ERR_FAULT: c_int = -6;
+pub const RUFUTEX_ERR_INVALID: c_int = -7;
+pub const RUFUTEX_ERR_NO_SYS: c_int = -8;
+pub const RUFUTEX_ERR_OS: c_int = -9;
+pub const RUFUTEX_ERR_PANIC: c_int = -10;
+pub const RUFUTEX_ERR_NOT_SUPPORTED: c_int = -11;
+
+/// Opaque handle given to C, `rufutex_t` in the header
+pub type RufutexHandle = SharedFutex;
+
+/// Maps a FutexError to the C error code
+fn error_code(err: FutexError) -> c_int {
+    match err {
+        FutexError::Misaligned => RUFUTEX_ERR_MISALIGNED,
+        FutexError::WouldBlock => RUFUTEX_ERR_WOULD_BLOCK,
+        FutexError::TimedOut => RUFUTEX_ERR_TIMED_OUT,
+        FutexError::Interrupted => RUFUTEX_ERR_INTERRUPTED,
+        FutexError::Fault => RUFUTEX_ERR_FAULT,
+        FutexError::Invalid => RUFUTEX_ERR_INVALID,
+        FutexError::NoSys => RUFUTEX_ERR_NO_SYS,
+        FutexError::NotSupported => RUFUTEX_ERR_NOT_SUPPORTED,
+        FutexError::Os(_) => RUFUTEX_ERR_OS,
+    }
+}
+
+/// Runs `f` on the handle, turning a null handle and panics into error codes
+unsafe fn with_handle(
+    handle: *const RufutexHandle,
+    f: impl FnOnce(&SharedFutex) -> c_int,
+) -> c_int {
+    let Some(futex) = handle.as_ref() else {
+        return RUFUTEX_ERR_NULL;
+    };
+    panic::catch_unwind(AssertUnwindSafe(|| f(futex))).unwrap_or(RUFUTEX_ERR_PANIC)
+}
+
+/// Creates a handle over a futex word
+/// # Arguments
+/// * `word` - A 4 bytes aligned word, usually in shared memory
+/// # Returns
+/// The handle, or NULL if the word is null or misaligned
+/// # Safety
+/// `word` must stay mapped until the handle is freed with [`rufutex_free`].
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_new(word: *mut c_void) -> *mut RufutexHandle {
+    if word.is_null() || validate_alignment(word).is_err() {
+        return ptr::null_mut();
+    }
+    panic::catch_unwind(|| Box::into_raw(Box::new(SharedFutex::new(word))))
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Frees a handle created by [`rufutex_new`], the futex word itself is left untouched
+/// # Safety
+/// `handle` must come from [`rufutex_new`] and not be used afterwards. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_free(handle: *mut RufutexHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Locks the futex
+/// # Returns
+/// RUFUTEX_OK once the lock is held
+/// # Safety
+/// `handle` must be NULL or a live handle from [`rufutex_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_lock(handle: *const RufutexHandle) -> c_int {
+    with_handle(handle, |futex| {
+        futex.lock();
+        RUFUTEX_OK
+    })
+}
+
+/// Tries to lock the futex without blocking
+/// # Returns
+/// RUFUTEX_OK if the lock was taken, RUFUTEX_ERR_WOULD_BLOCK if it is held
+/// # Safety
+/// `handle` must be NULL or a live handle from [`rufutex_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_try_lock(handle: *const RufutexHandle) -> c_int {
+    with_handle(handle, |futex| {
+        if futex.try_lock() {
+            RUFUTEX_OK
+        } else {
+            RUFUTEX_ERR_WOULD_BLOCK
+        }
+    })
+}
+
+/// Unlocks the futex and wakes one waiter
+/// # Safety
+/// `handle` must be NULL or a live handle from [`rufutex_new`] whose lock is held by the caller.
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_unlock(handle: *const RufutexHandle) -> c_int {
+    with_handle(handle, |futex| {
+        futex.unlock();
+        RUFUTEX_OK
+    })
+}
+
+/// Sleeps while the futex word holds `expected`
+/// # Returns
+/// RUFUTEX_OK when woken, RUFUTEX_ERR_WOULD_BLOCK if the word did not hold `expected`
+/// # Safety
+/// `handle` must be NULL or a live handle from [`rufutex_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_wait(handle: *const RufutexHandle, expected: u32) -> c_int {
+    with_handle(handle, |futex| {
+        match futex.futex(FutexOp::Wait {
+            expected,
+            timeout: None,
+        }) {
+            Ok(_) => RUFUTEX_OK,
+            Err(err) => error_code(err),
+        }
+    })
+}
+
+/// Sleeps while the futex word holds `expected`, at most `timeout_ns` nanoseconds
+/// # Returns
+/// RUFUTEX_OK when woken, RUFUTEX_ERR_TIMED_OUT when the timeout expired
+/// # Safety
+/// `handle` must be NULL or a live handle from [`rufutex_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_wait_timeout(
+    handle: *const RufutexHandle,
+    expected: u32,
+    timeout_ns: u64,
+) -> c_int {
+    with_handle(handle, |futex| {
+        let timeout = libc::timespec {
+            tv_sec: (timeout_ns / 1_000_000_000) as libc::time_t,
+            tv_nsec: (timeout_ns % 1_000_000_000) as libc::c_long,
+        };
+        match futex.futex(FutexOp::Wait {
+            expected,
+            timeout: Some(timeout),
+        }) {
+            Ok(_) => RUFUTEX_OK,
+            Err(err) => error_code(err),
+        }
+    })
+}
+
+/// Wakes up to `count` waiters
+/// # Returns
+/// The number of woken waiters, or a negative error code
+/// # Safety
+/// `handle` must be NULL or a live handle from [`rufutex_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_wake(handle: *const RufutexHandle, count: u32) -> c_int {
+    with_handle(handle, |futex| match futex.futex(FutexOp::Wake { count }) {
+        Ok(woken) => woken as c_int,
+        Err(err) => error_code(err),
+    })
+}
+
+/// Reads the futex word
+/// # Safety
+/// `handle` must be a live handle from [`rufutex_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rufutex_value(handle: *const RufutexHandle) -> u32 {
+    (*handle).get_futex_value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_ffi_lock_cycle() {
+        let word = AtomicU32::new(0);
+        unsafe {
+            let handle = rufutex_new(&word as *const AtomicU32 as *mut c_void);
+            assert!(!handle.is_null());
+            assert_eq!(rufutex_lock(handle), RUFUTEX_OK);
+            assert_eq!(rufutex_try_lock(handle), RUFUTEX_ERR_WOULD_BLOCK);
+            assert_eq!(rufutex_value(handle), 1);
+            assert_eq!(rufutex_unlock(handle), RUFUTEX_OK);
+            assert_eq!(word.load(Ordering::SeqCst), 0);
+            assert_eq!(rufutex_wait(handle, 5), RUFUTEX_ERR_WOULD_BLOCK);
+            assert_eq!(
+                rufutex_wait_timeout(handle, 0, 1_000_000),
+                RUFUTEX_ERR_TIMED_OUT
+            );
+            assert_eq!(rufutex_wake(handle, 1), 0);
+            rufutex_free(handle);
+        }
+    }
+
+    #[test]
+    fn test_ffi_rejects_bad_words() {
+        let words = [AtomicU32::new(0), AtomicU32::new(0)];
+        unsafe {
+            assert!(rufutex_new(ptr::null_mut()).is_null());
+            let misaligned = (words.as_ptr() as *mut u8).add(1) as *mut c_void;
+            assert!(rufutex_new(misaligned).is_null());
+            assert_eq!(rufutex_lock(ptr::null()), RUFUTEX_ERR_NULL);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Open addressing hash map living in shared memory
+//!
+//! The region starts with a header holding a [`RawSharedRwLock`] and the size of the table,
+//! followed by the array of slots. Lookups take the read lock and insertions the write lock, so
+//! any number of processes mapping the region can use it as a shared cache.
+
+use crate::rwlock::RawSharedRwLock;
+use libc::c_void;
+
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+
+/// State of a slot that never held an entry, zeroed memory starts here
+const EMPTY: u32 = 0;
+/// State of a slot holding an entry
+const FULL: u32 = 1;
+
+#[repr(C)]
+struct Header {
+    lock: RawSharedRwLock,
+    capacity: u64,
+    len: UnsafeCell<u64>,
+}
+
+#[repr(C)]
+struct Slot<K, V> {
+    state: UnsafeCell<u32>,
+    key: UnsafeCell<MaybeUninit<K>>,
+    value: UnsafeCell<MaybeUninit<V>>,
+}
+
+/// Error returned by [`SharedFlatHashMap::insert`] when every slot holds another key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapFullError;
+
+impl fmt::Display for MapFullError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shared flat hash map is full")
+    }
+}
+
+impl Error for MapFullError {}
+
+/// A fixed capacity hash map in shared memory
+/// Keys and values are plain `Copy` data since they are visible from every process mapping the
+/// region and never dropped; values are returned by copy. Keys are hashed with FNV-1a over
+/// their `Hash` implementation, which is stable across processes running the same build.
+/// Entries cannot be removed.
+pub struct SharedFlatHashMap<K: Copy + PartialEq + Hash, V: Copy + PartialEq> {
+    pub map: *mut c_void,
+    header: *const Header,
+    slots: *const Slot<K, V>,
+    _entry: PhantomData<(K, V)>,
+}
+
+unsafe impl<K: Copy + PartialEq + Hash + Send, V: Copy + PartialEq + Send> Send
+    for SharedFlatHashMap<K, V>
+{
+}
+unsafe impl<K: Copy + PartialEq + Hash + Send + Sync, V: Copy + PartialEq + Send + Sync> Sync
+    for SharedFlatHashMap<K, V>
+{
+}
+
+impl<K: Copy + PartialEq + Hash, V: Copy + PartialEq> SharedFlatHashMap<K, V> {
+    /// Returns the number of bytes needed for a map of `capacity` entries
+    pub fn memory_requirements(capacity: usize) -> usize {
+        Self::slots_offset() + capacity * mem::size_of::<Slot<K, V>>()
+    }
+
+    /// Lays out an empty map in the memory
+    /// Only one process initializes the region, the others [`SharedFlatHashMap::attach`] to it.
+    /// # Arguments
+    /// * `map` - A pointer to memory_requirements(capacity) bytes, 8 bytes aligned and aligned
+    ///   for the keys and values
+    /// * `capacity` - The number of entries the map can hold
+    /// # Returns
+    /// A new SharedFlatHashMap
+    pub fn init(map: *mut c_void, capacity: usize) -> Self {
+        let shared_map = Self::from_ptr(map);
+        unsafe {
+            (shared_map.header as *mut Header).write(Header {
+                lock: RawSharedRwLock::new(),
+                capacity: capacity as u64,
+                len: UnsafeCell::new(0),
+            });
+            for index in 0..capacity {
+                *(*shared_map.slots.add(index)).state.get() = EMPTY;
+            }
+        }
+        shared_map
+    }
+
+    /// Uses a map initialized by [`SharedFlatHashMap::init`], possibly in another process
+    /// # Arguments
+    /// * `map` - A pointer to the start of the region
+    /// # Returns
+    /// A SharedFlatHashMap over the existing entries
+    pub fn attach(map: *mut c_void) -> Self {
+        Self::from_ptr(map)
+    }
+
+    fn from_ptr(map: *mut c_void) -> Self {
+        assert!(
+            (map as usize)
+                .is_multiple_of(mem::align_of::<Header>().max(mem::align_of::<Slot<K, V>>())),
+            "map memory is not aligned for its header and slots"
+        );
+        Self {
+            map,
+            header: map as *const Header,
+            slots: unsafe { (map as *const u8).add(Self::slots_offset()) } as *const Slot<K, V>,
+            _entry: PhantomData,
+        }
+    }
+
+    /// The slots follow the header, aligned for a slot
+    fn slots_offset() -> usize {
+        mem::size_of::<Header>().next_multiple_of(mem::align_of::<Slot<K, V>>())
+    }
+
+    /// Returns the number of entries the map can hold
+    pub fn capacity(&self) -> usize {
+        self.header().capacity as usize
+    }
+
+    /// Returns the number of entries
+    pub fn len(&self) -> usize {
+        let header = self.header();
+        header.lock.read_lock();
+        let len = unsafe { *header.len.get() };
+        header.lock.read_unlock();
+        len as usize
+    }
+
+    /// Returns true if the map holds no entry
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a copy of the value stored under `key`
+    /// # Arguments
+    /// * `key` - The key to look up
+    pub fn get(&self, key: &K) -> Option<V> {
+        let header = self.header();
+        header.lock.read_lock();
+        let value = self
+            .find_slot(key)
+            .filter(|slot| unsafe { *slot.state.get() } == FULL)
+            .map(|slot| unsafe { (*slot.value.get()).assume_init() });
+        header.lock.read_unlock();
+        value
+    }
+
+    /// Stores `value` under `key`
+    /// # Arguments
+    /// * `key` - The key
+    /// * `value` - The value
+    /// # Returns
+    /// The value previously stored under the key, or MapFullError if the key is new and every
+    /// slot is taken
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, MapFullError> {
+        let header = self.header();
+        header.lock.write_lock();
+        let ret = match self.find_slot(&key) {
+            None => Err(MapFullError),
+            Some(slot) => unsafe {
+                let previous = if *slot.state.get() == FULL {
+                    Some((*slot.value.get()).assume_init())
+                } else {
+                    (*slot.key.get()).write(key);
+                    *slot.state.get() = FULL;
+                    *header.len.get() += 1;
+                    None
+                };
+                (*slot.value.get()).write(value);
+                Ok(previous)
+            },
+        };
+        header.lock.write_unlock();
+        ret
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    /// Linear probing from the home slot of the key, called with the lock held
+    /// Returns the slot holding the key or the empty slot ending the probe sequence.
+    fn find_slot(&self, key: &K) -> Option<&Slot<K, V>> {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return None;
+        }
+        let mut hasher = FnvHasher::new();
+        key.hash(&mut hasher);
+        let home = (hasher.finish() % capacity as u64) as usize;
+        for probe in 0..capacity {
+            let slot = unsafe { &*self.slots.add((home + probe) % capacity) };
+            if unsafe { *slot.state.get() } == EMPTY {
+                return Some(slot);
+            }
+            if unsafe { (*slot.key.get()).assume_init_ref() } == key {
+                return Some(slot);
+            }
+        }
+        None
+    }
+}
+
+/// 64 bit FNV-1a, unlike the std hasher it gives the same hash in every process
+struct FnvHasher(u64);
+
+impl FnvHasher {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::thread;
+
+    #[test]
+    fn test_insert_get() {
+        let len = SharedFlatHashMap::<u32, u64>::memory_requirements(4);
+        let mut memory = vec![0u64; len.div_ceil(8)];
+        let map = SharedFlatHashMap::<u32, u64>::init(memory.as_mut_ptr() as *mut c_void, 4);
+        assert_eq!(map.capacity(), 4);
+        assert!(map.is_empty());
+        assert_eq!(map.get(&1), None);
+
+        assert_eq!(map.insert(1, 10), Ok(None));
+        assert_eq!(map.insert(2, 20), Ok(None));
+        assert_eq!(map.insert(1, 11), Ok(Some(10)));
+        assert_eq!(map.get(&1), Some(11));
+        assert_eq!(map.get(&2), Some(20));
+        assert_eq!(map.len(), 2);
+
+        assert_eq!(map.insert(3, 30), Ok(None));
+        assert_eq!(map.insert(4, 40), Ok(None));
+        assert_eq!(map.insert(5, 50), Err(MapFullError));
+        // A full table still finds and updates its keys
+        assert_eq!(map.get(&5), None);
+        assert_eq!(map.insert(4, 41), Ok(Some(40)));
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_map_across_mappings() {
+        let len = SharedFlatHashMap::<u64, u64>::memory_requirements(256);
+        let segment = &ShmSegment::create("test_shared_flat_hash_map", len);
+        let map = SharedFlatHashMap::<u64, u64>::init(segment.as_ptr(), 256);
+
+        thread::scope(|s| {
+            for writer in 0..4u64 {
+                s.spawn(move || {
+                    let mapping = segment.map_again();
+                    let map = SharedFlatHashMap::<u64, u64>::attach(mapping.as_ptr());
+                    for key in (writer * 50)..(writer * 50 + 50) {
+                        assert_eq!(map.insert(key, key * 2), Ok(None));
+                        assert_eq!(map.get(&key), Some(key * 2));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(map.len(), 200);
+        for key in 0..200 {
+            assert_eq!(map.get(&key), Some(key * 2));
+        }
+    }
+}
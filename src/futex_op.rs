@@ -0,0 +1,431 @@
+//! Typed futex operations
+//!
+//! Each futex(2) operation interprets `val`, `timeout`/`val2`, `uaddr2` and `val3` differently.
+//! [`FutexOp`] carries exactly the arguments an operation needs and
+//! [`SharedFutex::futex`](crate::rufutex::SharedFutex::futex) lays them out for the syscall.
+
+use crate::rufutex::SharedFutex;
+use crate::sys;
+
+/// A futex(2) operation with the arguments it takes, for [`SharedFutex::futex`]
+pub enum FutexOp<'a> {
+    /// FUTEX_WAIT: sleep while the futex word holds `expected`.
+    /// `timeout` is relative, None sleeps until woken.
+    Wait {
+        expected: u32,
+        timeout: Option<libc::timespec>,
+    },
+    /// FUTEX_WAKE: wake up to `count` waiters
+    Wake { count: u32 },
+    /// FUTEX_REQUEUE: wake up to `wake` waiters and move up to `limit` of the remaining ones to
+    /// wait on `target` instead
+    Requeue {
+        wake: u32,
+        target: &'a SharedFutex,
+        limit: u32,
+    },
+    /// FUTEX_CMP_REQUEUE: like Requeue, but fails with EAGAIN unless the futex word still holds
+    /// `expected`
+    CmpRequeue {
+        wake: u32,
+        target: &'a SharedFutex,
+        limit: u32,
+        expected: u32,
+    },
+    /// FUTEX_WAIT_BITSET: sleep while the futex word holds `expected`, only wakes with a
+    /// matching `mask` release the waiter. `deadline` is an absolute CLOCK_MONOTONIC time.
+    WaitBitset {
+        expected: u32,
+        mask: u32,
+        deadline: Option<libc::timespec>,
+    },
+    /// FUTEX_WAIT_BITSET with FUTEX_CLOCK_REALTIME and every bit of the mask: sleep while the
+    /// futex word holds `expected`. `deadline` is an absolute CLOCK_REALTIME time.
+    WaitRealtime {
+        expected: u32,
+        deadline: libc::timespec,
+    },
+    /// FUTEX_WAKE_BITSET: wake up to `count` waiters whose mask shares a bit with `mask`
+    WakeBitset { count: u32, mask: u32 },
+    /// FUTEX_LOCK_PI: take the priority inheritance lock whose word holds the owner's thread
+    /// id, boosting the owner while waiting. `deadline` is an absolute CLOCK_REALTIME time.
+    LockPi { deadline: Option<libc::timespec> },
+    /// FUTEX_UNLOCK_PI: hand the priority inheritance lock to its highest priority waiter
+    UnlockPi,
+    /// FUTEX_WAIT_REQUEUE_PI: sleep while the futex word holds `expected` until a CmpRequeuePi
+    /// moves the waiter to the priority inheritance lock `target` and the lock is granted.
+    /// `deadline` is an absolute CLOCK_MONOTONIC time.
+    WaitRequeuePi {
+        expected: u32,
+        target: &'a SharedFutex,
+        deadline: Option<libc::timespec>,
+    },
+    /// FUTEX_CMP_REQUEUE_PI: grant `target` to one waiter if it is free and move up to `limit`
+    /// of the others to wait on it, failing with EAGAIN unless the futex word holds `expected`
+    CmpRequeuePi {
+        target: &'a SharedFutex,
+        limit: u32,
+        expected: u32,
+    },
+}
+
+/// Raw arguments of the futex syscall, in syscall order after the futex word itself
+pub(crate) struct FutexArgs {
+    pub op: i32,
+    pub val: u32,
+    pub timeout: *const libc::timespec,
+    pub val2: u32,
+    pub uaddr2: *mut libc::c_void,
+    pub val3: u32,
+}
+
+impl FutexOp<'_> {
+    /// Lays out the operation as syscall arguments
+    /// The timespecs are borrowed from the operation, which must outlive the syscall.
+    pub(crate) fn args(&self) -> FutexArgs {
+        let mut args = FutexArgs {
+            op: 0,
+            val: 0,
+            timeout: std::ptr::null(),
+            val2: 0,
+            uaddr2: std::ptr::null_mut(),
+            val3: 0,
+        };
+        match self {
+            FutexOp::Wait { expected, timeout } => {
+                args.op = sys::FUTEX_WAIT;
+                args.val = *expected;
+                args.timeout = timespec_ptr(timeout);
+            }
+            FutexOp::Wake { count } => {
+                args.op = sys::FUTEX_WAKE;
+                args.val = saturate(*count);
+            }
+            FutexOp::Requeue {
+                wake,
+                target,
+                limit,
+            } => {
+                args.op = sys::FUTEX_REQUEUE;
+                args.val = saturate(*wake);
+                args.val2 = saturate(*limit);
+                args.uaddr2 = target.futex;
+            }
+            FutexOp::CmpRequeue {
+                wake,
+                target,
+                limit,
+                expected,
+            } => {
+                args.op = sys::FUTEX_CMP_REQUEUE;
+                args.val = saturate(*wake);
+                args.val2 = saturate(*limit);
+                args.uaddr2 = target.futex;
+                args.val3 = *expected;
+            }
+            FutexOp::WaitBitset {
+                expected,
+                mask,
+                deadline,
+            } => {
+                args.op = sys::FUTEX_WAIT_BITSET;
+                args.val = *expected;
+                args.timeout = timespec_ptr(deadline);
+                args.val3 = *mask;
+            }
+            FutexOp::WaitRealtime { expected, deadline } => {
+                // FUTEX_WAIT reads its timeout as relative even with FUTEX_CLOCK_REALTIME
+                args.op = sys::FUTEX_WAIT_BITSET | sys::FUTEX_CLOCK_REALTIME;
+                args.val = *expected;
+                args.timeout = deadline;
+                args.val3 = sys::FUTEX_BITSET_MATCH_ANY as u32;
+            }
+            FutexOp::WakeBitset { count, mask } => {
+                args.op = sys::FUTEX_WAKE_BITSET;
+                args.val = saturate(*count);
+                args.val3 = *mask;
+            }
+            FutexOp::LockPi { deadline } => {
+                args.op = sys::FUTEX_LOCK_PI;
+                args.timeout = timespec_ptr(deadline);
+            }
+            FutexOp::UnlockPi => {
+                args.op = sys::FUTEX_UNLOCK_PI;
+            }
+            FutexOp::WaitRequeuePi {
+                expected,
+                target,
+                deadline,
+            } => {
+                args.op = sys::FUTEX_WAIT_REQUEUE_PI;
+                args.val = *expected;
+                args.timeout = timespec_ptr(deadline);
+                args.uaddr2 = target.futex;
+            }
+            FutexOp::CmpRequeuePi {
+                target,
+                limit,
+                expected,
+            } => {
+                // The kernel only accepts waking a single waiter
+                args.op = sys::FUTEX_CMP_REQUEUE_PI;
+                args.val = 1;
+                args.val2 = saturate(*limit);
+                args.uaddr2 = target.futex;
+                args.val3 = *expected;
+            }
+        }
+        args
+    }
+
+    /// Whether the operation takes a timeout pointer rather than a numeric val2
+    pub(crate) fn has_timeout(&self) -> bool {
+        matches!(
+            self,
+            FutexOp::Wait { .. }
+                | FutexOp::WaitBitset { .. }
+                | FutexOp::WaitRealtime { .. }
+                | FutexOp::LockPi { .. }
+                | FutexOp::WaitRequeuePi { .. }
+        )
+    }
+}
+
+/// The kernel reads counts as a signed int, larger values would turn negative
+fn saturate(count: u32) -> u32 {
+    count.min(i32::MAX as u32)
+}
+
+fn timespec_ptr(timeout: &Option<libc::timespec>) -> *const libc::timespec {
+    match timeout {
+        Some(timeout) => timeout,
+        None => std::ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FutexError;
+    use crate::test_support::ShmSegment;
+    use std::{thread, time};
+
+    /// Returns the futexes at offsets 0 and 4 of `segment`
+    fn pair(segment: &ShmSegment) -> (SharedFutex, SharedFutex) {
+        let first = SharedFutex::new(segment.as_ptr());
+        let second = SharedFutex::new(unsafe { segment.as_ptr().add(4) });
+        (first, second)
+    }
+
+    /// Issues `op` until it reports one affected waiter
+    fn until_one(futex: &mut SharedFutex, op: impl Fn() -> FutexOp<'static>) {
+        loop {
+            if futex.futex(op()).unwrap() == 1 {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_wait_and_wake() {
+        let segment = ShmSegment::create("test_futex_op_wait_wake", 8);
+        let (mut futex, _) = pair(&segment);
+        futex.set_futex_value(0);
+        assert_eq!(futex.futex(FutexOp::Wake { count: 1 }).unwrap(), 0);
+
+        let mapping = segment.map_again();
+        let handle = thread::spawn(move || {
+            let (futex, _) = pair(&mapping);
+            futex.futex(FutexOp::Wait {
+                expected: 0,
+                timeout: None,
+            })
+        });
+        until_one(&mut futex, || FutexOp::Wake { count: u32::MAX });
+
+        assert_eq!(handle.join().unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_wait_timeout_and_mismatch() {
+        let segment = ShmSegment::create("test_futex_op_wait_timeout", 8);
+        let (futex, _) = pair(&segment);
+        futex.set_futex_value(0);
+
+        let err = futex
+            .futex(FutexOp::Wait {
+                expected: 0,
+                timeout: Some(libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: 10 * 1000 * 1000,
+                }),
+            })
+            .unwrap_err();
+        assert_eq!(err, FutexError::TimedOut);
+
+        let err = futex
+            .futex(FutexOp::Wait {
+                expected: 1,
+                timeout: None,
+            })
+            .unwrap_err();
+        assert_eq!(err, FutexError::WouldBlock);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_requeue() {
+        let segment = ShmSegment::create("test_futex_op_requeue", 8);
+        let (first, second) = pair(&segment);
+        first.set_futex_value(0);
+
+        let mapping = segment.map_again();
+        let handle = thread::spawn(move || {
+            let (first, _) = pair(&mapping);
+            first.futex(FutexOp::Wait {
+                expected: 0,
+                timeout: None,
+            })
+        });
+        // Move the waiter to the second word without waking it
+        loop {
+            let moved = first
+                .futex(FutexOp::Requeue {
+                    wake: 0,
+                    target: &second,
+                    limit: 1,
+                })
+                .unwrap();
+            if moved == 1 {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(first.futex(FutexOp::Wake { count: 1 }).unwrap(), 0);
+        assert_eq!(second.futex(FutexOp::Wake { count: 1 }).unwrap(), 1);
+
+        assert_eq!(handle.join().unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_cmp_requeue() {
+        let segment = ShmSegment::create("test_futex_op_cmp_requeue", 8);
+        let (first, second) = pair(&segment);
+        first.set_futex_value(0);
+
+        let err = first
+            .futex(FutexOp::CmpRequeue {
+                wake: 1,
+                target: &second,
+                limit: 1,
+                expected: 7,
+            })
+            .unwrap_err();
+        assert_eq!(err, FutexError::WouldBlock);
+
+        let mapping = segment.map_again();
+        let handle = thread::spawn(move || {
+            let (first, _) = pair(&mapping);
+            first.futex(FutexOp::Wait {
+                expected: 0,
+                timeout: None,
+            })
+        });
+        // Wakes count as well as requeues in the result
+        loop {
+            let affected = first
+                .futex(FutexOp::CmpRequeue {
+                    wake: 1,
+                    target: &second,
+                    limit: 1,
+                    expected: 0,
+                })
+                .unwrap();
+            if affected == 1 {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+
+        assert_eq!(handle.join().unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_bitset() {
+        let segment = ShmSegment::create("test_futex_op_bitset", 8);
+        let (mut futex, _) = pair(&segment);
+        futex.set_futex_value(0);
+
+        let mapping = segment.map_again();
+        let handle = thread::spawn(move || {
+            let (futex, _) = pair(&mapping);
+            futex.futex(FutexOp::WaitBitset {
+                expected: 0,
+                mask: 0b01,
+                deadline: None,
+            })
+        });
+        thread::sleep(time::Duration::from_millis(100));
+        // The waiter is not interested in this channel
+        assert_eq!(
+            futex
+                .futex(FutexOp::WakeBitset {
+                    count: 1,
+                    mask: 0b10
+                })
+                .unwrap(),
+            0
+        );
+        until_one(&mut futex, || FutexOp::WakeBitset {
+            count: 1,
+            mask: 0b01,
+        });
+
+        assert_eq!(handle.join().unwrap().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_private_futex() {
+        let mut word = std::sync::atomic::AtomicU32::new(0);
+        let mut futex =
+            SharedFutex::new(&mut word as *mut std::sync::atomic::AtomicU32 as *mut libc::c_void);
+        futex.set_private(true);
+        let err = futex
+            .futex(FutexOp::Wait {
+                expected: 0,
+                timeout: Some(libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: 1000,
+                }),
+            })
+            .unwrap_err();
+        assert_eq!(err, FutexError::TimedOut);
+        assert_eq!(futex.futex(FutexOp::Wake { count: 1 }).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_timeout_pointer_keeps_its_width() {
+        let op = FutexOp::Wait {
+            expected: 0,
+            timeout: Some(libc::timespec {
+                tv_sec: 1,
+                tv_nsec: 0,
+            }),
+        };
+        let FutexOp::Wait {
+            timeout: Some(timeout),
+            ..
+        } = &op
+        else {
+            unreachable!()
+        };
+        let args = op.args();
+        assert_eq!(args.timeout, timeout as *const libc::timespec);
+        assert_eq!(args.timeout as usize as *const libc::timespec, args.timeout);
+    }
+}
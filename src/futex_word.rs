@@ -0,0 +1,196 @@
+//! A futex word that can be embedded as a field of a `#[repr(C)]` shared memory layout
+
+use crate::rufutex::{SharedFutex, SharedFutexRef};
+use crate::UNLOCKED;
+
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// A 4 bytes, 4 aligned futex word
+/// It has the same layout as a C `uint32_t`, so structs shared with C can declare the lock as a
+/// plain field instead of carving pointers out of the mapping by hand.
+#[repr(transparent)]
+pub struct FutexWord(AtomicU32);
+
+const _: () = assert!(mem::size_of::<FutexWord>() == 4);
+const _: () = assert!(mem::align_of::<FutexWord>() == 4);
+
+impl FutexWord {
+    /// Create a new FutexWord
+    /// # Arguments
+    /// * `initial` - The initial value of the word
+    /// # Returns
+    /// A new FutexWord
+    pub const fn new(initial: u32) -> Self {
+        Self(AtomicU32::new(initial))
+    }
+
+    /// Create a new unlocked FutexWord
+    /// # Returns
+    /// A FutexWord ready to be used as a mutex
+    pub const fn unlocked() -> Self {
+        Self::new(UNLOCKED)
+    }
+
+    /// Get a SharedFutex over this word
+    /// # Returns
+    /// A SharedFutexRef borrowing the word
+    pub fn as_futex(&self) -> SharedFutexRef<'_> {
+        SharedFutex::from_atomic(&self.0)
+    }
+
+    /// The word as an atomic
+    pub fn as_atomic(&self) -> &AtomicU32 {
+        &self.0
+    }
+
+    /// Loads the value of the word
+    /// # Arguments
+    /// * `order` - The memory ordering of the load
+    /// # Returns
+    /// The current value
+    pub fn load(&self, order: Ordering) -> u32 {
+        self.0.load(order)
+    }
+
+    /// Locks the word as a mutex, see [`SharedFutex::lock`]
+    pub fn lock(&self) {
+        self.as_futex().lock();
+    }
+
+    /// Tries to lock the word without blocking, see [`SharedFutex::try_lock`]
+    /// # Returns
+    /// true if the lock was acquired
+    #[must_use = "if false the lock was not acquired"]
+    pub fn try_lock(&self) -> bool {
+        self.as_futex().try_lock()
+    }
+
+    /// Unlocks the word, see [`SharedFutex::unlock`]
+    pub fn unlock(&self) {
+        self.as_futex().unlock();
+    }
+
+    /// Unlocks the word and wakes up to `how_may_waiters` waiters, see
+    /// [`SharedFutex::unlock_many`]
+    /// # Arguments
+    /// * `how_may_waiters` - The number of waiters to wake up
+    /// # Returns
+    /// The number of waiters woken
+    pub fn unlock_many(&self, how_may_waiters: u32) -> usize {
+        self.as_futex().unlock_many(how_may_waiters)
+    }
+}
+
+impl Default for FutexWord {
+    fn default() -> Self {
+        Self::unlocked()
+    }
+}
+
+/// The word lives inside the mutex, so unlike [`SharedFutex`] a FutexWord has a real INIT and
+/// `lock_api::Mutex::new` and `const_new` work, for in-process locks or for a mutex placed
+/// whole in a mapping shared between processes of the same build.
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawMutex for FutexWord {
+    const INIT: Self = Self::unlocked();
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        FutexWord::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        FutexWord::try_lock(self)
+    }
+
+    unsafe fn unlock(&self) {
+        FutexWord::unlock(self);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.load(Ordering::Relaxed) != UNLOCKED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use crate::LOCKED_NO_WAITERS;
+    use std::thread;
+
+    #[repr(C)]
+    struct Header {
+        lock: FutexWord,
+        len: u32,
+    }
+
+    const _: () = assert!(mem::size_of::<Header>() == 8);
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_word_in_shared_struct() {
+        let segment = ShmSegment::create("test_futex_word_struct", 8);
+        let header = segment.as_ptr() as usize;
+        unsafe {
+            (header as *mut Header).write(Header {
+                lock: FutexWord::unlocked(),
+                len: 0,
+            });
+        }
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                thread::spawn(move || {
+                    let header = unsafe { &mut *(header as *mut Header) };
+                    for _ in 0..1000 {
+                        header.lock.lock();
+                        let len = unsafe { std::ptr::read_volatile(&header.len) };
+                        unsafe { std::ptr::write_volatile(&mut header.len, len + 1) };
+                        header.lock.unlock();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let header = unsafe { &*(header as *const Header) };
+        assert_eq!(header.len, 2000);
+        assert_eq!(header.lock.load(Ordering::SeqCst), UNLOCKED);
+    }
+
+    #[test]
+    fn test_word_try_lock() {
+        let word = FutexWord::default();
+        assert!(word.try_lock());
+        assert_eq!(word.load(Ordering::SeqCst), LOCKED_NO_WAITERS);
+        assert!(!word.try_lock());
+        word.unlock();
+        assert_eq!(word.load(Ordering::SeqCst), UNLOCKED);
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn test_lock_api_mutex_new() {
+        static COUNTER: lock_api::Mutex<FutexWord, u32> =
+            lock_api::Mutex::const_new(FutexWord::unlocked(), 0);
+        let mutex = lock_api::Mutex::<FutexWord, u32>::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *mutex.lock() += 1;
+                        *COUNTER.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*mutex.lock(), 4000);
+        assert_eq!(*COUNTER.lock(), 4000);
+        assert!(!mutex.is_locked());
+    }
+}
@@ -0,0 +1,135 @@
+//! A futex owning the POSIX shared memory segment it lives in
+
+use crate::error::FutexError;
+use crate::rufutex::SharedFutex;
+use crate::UNLOCKED;
+use rushm::posixaccessor::POSIXShm;
+
+use std::ops::Deref;
+
+/// A SharedFutex at the start of a named POSIX shared memory segment
+/// The segment stays mapped for as long as the handle lives, so the futex cannot outlive its
+/// memory. Dropping the handle unmaps the segment but keeps the name, other processes can go on
+/// using it; [`FutexHandle::unlink`] removes it once nobody needs it anymore.
+/// The handle dereferences to the SharedFutex for the other operations.
+pub struct FutexHandle {
+    futex: SharedFutex,
+    /// Taken by unlink(), which closes the segment itself
+    shm: Option<POSIXShm<i32>>,
+}
+
+impl FutexHandle {
+    /// Opens the segment `shm_name` and initializes an unlocked futex in it
+    /// Only one process creates the futex, the others [`FutexHandle::attach`] to it.
+    /// # Arguments
+    /// * `shm_name` - The name of the shared memory segment
+    /// # Returns
+    /// The handle, or the error of the shared memory calls
+    pub fn create(shm_name: &str) -> Result<Self, FutexError> {
+        let handle = Self::attach(shm_name)?;
+        handle.futex.set_futex_value(UNLOCKED);
+        Ok(handle)
+    }
+
+    /// Opens the segment `shm_name` and uses the futex already in it
+    /// # Arguments
+    /// * `shm_name` - The name of the shared memory segment
+    /// # Returns
+    /// The handle, or the error of the shared memory calls
+    pub fn attach(shm_name: &str) -> Result<Self, FutexError> {
+        let mut shm =
+            POSIXShm::<i32>::new(shm_name.to_string(), SharedFutex::memory_requirements());
+        if unsafe { shm.open() }.is_err() {
+            return Err(FutexError::last_os_error());
+        }
+        let futex = SharedFutex::new(shm.get_cptr_mut());
+        Ok(Self {
+            futex,
+            shm: Some(shm),
+        })
+    }
+
+    /// Locks the futex, see [`SharedFutex::lock`]
+    pub fn lock(&self) {
+        self.futex.lock();
+    }
+
+    /// Unlocks the futex, see [`SharedFutex::unlock`]
+    pub fn unlock(&self) {
+        self.futex.unlock();
+    }
+
+    /// Unmaps the segment and removes its name
+    /// Processes that still map it keep working, new ones cannot open it anymore.
+    pub fn unlink(mut self) {
+        if let Some(mut shm) = self.shm.take() {
+            unsafe {
+                let _ = shm.close(true);
+            }
+        }
+    }
+}
+
+impl Deref for FutexHandle {
+    type Target = SharedFutex;
+
+    fn deref(&self) -> &SharedFutex {
+        &self.futex
+    }
+}
+
+impl Drop for FutexHandle {
+    fn drop(&mut self) {
+        if let Some(mut shm) = self.shm.take() {
+            unsafe {
+                let _ = shm.close(false);
+            }
+        }
+    }
+}
+
+impl SharedFutex {
+    /// Opens the segment `shm_name` and returns a handle owning it with the futex at its start
+    /// The futex is used as it is, see [`FutexHandle::create`] to initialize it.
+    /// # Arguments
+    /// * `shm_name` - The name of the shared memory segment
+    /// # Returns
+    /// The handle, or the error of the shared memory calls
+    pub fn owned_handle(shm_name: &str) -> Result<FutexHandle, FutexError> {
+        FutexHandle::attach(shm_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_handles_share_the_futex() {
+        let handle = FutexHandle::create("test_futex_handle").unwrap();
+        let counter = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    // Every thread opens the segment on its own, like separate processes would
+                    let handle = SharedFutex::owned_handle("test_futex_handle").unwrap();
+                    for _ in 0..1000 {
+                        handle.lock();
+                        let value = counter.load(SeqCst);
+                        thread::yield_now();
+                        counter.store(value + 1, SeqCst);
+                        handle.unlock();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(counter.load(SeqCst), 4000);
+        assert_eq!(handle.get_futex_value(), UNLOCKED);
+        handle.unlink();
+    }
+}
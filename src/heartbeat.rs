@@ -0,0 +1,165 @@
+//! Liveness reporting through a futex word
+//!
+//! A process holding shared locks can hang without crashing, leaving the others blocked forever.
+//! A Heartbeat thread keeps bumping a counter and a timestamp next to it, and watchers in other
+//! processes check how old the last beat is.
+//!
+//! Layout, starting at the futex word and 8 bytes aligned:
+//! `[counter: u32][padding: u32][last beat: u64 milliseconds of CLOCK_MONOTONIC]`
+
+use crate::rufutex::SharedFutex;
+
+use std::mem;
+use std::sync::atomic::{
+    AtomicBool, AtomicU64,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Counter value stored when the heartbeat is stopped on purpose, the counter wraps before it
+const STOPPED: u32 = u32::MAX;
+/// Offset of the timestamp from the futex word
+const TIMESTAMP_OFFSET: usize = 8;
+
+/// Periodically updates a futex word to tell other processes this one is alive
+pub struct Heartbeat;
+
+/// Handle of a running heartbeat thread, stops it when dropped
+pub struct HeartbeatHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Returns the number of bytes needed by the counter and the timestamp
+    pub fn memory_requirements() -> usize {
+        TIMESTAMP_OFFSET + mem::size_of::<u64>()
+    }
+
+    /// Starts a thread beating every `interval`
+    /// Every beat increments the counter, refreshes the timestamp and wakes threads waiting on
+    /// the futex word.
+    /// # Arguments
+    /// * `futex` - The SharedFutex, 8 bytes aligned, followed by the timestamp
+    /// * `interval` - The time between beats
+    /// # Returns
+    /// The handle to stop the heartbeat
+    pub fn start(futex: SharedFutex, interval: Duration) -> HeartbeatHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Acquire) {
+                Self::beat(&futex);
+                thread::park_timeout(interval);
+            }
+            futex.set_futex_value_with_ordering(STOPPED, Release);
+            let _ = futex.post(i32::MAX as u32);
+        });
+        HeartbeatHandle {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Checks whether the heartbeat on the futex word is recent enough
+    /// # Arguments
+    /// * `futex` - The SharedFutex the heartbeat updates
+    /// * `max_age` - The oldest acceptable beat
+    /// # Returns
+    /// false if the heartbeat never started, was stopped or its last beat is older than max_age
+    pub fn is_alive(futex: &SharedFutex, max_age: Duration) -> bool {
+        if futex.get_futex_value_with_ordering(Acquire) == STOPPED {
+            return false;
+        }
+        let last = Self::timestamp(futex).load(Acquire);
+        if last == 0 {
+            return false;
+        }
+        monotonic_ms().saturating_sub(last) <= max_age.as_millis() as u64
+    }
+
+    /// Returns the number of beats so far, modulo u32::MAX
+    /// # Arguments
+    /// * `futex` - The SharedFutex the heartbeat updates
+    pub fn beats(futex: &SharedFutex) -> u32 {
+        futex.get_futex_value_with_ordering(Relaxed)
+    }
+
+    fn beat(futex: &SharedFutex) {
+        Self::timestamp(futex).store(monotonic_ms(), Release);
+        let count = futex.get_futex_value_with_ordering(Relaxed);
+        futex.set_futex_value_with_ordering(count.wrapping_add(1) % STOPPED, Release);
+        let _ = futex.post(i32::MAX as u32);
+    }
+
+    fn timestamp(futex: &SharedFutex) -> &AtomicU64 {
+        unsafe { &*((futex.futex as *mut u8).add(TIMESTAMP_OFFSET) as *const AtomicU64) }
+    }
+}
+
+impl HeartbeatHandle {
+    /// Stops the heartbeat thread and marks the heartbeat as stopped
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Release);
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for HeartbeatHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Milliseconds of CLOCK_MONOTONIC, which is the same clock for every process of the system
+fn monotonic_ms() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1000 + now.tv_nsec as u64 / 1_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_heartbeat_start_stop() {
+        let segment = ShmSegment::create("test_heartbeat", Heartbeat::memory_requirements());
+        let watcher = SharedFutex::new(segment.as_ptr());
+        assert!(!Heartbeat::is_alive(&watcher, Duration::from_secs(1)));
+
+        let handle = Heartbeat::start(watcher.clone(), Duration::from_millis(10));
+        while Heartbeat::beats(&watcher) < 3 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(Heartbeat::is_alive(&watcher, Duration::from_millis(500)));
+
+        handle.stop();
+        assert!(!Heartbeat::is_alive(&watcher, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_heartbeat_goes_stale() {
+        let mut memory = [0u64; 2];
+        let futex = SharedFutex::new(memory.as_mut_ptr() as *mut libc::c_void);
+        Heartbeat::beat(&futex);
+        assert_eq!(Heartbeat::beats(&futex), 1);
+        assert!(Heartbeat::is_alive(&futex, Duration::from_millis(500)));
+        thread::sleep(Duration::from_millis(30));
+        assert!(!Heartbeat::is_alive(&futex, Duration::from_millis(10)));
+    }
+}
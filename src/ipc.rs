@@ -0,0 +1,231 @@
+//! Passing futex handles between processes over Unix domain sockets
+//!
+//! The file descriptor backing a futex (typically a `memfd_create` descriptor) is sent with
+//! SCM_RIGHTS, so the peer maps the very same memory without agreeing on a POSIX shm name.
+
+use crate::rufutex::SharedFutex;
+use libc::c_void;
+
+use std::io::{self, Read};
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+/// Longest name that can accompany a handle
+pub const MAX_NAME_LEN: usize = u8::MAX as usize;
+
+/// The received descriptors are closed on exec where recvmsg(2) can do it atomically
+#[cfg(target_os = "linux")]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const RECV_FLAGS: libc::c_int = 0;
+
+/// Sends the file descriptor of a futex mapping to the peer
+/// The message carries the descriptor and a name identifying the futex.
+/// # Arguments
+/// * `sock` - A connected Unix stream socket
+/// * `fd` - The descriptor of the memory holding the futex word at offset 0
+/// * `name` - A name for the futex, at most MAX_NAME_LEN bytes
+/// # Returns
+/// Ok once the message is sent
+pub fn send_futex_handle(sock: &UnixStream, fd: RawFd, name: &str) -> io::Result<()> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "futex name is too long",
+        ));
+    }
+    let mut payload = Vec::with_capacity(name.len() + 1);
+    payload.push(name.len() as u8);
+    payload.extend_from_slice(name.as_bytes());
+
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut c_void,
+        iov_len: payload.len(),
+    };
+    let mut control = ControlBuffer::new();
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr();
+    msg.msg_controllen = control.len() as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+
+        let sent = libc::sendmsg(sock.as_raw_fd(), &msg, libc::MSG_NOSIGNAL);
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // The descriptor went with the first byte, the rest is plain stream data
+        let sent = sent as usize;
+        if sent < payload.len() {
+            let mut sock = sock;
+            io::Write::write_all(&mut sock, &payload[sent..])?;
+        }
+    }
+    Ok(())
+}
+
+/// Receives a futex handle sent with [`send_futex_handle`]
+/// # Arguments
+/// * `sock` - A connected Unix stream socket
+/// # Returns
+/// A SharedFutex over the received memory, which it owns and unmaps on drop, and the received
+/// file descriptor
+pub fn recv_futex_handle(sock: &UnixStream) -> io::Result<(SharedFutex, OwnedFd)> {
+    let (futex, fd, _name) = recv_futex_handle_with_name(sock)?;
+    Ok((futex, fd))
+}
+
+/// Receives a futex handle sent with [`send_futex_handle`] together with its name
+/// # Arguments
+/// * `sock` - A connected Unix stream socket
+/// # Returns
+/// The SharedFutex, the received file descriptor and the name given by the sender
+pub fn recv_futex_handle_with_name(
+    sock: &UnixStream,
+) -> io::Result<(SharedFutex, OwnedFd, String)> {
+    // Only the length byte travels with the descriptor, the name is read afterwards
+    let mut payload = [0u8; MAX_NAME_LEN + 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut c_void,
+        iov_len: 1,
+    };
+    let mut control = ControlBuffer::new();
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr();
+    msg.msg_controllen = control.len() as _;
+
+    let received = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, RECV_FLAGS) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut fd = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let raw = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+                fd = Some(OwnedFd::from_raw_fd(raw));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    let fd = fd.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "message carries no descriptor")
+    })?;
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "descriptor message truncated",
+        ));
+    }
+
+    let name_len = payload[0] as usize;
+    let mut sock = sock;
+    sock.read_exact(&mut payload[1..1 + name_len])?;
+    let name = String::from_utf8(payload[1..1 + name_len].to_vec())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let futex = map_futex_fd(fd.as_fd())?;
+    Ok((futex, fd, name))
+}
+
+/// Maps a file descriptor and returns a SharedFutex over its first word
+/// The whole file is mapped MAP_SHARED and unmapped when the SharedFutex is dropped.
+/// # Arguments
+/// * `fd` - The descriptor, its size must cover at least the futex word
+/// # Returns
+/// The SharedFutex or the error of fstat/mmap
+pub fn map_futex_fd(fd: BorrowedFd<'_>) -> io::Result<SharedFutex> {
+    SharedFutex::from_fd(fd, 0)
+}
+
+/// Control message buffer for one descriptor, aligned for cmsghdr
+struct ControlBuffer {
+    buf: [u64; 8],
+}
+
+impl ControlBuffer {
+    fn new() -> Self {
+        Self { buf: [0; 8] }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut c_void {
+        self.buf.as_mut_ptr() as *mut c_void
+    }
+
+    fn len(&self) -> usize {
+        unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn memfd(name: &str, len: usize) -> OwnedFd {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            let fd = libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC);
+            assert!(fd >= 0);
+            assert_eq!(libc::ftruncate(fd, len as libc::off_t), 0);
+            OwnedFd::from_raw_fd(fd)
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_send_recv_handle() {
+        let fd = memfd("test_send_recv_handle", 4096);
+        let local = map_futex_fd(fd.as_fd()).unwrap();
+        local.set_futex_value(42);
+
+        let (left, right) = UnixStream::pair().unwrap();
+        send_futex_handle(&left, fd.as_raw_fd(), "counter").unwrap();
+        let (remote, _fd, name) = recv_futex_handle_with_name(&right).unwrap();
+
+        assert_eq!(name, "counter");
+        assert_ne!(remote.futex, local.futex);
+        assert_eq!(remote.get_futex_value(), 42);
+        remote.set_futex_value(7);
+        assert_eq!(local.get_futex_value(), 7);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_name_too_long() {
+        let fd = memfd("test_name_too_long", 4096);
+        let (left, _right) = UnixStream::pair().unwrap();
+        let name = "x".repeat(MAX_NAME_LEN + 1);
+        let err = send_futex_handle(&left, fd.as_raw_fd(), &name).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_clone_keeps_mapping_alive() {
+        let fd = memfd("test_clone_keeps_mapping", 4096);
+        let futex = map_futex_fd(fd.as_fd()).unwrap();
+        let clone = futex.clone();
+        assert_eq!(clone.futex, futex.futex);
+
+        drop(futex);
+        // The mapping is shared, the clone still points to mapped memory
+        clone.set_futex_value(3);
+        assert_eq!(clone.get_futex_value(), 3);
+    }
+}
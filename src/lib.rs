@@ -3,9 +3,97 @@
 //!
 //! [`rufutex`]: https://github.com/yangosoft/rufutex
 //! YangoSoft
+//!
+//! With the `lock_api` feature [`rufutex::SharedFutex`] and [`std_adapter::StdMutexAdapter`]
+//! implement `lock_api::RawMutex`, but they only point at a futex word and have no usable
+//! `INIT`: `lock_api::Mutex::new` and `const_new` do not compile with them, the mutex is built
+//! with `lock_api::Mutex::from_raw` over a word in shared memory. [`futex_word::FutexWord`]
+//! owns its word and works with `lock_api::Mutex::new`.
 
+pub mod anonymous;
+#[cfg(all(feature = "async", not(loom)))]
+pub mod async_lock;
+pub mod atomic_futex;
+pub mod backend;
+pub mod builder;
+#[cfg(not(loom))]
+pub mod cancel;
+#[cfg(not(loom))]
+pub mod condition_set;
+#[cfg(not(loom))]
+pub mod condvar;
+#[cfg(not(loom))]
+pub mod counting_lock;
+pub mod deadline;
+#[cfg(not(loom))]
+pub mod deque;
+#[cfg(not(loom))]
+pub mod epoch;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(not(loom))]
+pub mod flat_hash_map;
+pub mod futex_op;
+#[cfg(not(loom))]
+pub mod futex_word;
+pub mod handle;
+pub mod heartbeat;
+pub mod ipc;
+#[cfg(not(loom))]
+pub mod mailbox;
+mod mapping;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(not(loom))]
+pub mod once;
+#[cfg(not(loom))]
+pub mod oneshot;
+pub mod park;
+#[cfg(not(loom))]
+pub mod parking_table;
+#[cfg(not(loom))]
+pub mod pi;
+#[cfg(all(target_os = "linux", not(loom)))]
+pub mod posix_sem;
+#[cfg(not(loom))]
+pub mod priority_mutex;
+#[cfg(all(feature = "pthread-interop", target_os = "linux", not(loom)))]
+pub mod pthread_interop;
+#[cfg(not(loom))]
+pub mod registry;
 pub mod rufutex;
+#[cfg(not(loom))]
+pub mod rwlock;
+#[cfg(not(loom))]
+pub mod semaphore;
+#[cfg(not(loom))]
+pub mod seqlock;
+pub mod shared_stats;
+#[cfg(not(loom))]
+pub mod shm_channel;
+#[cfg(not(loom))]
+mod sleepers;
+pub mod spin_wait;
+#[cfg(not(loom))]
+pub mod spsc_ring;
+pub mod std_adapter;
+mod sync;
+mod sys;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod thread_local_futex;
+#[cfg(all(feature = "tokio", not(loom)))]
+pub mod tokio_sync;
+mod trace;
+#[cfg(all(feature = "io_uring", target_os = "linux", not(loom)))]
+pub mod uring;
+#[cfg(not(loom))]
+pub mod wait_group;
+#[cfg(not(loom))]
+pub mod watch;
 
 const UNLOCKED: u32 = 0;
 const LOCKED_NO_WAITERS: u32 = 1;
 const LOCKED_WAITERS: u32 = 2;
+const HANDED_OFF: u32 = 3;
@@ -0,0 +1,327 @@
+//! Single slot mailbox holding the latest value
+//!
+//! The state word is EMPTY, FULL or WRITING. A put or a take claims the slot by moving the
+//! state to WRITING, copies the value in or out and publishes the new state, so a taker never
+//! sees half of a put. Takers sleep on the state word while it is EMPTY, and puts and takes
+//! finding it WRITING spin a while and then sleep until the copy is published. Publishing a
+//! state wakes the sleepers; without sleepers neither side makes a system call.
+
+use crate::deadline::Deadline;
+use crate::futex_word::FutexWord;
+use crate::sleepers;
+
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::fmt;
+use std::hint;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::time::{Duration, Instant};
+
+const EMPTY: u32 = 0;
+const FULL: u32 = 1;
+/// A put or a take is copying the value
+const WRITING: u32 = 2;
+/// Checks of a WRITING state before sleeping until the copy is published
+const WRITING_SPINS: u32 = 100;
+
+/// What a put does to a value nobody took yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum MailboxMode {
+    /// The new value replaces it
+    #[default]
+    Overwrite = 0,
+    /// The put fails and hands its value back
+    Reject = 1,
+}
+
+/// Error returned by a put on a full mailbox in [`MailboxMode::Reject`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxFullError<T>(pub T);
+
+impl<T> fmt::Display for MailboxFullError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shared mailbox is full")
+    }
+}
+
+impl<T: fmt::Debug> Error for MailboxFullError<T> {}
+
+/// Cross-process single slot mailbox
+/// The layout is the state word, the count of its sleepers, the mode and then `T`, aligned
+/// for `T`, so one mapping of [`SharedMailbox::required_size`] bytes holds the whole mailbox.
+/// Zeroed memory is an empty mailbox in [`MailboxMode::Overwrite`].
+#[repr(C)]
+pub struct SharedMailbox<T: Copy> {
+    state: FutexWord,
+    sleepers: AtomicU32,
+    mode: u32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Copy + Send> Send for SharedMailbox<T> {}
+unsafe impl<T: Copy + Send> Sync for SharedMailbox<T> {}
+
+impl<T: Copy> SharedMailbox<T> {
+    /// Create a new SharedMailbox
+    /// # Arguments
+    /// * `mode` - What a put does to a value nobody took yet
+    /// # Returns
+    /// An empty SharedMailbox
+    pub const fn new(mode: MailboxMode) -> Self {
+        Self {
+            state: FutexWord::new(EMPTY),
+            sleepers: AtomicU32::new(0),
+            mode: mode as u32,
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the number of bytes the mailbox and its value take in shared memory
+    pub const fn required_size() -> usize {
+        mem::size_of::<Self>()
+    }
+
+    /// Returns the mode the mailbox was created with
+    pub fn mode(&self) -> MailboxMode {
+        match self.mode {
+            0 => MailboxMode::Overwrite,
+            _ => MailboxMode::Reject,
+        }
+    }
+
+    /// Returns true if a value waits to be taken
+    pub fn is_full(&self) -> bool {
+        self.state.load(SeqCst) == FULL
+    }
+
+    /// Stores a value and wakes the sleeping takers
+    /// # Arguments
+    /// * `value` - The value to store
+    /// # Returns
+    /// Ok, or MailboxFullError with the value if the mailbox is full and rejects puts
+    pub fn put(&self, value: T) -> Result<(), MailboxFullError<T>> {
+        let reject = self.mode() == MailboxMode::Reject;
+        let mut state = self.state.load(SeqCst);
+        loop {
+            match state {
+                FULL if reject => return Err(MailboxFullError(value)),
+                WRITING => {
+                    self.wait_for_copy(Deadline::never());
+                    state = self.state.load(SeqCst);
+                    continue;
+                }
+                _ => {}
+            }
+            match self
+                .state
+                .as_atomic()
+                .compare_exchange_weak(state, WRITING, SeqCst, SeqCst)
+            {
+                Ok(_) => break,
+                Err(current) => state = current,
+            }
+        }
+        // WRITING excludes every other put and take
+        unsafe { (*self.value.get()).write(value) };
+        self.publish(FULL);
+        Ok(())
+    }
+
+    /// Takes the value, sleeping until there is one
+    /// # Returns
+    /// The latest value put, the mailbox is empty again
+    pub fn take(&self) -> T {
+        // Without a deadline it only returns with a value
+        self.take_until(Deadline::never()).unwrap()
+    }
+
+    /// Takes the value without sleeping
+    /// # Returns
+    /// The latest value put, or None if the mailbox is empty
+    pub fn try_take(&self) -> Option<T> {
+        self.take_until(Deadline::at(Instant::now()))
+    }
+
+    /// Takes the value, sleeping at most `timeout` until there is one
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for a value
+    /// # Returns
+    /// The latest value put, or None if the timeout expired
+    pub fn take_timeout(&self, timeout: Duration) -> Option<T> {
+        self.take_until(Deadline::after(timeout))
+    }
+
+    fn take_until(&self, deadline: Deadline) -> Option<T> {
+        let mut state = self.state.load(SeqCst);
+        loop {
+            match state {
+                FULL => match self
+                    .state
+                    .as_atomic()
+                    .compare_exchange_weak(FULL, WRITING, SeqCst, SeqCst)
+                {
+                    Ok(_) => break,
+                    Err(current) => state = current,
+                },
+                WRITING => {
+                    if !self.wait_for_copy(deadline) {
+                        return None;
+                    }
+                    state = self.state.load(SeqCst);
+                }
+                _ => {
+                    if deadline.has_passed() {
+                        return None;
+                    }
+                    // Returns at once when a put landed in between
+                    let slept = sleepers::sleep(&self.sleepers, || {
+                        deadline.wait(&self.state.as_futex(), EMPTY)
+                    });
+                    if !slept {
+                        return None;
+                    }
+                    state = self.state.load(SeqCst);
+                }
+            }
+        }
+        // FULL means a put initialized the value, WRITING keeps the others out
+        let value = unsafe { (*self.value.get()).assume_init() };
+        self.publish(EMPTY);
+        Some(value)
+    }
+
+    /// Spins a while on the put or take copying the value, then sleeps until it publishes
+    /// # Arguments
+    /// * `deadline` - When to stop waiting
+    /// # Returns
+    /// false if the deadline passed first
+    fn wait_for_copy(&self, deadline: Deadline) -> bool {
+        for _ in 0..WRITING_SPINS {
+            if self.state.load(SeqCst) != WRITING {
+                return true;
+            }
+            hint::spin_loop();
+        }
+        // Returns at once when the copy was published in between
+        sleepers::sleep(&self.sleepers, || {
+            deadline.wait(&self.state.as_futex(), WRITING)
+        })
+    }
+
+    /// Ends a copy by storing `state` and wakes the sleepers
+    /// Takers waiting for a value and puts or takes waiting for the copy sleep on the same
+    /// word, so all of them are woken to check the new state.
+    fn publish(&self, state: u32) {
+        self.state.as_atomic().store(state, SeqCst);
+        if sleepers::any(&self.sleepers) {
+            let _ = self.state.as_futex().wake_all();
+        }
+    }
+}
+
+impl<T: Copy> Default for SharedMailbox<T> {
+    fn default() -> Self {
+        Self::new(MailboxMode::default())
+    }
+}
+
+impl<T: Copy> fmt::Debug for SharedMailbox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedMailbox")
+            .field("mode", &self.mode())
+            .field("full", &self.is_full())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::thread;
+
+    #[test]
+    fn test_modes() {
+        let mailbox = SharedMailbox::new(MailboxMode::Overwrite);
+        assert_eq!(mailbox.try_take(), None);
+        assert_eq!(mailbox.put(1), Ok(()));
+        assert_eq!(mailbox.put(2), Ok(()));
+        assert_eq!(mailbox.try_take(), Some(2));
+        assert_eq!(mailbox.try_take(), None);
+
+        let mailbox = SharedMailbox::new(MailboxMode::Reject);
+        assert_eq!(mailbox.put(1), Ok(()));
+        assert_eq!(mailbox.put(2), Err(MailboxFullError(2)));
+        assert_eq!(mailbox.try_take(), Some(1));
+        assert_eq!(mailbox.put(3), Ok(()));
+        assert_eq!(mailbox.try_take(), Some(3));
+
+        let zeroed: [u64; 2] = [0; 2];
+        let mailbox = unsafe { &*(zeroed.as_ptr() as *const SharedMailbox<u32>) };
+        assert_eq!(mailbox.mode(), MailboxMode::Overwrite);
+        assert!(!mailbox.is_full());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_takers_only_see_the_latest_value_of_a_burst() {
+        let len = SharedMailbox::<u64>::required_size();
+        let segment = ShmSegment::create("test_shared_mailbox", len);
+        unsafe {
+            (segment.as_ptr() as *mut SharedMailbox<u64>)
+                .write(SharedMailbox::new(MailboxMode::Overwrite));
+        }
+        let mailbox = unsafe { segment.get::<SharedMailbox<u64>>() };
+
+        for burst in 1..=100u64 {
+            for value in 1..=burst {
+                assert_eq!(mailbox.put(burst * 1000 + value), Ok(()));
+            }
+            let mapping = segment.map_again();
+            let taker = thread::spawn(move || {
+                let mailbox = unsafe { mapping.get::<SharedMailbox<u64>>() };
+                (mailbox.take(), mailbox.try_take())
+            });
+            assert_eq!(taker.join().unwrap(), (burst * 1001, None));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_blocked_take_wakes_on_the_next_put() {
+        let mailbox = SharedMailbox::<u64>::default();
+        thread::scope(|s| {
+            let taker = s.spawn(|| mailbox.take());
+            while mailbox.sleepers.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            assert_eq!(mailbox.put(7), Ok(()));
+            assert_eq!(taker.join().unwrap(), 7);
+        });
+        assert_eq!(mailbox.take_timeout(Duration::from_millis(20)), None);
+        assert_eq!(mailbox.sleepers.load(SeqCst), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_copy_in_progress_puts_callers_to_sleep() {
+        let mailbox = SharedMailbox::<u64>::default();
+        // Stands for a peer descheduled in the middle of its copy
+        mailbox.state.as_atomic().store(WRITING, SeqCst);
+        assert_eq!(mailbox.try_take(), None);
+        assert_eq!(mailbox.take_timeout(Duration::from_millis(20)), None);
+
+        thread::scope(|s| {
+            let putter = s.spawn(|| mailbox.put(7));
+            while mailbox.sleepers.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            mailbox.publish(EMPTY);
+            assert_eq!(putter.join().unwrap(), Ok(()));
+        });
+        assert_eq!(mailbox.try_take(), Some(7));
+        assert_eq!(mailbox.sleepers.load(SeqCst), 0);
+    }
+}
@@ -0,0 +1,97 @@
+//! Shared memory mappings owned by futex handles
+
+use libc::c_void;
+
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd};
+use std::ptr;
+
+/// A MAP_SHARED mapping that is unmapped on drop
+pub(crate) struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+/// The mapping only hands out its address, munmap can be called from any thread
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Maps `len` bytes of a file descriptor read/write and shared
+    /// # Arguments
+    /// * `fd` - The file descriptor to map
+    /// * `len` - The number of bytes to map
+    /// # Returns
+    /// The mapping or the mmap error
+    pub(crate) fn map_fd(fd: BorrowedFd<'_>, len: usize) -> io::Result<Self> {
+        Self::map_fd_at(fd, 0, len)
+    }
+
+    /// Maps `len` bytes of a file descriptor starting at `offset`
+    /// # Arguments
+    /// * `fd` - The file descriptor to map
+    /// * `offset` - The file offset of the mapping, a multiple of the page size
+    /// * `len` - The number of bytes to map
+    /// # Returns
+    /// The mapping or the mmap error
+    pub(crate) fn map_fd_at(fd: BorrowedFd<'_>, offset: usize, len: usize) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                offset as libc::off_t,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    /// Maps `len` bytes of anonymous shared memory
+    /// The memory is zero filled and shared with the children forked afterwards.
+    /// # Arguments
+    /// * `len` - The number of bytes to map
+    /// # Returns
+    /// The mapping or the mmap error
+    pub(crate) fn anonymous(len: usize) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    /// Returns the size of a page
+    pub(crate) fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut c_void {
+        self.ptr
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Contention counters of a SharedFutex
+//!
+//! Only compiled with the `metrics` feature. The counters live in the SharedFutex handle, not in
+//! the shared memory, so they describe what this process did with the lock. Every update is a
+//! Relaxed atomic, the counters only need to be eventually consistent.
+
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Duration;
+
+/// A snapshot of the counters of a SharedFutex, see [`crate::rufutex::SharedFutex::metrics`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FutexMetrics {
+    /// Locks taken with lock() or try_lock()
+    pub acquisitions: u64,
+    /// Locks taken by lock() after finding the lock held
+    pub contended_acquisitions: u64,
+    /// FUTEX_WAIT operations issued
+    pub wait_calls: u64,
+    /// Time spent in lock() by the contended acquisitions
+    pub total_wait_ns: u64,
+    /// Longest time spent in lock() by a contended acquisition
+    pub max_wait_ns: u64,
+    /// FUTEX_WAKE operations issued
+    pub wake_calls: u64,
+    /// Waiters woken by unlock()
+    pub woken_waiters: u64,
+}
+
+/// The live counters
+/// Cloning a SharedFutex gives the clone its own counters, starting at zero.
+pub(crate) struct Counters {
+    acquisitions: AtomicU64,
+    contended_acquisitions: AtomicU64,
+    wait_calls: AtomicU64,
+    total_wait_ns: AtomicU64,
+    max_wait_ns: AtomicU64,
+    wake_calls: AtomicU64,
+    woken_waiters: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            acquisitions: AtomicU64::new(0),
+            contended_acquisitions: AtomicU64::new(0),
+            wait_calls: AtomicU64::new(0),
+            total_wait_ns: AtomicU64::new(0),
+            max_wait_ns: AtomicU64::new(0),
+            wake_calls: AtomicU64::new(0),
+            woken_waiters: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn acquired(&self) {
+        self.acquisitions.fetch_add(1, Relaxed);
+    }
+
+    /// Records a contended acquisition that spent `waited` in lock()
+    pub(crate) fn contended(&self, waited: Duration) {
+        let waited = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+        self.acquisitions.fetch_add(1, Relaxed);
+        self.contended_acquisitions.fetch_add(1, Relaxed);
+        self.total_wait_ns.fetch_add(waited, Relaxed);
+        self.max_wait_ns.fetch_max(waited, Relaxed);
+    }
+
+    pub(crate) fn wait_call(&self) {
+        self.wait_calls.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn wake_call(&self) {
+        self.wake_calls.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn woken(&self, waiters: usize) {
+        self.woken_waiters.fetch_add(waiters as u64, Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> FutexMetrics {
+        FutexMetrics {
+            acquisitions: self.acquisitions.load(Relaxed),
+            contended_acquisitions: self.contended_acquisitions.load(Relaxed),
+            wait_calls: self.wait_calls.load(Relaxed),
+            total_wait_ns: self.total_wait_ns.load(Relaxed),
+            max_wait_ns: self.max_wait_ns.load(Relaxed),
+            wake_calls: self.wake_calls.load(Relaxed),
+            woken_waiters: self.woken_waiters.load(Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.acquisitions.store(0, Relaxed);
+        self.contended_acquisitions.store(0, Relaxed);
+        self.wait_calls.store(0, Relaxed);
+        self.total_wait_ns.store(0, Relaxed);
+        self.max_wait_ns.store(0, Relaxed);
+        self.wake_calls.store(0, Relaxed);
+        self.woken_waiters.store(0, Relaxed);
+    }
+}
+
+impl Clone for Counters {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,132 @@
+//! One-time initialization shared between processes
+//!
+//! A SharedOnce is a single futex word placed in shared memory. The first caller of
+//! [`SharedOnce::call_once`] in any process runs the initializer while every other caller sleeps
+//! on the word until it completes.
+
+use crate::futex_word::FutexWord;
+
+use std::mem;
+use std::sync::atomic::Ordering::{Acquire, Release};
+
+/// Nobody ran the initializer yet, zeroed memory starts here
+const INCOMPLETE: u32 = 0;
+/// A thread is running the initializer
+const RUNNING: u32 = 1;
+/// The initializer has completed
+const COMPLETE: u32 = 2;
+
+/// Cross-process equivalent of `std::sync::Once`
+/// Zeroed memory is a valid, not yet completed SharedOnce.
+/// If a process dies while running the initializer the others wait forever, there is no owner
+/// tracking.
+#[repr(C)]
+pub struct SharedOnce {
+    state: FutexWord,
+}
+
+/// Resets the state if the initializer panics so another caller can retry
+struct ResetOnUnwind<'a>(&'a SharedOnce);
+
+impl Drop for ResetOnUnwind<'_> {
+    fn drop(&mut self) {
+        self.0.state.as_atomic().store(INCOMPLETE, Release);
+        let _ = self.0.state.as_futex().post(i32::MAX as u32);
+    }
+}
+
+impl SharedOnce {
+    /// Create a new SharedOnce
+    /// # Returns
+    /// A SharedOnce that has not run yet
+    pub const fn new() -> Self {
+        Self {
+            state: FutexWord::new(INCOMPLETE),
+        }
+    }
+
+    /// Returns true once an initializer has completed
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Acquire) == COMPLETE
+    }
+
+    /// Runs `f` if no initializer has completed yet, in this or any other process
+    /// Concurrent callers block until the running initializer completes. If it panics the
+    /// SharedOnce goes back to not run and one of the waiters runs its own initializer.
+    /// # Arguments
+    /// * `f` - The initializer
+    pub fn call_once(&self, f: impl FnOnce()) {
+        let atom = self.state.as_atomic();
+        let mut f = Some(f);
+        loop {
+            match atom.compare_exchange(INCOMPLETE, RUNNING, Acquire, Acquire) {
+                Ok(_) => {
+                    let reset = ResetOnUnwind(self);
+                    if let Some(f) = f.take() {
+                        f();
+                    }
+                    mem::forget(reset);
+                    atom.store(COMPLETE, Release);
+                    let _ = self.state.as_futex().post(i32::MAX as u32);
+                    return;
+                }
+                Err(COMPLETE) => return,
+                Err(_) => {
+                    // EAGAIN when the initializer completed meanwhile, the loop checks again
+                    let _ = self.state.as_futex().wait(RUNNING);
+                }
+            }
+        }
+    }
+}
+
+impl Default for SharedOnce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::panic;
+    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+    use std::{thread, time};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_call_once_runs_once() {
+        let segment = ShmSegment::create("test_shared_once", 8);
+        let once = unsafe { segment.get::<SharedOnce>() };
+        assert!(!once.is_completed());
+        let calls = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    once.call_once(|| {
+                        thread::sleep(time::Duration::from_millis(50));
+                        calls.fetch_add(1, SeqCst);
+                    });
+                    assert!(once.is_completed());
+                });
+            }
+        });
+
+        assert_eq!(calls.load(SeqCst), 1);
+    }
+
+    #[test]
+    fn test_panicking_initializer_allows_retry() {
+        let once = SharedOnce::new();
+        let ret = panic::catch_unwind(|| once.call_once(|| panic!("init failed")));
+        assert!(ret.is_err());
+        assert!(!once.is_completed());
+
+        let mut ran = false;
+        once.call_once(|| ran = true);
+        assert!(ran);
+        assert!(once.is_completed());
+    }
+}
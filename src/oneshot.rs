@@ -0,0 +1,206 @@
+//! One-shot completion signal on a futex word
+//!
+//! The state word carries a waiters flag, a claimed flag and a done flag, and the word after it
+//! the result code. [`SharedOneshot::complete_with`] claims the signal, stores the result and
+//! only then sets the done flag, so a waiter seeing it reads the final result. A waiter never
+//! sleeps once the flag is set: it checks the flag first, and the value it sleeps on lacks the
+//! flag, so FUTEX_WAIT fails at once against a completed word. Zeroed memory is a pending
+//! oneshot.
+
+use crate::deadline::Deadline;
+use crate::rufutex::SharedFutex;
+use libc::c_void;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, SeqCst};
+use std::time::Duration;
+
+/// A waiter sleeps on the word
+const WAITERS: u32 = 1;
+/// A completion is storing the result
+const CLAIMED: u32 = 2;
+/// The result is stored
+const DONE: u32 = 4;
+
+/// Error returned by the misuses of a oneshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OneshotError {
+    /// complete() was called a second time
+    AlreadyCompleted,
+}
+
+impl fmt::Display for OneshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OneshotError::AlreadyCompleted => write!(f, "oneshot completed twice"),
+        }
+    }
+}
+
+impl Error for OneshotError {}
+
+/// A one-shot completion signal shared between processes
+pub struct SharedOneshot {
+    state: SharedFutex,
+    result: SharedFutex,
+}
+
+impl SharedOneshot {
+    /// Create a new SharedOneshot
+    /// # Arguments
+    /// * `oneshot` - A pointer to two 4 bytes aligned words, zeroed before the first use
+    /// # Returns
+    /// A new SharedOneshot
+    pub fn new(oneshot: *mut c_void) -> Self {
+        Self {
+            state: SharedFutex::new(oneshot),
+            result: SharedFutex::new(oneshot.cast::<u32>().wrapping_add(1).cast()),
+        }
+    }
+
+    /// Returns true once the oneshot completed
+    pub fn is_completed(&self) -> bool {
+        self.state.get_futex_value_with_ordering(Acquire) & DONE != 0
+    }
+
+    /// Completes the oneshot with the result 0, waking every waiter
+    /// # Returns
+    /// Ok, or AlreadyCompleted if it completed before
+    pub fn complete(&self) -> Result<(), OneshotError> {
+        self.complete_with(0)
+    }
+
+    /// Completes the oneshot with a result code, waking every waiter
+    /// # Arguments
+    /// * `value` - The result code the waiters return
+    /// # Returns
+    /// Ok, or AlreadyCompleted if it completed before
+    pub fn complete_with(&self, value: u32) -> Result<(), OneshotError> {
+        let atom = self.state.as_atomic();
+        if atom.fetch_or(CLAIMED, SeqCst) & CLAIMED != 0 {
+            return Err(OneshotError::AlreadyCompleted);
+        }
+        // The done flag below publishes the result
+        self.result.as_atomic().store(value, Relaxed);
+        if atom.fetch_or(DONE, SeqCst) & WAITERS != 0 {
+            let _ = self.state.post(i32::MAX as u32);
+        }
+        Ok(())
+    }
+
+    /// Sleeps until the oneshot completes, returning at once if it already did
+    /// # Returns
+    /// The result code of the completion
+    pub fn wait(&self) -> u32 {
+        // Without a deadline it only returns completed
+        self.wait_until(Deadline::never()).unwrap()
+    }
+
+    /// Sleeps until the oneshot completes or `timeout` expires
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait
+    /// # Returns
+    /// The result code of the completion, or None if the timeout expired
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<u32> {
+        self.wait_until(Deadline::after(timeout))
+    }
+
+    fn wait_until(&self, deadline: Deadline) -> Option<u32> {
+        let atom = self.state.as_atomic();
+        let mut value = atom.load(SeqCst);
+        loop {
+            if value & DONE != 0 {
+                return Some(self.result.as_atomic().load(Relaxed));
+            }
+            if value & WAITERS == 0 {
+                if let Err(current) = atom.compare_exchange(value, value | WAITERS, SeqCst, SeqCst)
+                {
+                    value = current;
+                    continue;
+                }
+                value |= WAITERS;
+            }
+            if !deadline.wait(&self.state, value) {
+                return None;
+            }
+            value = atom.load(SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_double_complete() {
+        let mut words = [0u32; 2];
+        let oneshot = SharedOneshot::new(words.as_mut_ptr() as *mut c_void);
+        assert!(!oneshot.is_completed());
+        assert_eq!(oneshot.complete_with(42), Ok(()));
+        assert_eq!(oneshot.complete(), Err(OneshotError::AlreadyCompleted));
+        assert_eq!(
+            oneshot.complete_with(7),
+            Err(OneshotError::AlreadyCompleted)
+        );
+        // The failed completions kept the first result
+        assert!(oneshot.is_completed());
+        assert_eq!(oneshot.wait(), 42);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_waiters_before_and_after_completion() {
+        let segment = ShmSegment::create("test_shared_oneshot", 8);
+        let oneshot = SharedOneshot::new(segment.as_ptr());
+
+        thread::scope(|s| {
+            let waiters: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mapping = segment.map_again();
+                        SharedOneshot::new(mapping.as_ptr()).wait()
+                    })
+                })
+                .collect();
+            while oneshot.state.get_futex_value() & WAITERS == 0 {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(oneshot.complete_with(3), Ok(()));
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap(), 3);
+            }
+        });
+
+        // Waiters arriving late never sleep
+        let start = Instant::now();
+        for _ in 0..1000 {
+            assert_eq!(oneshot.wait(), 3);
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let mut words = [0u32; 2];
+        let oneshot = SharedOneshot::new(words.as_mut_ptr() as *mut c_void);
+
+        let start = Instant::now();
+        assert_eq!(oneshot.wait_timeout(Duration::from_millis(100)), None);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                oneshot.complete_with(9).unwrap();
+            });
+            assert_eq!(oneshot.wait_timeout(Duration::from_secs(10)), Some(9));
+        });
+        assert_eq!(oneshot.wait_timeout(Duration::ZERO), Some(9));
+    }
+}
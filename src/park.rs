@@ -0,0 +1,314 @@
+//! Cross-process thread parking
+//!
+//! `std::thread::park` only works inside a process. These functions park and unpark threads on a
+//! futex word in shared memory. While a thread is parked the word holds its kernel thread id, so
+//! the unparking side can tell which thread it is about to wake.
+//!
+//! [`Parker`] and [`Unparker`] are the token based variant: any number of threads may park on
+//! the word and each unpark releases exactly one of them.
+
+use crate::deadline::Deadline;
+use crate::rufutex::SharedFutex;
+use crate::sys;
+
+use std::hint;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+
+/// Nobody is parked and no unpark is pending
+const IDLE: u32 = 0;
+/// An unpark happened while nobody was parked, the next park returns immediately.
+/// Thread ids are bounded by pid_max (at most 2^22) so they never collide with this value.
+const NOTIFIED: u32 = u32::MAX;
+
+/// Returns the identifier stored in the futex word by [`park`]
+/// This is the kernel thread id, unique across processes of the same pid namespace. Other
+/// targets have no kernel thread id, there it is only unique within the process.
+pub fn park_id() -> u32 {
+    sys::gettid()
+}
+
+/// Parks the current thread on the futex word until [`unpark`] is called
+/// If an unpark is already pending the call consumes it and returns immediately.
+/// Only one thread may be parked on a given word at a time.
+/// # Arguments
+/// * `futex` - The SharedFutex to park on
+/// # Panics
+/// If another thread is already parked on the word
+pub fn park(futex: &SharedFutex) {
+    let id = park_id();
+    let atom = futex.as_atomic();
+    match atom.compare_exchange(IDLE, id, SeqCst, SeqCst) {
+        Ok(_) => {}
+        Err(NOTIFIED) => {
+            atom.store(IDLE, SeqCst);
+            return;
+        }
+        Err(other) => panic!("thread {} is already parked on this futex", other),
+    }
+
+    // Spurious wakeups leave our id in the word, so we go back to sleep
+    while futex.get_futex_value() == id {
+        let _ = futex.wait(id);
+    }
+    futex.set_futex_value(IDLE);
+}
+
+/// Unparks the thread parked on the futex word
+/// If no thread is parked the unpark is remembered and the next [`park`] returns immediately.
+/// # Arguments
+/// * `futex` - The SharedFutex to unpark
+/// # Returns
+/// The id of the thread that was parked, or None if nobody was parked
+pub fn unpark(futex: &SharedFutex) -> Option<u32> {
+    match futex.as_atomic().swap(NOTIFIED, SeqCst) {
+        IDLE | NOTIFIED => None,
+        id => {
+            let _ = futex.post(1);
+            Some(id)
+        }
+    }
+}
+
+/// Unparks a specific thread
+/// # Arguments
+/// * `futex` - The SharedFutex the thread is parked on
+/// * `id` - The id returned by [`park_id`] on the parked thread
+/// # Returns
+/// true if that thread was parked and has been unparked
+#[must_use = "if false the thread was not parked"]
+pub fn unpark_thread(futex: &SharedFutex, id: u32) -> bool {
+    if futex
+        .as_atomic()
+        .compare_exchange(id, NOTIFIED, SeqCst, SeqCst)
+        .is_err()
+    {
+        return false;
+    }
+    let _ = futex.post(1);
+    true
+}
+
+/// Returns the id of the thread currently parked on the futex word, if any
+/// # Arguments
+/// * `futex` - The SharedFutex to inspect
+pub fn parked_thread(futex: &SharedFutex) -> Option<u32> {
+    match futex.get_futex_value() {
+        IDLE | NOTIFIED => None,
+        id => Some(id),
+    }
+}
+
+/// Bit 0 of a Parker word: an unpark token is available
+const TOKEN: u32 = 1;
+/// The upper bits of a Parker word count the threads sleeping in FUTEX_WAIT
+const SLEEPER: u32 = 2;
+/// Checks of the token before sleeping
+const PARK_SPINS: u32 = 100;
+
+/// The parking side of a token based parker on a futex word
+/// Zeroed memory is a parker without token. An [`Unparker::unpark`] leaves a single token that
+/// the next park consumes, whether it was already parked or comes later; unparks do not
+/// accumulate.
+pub struct Parker {
+    futex: SharedFutex,
+}
+
+/// The unparking side of a [`Parker`], it can live in another thread or process
+#[derive(Clone)]
+pub struct Unparker {
+    futex: SharedFutex,
+}
+
+impl Parker {
+    /// Create a new Parker
+    /// # Arguments
+    /// * `futex` - The SharedFutex holding the parker word
+    /// # Returns
+    /// A new Parker
+    pub fn new(futex: SharedFutex) -> Self {
+        Self { futex }
+    }
+
+    /// Returns an Unparker for this parker
+    pub fn unparker(&self) -> Unparker {
+        Unparker {
+            futex: self.futex.clone(),
+        }
+    }
+
+    /// Parks the calling thread until it consumes an unpark token
+    pub fn park(&self) {
+        let parked = self.park_until(Deadline::never());
+        debug_assert!(parked);
+    }
+
+    /// Parks the calling thread until it consumes an unpark token or `timeout` expires
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait
+    /// # Returns
+    /// true if a token was consumed, false if the timeout expired first
+    #[must_use = "if false the timeout expired"]
+    pub fn park_timeout(&self, timeout: Duration) -> bool {
+        self.park_until(Deadline::after(timeout))
+    }
+
+    fn park_until(&self, deadline: Deadline) -> bool {
+        for _ in 0..PARK_SPINS {
+            if self.try_take_token() {
+                return true;
+            }
+            hint::spin_loop();
+        }
+
+        let atom = self.futex.as_atomic();
+        let mut value = atom.fetch_add(SLEEPER, SeqCst) + SLEEPER;
+        loop {
+            if value & TOKEN != 0 {
+                // Take the token and leave the sleepers in one step
+                match atom.compare_exchange(value, value - SLEEPER - TOKEN, SeqCst, SeqCst) {
+                    Ok(_) => return true,
+                    Err(observed) => {
+                        value = observed;
+                        continue;
+                    }
+                }
+            }
+            if !deadline.wait(&self.futex, value) {
+                atom.fetch_sub(SLEEPER, SeqCst);
+                return false;
+            }
+            value = atom.load(SeqCst);
+        }
+    }
+
+    fn try_take_token(&self) -> bool {
+        let atom = self.futex.as_atomic();
+        let value = atom.load(SeqCst);
+        value & TOKEN != 0
+            && atom
+                .compare_exchange(value, value & !TOKEN, SeqCst, SeqCst)
+                .is_ok()
+    }
+}
+
+impl Unparker {
+    /// Create a new Unparker
+    /// # Arguments
+    /// * `futex` - The SharedFutex holding the parker word
+    /// # Returns
+    /// A new Unparker
+    pub fn new(futex: SharedFutex) -> Self {
+        Self { futex }
+    }
+
+    /// Leaves an unpark token, waking one parked thread if there is one
+    pub fn unpark(&self) {
+        let previous = self.futex.as_atomic().fetch_or(TOKEN, SeqCst);
+        if previous & TOKEN == 0 && previous >= SLEEPER {
+            let _ = self.futex.post(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::AtomicU32;
+    use std::sync::mpsc;
+    use std::{thread, time};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_unpark_before_park() {
+        let segment = ShmSegment::create("test_unpark_before_park", 8);
+        let futex = SharedFutex::new(segment.as_ptr());
+        futex.set_futex_value(IDLE);
+
+        assert_eq!(unpark(&futex), None);
+        // The pending unpark makes this return immediately
+        park(&futex);
+        assert_eq!(parked_thread(&futex), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_park_unpark_identifies_thread() {
+        let (tx, rx) = mpsc::channel();
+        let segment = ShmSegment::create("test_park_unpark", 8);
+        let futex = SharedFutex::new(segment.as_ptr());
+        futex.set_futex_value(IDLE);
+
+        let mapping = segment.map_again();
+
+        let handle = thread::spawn(move || {
+            let futex = SharedFutex::new(mapping.as_ptr());
+            tx.send(park_id()).unwrap();
+            park(&futex);
+        });
+
+        let id = rx.recv().unwrap();
+        while parked_thread(&futex) != Some(id) {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert!(!unpark_thread(&futex, id + 1));
+        assert_eq!(unpark(&futex), Some(id));
+
+        handle.join().unwrap();
+        assert_eq!(parked_thread(&futex), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_unpark_before_park_does_not_sleep() {
+        let parker = Parker::new(SharedFutex::new_anonymous().unwrap());
+        let unparker = parker.unparker();
+        unparker.unpark();
+        unparker.unpark();
+
+        let start = time::Instant::now();
+        parker.park();
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+        // Both unparks left a single token, it is gone now
+        assert!(!parker.park_timeout(time::Duration::from_millis(10)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_park_timeout_expires() {
+        let parker = Parker::new(SharedFutex::new_anonymous().unwrap());
+        let start = time::Instant::now();
+        assert!(!parker.park_timeout(time::Duration::from_millis(50)));
+        assert!(start.elapsed() >= time::Duration::from_millis(50));
+        assert_eq!(parker.futex.get_futex_value(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_unpark_releases_one_thread() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let unparker = Unparker::new(futex.clone());
+        let released = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                let parker = Parker::new(futex.clone());
+                let released = &released;
+                s.spawn(move || {
+                    if parker.park_timeout(time::Duration::from_millis(500)) {
+                        released.fetch_add(1, SeqCst);
+                    }
+                });
+            }
+            // Every parker is asleep in FUTEX_WAIT
+            while futex.get_futex_value() != 3 * SLEEPER {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            unparker.unpark();
+        });
+
+        assert_eq!(released.load(SeqCst), 1);
+        assert_eq!(futex.get_futex_value(), 0);
+    }
+}
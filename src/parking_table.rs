@@ -0,0 +1,240 @@
+//! A table of parking slots addressed by index
+//!
+//! Each slot is a [`Parker`] word of its own, so an unpark on one slot only ever releases the
+//! thread parked on that slot, and its token stays there. A bitmap in front of the slots hands
+//! them out to workers, so a scheduler can keep a slot per worker process and wake a specific
+//! one.
+//!
+//! Layout, 4 bytes aligned: the number of slots, the allocation bitmap with one bit per slot,
+//! then one parker word per slot.
+
+use crate::error::FutexError;
+use crate::park::{Parker, Unparker};
+use crate::rufutex::SharedFutex;
+use libc::c_void;
+
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::time::Duration;
+
+const BITS: usize = u32::BITS as usize;
+
+/// A fixed size table of parking slots in shared memory
+pub struct ParkingTable {
+    pub table: *mut c_void,
+    slots: usize,
+    bitmap: *const AtomicU32,
+    words: *mut u32,
+}
+
+unsafe impl Send for ParkingTable {}
+unsafe impl Sync for ParkingTable {}
+
+impl ParkingTable {
+    /// Returns the number of bytes needed for `slots` slots
+    pub fn memory_requirements(slots: usize) -> usize {
+        mem::size_of::<u32>() * (1 + slots.div_ceil(BITS) + slots)
+    }
+
+    /// Lays out a table with every slot free and without tokens
+    /// Only one process creates the table, the others [`ParkingTable::attach`] to it.
+    /// # Arguments
+    /// * `table` - A pointer to memory_requirements(slots) bytes, 4 bytes aligned
+    /// * `slots` - The number of slots
+    /// # Returns
+    /// A new ParkingTable
+    pub fn create(table: *mut c_void, slots: usize) -> Self {
+        let words = table as *mut u32;
+        unsafe {
+            words.write(slots as u32);
+            for index in 1..Self::memory_requirements(slots) / mem::size_of::<u32>() {
+                words.add(index).write(0);
+            }
+        }
+        Self::attach(table)
+    }
+
+    /// Uses a table created by [`ParkingTable::create`], possibly in another process
+    /// # Arguments
+    /// * `table` - A pointer to the start of the table
+    /// # Returns
+    /// A ParkingTable over the existing slots
+    pub fn attach(table: *mut c_void) -> Self {
+        assert!(
+            (table as usize).is_multiple_of(mem::align_of::<AtomicU32>()),
+            "parking table memory is not 4 bytes aligned"
+        );
+        let slots = unsafe { (table as *const u32).read() } as usize;
+        let bitmap = unsafe { (table as *const AtomicU32).add(1) };
+        let words = unsafe { (bitmap as *mut u32).add(slots.div_ceil(BITS)) };
+        Self {
+            table,
+            slots,
+            bitmap,
+            words,
+        }
+    }
+
+    /// Returns the number of slots
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Hands out a free slot
+    /// Each free slot is claimed by a CAS on its bitmap word, so concurrent callers in any
+    /// process never get the same slot.
+    /// # Returns
+    /// The index of the slot, or None if every slot is taken
+    pub fn register(&self) -> Option<usize> {
+        for word_index in 0..self.slots.div_ceil(BITS) {
+            let word = unsafe { &*self.bitmap.add(word_index) };
+            let bits_in_word = (self.slots - word_index * BITS).min(BITS);
+            let mut current = word.load(SeqCst);
+            loop {
+                let free = (!current).trailing_zeros() as usize;
+                if free >= bits_in_word {
+                    break;
+                }
+                match word.compare_exchange(current, current | (1 << free), SeqCst, SeqCst) {
+                    Ok(_) => return Some(word_index * BITS + free),
+                    Err(observed) => current = observed,
+                }
+            }
+        }
+        None
+    }
+
+    /// Gives a slot back to the allocator
+    /// A token left in the slot is dropped so the next owner starts clean.
+    /// # Arguments
+    /// * `slot` - A slot returned by [`ParkingTable::register`]
+    /// # Returns
+    /// Ok, or Invalid if the slot is out of bounds
+    pub fn release(&self, slot: usize) -> Result<(), FutexError> {
+        self.check(slot)?;
+        unsafe { &*(self.words.add(slot) as *const AtomicU32) }.store(0, SeqCst);
+        let word = unsafe { &*self.bitmap.add(slot / BITS) };
+        word.fetch_and(!(1 << (slot % BITS)), SeqCst);
+        Ok(())
+    }
+
+    /// Parks the calling thread on `slot` until it consumes an unpark token
+    /// # Arguments
+    /// * `slot` - The slot to park on
+    /// # Returns
+    /// Ok once unparked, or Invalid if the slot is out of bounds
+    pub fn park(&self, slot: usize) -> Result<(), FutexError> {
+        self.parker(slot)?.park();
+        Ok(())
+    }
+
+    /// Parks the calling thread on `slot` until it consumes an unpark token or `timeout` expires
+    /// # Arguments
+    /// * `slot` - The slot to park on
+    /// * `timeout` - The maximum time to wait
+    /// # Returns
+    /// Ok(true) if unparked, Ok(false) if the timeout expired, or Invalid if the slot is out
+    /// of bounds
+    #[must_use = "check the return value for errors"]
+    pub fn park_timeout(&self, slot: usize, timeout: Duration) -> Result<bool, FutexError> {
+        Ok(self.parker(slot)?.park_timeout(timeout))
+    }
+
+    /// Leaves an unpark token on `slot`, waking the thread parked there if there is one
+    /// # Arguments
+    /// * `slot` - The slot to unpark
+    /// # Returns
+    /// Ok, or Invalid if the slot is out of bounds
+    pub fn unpark(&self, slot: usize) -> Result<(), FutexError> {
+        self.check(slot)?;
+        Unparker::new(self.futex(slot)).unpark();
+        Ok(())
+    }
+
+    fn parker(&self, slot: usize) -> Result<Parker, FutexError> {
+        self.check(slot)?;
+        Ok(Parker::new(self.futex(slot)))
+    }
+
+    fn futex(&self, slot: usize) -> SharedFutex {
+        SharedFutex::new(unsafe { self.words.add(slot) } as *mut c_void)
+    }
+
+    fn check(&self, slot: usize) -> Result<(), FutexError> {
+        if slot < self.slots {
+            Ok(())
+        } else {
+            Err(FutexError::Invalid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn test_register_never_hands_out_twice() {
+        let mut memory = vec![0u32; ParkingTable::memory_requirements(70) / 4];
+        let table = ParkingTable::create(memory.as_mut_ptr() as *mut c_void, 70);
+        let taken = Mutex::new(HashSet::new());
+
+        thread::scope(|s| {
+            for _ in 0..7 {
+                s.spawn(|| {
+                    for _ in 0..10 {
+                        let slot = table.register().unwrap();
+                        assert!(taken.lock().unwrap().insert(slot));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(taken.lock().unwrap().len(), 70);
+        assert_eq!(table.register(), None);
+        table.release(42).unwrap();
+        assert_eq!(table.register(), Some(42));
+        assert_eq!(table.release(70), Err(FutexError::Invalid));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_targeted_unpark() {
+        let len = ParkingTable::memory_requirements(4);
+        let segment = &ShmSegment::create("test_parking_table", len);
+        let table = ParkingTable::create(segment.as_ptr(), 4);
+        let first = table.register().unwrap();
+        let second = table.register().unwrap();
+
+        thread::scope(|s| {
+            let parked: Vec<_> = [first, second]
+                .into_iter()
+                .map(|slot| {
+                    s.spawn(move || {
+                        let mapping = segment.map_again();
+                        let table = ParkingTable::attach(mapping.as_ptr());
+                        table
+                            .park_timeout(slot, Duration::from_millis(300))
+                            .unwrap()
+                    })
+                })
+                .collect();
+            table.unpark(second).unwrap();
+            let released: Vec<_> = parked.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_eq!(released, [false, true]);
+        });
+
+        // No token leaked to the other slots
+        assert_eq!(table.park_timeout(first, Duration::ZERO), Ok(false));
+        assert_eq!(table.park_timeout(second, Duration::ZERO), Ok(false));
+        assert_eq!(table.unpark(4), Err(FutexError::Invalid));
+        assert_eq!(
+            table.park_timeout(4, Duration::ZERO),
+            Err(FutexError::Invalid)
+        );
+    }
+}
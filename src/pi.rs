@@ -0,0 +1,376 @@
+//! Priority inheritance mutex and its condition variable
+//!
+//! A [`PiFutex`] word holds the thread id of its owner, or 0 when unlocked, as FUTEX_LOCK_PI
+//! expects: the kernel boosts the owner to the priority of its highest priority waiter.
+//! Uncontended lock and unlock stay in userspace.
+//!
+//! Waking a condvar waiter with FUTEX_WAKE or moving it with FUTEX_CMP_REQUEUE would bypass the
+//! priority inheritance chain, so [`PiCondvar`] sleeps with FUTEX_WAIT_REQUEUE_PI and notifies
+//! with FUTEX_CMP_REQUEUE_PI. The kernel either grants the mutex to the woken waiter at once or
+//! queues it on the mutex, where it inherits like any locker; either way the waiter returns
+//! owning the mutex. A waiter whose sequence went stale before it slept returns without the
+//! mutex and locks it itself.
+
+use crate::error::FutexError;
+use crate::futex_op::FutexOp;
+use crate::futex_word::FutexWord;
+use crate::rufutex::SharedFutexRef;
+use crate::sleepers;
+use crate::sys;
+
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+/// Bits of a PI futex word holding the owner's thread id
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+/// Cross-process priority inheritance mutex
+/// Zeroed memory is an unlocked PiFutex. The lock belongs to the locking thread, only that
+/// thread can unlock it.
+#[repr(C)]
+pub struct PiFutex {
+    word: FutexWord,
+}
+
+const _: () = assert!(mem::size_of::<PiFutex>() == 4);
+
+impl PiFutex {
+    /// Create a new PiFutex
+    /// # Returns
+    /// An unlocked PiFutex
+    pub const fn new() -> Self {
+        Self {
+            word: FutexWord::new(0),
+        }
+    }
+
+    /// Tries to lock the mutex without sleeping
+    /// # Returns
+    /// true if the lock was acquired
+    #[must_use = "if false the lock was not acquired"]
+    pub fn try_lock(&self) -> bool {
+        self.word
+            .as_atomic()
+            .compare_exchange(0, sys::gettid(), SeqCst, SeqCst)
+            .is_ok()
+    }
+
+    /// Locks the mutex, sleeping in FUTEX_LOCK_PI while it is held
+    /// # Returns
+    /// Ok once locked, or the error of FUTEX_LOCK_PI, Os(EDEADLK) when the thread already holds
+    /// the lock
+    pub fn lock(&self) -> Result<(), FutexError> {
+        if self.try_lock() {
+            return Ok(());
+        }
+        loop {
+            match self
+                .word
+                .as_futex()
+                .futex(FutexOp::LockPi { deadline: None })
+            {
+                Ok(_) => return Ok(()),
+                // The owner is exiting, the kernel asks to try again
+                Err(FutexError::WouldBlock) | Err(FutexError::Interrupted) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Unlocks the mutex, handing it to the highest priority waiter if any
+    /// # Returns
+    /// Ok once unlocked, or the error of FUTEX_UNLOCK_PI, Os(EPERM) when the thread does not
+    /// hold the lock
+    pub fn unlock(&self) -> Result<(), FutexError> {
+        if self
+            .word
+            .as_atomic()
+            .compare_exchange(sys::gettid(), 0, SeqCst, SeqCst)
+            .is_ok()
+        {
+            return Ok(());
+        }
+        self.word.as_futex().futex(FutexOp::UnlockPi).map(|_| ())
+    }
+
+    /// Returns a SharedFutex over the word, the target of the requeue PI operations
+    pub(crate) fn as_futex(&self) -> SharedFutexRef<'_> {
+        self.word.as_futex()
+    }
+
+    /// Returns the thread id of the owner, None when unlocked
+    pub fn owner(&self) -> Option<u32> {
+        match self.word.load(SeqCst) & FUTEX_TID_MASK {
+            0 => None,
+            tid => Some(tid),
+        }
+    }
+}
+
+impl Default for PiFutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a [`PiCondvar::wait`] got the mutex back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiWakeReason {
+    /// Notified: the kernel granted the mutex to the waiter, at once or after queueing it on
+    /// the mutex
+    Granted,
+    /// Woken without the mutex, the sequence had changed before the waiter slept, and the
+    /// waiter locked the mutex itself
+    Reacquired,
+}
+
+/// Cross-process condition variable used with a [`PiFutex`]
+/// Zeroed memory is a valid PiCondvar. Every waiter and notifier must use the same mutex.
+/// Waits may return spuriously, callers check their condition in a loop.
+#[repr(C)]
+pub struct PiCondvar {
+    sequence: FutexWord,
+    waiters: AtomicU32,
+}
+
+const _: () = assert!(mem::size_of::<PiCondvar>() == 8);
+
+impl PiCondvar {
+    /// Create a new PiCondvar
+    /// # Returns
+    /// A PiCondvar without waiters
+    pub const fn new() -> Self {
+        Self {
+            sequence: FutexWord::new(0),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlocks `mutex`, sleeps until notified and returns with `mutex` locked again
+    /// # Arguments
+    /// * `mutex` - The mutex guarding the condition, held by the caller
+    /// # Returns
+    /// How the mutex was recovered, or the error of the wait. The mutex is held on success and
+    /// on error alike, unless unlocking it failed.
+    pub fn wait(&self, mutex: &PiFutex) -> Result<PiWakeReason, FutexError> {
+        self.wait_on(mutex, self.sequence.load(SeqCst))
+    }
+
+    /// Waits with `sequence` as the value the notifications change
+    fn wait_on(&self, mutex: &PiFutex, sequence: u32) -> Result<PiWakeReason, FutexError> {
+        let ret = sleepers::sleep(&self.waiters, || {
+            mutex.unlock()?;
+            let target = mutex.word.as_futex();
+            Ok(self.sequence.as_futex().futex(FutexOp::WaitRequeuePi {
+                expected: sequence,
+                target: &target,
+                deadline: None,
+            }))
+        })?;
+        match ret {
+            // The kernel made the thread the owner of the mutex
+            Ok(_) => Ok(PiWakeReason::Granted),
+            Err(err) => {
+                mutex.lock()?;
+                match err {
+                    FutexError::WouldBlock | FutexError::Interrupted => {
+                        Ok(PiWakeReason::Reacquired)
+                    }
+                    err => Err(err),
+                }
+            }
+        }
+    }
+
+    /// Wakes one waiter, if any
+    /// # Arguments
+    /// * `mutex` - The mutex the waiters passed to wait(), it may be held by the caller
+    /// # Returns
+    /// The number of waiters woken or queued on the mutex, or the error of the requeue
+    pub fn notify_one(&self, mutex: &PiFutex) -> Result<u32, FutexError> {
+        self.notify(mutex, 0)
+    }
+
+    /// Wakes every waiter, one gets the mutex and the others queue on it
+    /// # Arguments
+    /// * `mutex` - The mutex the waiters passed to wait(), it may be held by the caller
+    /// # Returns
+    /// The number of waiters woken or queued on the mutex, or the error of the requeue
+    pub fn notify_all(&self, mutex: &PiFutex) -> Result<u32, FutexError> {
+        self.notify(mutex, i32::MAX as u32)
+    }
+
+    fn notify(&self, mutex: &PiFutex, limit: u32) -> Result<u32, FutexError> {
+        let mut sequence = self
+            .sequence
+            .as_atomic()
+            .fetch_add(1, SeqCst)
+            .wrapping_add(1);
+        if !sleepers::any(&self.waiters) {
+            return Ok(0);
+        }
+        let target = mutex.word.as_futex();
+        loop {
+            match self.sequence.as_futex().futex(FutexOp::CmpRequeuePi {
+                target: &target,
+                limit,
+                expected: sequence,
+            }) {
+                Ok(moved) => return Ok(moved as u32),
+                // Another notification bumped the sequence in between
+                Err(FutexError::WouldBlock) => sequence = self.sequence.load(SeqCst),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Default for PiCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    /// FUTEX_WAITERS, set in the word while threads sleep on the lock
+    const FUTEX_WAITERS: u32 = 0x8000_0000;
+
+    fn wait_for_waiters(condvar: &PiCondvar, count: u32) {
+        while condvar.waiters.load(SeqCst) != count {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Registered, now give them the time to sleep in the kernel
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_lock_is_exclusive() {
+        let mutex = PiFutex::new();
+        let counter = AtomicU32::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        mutex.lock().unwrap();
+                        assert_eq!(mutex.owner(), Some(sys::gettid()));
+                        let value = counter.load(SeqCst);
+                        counter.store(value + 1, SeqCst);
+                        mutex.unlock().unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(SeqCst), 4000);
+        assert_eq!(mutex.owner(), None);
+        assert!(mutex.unlock().is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_woken_owning_the_mutex() {
+        let mutex = PiFutex::new();
+        let condvar = PiCondvar::new();
+        let ready = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                mutex.lock().unwrap();
+                let mut reasons = Vec::new();
+                while !ready.load(SeqCst) {
+                    let reason = condvar.wait(&mutex).unwrap();
+                    // Returned owning the mutex
+                    assert_eq!(mutex.owner(), Some(sys::gettid()));
+                    reasons.push(reason);
+                }
+                mutex.unlock().unwrap();
+                reasons
+            });
+            wait_for_waiters(&condvar, 1);
+            // The mutex is free, the kernel grants it to the waiter while waking it
+            ready.store(true, SeqCst);
+            assert_eq!(condvar.notify_one(&mutex), Ok(1));
+            assert_eq!(waiter.join().unwrap(), [PiWakeReason::Granted]);
+        });
+        assert_eq!(mutex.owner(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_requeued_behind_the_holder() {
+        let mutex = PiFutex::new();
+        let condvar = PiCondvar::new();
+        let returned = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                mutex.lock().unwrap();
+                let reason = condvar.wait(&mutex).unwrap();
+                returned.store(true, SeqCst);
+                assert_eq!(mutex.owner(), Some(sys::gettid()));
+                mutex.unlock().unwrap();
+                reason
+            });
+            wait_for_waiters(&condvar, 1);
+            mutex.lock().unwrap();
+            // Held here, so the waiter is queued on the mutex instead
+            assert_eq!(condvar.notify_one(&mutex), Ok(1));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!returned.load(SeqCst));
+            assert_ne!(mutex.word.load(SeqCst) & FUTEX_WAITERS, 0);
+            mutex.unlock().unwrap();
+            assert_eq!(waiter.join().unwrap(), PiWakeReason::Granted);
+        });
+        assert_eq!(mutex.owner(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_stale_sequence_reacquires() {
+        let mutex = PiFutex::new();
+        let condvar = PiCondvar::new();
+        mutex.lock().unwrap();
+        let stale = condvar.sequence.load(SeqCst);
+        assert_eq!(condvar.notify_one(&mutex), Ok(0));
+        // A notification slipped in before the wait, it returns without sleeping
+        assert_eq!(condvar.wait_on(&mutex, stale), Ok(PiWakeReason::Reacquired));
+        assert_eq!(mutex.owner(), Some(sys::gettid()));
+        mutex.unlock().unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_notify_all_hands_the_mutex_over_in_turn() {
+        let mutex = PiFutex::new();
+        let condvar = PiCondvar::new();
+        let ready = AtomicBool::new(false);
+        let inside = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    mutex.lock().unwrap();
+                    while !ready.load(SeqCst) {
+                        let _ = condvar.wait(&mutex).unwrap();
+                    }
+                    assert_eq!(inside.fetch_add(1, SeqCst), 0);
+                    thread::sleep(Duration::from_millis(5));
+                    inside.fetch_sub(1, SeqCst);
+                    mutex.unlock().unwrap();
+                });
+            }
+            wait_for_waiters(&condvar, 3);
+            mutex.lock().unwrap();
+            ready.store(true, SeqCst);
+            assert_eq!(condvar.notify_all(&mutex), Ok(3));
+            mutex.unlock().unwrap();
+        });
+        assert_eq!(mutex.owner(), None);
+    }
+}
@@ -0,0 +1,262 @@
+//! POSIX named semaphores behind the [`Semaphore`] interface
+//!
+//! [`PosixSemaphore`] wraps sem_open(3) and friends, so a process can coordinate with tools
+//! that only know named semaphores while its code stays written against [`Semaphore`], like
+//! for a [`SharedSemaphore`](crate::semaphore::SharedSemaphore).
+//!
+//! sem_timedwait(3) sleeps until a CLOCK_REALTIME time, where the other timed operations of
+//! the crate measure CLOCK_MONOTONIC. The timeout becomes a monotonic [`Deadline`] up front and
+//! each sleep gets its conversion to wall clock time. A sleep cut short by a signal or by the
+//! wall clock jumping forward goes back to sleep until the monotonic deadline. A wall clock set
+//! back during a sleep still extends it, sem_clockwait would fix that but is not in every libc.
+
+use crate::deadline::Deadline;
+use crate::error::RufutexError;
+use crate::semaphore::Semaphore;
+
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// A POSIX named semaphore opened by this process
+/// Dropping it closes the semaphore but keeps the name, see [`PosixSemaphore::unlink`].
+pub struct PosixSemaphore {
+    sem: *mut libc::sem_t,
+}
+
+// sem_t is shared between processes, so between threads too
+unsafe impl Send for PosixSemaphore {}
+unsafe impl Sync for PosixSemaphore {}
+
+/// Converts a semaphore name for libc, a NUL inside it is an EINVAL like the others sem_open
+/// rejects
+fn c_name(name: &str) -> Result<CString, RufutexError> {
+    CString::new(name).map_err(|_| RufutexError::Syscall {
+        operation: "sem_open",
+        error: io::Error::from_raw_os_error(libc::EINVAL),
+    })
+}
+
+impl PosixSemaphore {
+    /// Opens the named semaphore `name`, creating it with `permits` if it does not exist
+    /// # Arguments
+    /// * `name` - The name of the semaphore, like "/jobs"
+    /// * `permits` - The permits of a semaphore created by the call, ignored otherwise
+    /// # Returns
+    /// The semaphore, or the error of sem_open
+    pub fn open(name: &str, permits: u32) -> Result<Self, RufutexError> {
+        let name = c_name(name)?;
+        let sem = unsafe {
+            libc::sem_open(
+                name.as_ptr(),
+                libc::O_CREAT,
+                0o600 as libc::c_uint,
+                permits as libc::c_uint,
+            )
+        };
+        Self::opened(sem)
+    }
+
+    /// Opens the named semaphore `name`, which another process created
+    /// # Arguments
+    /// * `name` - The name of the semaphore
+    /// # Returns
+    /// The semaphore, or the error of sem_open, with ENOENT if it does not exist
+    pub fn open_existing(name: &str) -> Result<Self, RufutexError> {
+        let name = c_name(name)?;
+        let sem = unsafe { libc::sem_open(name.as_ptr(), 0) };
+        Self::opened(sem)
+    }
+
+    fn opened(sem: *mut libc::sem_t) -> Result<Self, RufutexError> {
+        if sem == libc::SEM_FAILED {
+            return Err(RufutexError::last_os_error("sem_open"));
+        }
+        Ok(Self { sem })
+    }
+
+    /// Removes the name `name`
+    /// Processes that opened the semaphore keep using it, new ones cannot open it anymore.
+    /// # Arguments
+    /// * `name` - The name of the semaphore
+    /// # Returns
+    /// Ok, or the error of sem_unlink, with ENOENT if the name does not exist
+    pub fn unlink(name: &str) -> Result<(), RufutexError> {
+        let name = c_name(name)?;
+        if unsafe { libc::sem_unlink(name.as_ptr()) } != 0 {
+            return Err(RufutexError::last_os_error("sem_unlink"));
+        }
+        Ok(())
+    }
+
+    /// Returns the permits available now
+    pub fn permits(&self) -> u32 {
+        let mut value: libc::c_int = 0;
+        unsafe { libc::sem_getvalue(self.sem, &mut value) };
+        // Linux never reports the sleepers as a negative value
+        value.max(0) as u32
+    }
+}
+
+impl Semaphore for PosixSemaphore {
+    fn acquire_permit(&self, timeout: Option<Duration>) -> Result<(), RufutexError> {
+        let deadline = timeout.map_or(Deadline::never(), Deadline::after);
+        loop {
+            let (ret, operation) = match deadline.realtime_timespec() {
+                None => (unsafe { libc::sem_wait(self.sem) }, "sem_wait"),
+                Some(abstime) => (
+                    unsafe { libc::sem_timedwait(self.sem, &abstime) },
+                    "sem_timedwait",
+                ),
+            };
+            if ret == 0 {
+                return Ok(());
+            }
+            let err = RufutexError::last_os_error(operation);
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // The wall clock jumped past the monotonic deadline
+                Some(libc::ETIMEDOUT) if !deadline.has_passed() => continue,
+                Some(libc::ETIMEDOUT) => return Err(RufutexError::TimedOut),
+                _ => return Err(err),
+            }
+        }
+    }
+
+    fn try_acquire_permit(&self) -> Result<(), RufutexError> {
+        if unsafe { libc::sem_trywait(self.sem) } == 0 {
+            return Ok(());
+        }
+        let err = RufutexError::last_os_error("sem_trywait");
+        match err.raw_os_error() {
+            Some(libc::EAGAIN) => Err(RufutexError::WouldBlock),
+            _ => Err(err),
+        }
+    }
+
+    fn release(&self) -> Result<(), RufutexError> {
+        if unsafe { libc::sem_post(self.sem) } != 0 {
+            return Err(RufutexError::last_os_error("sem_post"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PosixSemaphore {
+    fn drop(&mut self) {
+        unsafe { libc::sem_close(self.sem) };
+    }
+}
+
+impl fmt::Debug for PosixSemaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PosixSemaphore")
+            .field("permits", &self.permits())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::semaphore::SharedSemaphore;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Written against the trait, runs on either semaphore
+    fn ping_pong<S: Semaphore + Sync>(ping: &S, pong: &S) -> u32 {
+        let mut rounds = 0;
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    ping.acquire().unwrap().leak();
+                    pong.release().unwrap();
+                }
+            });
+            for _ in 0..100 {
+                ping.release().unwrap();
+                pong.acquire_timeout(Duration::from_secs(10))
+                    .unwrap()
+                    .leak();
+                rounds += 1;
+            }
+        });
+        rounds
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_threads_coordinate_through_named_semaphores() {
+        let ping = PosixSemaphore::open("/test_posix_sem_ping", 0).unwrap();
+        let pong = PosixSemaphore::open("/test_posix_sem_pong", 0).unwrap();
+        assert_eq!(ping_pong(&ping, &pong), 100);
+        // A drop-in substitute
+        assert_eq!(
+            ping_pong(&SharedSemaphore::new(0), &SharedSemaphore::new(0)),
+            100
+        );
+
+        // The other side opens the semaphores by name, like a separate process would
+        thread::scope(|s| {
+            s.spawn(|| {
+                let ping = PosixSemaphore::open_existing("/test_posix_sem_ping").unwrap();
+                let pong = PosixSemaphore::open_existing("/test_posix_sem_pong").unwrap();
+                let _guard = ping.acquire().unwrap();
+                pong.release().unwrap();
+            });
+            ping.release().unwrap();
+            pong.acquire().unwrap().leak();
+        });
+        // The guard of the other side gave its permit back
+        assert_eq!(ping.permits(), 1);
+
+        PosixSemaphore::unlink("/test_posix_sem_ping").unwrap();
+        PosixSemaphore::unlink("/test_posix_sem_pong").unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_unlink_keeps_open_semaphores() {
+        let sem = PosixSemaphore::open("/test_posix_sem_unlink", 1).unwrap();
+        PosixSemaphore::unlink("/test_posix_sem_unlink").unwrap();
+        let err = PosixSemaphore::open_existing("/test_posix_sem_unlink").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let err = PosixSemaphore::unlink("/test_posix_sem_unlink").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let err = PosixSemaphore::open("/test_posix\0sem", 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        // Unnamed but still open
+        let guard = sem.try_acquire().unwrap();
+        assert!(matches!(sem.try_acquire(), Err(RufutexError::WouldBlock)));
+        drop(guard);
+        assert_eq!(sem.permits(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_acquire_timeout() {
+        let sem = PosixSemaphore::open("/test_posix_sem_timeout", 0).unwrap();
+        PosixSemaphore::unlink("/test_posix_sem_timeout").unwrap();
+
+        let start = Instant::now();
+        assert!(matches!(
+            sem.acquire_timeout(Duration::from_millis(100)),
+            Err(RufutexError::TimedOut)
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(matches!(
+            sem.acquire_timeout(Duration::ZERO),
+            Err(RufutexError::TimedOut)
+        ));
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sem.release().unwrap();
+            });
+            assert!(sem.acquire_timeout(Duration::from_secs(10)).is_ok());
+        });
+    }
+}
@@ -0,0 +1,465 @@
+//! Mutex handing the lock to its highest priority waiter
+//!
+//! FUTEX_WAKE picks the waiter to wake itself. A [`PriorityMutex`] keeps its waiters in a table
+//! in shared memory instead, each with its thread id, its priority and a ticket, and unlock()
+//! hands the lock to the waiter with the highest priority, the oldest one on ties. Every table
+//! slot has its own bit of the futex bitset: the waiters sleep with FUTEX_WAIT_BITSET on the
+//! state word and unlock() wakes exactly the chosen one with FUTEX_WAKE_BITSET.
+//!
+//! The state word holds the locked bit, a queued bit saying the table has waiters and a
+//! handoff generation above them. Lock and unlock without waiters are a single CAS; the table
+//! is only touched, under its own small mutex, once a thread has to wait. The lock stays held
+//! while it is handed over, so no thread can barge in ahead of the chosen waiter.
+//!
+//! Priorities follow the nice value of getpriority(2): the lower the value, the higher the
+//! priority. The table has [`PriorityMutex::SLOTS`] slots, one per bit of the bitset; more
+//! waiters than that are counted apart and keep the queued bit set, and sleep with every bit
+//! set, so each handoff wakes them to look for a free slot.
+
+use crate::futex_op::FutexOp;
+use crate::futex_word::FutexWord;
+use crate::sys;
+
+use std::mem;
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering::SeqCst};
+
+/// The mutex is held
+const LOCKED: u32 = 1;
+/// The table has waiters, unlock() hands the lock over
+const QUEUED: u32 = 2;
+/// Added to the state on every handoff, the waiters notice it when they are about to sleep
+const GENERATION: u32 = 4;
+
+/// Waiter slot states
+const FREE: u32 = 0;
+const WAITING: u32 = 1;
+const GRANTED: u32 = 2;
+
+/// What enqueue() did
+enum Enqueued {
+    /// Every slot is taken, the caller is counted as overflowing and sleeps on the state
+    Full(u32),
+    /// The lock was free after all and is now held
+    Locked,
+    /// The caller waits in the slot
+    Waiting(usize),
+}
+
+#[repr(C)]
+struct Waiter {
+    state: AtomicU32,
+    tid: AtomicU32,
+    priority: AtomicI32,
+    ticket: AtomicU32,
+}
+
+impl Waiter {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(FREE),
+            tid: AtomicU32::new(0),
+            priority: AtomicI32::new(0),
+            ticket: AtomicU32::new(0),
+        }
+    }
+}
+
+/// Cross-process mutex waking the highest priority waiter
+/// Zeroed memory is a valid, unlocked PriorityMutex. It can be embedded in a `#[repr(C)]` shared
+/// memory layout and, like the plain mutex, it has no owner tracking.
+#[repr(C)]
+pub struct PriorityMutex {
+    state: FutexWord,
+    /// Guards the table
+    queue: FutexWord,
+    /// Number of waiters in the table
+    len: AtomicU32,
+    next_ticket: AtomicU32,
+    /// Number of waiters that found the table full
+    overflow: AtomicU32,
+    waiters: [Waiter; PriorityMutex::SLOTS],
+}
+
+const _: () = assert!(mem::size_of::<PriorityMutex>() == 20 + 16 * PriorityMutex::SLOTS);
+
+impl PriorityMutex {
+    /// Number of waiters the table holds, one per bit of the futex bitset
+    pub const SLOTS: usize = 32;
+
+    /// Create a new PriorityMutex
+    /// # Returns
+    /// An unlocked PriorityMutex without waiters
+    pub const fn new() -> Self {
+        Self {
+            state: FutexWord::new(0),
+            queue: FutexWord::unlocked(),
+            len: AtomicU32::new(0),
+            next_ticket: AtomicU32::new(0),
+            overflow: AtomicU32::new(0),
+            waiters: [const { Waiter::new() }; Self::SLOTS],
+        }
+    }
+
+    /// Tries to lock the mutex without blocking
+    /// A free mutex is not taken while waiters are being handed the lock.
+    /// # Returns
+    /// true if the lock was acquired
+    #[must_use = "if false the lock was not acquired"]
+    pub fn try_lock(&self) -> bool {
+        let state = self.state.load(SeqCst);
+        state & (LOCKED | QUEUED) == 0
+            && self
+                .state
+                .as_atomic()
+                .compare_exchange(state, state | LOCKED, SeqCst, SeqCst)
+                .is_ok()
+    }
+
+    /// Locks the mutex, waiting with the priority of the calling thread
+    /// The priority is the nice value getpriority(2) reports for the thread.
+    pub fn lock(&self) {
+        if self.try_lock() {
+            return;
+        }
+        // Cannot fail for the calling thread. Elsewhere than on Linux the nice value is the
+        // process's, `who` 0 is the calling process.
+        let who = if cfg!(target_os = "linux") {
+            sys::gettid()
+        } else {
+            0
+        };
+        let priority = unsafe { libc::getpriority(libc::PRIO_PROCESS, who as libc::id_t) };
+        self.lock_with_priority(priority);
+    }
+
+    /// Locks the mutex, waiting with the given priority
+    /// # Arguments
+    /// * `priority` - A nice value, the lower the sooner the lock is handed over
+    pub fn lock_with_priority(&self, priority: i32) {
+        if self.try_lock() {
+            return;
+        }
+        loop {
+            match self.enqueue(priority) {
+                Enqueued::Locked => return,
+                Enqueued::Waiting(slot) => return self.await_handoff(slot),
+                Enqueued::Full(state) => {
+                    // Every handoff wakes this sleep, its mask has every bit set
+                    let _ = self.state.as_futex().futex(FutexOp::WaitBitset {
+                        expected: state,
+                        mask: sys::FUTEX_BITSET_MATCH_ANY as u32,
+                        deadline: None,
+                    });
+                    // A handoff seeing the stale count keeps the queued bit, the next unlock
+                    // then finds no waiter and clears it
+                    self.overflow.fetch_sub(1, SeqCst);
+                }
+            }
+        }
+    }
+
+    /// Unlocks the mutex, handing it to the highest priority waiter if there is one
+    /// # Returns
+    /// The thread id of the waiter the lock was handed to, None if the mutex is now free
+    pub fn unlock(&self) -> Option<u32> {
+        let state = self.state.load(SeqCst);
+        debug_assert!(state & LOCKED != 0, "unlock of an unlocked PriorityMutex");
+        if state & QUEUED == 0
+            && self
+                .state
+                .as_atomic()
+                .compare_exchange(state, state & !LOCKED, SeqCst, SeqCst)
+                .is_ok()
+        {
+            return None;
+        }
+        self.hand_off()
+    }
+
+    /// Returns the number of threads waiting in the table
+    pub fn waiters(&self) -> u32 {
+        self.len.load(SeqCst)
+    }
+
+    /// Takes a slot, or the lock if it was released meanwhile
+    fn enqueue(&self, priority: i32) -> Enqueued {
+        self.queue.lock();
+        let Some(slot) = self
+            .waiters
+            .iter()
+            .position(|waiter| waiter.state.load(SeqCst) == FREE)
+        else {
+            return self.enqueue_overflow();
+        };
+        let waiter = &self.waiters[slot];
+        waiter.tid.store(sys::gettid(), SeqCst);
+        waiter.priority.store(priority, SeqCst);
+        waiter
+            .ticket
+            .store(self.next_ticket.fetch_add(1, SeqCst), SeqCst);
+        waiter.state.store(WAITING, SeqCst);
+        self.len.fetch_add(1, SeqCst);
+        // Either take the free lock or mark the table queued, in one step against unlock()
+        let atom = self.state.as_atomic();
+        let mut state = atom.load(SeqCst);
+        let taken = loop {
+            let (next, taken) = if state & LOCKED == 0 {
+                (state | LOCKED, true)
+            } else {
+                (state | QUEUED, false)
+            };
+            match atom.compare_exchange(state, next, SeqCst, SeqCst) {
+                Ok(_) => break taken,
+                Err(current) => state = current,
+            }
+        };
+        if taken {
+            waiter.state.store(FREE, SeqCst);
+            self.len.fetch_sub(1, SeqCst);
+        }
+        self.queue.unlock();
+        if taken {
+            Enqueued::Locked
+        } else {
+            Enqueued::Waiting(slot)
+        }
+    }
+
+    /// Counts the caller as overflowing, or takes the lock if it was released meanwhile
+    /// Called with the table locked, unlocks it.
+    fn enqueue_overflow(&self) -> Enqueued {
+        self.overflow.fetch_add(1, SeqCst);
+        // The queued bit sends the next unlock() through hand_off(), which wakes the overflow
+        let atom = self.state.as_atomic();
+        let mut state = atom.load(SeqCst);
+        let enqueued = loop {
+            let (next, enqueued) = if state & LOCKED == 0 {
+                (state | LOCKED, Enqueued::Locked)
+            } else {
+                (state | QUEUED, Enqueued::Full(state | QUEUED))
+            };
+            match atom.compare_exchange(state, next, SeqCst, SeqCst) {
+                Ok(_) => break enqueued,
+                Err(current) => state = current,
+            }
+        };
+        if let Enqueued::Locked = enqueued {
+            self.overflow.fetch_sub(1, SeqCst);
+        }
+        self.queue.unlock();
+        enqueued
+    }
+
+    /// Sleeps on the bit of `slot` until unlock() hands the lock over
+    fn await_handoff(&self, slot: usize) {
+        let waiter = &self.waiters[slot];
+        loop {
+            // Read before the check: a handoff in between changes the generation
+            let state = self.state.load(SeqCst);
+            if waiter.state.load(SeqCst) == GRANTED {
+                waiter.state.store(FREE, SeqCst);
+                return;
+            }
+            let _ = self.state.as_futex().futex(FutexOp::WaitBitset {
+                expected: state,
+                mask: 1 << slot,
+                deadline: None,
+            });
+        }
+    }
+
+    /// Passes the lock to the highest priority waiter, with the lock held
+    fn hand_off(&self) -> Option<u32> {
+        self.queue.lock();
+        let chosen = self
+            .waiters
+            .iter()
+            .enumerate()
+            .filter(|(_, waiter)| waiter.state.load(SeqCst) == WAITING)
+            .min_by_key(|(_, waiter)| {
+                let ticket = waiter.ticket.load(SeqCst);
+                // Tickets wrap, the oldest is the furthest behind the next one
+                let age = self.next_ticket.load(SeqCst).wrapping_sub(ticket);
+                (waiter.priority.load(SeqCst), u32::MAX - age)
+            })
+            .map(|(slot, _)| slot);
+        let atom = self.state.as_atomic();
+        let Some(slot) = chosen else {
+            // Only overflowing waiters, or none: they look for a slot again
+            atom.fetch_and(!(LOCKED | QUEUED), SeqCst);
+            self.queue.unlock();
+            let _ = self.state.as_futex().futex(FutexOp::WakeBitset {
+                count: i32::MAX as u32,
+                mask: sys::FUTEX_BITSET_MATCH_ANY as u32,
+            });
+            return None;
+        };
+        let waiter = &self.waiters[slot];
+        let tid = waiter.tid.load(SeqCst);
+        waiter.state.store(GRANTED, SeqCst);
+        let remaining = self.len.fetch_sub(1, SeqCst) - 1;
+        let queued = if remaining == 0 && self.overflow.load(SeqCst) == 0 {
+            0
+        } else {
+            QUEUED
+        };
+        let _ = atom.fetch_update(SeqCst, SeqCst, |state| {
+            Some((state & !QUEUED).wrapping_add(GENERATION) | queued)
+        });
+        self.queue.unlock();
+        // Also wakes the threads that found the table full, they sleep with every bit set
+        let _ = self.state.as_futex().futex(FutexOp::WakeBitset {
+            count: i32::MAX as u32,
+            mask: 1 << slot,
+        });
+        Some(tid)
+    }
+}
+
+impl Default for PriorityMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    /// The counter after the mutex, aligned for a u64
+    const COUNTER_OFFSET: usize = mem::size_of::<PriorityMutex>().next_multiple_of(8);
+
+    fn wait_for_waiters(mutex: &PriorityMutex, count: u32) {
+        while mutex.waiters() < count {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_handoff_follows_priority() {
+        let mutex = PriorityMutex::new();
+        let order = Mutex::new(Vec::new());
+        mutex.lock();
+
+        thread::scope(|s| {
+            // Queued in this order, served by priority and then by arrival
+            for (waiter, priority) in [(0, 10), (1, -5), (2, 3), (3, -5), (4, 19)] {
+                let (mutex, order) = (&mutex, &order);
+                s.spawn(move || {
+                    mutex.lock_with_priority(priority);
+                    order.lock().unwrap().push(waiter);
+                    mutex.unlock();
+                });
+                wait_for_waiters(mutex, waiter + 1);
+            }
+            assert!(!mutex.try_lock());
+            mutex.unlock();
+        });
+
+        assert_eq!(*order.lock().unwrap(), [1, 3, 2, 0, 4]);
+        assert_eq!(mutex.waiters(), 0);
+        assert!(mutex.try_lock());
+        assert_eq!(mutex.unlock(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_overflowing_waiters_are_handed_the_lock() {
+        let mutex = PriorityMutex::new();
+        let overflowing = 4;
+        mutex.lock();
+
+        thread::scope(|s| {
+            for _ in 0..PriorityMutex::SLOTS + overflowing {
+                let mutex = &mutex;
+                s.spawn(move || {
+                    mutex.lock_with_priority(0);
+                    mutex.unlock();
+                });
+            }
+            wait_for_waiters(&mutex, PriorityMutex::SLOTS as u32);
+            while mutex.overflow.load(SeqCst) < overflowing as u32 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            // Every unlock from here on is a handoff, the last ones to the overflow
+            mutex.unlock();
+        });
+
+        // Quiescent: no waiter left behind, and the queued bit is gone
+        assert_eq!(mutex.waiters(), 0);
+        assert_eq!(mutex.overflow.load(SeqCst), 0);
+        assert_eq!(mutex.state.load(SeqCst) & (LOCKED | QUEUED), 0);
+        assert!(mutex.try_lock());
+        assert_eq!(mutex.unlock(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs gettid")]
+    fn test_lock_uses_the_nice_value() {
+        let mutex = PriorityMutex::new();
+        mutex.lock();
+
+        thread::scope(|s| {
+            let mutex = &mutex;
+            let nice = s.spawn(move || {
+                // Raising the nice value needs no privilege
+                let tid = unsafe { libc::gettid() };
+                unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, 10) };
+                mutex.lock();
+                mutex.unlock();
+                tid as u32
+            });
+            wait_for_waiters(mutex, 1);
+            let eager = s.spawn(move || {
+                mutex.lock_with_priority(-1);
+                mutex.unlock();
+                unsafe { libc::gettid() as u32 }
+            });
+            wait_for_waiters(mutex, 2);
+            // The eager waiter came second but goes first
+            let first = mutex.unlock();
+            assert_eq!(first, Some(eager.join().unwrap()));
+            assert_ne!(first, Some(nice.join().unwrap()));
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_mutual_exclusion_with_a_full_table() {
+        let len = COUNTER_OFFSET + mem::size_of::<u64>();
+        let segment = &ShmSegment::create("test_priority_mutex", len);
+        unsafe {
+            (segment.as_ptr() as *mut PriorityMutex).write(PriorityMutex::new());
+        }
+
+        thread::scope(|s| {
+            // More threads than slots, some of them wait for a free slot
+            for thread in 0..PriorityMutex::SLOTS + 8 {
+                s.spawn(move || {
+                    let mapping = segment.map_again();
+                    let mutex = unsafe { mapping.get::<PriorityMutex>() };
+                    let counter =
+                        unsafe { (mapping.as_ptr() as *mut u8).add(COUNTER_OFFSET) as *mut u64 };
+                    for _ in 0..200 {
+                        mutex.lock_with_priority(thread as i32 % 5);
+                        unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                        mutex.unlock();
+                    }
+                });
+            }
+        });
+
+        let mutex = unsafe { segment.get::<PriorityMutex>() };
+        let counter = unsafe { (segment.as_ptr() as *mut u8).add(COUNTER_OFFSET) as *const u64 };
+        assert_eq!(
+            unsafe { counter.read_volatile() },
+            200 * (PriorityMutex::SLOTS as u64 + 8)
+        );
+        assert_eq!(mutex.waiters(), 0);
+    }
+}
@@ -0,0 +1,219 @@
+//! Locking the process-shared pthread mutexes of C programs
+//!
+//! Segments shared with C services often carry a `pthread_mutex_t` initialized with
+//! PTHREAD_PROCESS_SHARED. Its layout and protocol belong to the C library, so
+//! [`PthreadShmMutex`] never touches the word itself: every operation calls the
+//! pthread_mutex_* function of the libc the process is linked against, on the mapped object.
+//!
+//! For a robust mutex, a lock taken after its owner died without unlocking succeeds with
+//! [`PthreadShmMutexGuard::owner_died`] set. The protected data may be inconsistent: the guard
+//! either repairs it and calls [`PthreadShmMutexGuard::mark_consistent`], or drops, which
+//! leaves the mutex unusable for every process.
+
+use crate::deadline::Deadline;
+use crate::error::RufutexError;
+use libc::c_void;
+
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// A process-shared pthread mutex living in shared memory
+pub struct PthreadShmMutex {
+    mutex: *mut libc::pthread_mutex_t,
+}
+
+// The mutex is process-shared, so any thread may lock it
+unsafe impl Send for PthreadShmMutex {}
+unsafe impl Sync for PthreadShmMutex {}
+
+impl PthreadShmMutex {
+    /// Attach to a pthread mutex initialized by another program
+    /// # Arguments
+    /// * `mutex` - A pointer to the pthread_mutex_t in the shared memory
+    /// # Returns
+    /// A new PthreadShmMutex
+    /// # Safety
+    /// `mutex` must point to a mutex initialized with PTHREAD_PROCESS_SHARED that stays
+    /// mapped, and not destroyed, while the PthreadShmMutex is used.
+    pub unsafe fn attach(mutex: *mut c_void) -> Self {
+        Self {
+            mutex: mutex.cast(),
+        }
+    }
+
+    /// Locks the mutex, sleeping while another thread or process holds it
+    /// # Returns
+    /// The guard unlocking the mutex on drop, or a Syscall error, with EDEADLK for an error
+    /// checking mutex already held by the caller or ENOTRECOVERABLE for a robust mutex left
+    /// inconsistent
+    pub fn lock(&self) -> Result<PthreadShmMutexGuard<'_>, RufutexError> {
+        let ret = unsafe { libc::pthread_mutex_lock(self.mutex) };
+        self.guard(ret, "pthread_mutex_lock")
+    }
+
+    /// Locks the mutex if no one holds it
+    /// # Returns
+    /// The guard, WouldBlock if the mutex is held, or a Syscall error like lock()
+    pub fn try_lock(&self) -> Result<PthreadShmMutexGuard<'_>, RufutexError> {
+        let ret = unsafe { libc::pthread_mutex_trylock(self.mutex) };
+        self.guard(ret, "pthread_mutex_trylock")
+    }
+
+    /// Locks the mutex, sleeping at most `timeout`
+    /// pthread_mutex_timedlock measures against CLOCK_REALTIME, so a change of the wall clock
+    /// while sleeping shortens or extends the wait.
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for the lock
+    /// # Returns
+    /// The guard, TimedOut if the timeout expired, or a Syscall error like lock()
+    pub fn lock_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<PthreadShmMutexGuard<'_>, RufutexError> {
+        let ret = match Deadline::after(timeout).realtime_timespec() {
+            Some(abstime) => unsafe { libc::pthread_mutex_timedlock(self.mutex, &abstime) },
+            None => unsafe { libc::pthread_mutex_lock(self.mutex) },
+        };
+        self.guard(ret, "pthread_mutex_timedlock")
+    }
+
+    /// Maps the return value of a lock call to its guard or error
+    fn guard(
+        &self,
+        ret: libc::c_int,
+        operation: &'static str,
+    ) -> Result<PthreadShmMutexGuard<'_>, RufutexError> {
+        let owner_died = match ret {
+            0 => false,
+            libc::EOWNERDEAD => true,
+            libc::EBUSY => return Err(RufutexError::WouldBlock),
+            libc::ETIMEDOUT => return Err(RufutexError::TimedOut),
+            errno => {
+                return Err(RufutexError::Syscall {
+                    operation,
+                    error: io::Error::from_raw_os_error(errno),
+                })
+            }
+        };
+        Ok(PthreadShmMutexGuard {
+            mutex: self,
+            owner_died,
+            _not_send: PhantomData,
+        })
+    }
+}
+
+impl fmt::Debug for PthreadShmMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PthreadShmMutex")
+            .field("mutex", &self.mutex)
+            .finish()
+    }
+}
+
+/// Holds a [`PthreadShmMutex`] locked, unlocking it on drop
+/// pthread mutexes are unlocked by the thread that locked them, so the guard is not Send.
+pub struct PthreadShmMutexGuard<'a> {
+    mutex: &'a PthreadShmMutex,
+    owner_died: bool,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl PthreadShmMutexGuard<'_> {
+    /// Returns true if the previous owner of a robust mutex died holding it
+    pub fn owner_died(&self) -> bool {
+        self.owner_died
+    }
+
+    /// Marks the state protected by a robust mutex consistent again, after the owner died
+    /// # Returns
+    /// Ok, or a Syscall error with EINVAL if the owner did not die
+    pub fn mark_consistent(&mut self) -> Result<(), RufutexError> {
+        match unsafe { libc::pthread_mutex_consistent(self.mutex.mutex) } {
+            0 => {
+                self.owner_died = false;
+                Ok(())
+            }
+            errno => Err(RufutexError::Syscall {
+                operation: "pthread_mutex_consistent",
+                error: io::Error::from_raw_os_error(errno),
+            }),
+        }
+    }
+}
+
+impl Drop for PthreadShmMutexGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_unlock(self.mutex.mutex) };
+    }
+}
+
+impl fmt::Debug for PthreadShmMutexGuard<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PthreadShmMutexGuard")
+            .field("owner_died", &self.owner_died)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::mem;
+    use std::thread;
+    use std::time::Instant;
+
+    /// Initializes a robust process-shared mutex, like the C services do
+    unsafe fn init_shared_mutex(mutex: *mut c_void) {
+        let mut attr: libc::pthread_mutexattr_t = mem::zeroed();
+        assert_eq!(libc::pthread_mutexattr_init(&mut attr), 0);
+        assert_eq!(
+            libc::pthread_mutexattr_setpshared(&mut attr, libc::PTHREAD_PROCESS_SHARED),
+            0
+        );
+        assert_eq!(
+            libc::pthread_mutexattr_setrobust(&mut attr, libc::PTHREAD_MUTEX_ROBUST),
+            0
+        );
+        assert_eq!(libc::pthread_mutex_init(mutex.cast(), &attr), 0);
+        libc::pthread_mutexattr_destroy(&mut attr);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_contention_across_mappings() {
+        let len = mem::size_of::<libc::pthread_mutex_t>();
+        let segment = ShmSegment::create("test_pthread_shm_mutex", len);
+        unsafe {
+            init_shared_mutex(segment.as_ptr());
+        }
+        let mutex = unsafe { PthreadShmMutex::attach(segment.as_ptr()) };
+
+        let guard = mutex.lock().unwrap();
+        assert!(!guard.owner_died());
+        thread::scope(|s| {
+            let contender = s.spawn(|| {
+                let mapping = segment.map_again();
+                let mutex = unsafe { PthreadShmMutex::attach(mapping.as_ptr()) };
+                assert!(matches!(mutex.try_lock(), Err(RufutexError::WouldBlock)));
+                let start = Instant::now();
+                assert!(matches!(
+                    mutex.lock_timeout(Duration::from_millis(50)),
+                    Err(RufutexError::TimedOut)
+                ));
+                assert!(start.elapsed() >= Duration::from_millis(50));
+                let locked = mutex.lock_timeout(Duration::from_secs(10)).is_ok();
+                locked
+            });
+            thread::sleep(Duration::from_millis(100));
+            drop(guard);
+            assert!(contender.join().unwrap());
+        });
+        assert!(mutex.try_lock().is_ok());
+
+        unsafe { libc::pthread_mutex_destroy(segment.as_ptr().cast()) };
+    }
+}
@@ -0,0 +1,193 @@
+//! Named one-time initialized values shared between processes
+//!
+//! The registry is a flat array of slots in shared memory. A slot is claimed by storing the hash
+//! of its name and its value is initialized through a [`SharedOnce`], so cooperating processes
+//! agree on a single value per name, like `std::sync::OnceLock::get_or_init`.
+
+use crate::once::SharedOnce;
+use libc::c_void;
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering::{AcqRel, Acquire},
+};
+
+/// Hash of an empty slot
+const EMPTY: u64 = 0;
+
+#[repr(C)]
+struct Slot<T> {
+    hash: AtomicU64,
+    once: SharedOnce,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// A fixed size table of named values living in shared memory
+/// Names are identified by their 64 bit FNV-1a hash, which is stable across processes and builds;
+/// two names with the same hash share a slot. Values are plain `Copy` data since they are
+/// visible from every process mapping the region and never dropped.
+pub struct SharedRegistry<T: Copy> {
+    pub registry: *mut c_void,
+    slots: *mut Slot<T>,
+    capacity: usize,
+    _value: PhantomData<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SharedRegistry<T> {}
+unsafe impl<T: Copy + Sync> Sync for SharedRegistry<T> {}
+
+impl<T: Copy> SharedRegistry<T> {
+    /// Create a new SharedRegistry
+    /// # Arguments
+    /// * `registry` - A pointer to zero initialized memory, aligned for the slots
+    /// * `len` - The size of the memory in bytes
+    /// # Returns
+    /// A new SharedRegistry holding as many slots as fit in `len` bytes
+    pub fn new(registry: *mut c_void, len: usize) -> Self {
+        assert!(
+            (registry as usize).is_multiple_of(mem::align_of::<Slot<T>>()),
+            "registry memory is not aligned for its slots"
+        );
+        Self {
+            registry,
+            slots: registry as *mut Slot<T>,
+            capacity: len / mem::size_of::<Slot<T>>(),
+            _value: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes needed to hold `capacity` slots
+    pub fn memory_requirements(capacity: usize) -> usize {
+        capacity * mem::size_of::<Slot<T>>()
+    }
+
+    /// Returns the number of slots
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the value registered under `name`, initializing it with `f` if nobody did yet
+    /// Concurrent callers for the same name, in any process, wait for the one running `f`.
+    /// # Arguments
+    /// * `name` - The name of the value
+    /// * `f` - The initializer
+    /// # Returns
+    /// A reference to the value in shared memory
+    /// # Panics
+    /// If the name is not registered and every slot is taken
+    pub fn get_or_init(&self, name: &str, f: impl FnOnce() -> T) -> &T {
+        let slot = self
+            .find_slot(name_hash(name), true)
+            .expect("shared registry is full");
+        slot.once.call_once(|| unsafe {
+            (*slot.value.get()).write(f());
+        });
+        unsafe { (*slot.value.get()).assume_init_ref() }
+    }
+
+    /// Returns the value registered under `name` if it has been initialized
+    /// # Arguments
+    /// * `name` - The name of the value
+    pub fn get(&self, name: &str) -> Option<&T> {
+        let slot = self.find_slot(name_hash(name), false)?;
+        if !slot.once.is_completed() {
+            return None;
+        }
+        Some(unsafe { (*slot.value.get()).assume_init_ref() })
+    }
+
+    /// Linear probing from the home slot of the hash
+    /// Slots are never released, so an empty slot ends the probe sequence.
+    fn find_slot(&self, hash: u64, claim: bool) -> Option<&Slot<T>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let home = (hash % self.capacity as u64) as usize;
+        for probe in 0..self.capacity {
+            let slot = unsafe { &*self.slots.add((home + probe) % self.capacity) };
+            let current = slot.hash.load(Acquire);
+            if current == hash {
+                return Some(slot);
+            }
+            if current != EMPTY {
+                continue;
+            }
+            if !claim {
+                return None;
+            }
+            match slot.hash.compare_exchange(EMPTY, hash, AcqRel, Acquire) {
+                Ok(_) => return Some(slot),
+                Err(observed) if observed == hash => return Some(slot),
+                Err(_) => continue,
+            }
+        }
+        None
+    }
+}
+
+/// FNV-1a hash of a name, never EMPTY
+fn name_hash(name: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in name.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    if hash == EMPTY {
+        1
+    } else {
+        hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_get_or_init_across_mappings() {
+        let len = SharedRegistry::<u64>::memory_requirements(8);
+        let segment = ShmSegment::create("test_shared_registry", len);
+        let registry = SharedRegistry::<u64>::new(segment.as_ptr(), len);
+        assert_eq!(registry.capacity(), 8);
+        assert_eq!(registry.get("answer"), None);
+        let calls = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mapping = segment.map_again();
+                    let registry = SharedRegistry::<u64>::new(mapping.as_ptr(), len);
+                    let value = registry.get_or_init("answer", || {
+                        calls.fetch_add(1, SeqCst);
+                        42
+                    });
+                    assert_eq!(*value, 42);
+                });
+            }
+        });
+
+        assert_eq!(calls.load(SeqCst), 1);
+        assert_eq!(registry.get("answer"), Some(&42));
+        assert_eq!(*registry.get_or_init("other", || 7), 7);
+        assert_eq!(registry.get("answer"), Some(&42));
+    }
+
+    #[test]
+    #[should_panic(expected = "shared registry is full")]
+    fn test_full_registry() {
+        let mut memory = vec![0u64; 16];
+        let len = SharedRegistry::<u32>::memory_requirements(2);
+        assert!(len <= memory.len() * 8);
+        let registry = SharedRegistry::<u32>::new(memory.as_mut_ptr() as *mut c_void, len);
+        registry.get_or_init("a", || 1);
+        registry.get_or_init("b", || 2);
+        registry.get_or_init("c", || 3);
+    }
+}
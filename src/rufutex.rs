@@ -1,29 +1,509 @@
 use libc::c_void;
-//use log::debug;
 
-use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+#[cfg(all(feature = "async", not(loom)))]
+use crate::async_lock::LockFuture;
+use crate::backend::{FutexBackend, SyscallBackend};
+use crate::builder::SharedFutexBuilder;
+use crate::deadline::Deadline;
+use crate::error::{FutexError, RufutexError};
+use crate::futex_op::FutexOp;
+use crate::mapping::Mapping;
+#[cfg(feature = "metrics")]
+use crate::metrics::{Counters, FutexMetrics};
+#[cfg(not(loom))]
+use crate::pi::PiFutex;
+use crate::shared_stats::StatsBlock;
+use crate::sync::{fence, AtomicU32};
+use crate::trace::trace_event;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::atomic::{
+    self, Ordering,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+#[cfg(not(loom))]
+mod cancellable;
+mod fair;
+#[cfg(feature = "lock_api")]
+mod raw_mutex;
+mod timed;
+
+/// futex(2) operation removed from the uapi headers with Linux 2.6.26
+const FUTEX_FD: i32 = 2;
+
+/// The mask matching every other one, masked waits and wakes with it behave like plain ones
+pub const FUTEX_BITSET_MATCH_ANY: u32 = u32::MAX;
+
+use crate::sys;
 /// Mutex implementation based on https://eli.thegreenplace.net/2018/basics-of-futexes/ of the
 /// Ulrich Drepper's Futexes are Tricky paper https://www.akkadia.org/drepper/futex.pdf
 /// UNLOCKED 0 means unlocked
 /// LOCKED_NO_WAITERS 1 means locked, no waiters
 /// LOCKED_WAITERS 2 means locked, there are waiters in lock()
-use crate::{LOCKED_NO_WAITERS, LOCKED_WAITERS, UNLOCKED};
+/// HANDED_OFF 3 means unlock_fair() passed the lock to a thread sleeping in lock()
+use crate::{HANDED_OFF, LOCKED_NO_WAITERS, LOCKED_WAITERS, UNLOCKED};
+
+/// Error returned by [`SharedFutex::transition`] when the futex word is not in the expected
+/// state. It carries the value that was observed instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransitionError {
+    pub observed: u32,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unexpected futex state {}", self.observed)
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+/// How [`SharedFutex::lock_traced`] acquired the lock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockOutcome {
+    /// The lock was free, the fast path took it
+    Uncontended,
+    /// The lock was held but became free before going to sleep
+    Spun { iterations: u32 },
+    /// The lock was taken after sleeping in FUTEX_WAIT
+    Slept { times: u32, waited: Duration },
+}
+
+/// Why [`SharedFutex::wait`] or [`SharedFutex::wait_with_timeout`] returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexWakeReason {
+    /// Woken by FUTEX_WAKE, and the word no longer holds the expected value
+    Woken,
+    /// The wait returned but the word still holds the expected value: a FUTEX_WAKE that did
+    /// not change the word, or a wake up nobody asked for. Callers check their condition again.
+    Spurious,
+    /// EINTR: a signal handler ran
+    Interrupted,
+    /// EAGAIN: the word did not hold the expected value, the call did not sleep
+    ValueMismatch,
+    /// ETIMEDOUT: the timeout expired
+    TimedOut,
+}
+
+/// A SharedFutex borrowed from an AtomicU32, see [`SharedFutex::from_atomic`]
+pub struct SharedFutexRef<'a> {
+    futex: SharedFutex,
+    _word: PhantomData<&'a AtomicU32>,
+}
+
+impl Deref for SharedFutexRef<'_> {
+    type Target = SharedFutex;
 
-pub struct SharedFutex {
+    fn deref(&self) -> &SharedFutex {
+        &self.futex
+    }
+}
+
+impl DerefMut for SharedFutexRef<'_> {
+    fn deref_mut(&mut self) -> &mut SharedFutex {
+        &mut self.futex
+    }
+}
+
+/// Clones refer to the same futex word. When the SharedFutex owns the mapping of the word the
+/// clones share it, and it is unmapped once the last of them is dropped.
+/// The futex syscalls go through the backend `B`, futex(2) itself by default.
+#[derive(Clone)]
+pub struct SharedFutex<B: FutexBackend = SyscallBackend> {
     pub futex: *mut c_void,
     atom: *mut AtomicU32,
+    private: bool,
+    /// The mapping holding the futex word when the SharedFutex created it
+    mapping: Option<Arc<Mapping>>,
+    backend: B,
+    /// The statistics block of a segment built with shared stats
+    pub(crate) stats: Option<NonNull<StatsBlock>>,
+    /// Contention counters of this handle
+    #[cfg(feature = "metrics")]
+    metrics: Counters,
 }
 
+/// # Safety
+/// A SharedFutex is a pointer to a futex word that is only accessed through atomics and futex
+/// syscalls, so it can be moved to and shared between threads as long as the memory behind the
+/// pointer stays mapped, and shared with every other user of the word, for as long as any thread
+/// uses it. A SharedFutex owning its mapping keeps it mapped until it is dropped.
+unsafe impl<B: FutexBackend + Send> Send for SharedFutex<B> {}
+unsafe impl<B: FutexBackend + Sync> Sync for SharedFutex<B> {}
+
 impl SharedFutex {
     /// Create a new SharedFutex
     /// # Arguments
     /// * `futex` - A mutable pointer to a c_void
     /// # Returns
     /// A new SharedFutex
-    pub fn new(futex: *mut c_void) -> Self {
+    pub const fn new(futex: *mut c_void) -> Self {
         let atom: *mut AtomicU32 = futex as *mut AtomicU32;
-        Self { futex, atom }
+        Self {
+            futex,
+            atom,
+            private: false,
+            mapping: None,
+            backend: SyscallBackend,
+            stats: None,
+            #[cfg(feature = "metrics")]
+            metrics: Counters::new(),
+        }
+    }
+
+    /// Returns a builder laying out a versioned segment around the futex word
+    /// # Arguments
+    /// * `segment` - A pointer to the segment, starting with the futex word
+    /// # Returns
+    /// A SharedFutexBuilder without extras
+    pub fn builder(segment: *mut c_void) -> SharedFutexBuilder {
+        SharedFutexBuilder::new(segment)
+    }
+
+    /// Create a SharedFutex that owns the mapping holding its futex word
+    /// The mapping is unmapped when the SharedFutex and all its clones are dropped.
+    /// # Arguments
+    /// * `mapping` - The mapping
+    /// * `offset` - The offset of the futex word inside the mapping
+    pub(crate) fn from_mapping(mapping: Mapping, offset: usize) -> Self {
+        let futex = unsafe { (mapping.as_ptr() as *mut u8).add(offset) } as *mut c_void;
+        let mut shared_futex = Self::new(futex);
+        shared_futex.mapping = Some(Arc::new(mapping));
+        shared_futex
+    }
+
+    /// Create a SharedFutex in a new anonymous memfd
+    /// Nothing is left in /dev/shm and no name has to be agreed on, the descriptor can be
+    /// inherited or sent to another process with [`crate::ipc::send_futex_handle`].
+    /// # Arguments
+    /// * `len` - The size of the memory, at least the 4 bytes of the futex word
+    /// # Returns
+    /// The SharedFutex at offset 0, unlocked, and the memfd descriptor, Unsupported on targets
+    /// without memfd_create(2)
+    pub fn create_memfd(len: usize) -> io::Result<(Self, OwnedFd)> {
+        if len < mem::size_of::<u32>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "memory is too small to hold a futex word",
+            ));
+        }
+        let fd = sys::memfd_create(c"rufutex")?;
+        if unsafe { libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let futex = Self::from_fd(fd.as_fd(), 0)?;
+        Ok((futex, fd))
+    }
+
+    /// Create a SharedFutex in a new anonymous MAP_SHARED page
+    /// Share by fork only: the page has no name and no descriptor, it reaches other processes
+    /// by being inherited by the children forked after this call. The rest of the page after
+    /// the word is free for the data the futex protects.
+    /// # Returns
+    /// The SharedFutex, unlocked, or the mmap error
+    pub fn new_anonymous() -> io::Result<Self> {
+        let mapping = Mapping::anonymous(Mapping::page_size())?;
+        let futex = Self::from_mapping(mapping, 0);
+        futex.set_futex_value(UNLOCKED);
+        Ok(futex)
+    }
+
+    /// Create a SharedFutex over a file descriptor
+    /// The whole file is mapped MAP_SHARED and unmapped when the SharedFutex and all its clones
+    /// are dropped. The descriptor can be closed afterwards.
+    /// # Arguments
+    /// * `fd` - The descriptor, a memfd or a shm/regular file
+    /// * `offset` - The offset of the futex word, a multiple of 4 inside the file
+    /// # Returns
+    /// The SharedFutex or the error of fstat/mmap
+    pub fn from_fd(fd: BorrowedFd<'_>, offset: usize) -> io::Result<Self> {
+        if !offset.is_multiple_of(mem::align_of::<AtomicU32>()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "futex offset is not 4 bytes aligned",
+            ));
+        }
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(fd.as_raw_fd(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = stat.st_size as usize;
+        if offset.saturating_add(mem::size_of::<u32>()) > len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file is too small to hold a futex word at this offset",
+            ));
+        }
+        let mapping = Mapping::map_fd(fd, len)?;
+        Ok(Self::from_mapping(mapping, offset))
+    }
+
+    /// Create a SharedFutex over a word of a file, creating the file if needed
+    /// Unrelated processes rendezvous on the path, typically on a tmpfs, or lock a record of a
+    /// larger data file. A new file is created with O_EXCL and sized to cover the word, which
+    /// starts unlocked. An existing file is never resized: a word past its end is an error
+    /// instead of a SIGBUS on first access, so a process attaching while the creator has not
+    /// sized the file yet gets an error and can retry.
+    /// Only the pages holding the word are mapped, they are unmapped when the SharedFutex and
+    /// all its clones are dropped.
+    /// # Arguments
+    /// * `path` - The file
+    /// * `offset` - The offset of the futex word in the file, a multiple of 4
+    /// # Returns
+    /// The SharedFutex or the error of open/ftruncate/mmap
+    pub fn open_path(path: impl AsRef<Path>, offset: usize) -> io::Result<Self> {
+        if !offset.is_multiple_of(mem::align_of::<AtomicU32>()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "futex offset is not 4 bytes aligned",
+            ));
+        }
+        let end = offset.checked_add(mem::size_of::<u32>()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "futex offset is past the largest file size",
+            )
+        })?;
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path.as_ref())
+        {
+            Ok(file) => {
+                file.set_len(end as u64)?;
+                file
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path.as_ref())?,
+            Err(err) => return Err(err),
+        };
+        if file.metadata()?.len() < end as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file is too small to hold a futex word at this offset",
+            ));
+        }
+
+        let page_start = offset - offset % Mapping::page_size();
+        let mapping = Mapping::map_fd_at(file.as_fd(), page_start, end - page_start)?;
+        Ok(Self::from_mapping(mapping, offset - page_start))
+    }
+
+    /// Create a SharedFutex borrowing an AtomicU32
+    /// The returned handle cannot outlive the word, which makes the common case of a futex
+    /// living inside a struct the caller already references entirely safe.
+    /// # Arguments
+    /// * `atom` - The futex word
+    /// # Returns
+    /// A SharedFutexRef dereferencing to a SharedFutex over the word
+    pub fn from_atomic(atom: &AtomicU32) -> SharedFutexRef<'_> {
+        SharedFutexRef {
+            futex: Self::new(atom as *const AtomicU32 as *mut c_void),
+            _word: PhantomData,
+        }
+    }
+
+    /// Create a new SharedFutex from a non null pointer to the futex word
+    /// NonNull<AtomicU32> keeps the type and alignment information a `*mut c_void` loses.
+    /// # Arguments
+    /// * `atom` - The futex word, it must stay mapped while the SharedFutex is used
+    /// # Returns
+    /// A new SharedFutex
+    pub fn from_non_null(atom: NonNull<AtomicU32>) -> Self {
+        Self::new(atom.as_ptr() as *mut c_void)
+    }
+
+    /// Create a new SharedFutex, checking the pointer first
+    /// # Arguments
+    /// * `futex` - A mutable pointer to a c_void
+    /// # Returns
+    /// A new SharedFutex, or FutexError::Misaligned if the pointer is not 4 bytes aligned
+    pub fn try_new(futex: *mut c_void) -> Result<Self, FutexError> {
+        validate_alignment(futex)?;
+        Ok(Self::new(futex))
+    }
+
+    /// Returns the number of bytes of a futex word
+    pub const fn memory_requirements() -> usize {
+        mem::size_of::<u32>()
+    }
+
+    /// Create two SharedFutex over adjacent words, at `pair` and `pair + 4`
+    /// The usual pair for a double buffer: one word signals that data is ready, the other that
+    /// the slot is free again.
+    /// # Arguments
+    /// * `pair` - A pointer to `2 * SharedFutex::memory_requirements()` bytes, 4 bytes aligned
+    /// # Returns
+    /// The SharedFutex over the first word and the one over the second word
+    pub fn futex_pair(pair: *mut c_void) -> (Self, Self) {
+        let second = (pair as *mut u8).wrapping_add(Self::memory_requirements()) as *mut c_void;
+        assert!(
+            validate_alignment(pair).is_ok() && validate_alignment(second).is_ok(),
+            "futex pair is not 4 bytes aligned"
+        );
+        (Self::new(pair), Self::new(second))
+    }
+
+    /// Full memory barrier, `fence(SeqCst)`
+    /// lock() acquires and unlock() releases, so data guarded by the mutex is already visible to
+    /// the next owner without this. It is needed when plain shared data is published through
+    /// the word with Relaxed operations or with post()/wait() alone, since wake ups do not order
+    /// memory by themselves.
+    pub fn memory_barrier() {
+        fence(SeqCst);
+    }
+
+    /// Compiler only barrier, `compiler_fence(SeqCst)`
+    /// It stops the compiler from reordering memory accesses across it but emits no instruction,
+    /// so it is only enough against code running on the same thread, like a signal handler.
+    pub fn compiler_barrier() {
+        atomic::compiler_fence(SeqCst);
+    }
+}
+
+impl<B: FutexBackend> SharedFutex<B> {
+    /// Create a new SharedFutex issuing its futex operations through `backend`
+    /// # Arguments
+    /// * `futex` - A mutable pointer to a c_void
+    /// * `backend` - The backend
+    /// # Returns
+    /// A new SharedFutex
+    pub fn with_backend(futex: *mut c_void, backend: B) -> Self {
+        Self {
+            futex,
+            atom: futex as *mut AtomicU32,
+            private: false,
+            mapping: None,
+            backend,
+            stats: None,
+            #[cfg(feature = "metrics")]
+            metrics: Counters::new(),
+        }
+    }
+
+    /// Returns the backend
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns a snapshot of the contention counters of this handle
+    /// The counters are local to the handle: other processes, other handles to the same word
+    /// and clones count separately.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> FutexMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Sets the contention counters of this handle back to zero
+    #[cfg(feature = "metrics")]
+    pub fn reset_metrics(&self) {
+        self.metrics.reset();
+    }
+
+    /// Returns the shared statistics block, if the segment has one
+    pub(crate) fn shared_stats_block(&self) -> Option<&StatsBlock> {
+        self.stats.map(|stats| unsafe { stats.as_ref() })
+    }
+
+    /// Marks the futex as process private
+    /// Private futexes add FUTEX_PRIVATE_FLAG to every operation, which is cheaper for the
+    /// kernel but only works if every user of the word lives in the same process.
+    /// # Arguments
+    /// * `private` - true to use private futex operations
+    pub fn set_private(&mut self, private: bool) {
+        self.private = private;
+    }
+
+    /// Returns the virtual address of the futex word in this process
+    /// For debugging: the address can be looked up in /proc/<pid>/maps to find the mapping,
+    /// and file offset, the word lives in.
+    pub fn futex_address(&self) -> usize {
+        self.futex as usize
+    }
+
+    /// Returns the offset of the futex word in its page
+    /// Two processes sharing the word map the same page, possibly at different addresses, but
+    /// always see the word at the same offset in it.
+    pub fn futex_page_offset(&self) -> usize {
+        self.futex_address() % Mapping::page_size()
+    }
+
+    /// Performs a futex operation
+    /// This is the typed entry point to futex(2): the operation carries exactly the arguments
+    /// it needs, and FUTEX_PRIVATE_FLAG is added when the futex is private.
+    /// # Arguments
+    /// * `op` - The operation to perform
+    /// # Returns
+    /// The non negative result of the syscall, or the decoded errno
+    #[must_use = "check the return value for errors"]
+    pub fn futex(&self, op: FutexOp<'_>) -> Result<i64, FutexError> {
+        let args = op.args();
+        match op {
+            FutexOp::Wait { timeout, .. } => {
+                #[cfg(feature = "metrics")]
+                self.metrics.wait_call();
+                self.backend
+                    .wait(self.as_atomic(), args.val, timeout.as_ref(), self.private)
+            }
+            FutexOp::Wake { .. } => {
+                #[cfg(feature = "metrics")]
+                self.metrics.wake_call();
+                self.backend.wake(self.as_atomic(), args.val, self.private)
+            }
+            _ => {
+                #[cfg(feature = "metrics")]
+                if matches!(
+                    op,
+                    FutexOp::WaitBitset { .. }
+                        | FutexOp::WaitRealtime { .. }
+                        | FutexOp::LockPi { .. }
+                        | FutexOp::WaitRequeuePi { .. }
+                ) {
+                    self.metrics.wait_call();
+                }
+                let mut futex_op = args.op;
+                if self.private {
+                    futex_op |= sys::FUTEX_PRIVATE_FLAG;
+                }
+                // The timeout slot also carries val2 for the requeue operations. It is pointer
+                // sized on every target, a timeout pointer passes through it unchanged.
+                let timeout_or_val2 = if op.has_timeout() {
+                    args.timeout as usize
+                } else {
+                    args.val2 as usize
+                };
+                unsafe {
+                    self.backend.futex(
+                        self.futex,
+                        futex_op,
+                        args.val,
+                        timeout_or_val2,
+                        args.uaddr2,
+                        args.val3,
+                    )
+                }
+            }
+        }
+    }
+
+    /// Returns the futex word as an AtomicU32
+    pub(crate) fn as_atomic(&self) -> &AtomicU32 {
+        unsafe { &*self.atom }
     }
 
     /// Compare and exchange atomically
@@ -39,81 +519,342 @@ impl SharedFutex {
     /// * `desired` - The value to set the atomic variable to if the value of the atomic variable is equal to expected
     /// # Returns
     /// The value of the atomic variable before the operation
+    /// # Ordering
+    /// A successful exchange is Acquire: every CAS of the lock protocol that succeeds either
+    /// takes the lock or marks it contended while another thread owns it, and the lock must
+    /// observe everything the previous owner wrote before its Release in unlock().
+    /// A failed exchange publishes and acquires nothing, so it is Relaxed.
     fn cmpxchg(atom: *mut AtomicU32, expected: u32, desired: u32) -> u32 {
         unsafe {
-            match (*atom).compare_exchange(expected, desired, SeqCst, SeqCst) {
+            match (*atom).compare_exchange(expected, desired, Acquire, Relaxed) {
                 Err(err) => err,
                 Ok(val) => val,
             }
         }
     }
 
+    /// Compare and exchange the futex word
+    /// Same semantics as AtomicU32::compare_exchange with SeqCst ordering
+    /// # Arguments
+    /// * `expected` - The value the futex word is expected to hold
+    /// * `desired` - The value to store if the futex word holds `expected`
+    /// # Returns
+    /// Ok with the previous value on success, Err with the observed value on failure
+    pub fn compare_exchange(&self, expected: u32, desired: u32) -> Result<u32, u32> {
+        self.compare_exchange_with_ordering(expected, desired, SeqCst, SeqCst)
+    }
+
+    /// Compare and exchange the futex word with explicit memory orderings
+    /// # Arguments
+    /// * `expected` - The value the futex word is expected to hold
+    /// * `desired` - The value to store if the futex word holds `expected`
+    /// * `success` - The ordering of the read-modify-write if the exchange happens
+    /// * `failure` - The ordering of the load if it does not, cannot be Release or AcqRel
+    /// # Returns
+    /// Ok with the previous value on success, Err with the observed value on failure
+    pub fn compare_exchange_with_ordering(
+        &self,
+        expected: u32,
+        desired: u32,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<u32, u32> {
+        debug_assert!(
+            is_load_ordering(failure),
+            "failure ordering {:?} is not valid for a load",
+            failure
+        );
+        self.as_atomic()
+            .compare_exchange(expected, desired, success, failure)
+    }
+
+    /// Adds to the futex word
+    /// # Arguments
+    /// * `value` - The value to add, wrapping around on overflow
+    /// # Returns
+    /// The previous value
+    pub fn fetch_add(&self, value: u32) -> u32 {
+        self.fetch_add_with_ordering(value, SeqCst)
+    }
+
+    /// Adds to the futex word with an explicit memory ordering
+    /// # Arguments
+    /// * `value` - The value to add, wrapping around on overflow
+    /// * `order` - The ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn fetch_add_with_ordering(&self, value: u32, order: Ordering) -> u32 {
+        self.as_atomic().fetch_add(value, order)
+    }
+
+    /// Subtracts from the futex word
+    /// # Arguments
+    /// * `value` - The value to subtract, wrapping around on overflow
+    /// # Returns
+    /// The previous value
+    pub fn fetch_sub(&self, value: u32) -> u32 {
+        self.fetch_sub_with_ordering(value, SeqCst)
+    }
+
+    /// Subtracts from the futex word with an explicit memory ordering
+    /// # Arguments
+    /// * `value` - The value to subtract, wrapping around on overflow
+    /// * `order` - The ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn fetch_sub_with_ordering(&self, value: u32, order: Ordering) -> u32 {
+        self.as_atomic().fetch_sub(value, order)
+    }
+
+    /// Bitwise or with the futex word
+    /// # Arguments
+    /// * `value` - The bits to set
+    /// # Returns
+    /// The previous value
+    pub fn fetch_or(&self, value: u32) -> u32 {
+        self.fetch_or_with_ordering(value, SeqCst)
+    }
+
+    /// Bitwise or with the futex word with an explicit memory ordering
+    /// # Arguments
+    /// * `value` - The bits to set
+    /// * `order` - The ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn fetch_or_with_ordering(&self, value: u32, order: Ordering) -> u32 {
+        self.as_atomic().fetch_or(value, order)
+    }
+
+    /// Swaps the futex word
+    /// # Arguments
+    /// * `new_value` - The value to store
+    /// # Returns
+    /// The previous value
+    pub fn swap(&self, new_value: u32) -> u32 {
+        self.swap_with_ordering(new_value, SeqCst)
+    }
+
+    /// Swaps the futex word with an explicit memory ordering
+    /// # Arguments
+    /// * `new_value` - The value to store
+    /// * `order` - The ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn swap_with_ordering(&self, new_value: u32, order: Ordering) -> u32 {
+        self.as_atomic().swap(new_value, order)
+    }
+
+    /// Bitwise and with the futex word
+    /// # Arguments
+    /// * `value` - The bits to keep
+    /// # Returns
+    /// The previous value
+    pub fn fetch_and(&self, value: u32) -> u32 {
+        self.fetch_and_with_ordering(value, SeqCst)
+    }
+
+    /// Bitwise and with the futex word with an explicit memory ordering
+    /// # Arguments
+    /// * `value` - The bits to keep
+    /// * `order` - The ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn fetch_and_with_ordering(&self, value: u32, order: Ordering) -> u32 {
+        self.as_atomic().fetch_and(value, order)
+    }
+
+    /// Bitwise xor with the futex word
+    /// # Arguments
+    /// * `value` - The bits to flip
+    /// # Returns
+    /// The previous value
+    pub fn fetch_xor(&self, value: u32) -> u32 {
+        self.fetch_xor_with_ordering(value, SeqCst)
+    }
+
+    /// Bitwise xor with the futex word with an explicit memory ordering
+    /// # Arguments
+    /// * `value` - The bits to flip
+    /// * `order` - The ordering of the operation
+    /// # Returns
+    /// The previous value
+    pub fn fetch_xor_with_ordering(&self, value: u32, order: Ordering) -> u32 {
+        self.as_atomic().fetch_xor(value, order)
+    }
+
+    /// Syscall futex with errno decoding
+    /// Low level escape hatch, prefer the typed [`SharedFutex::futex`]
+    /// # Arguments
+    /// * `futex_op` - The futex operation, including flags
+    /// * `value` - The value to pass to the futex operation
+    /// * `timeout_or_val2` - The timeout pointer or val2, depending on the operation
+    /// * `uaddr2` - The second futex word of requeue operations
+    /// * `val3` - The third value to pass to the futex operation
+    /// # Returns
+    /// The non negative result of the syscall, or the decoded errno
+    /// # Safety
+    /// The futex pointer must point to a valid, mapped and 4 bytes aligned word and the other
+    /// pointers must be valid for the operation
+    #[must_use = "check the return value for errors"]
+    pub unsafe fn syscall_futex_checked(
+        &self,
+        futex_op: i32,
+        value: u32,
+        timeout_or_val2: usize,
+        uaddr2: *mut c_void,
+        val3: u32,
+    ) -> Result<i64, FutexError> {
+        self.backend
+            .futex(self.futex, futex_op, value, timeout_or_val2, uaddr2, val3)
+    }
+
     /// Syscall futex
+    /// Low level escape hatch, prefer the typed [`SharedFutex::futex`]
     /// # Arguments
     /// * `futex_op` - The futex operation
     /// * `value` - The value to pass to the futex operation
     /// * `val3` - The third value to pass to the futex operation
     /// # Returns
     /// The result of the syscall
-    pub unsafe fn syscall_futex(&mut self, futex_op: i32, value: u32, val3: u32) -> i64 {
-        libc::syscall(libc::SYS_futex, self.futex, futex_op, value, 0, 0, val3)
+    /// # Safety
+    /// The futex pointer must point to a valid, mapped and 4 bytes aligned word
+    #[must_use = "check the return value for errors"]
+    pub unsafe fn syscall_futex(&self, futex_op: i32, value: u32, val3: u32) -> i64 {
+        self.syscall_futex_checked(futex_op, value, 0, std::ptr::null_mut(), val3)
+            .unwrap_or(-1)
     }
 
     /// Syscall futex
+    /// Low level escape hatch, prefer the typed [`SharedFutex::futex`]
     /// # Arguments
     /// * `futex_op` - The futex operation
     /// * `value` - The value to pass to the futex operation
-    /// * `val2` - The second value to pass to the futex operation
+    /// * `val2` - The second value to pass to the futex operation, sent in the 4th syscall
+    ///   argument. Waits take their timeout pointer there, use
+    ///   [`SharedFutex::syscall_futex3_wait`] for them.
     /// * `val3` - The third value to pass to the futex operation
     /// # Returns
     /// The result of the syscall
-    pub unsafe fn syscall_futex3(
-        &mut self,
-        futex_op: i32,
-        value: u32,
-        val2: u32,
-        val3: u32,
-    ) -> i64 {
-        libc::syscall(libc::SYS_futex, self.futex, futex_op, value, val2, 0, val3)
+    /// # Safety
+    /// The futex pointer must point to a valid, mapped and 4 bytes aligned word
+    #[must_use = "check the return value for errors"]
+    pub unsafe fn syscall_futex3(&self, futex_op: i32, value: u32, val2: u32, val3: u32) -> i64 {
+        self.syscall_futex_checked(futex_op, value, val2 as usize, std::ptr::null_mut(), val3)
+            .unwrap_or(-1)
     }
 
     /// Syscall futex
+    /// Low level escape hatch, prefer the typed [`SharedFutex::futex`]
     /// # Arguments
     /// * `futex_op` - The futex operation
     /// * `value` - The value to pass to the futex operation
-    /// * `timeout` - The timespec value to pass to the futex operation
+    /// * `timeout` - The timespec value to pass to the futex operation, sent in the 4th syscall
+    ///   argument (utime)
     /// * `val3` - The third value to pass to the futex operation
     /// # Returns
     /// The result of the syscall
+    /// # Safety
+    /// The futex pointer must point to a valid, mapped and 4 bytes aligned word
+    #[must_use = "check the return value for errors"]
     pub unsafe fn syscall_futex3_wait(
-        &mut self,
+        &self,
         futex_op: i32,
         value: u32,
         timeout: *const libc::timespec,
         val3: u32,
     ) -> i64 {
-        libc::syscall(
-            libc::SYS_futex,
-            self.futex,
+        self.syscall_futex_checked(
             futex_op,
             value,
-            timeout,
-            0,
+            timeout as usize,
+            std::ptr::null_mut(),
             val3,
         )
+        .unwrap_or(-1)
     }
 
     /// Post a futex
     /// # Arguments
     /// * `number_of_waiters` - The number of waiters to notify
     /// # Returns
-    /// the ret value of the syscall
-    /// Nothing
-    pub fn post(&mut self, number_of_waiters: u32) -> i64 {
-        unsafe {
-            let s = self.syscall_futex(libc::FUTEX_WAKE, number_of_waiters, 0);
-            s
+    /// The number of waiters the kernel woke, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn post(&self, number_of_waiters: u32) -> Result<usize, RufutexError> {
+        let woken = self.futex(FutexOp::Wake {
+            count: number_of_waiters,
+        })?;
+        Ok(woken as usize)
+    }
+
+    /// Wakes one waiter
+    /// # Returns
+    /// The number of waiters woken, 0 or 1, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn wake_one(&self) -> Result<usize, RufutexError> {
+        self.post(1)
+    }
+
+    /// Wakes every waiter
+    /// The kernel reads the count as a signed int, so it is passed as i32::MAX.
+    /// # Returns
+    /// The number of waiters woken, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn wake_all(&self) -> Result<usize, RufutexError> {
+        self.post(i32::MAX as u32)
+    }
+
+    /// Wakes up to `n` waiters and returns how many the kernel actually woke
+    /// Counts above i32::MAX are clamped, the kernel would read them as negative. A count of
+    /// 0 makes no system call: FUTEX_WAKE with 0 wakes one waiter, the kernel only checks the
+    /// count after each wake up, so it cannot count the waiters without waking them either.
+    /// # Arguments
+    /// * `n` - The maximum number of waiters to wake
+    /// # Returns
+    /// The number of waiters woken, at most `n`, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn wake_n(&self, n: u32) -> Result<u32, FutexError> {
+        if n == 0 {
+            return Ok(0);
+        }
+        let woken = self.futex(FutexOp::Wake {
+            count: n.min(i32::MAX as u32),
+        })?;
+        Ok(woken as u32)
+    }
+
+    /// Kicks up to `n` waiters out of their sleep without changing the word
+    /// Their wait returns Spurious, a waiter that loops on the word goes back to sleep unless
+    /// the caller changed some condition it checks first. Moving the waiters to another word
+    /// with FUTEX_REQUEUE and a requeue count of 0 would do exactly this wake, so it is issued
+    /// as a FUTEX_WAKE. [`SharedFutex::wait_cancellable`] cancels a wait without a lost wake up.
+    /// # Arguments
+    /// * `n` - The maximum number of waiters to kick out
+    /// # Returns
+    /// The number of waiters woken, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn cancel_waiters(&self, n: u32) -> Result<i64, FutexError> {
+        self.wake_n(n).map(i64::from)
+    }
+
+    /// Wakes up to `n` waiters whose mask shares a bit with `mask`
+    /// Several conditions can share one word, each with its own bits, and a wake only
+    /// releases the waiters of its conditions. [`FUTEX_BITSET_MATCH_ANY`] makes it a plain
+    /// wake. A count of 0 makes no system call, like [`SharedFutex::wake_n`].
+    /// # Arguments
+    /// * `n` - The maximum number of waiters to wake
+    /// * `mask` - The conditions signalled, the kernel rejects 0
+    /// # Returns
+    /// The number of waiters woken, Invalid for an empty mask, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn wake_masked(&self, n: u32, mask: u32) -> Result<u32, FutexError> {
+        if mask == 0 {
+            return Err(FutexError::Invalid);
+        }
+        if n == 0 {
+            return Ok(0);
         }
+        let woken = self.futex(FutexOp::WakeBitset { count: n, mask })?;
+        Ok(woken as u32)
     }
 
     /// Post a futex
@@ -121,121 +862,700 @@ impl SharedFutex {
     /// * `number_of_waiters` - The number of waiters to notify
     /// * `value` - The value to set the futex to
     /// # Returns
-    /// the ret value of the syscall
-    /// Nothing
-    pub fn post_with_value(&mut self, value: u32, number_of_waiters: u32) -> i64 {
-        unsafe {
-            (*self.atom).store(value, SeqCst);
-            let s = self.syscall_futex(libc::FUTEX_WAKE, number_of_waiters, 0);
-            s
-        }
+    /// The number of waiters the kernel woke, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn post_with_value(
+        &self,
+        value: u32,
+        number_of_waiters: u32,
+    ) -> Result<usize, RufutexError> {
+        self.set_futex_value(value);
+        self.post(number_of_waiters)
     }
 
-    /// Sets the value of the futex
+    /// Parks the calling thread if the futex word holds `expected`
+    /// The kernel compares the word and puts the thread to sleep atomically, so a store and
+    /// [`SharedFutex::unpark_by_value`] racing with the call cannot be missed.
     /// # Arguments
-    /// * `value` - The value to set the futex to
+    /// * `expected` - The value the word must hold for the thread to park
     /// # Returns
-    /// Nothing
-    pub fn set_futex_value(&mut self, value: u32) {
-        unsafe {
-            (*self.atom).store(value, SeqCst);
-        }
+    /// Ok once woken, which may be spurious, WouldBlock if the word did not hold `expected`, or
+    /// the error of the wait
+    #[must_use = "check the return value for errors"]
+    pub fn park_on_value(&self, expected: u32) -> Result<(), FutexError> {
+        self.futex(FutexOp::Wait {
+            expected,
+            timeout: None,
+        })
+        .map(|_| ())
     }
 
-    /// Sets the value of the futex
+    /// Stores `new_value` in the futex word and wakes threads parked on it
     /// # Arguments
-    /// * `value` - The value to set the futex to
+    /// * `new_value` - The value to store
+    /// * `count` - The number of threads to wake
     /// # Returns
-    /// Nothing
-    pub fn get_futex_value(&mut self) -> u32 {
-        let ret: u32;
-        unsafe {
-            ret = (*self.atom).load(SeqCst);
-        }
-        ret
+    /// The number of threads woken, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn unpark_by_value(&self, new_value: u32, count: u32) -> Result<i64, FutexError> {
+        self.set_futex_value(new_value);
+        self.futex(FutexOp::Wake { count })
     }
 
-    /// Wait on a futex
+    /// Adds `delta` to the futex word and wakes waiters
+    /// A consumer loads the word, decides to sleep and calls `wait(old_value)`. The kernel
+    /// compares the word with `old_value` under the futex hash bucket lock before sleeping, and
+    /// FUTEX_WAKE takes the same lock. So either the add happened before that comparison, the
+    /// word no longer holds `old_value` and the wait returns EAGAIN at once, or the consumer is
+    /// already queued when the wake looks for waiters and is woken. There is no window in which
+    /// the consumer sleeps on a value the producer has already changed.
     /// # Arguments
-    /// * `wait_value` - The value to wait on
+    /// * `delta` - The value to add, wrapping around
+    /// * `wake_count` - The number of waiters to wake
     /// # Returns
-    /// the ret value of the syscall
-    pub fn wait(&mut self, wait_value: u32) -> i64 {
-        unsafe {
-            let ret = self.syscall_futex(libc::FUTEX_WAIT, wait_value, 0);
+    /// The number of waiters woken, or the error of the wake
+    #[must_use = "check the return value for errors"]
+    pub fn atomic_add_and_wake(&self, delta: u32, wake_count: u32) -> Result<i64, FutexError> {
+        self.as_atomic().fetch_add(delta, SeqCst);
+        self.futex(FutexOp::Wake { count: wake_count })
+    }
 
-            ret
+    /// Wakes a waiter of this word onto `pi_futex` and moves others to wait on it
+    /// The waiters must sleep with [`FutexOp::WaitRequeuePi`] targeting `pi_futex`. Linux only
+    /// offers the compare variant, FUTEX_CMP_REQUEUE_PI, so the call compares against the word
+    /// as loaded on entry and fails with WouldBlock if it changes before the kernel looks. The
+    /// woken waiter returns owning `pi_futex`, the moved ones inherit through it like lockers.
+    /// # Arguments
+    /// * `wake_count` - The number of waiters to wake, the kernel only accepts 1
+    /// * `requeue_count` - The maximum number of waiters to move to `pi_futex`
+    /// * `pi_futex` - The priority inheritance mutex the waiters sleep for
+    /// # Returns
+    /// The number of waiters woken or moved, Invalid unless `wake_count` is 1, or the error of
+    /// the requeue
+    #[cfg(not(loom))]
+    #[must_use = "check the return value for errors"]
+    pub fn requeue_pi(
+        &self,
+        wake_count: u32,
+        requeue_count: u32,
+        pi_futex: &PiFutex,
+    ) -> Result<i64, FutexError> {
+        if wake_count != 1 {
+            return Err(FutexError::Invalid);
         }
+        let target = pi_futex.as_futex();
+        self.futex(FutexOp::CmpRequeuePi {
+            target: &target,
+            limit: requeue_count,
+            expected: self.get_futex_value(),
+        })
     }
 
-    /// Wait on a futex
+    /// Sets the value of the futex
     /// # Arguments
-    /// * `wait_value` - The value to wait on
+    /// * `value` - The value to set the futex to
     /// # Returns
-    /// the ret value of the syscall
-    pub fn wait_with_timeout(&mut self, wait_value: u32, timeout: libc::timespec) -> i64 {
-        let timeout = timeout;
+    /// Nothing
+    pub fn set_futex_value(&self, value: u32) {
+        self.set_futex_value_with_ordering(value, SeqCst);
+    }
+
+    /// Sets the value of the futex with an explicit memory ordering
+    /// # Arguments
+    /// * `value` - The value to set the futex to
+    /// * `order` - The ordering of the store, cannot be Acquire or AcqRel
+    pub fn set_futex_value_with_ordering(&self, value: u32, order: Ordering) {
+        debug_assert!(
+            is_store_ordering(order),
+            "ordering {:?} is not valid for a store",
+            order
+        );
+        self.as_atomic().store(value, order);
+    }
+
+    /// Hints the CPU to fetch the cache line of the word for a write
+    /// Meant right before a contended lock(), so the line is owned by the time the CAS runs.
+    /// Issues PREFETCHW, or PREFETCHT0 without that instruction, on x86, PRFM PSTL1KEEP on
+    /// aarch64 and nothing on the other targets.
+    #[inline]
+    pub fn prefetch_for_write(&self) {
+        #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(miri)))]
+        unsafe {
+            #[cfg(target_arch = "x86")]
+            use std::arch::x86::{_mm_prefetch, _MM_HINT_ET0};
+            #[cfg(target_arch = "x86_64")]
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_ET0};
+            _mm_prefetch::<_MM_HINT_ET0>(self.atom as *const i8);
+        }
+        #[cfg(all(target_arch = "aarch64", not(miri)))]
         unsafe {
-            let ret = self.syscall_futex3_wait(libc::FUTEX_WAIT, wait_value, &timeout, 0);
-            ret
+            std::arch::asm!("prfm pstl1keep, [{0}]", in(reg) self.atom, options(nostack, preserves_flags));
         }
     }
 
-    /// Lock the futex
-    pub fn lock(&mut self) {
-        let mut ret = Self::cmpxchg(self.atom, UNLOCKED, LOCKED_NO_WAITERS);
-
-        // If the lock was previously unlocked, there's nothing else for us to do.
-        // Otherwise, we'll probably have to wait.
-        if ret != 0 {
-            loop {
-                // If the mutex is locked, we signal that we're waiting by setting the
-                // atom to 2. A shortcut checks is it's LOCKED_WAITERS already and avoids the atomic
-                // operation in this case.
-                if (ret == LOCKED_WAITERS)
-                    || (Self::cmpxchg(self.atom, LOCKED_NO_WAITERS, LOCKED_WAITERS) != UNLOCKED)
-                {
-                    // Here we have to actually sleep, because the mutex is actually
-                    // locked. Note that it's not necessary to loop around this syscall;
-                    // a spurious wakeup will do no harm since we only exit the do...while
-                    // loop when atom_ is indeed 0.
-                    //self.syscall_futex(libc::FUTEX_WAIT, 2, 0);
-                    self.wait(LOCKED_WAITERS);
-                }
-                // We're here when either:
-                // (a) the mutex was in fact unlocked (by an intervening thread).
-                // (b) we slept waiting for the atom and were awoken.
-                //
-                // So we try to lock the atom again. We set teh state to 2 because we
-                // can't be certain there's no other thread at this exact point. So we
-                // prefer to err on the safe side.
-                ret = Self::cmpxchg(self.atom, UNLOCKED, LOCKED_WAITERS);
-                if ret == 0 {
-                    break;
-                }
-            }
+    /// Hints the CPU to fetch the cache line of the word for a read
+    /// Issues PREFETCHT0 on x86, PRFM PLDL1KEEP on aarch64 and nothing on the other targets.
+    #[inline]
+    pub fn prefetch_for_read(&self) {
+        #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), not(miri)))]
+        unsafe {
+            #[cfg(target_arch = "x86")]
+            use std::arch::x86::{_mm_prefetch, _MM_HINT_T0};
+            #[cfg(target_arch = "x86_64")]
+            use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+            _mm_prefetch::<_MM_HINT_T0>(self.atom as *const i8);
+        }
+        #[cfg(all(target_arch = "aarch64", not(miri)))]
+        unsafe {
+            std::arch::asm!("prfm pldl1keep, [{0}]", in(reg) self.atom, options(nostack, preserves_flags));
         }
     }
 
+    /// Gets the value of the futex
+    /// # Returns
+    /// The current value of the futex
+    pub fn get_futex_value(&self) -> u32 {
+        self.get_futex_value_with_ordering(SeqCst)
+    }
+
+    /// Gets the value of the futex with an explicit memory ordering
+    /// # Arguments
+    /// * `order` - The ordering of the load, cannot be Release or AcqRel
+    /// # Returns
+    /// The current value of the futex
+    pub fn get_futex_value_with_ordering(&self, order: Ordering) -> u32 {
+        debug_assert!(
+            is_load_ordering(order),
+            "ordering {:?} is not valid for a load",
+            order
+        );
+        self.as_atomic().load(order)
+    }
+
+    /// Publishes `value` with a Release store
+    /// Everything written before the store is visible to a thread that reads the value with
+    /// [`SharedFutex::load_acquire`]. A `store_release` followed by a FUTEX_WAKE thus
+    /// synchronizes-with a waiter whose FUTEX_WAIT returns and which then calls `load_acquire`:
+    /// the kernel orders the wake after the store, the waiter loads the published value.
+    /// # Arguments
+    /// * `value` - The value to publish
+    pub fn store_release(&self, value: u32) {
+        self.as_atomic().store(value, Release);
+    }
+
+    /// Reads the futex word with an Acquire load
+    /// Pairs with [`SharedFutex::store_release`], the writes published before the store are
+    /// visible after the load returns its value.
+    /// # Returns
+    /// The current value of the futex
+    pub fn load_acquire(&self) -> u32 {
+        self.as_atomic().load(Acquire)
+    }
+
+    /// Create a file descriptor that becomes readable when the futex is woken, FUTEX_FD
+    /// Deprecated by the kernel: FUTEX_FD was racy and has been removed in Linux 2.6.26, newer
+    /// kernels reject it. It is kept for event loops on old kernels; io_uring futex operations
+    /// (Linux 6.7) are the way to wait on a futex from an event loop today.
+    /// The descriptor is armed once, a wake up makes it readable for select/poll/epoll.
+    /// # Arguments
+    /// * `signal` - The signal to deliver on wake up through F_SETOWN, 0 for none
+    /// # Returns
+    /// The descriptor, or FutexError::NotSupported if the kernel does not know the operation
+    pub fn futex_fd(&self, signal: i32) -> Result<OwnedFd, FutexError> {
+        // FUTEX_FD cannot be combined with FUTEX_PRIVATE_FLAG
+        let ret = unsafe {
+            self.backend.futex(
+                self.futex,
+                FUTEX_FD,
+                signal as u32,
+                0,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        match ret {
+            Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) }),
+            Err(FutexError::Invalid) | Err(FutexError::NoSys) => Err(FutexError::NotSupported),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Wait on a futex
+    /// # Arguments
+    /// * `wait_value` - The value to wait on
+    /// # Returns
+    /// Why the wait returned, or the FutexError of a failed call
+    #[must_use = "check the return value for errors"]
+    pub fn wait(&self, wait_value: u32) -> Result<FutexWakeReason, FutexError> {
+        let ret = self.futex(FutexOp::Wait {
+            expected: wait_value,
+            timeout: None,
+        });
+        self.wake_reason(wait_value, ret)
+    }
+
+    /// Maps the result of FUTEX_WAIT to a FutexWakeReason
+    fn wake_reason(
+        &self,
+        wait_value: u32,
+        ret: Result<i64, FutexError>,
+    ) -> Result<FutexWakeReason, FutexError> {
+        match ret {
+            // The kernel returns 0 for both, the word tells whether anything happened
+            Ok(_) if self.get_futex_value_with_ordering(Acquire) == wait_value => {
+                Ok(FutexWakeReason::Spurious)
+            }
+            Ok(_) => Ok(FutexWakeReason::Woken),
+            Err(FutexError::Interrupted) => Ok(FutexWakeReason::Interrupted),
+            Err(FutexError::WouldBlock) => Ok(FutexWakeReason::ValueMismatch),
+            Err(FutexError::TimedOut) => Ok(FutexWakeReason::TimedOut),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Loads the futex word and sleeps on it if `condition` holds for the loaded value
+    /// The kernel compares the word with the loaded value before sleeping, so a change made
+    /// between the load and the wait makes the wait return at once instead of missing the wake
+    /// up. The call returns after one wait, callers loop until the condition no longer holds.
+    /// # Arguments
+    /// * `condition` - Decides from the loaded value whether to wait
+    /// # Returns
+    /// The value that was loaded
+    pub fn load_and_wait_if(&self, condition: impl Fn(u32) -> bool) -> u32 {
+        let value = self.get_futex_value_with_ordering(Acquire);
+        if condition(value) {
+            // EAGAIN means the word already moved on, which is what the caller waits for
+            let _ = self.wait(value);
+        }
+        value
+    }
+
+    /// Moves the futex word from one state to another and wakes waiters
+    /// This is the building block for small state machines shared between processes:
+    /// the word is compare-and-exchanged from `from` to `to` and, on success, up to `wake`
+    /// waiters blocked in [`SharedFutex::await_state`] are woken.
+    /// # Arguments
+    /// * `from` - The state the futex word is expected to hold
+    /// * `to` - The new state
+    /// * `wake` - The number of waiters to wake after the transition
+    /// # Returns
+    /// Ok if the transition happened, or a TransitionError with the observed state
+    #[must_use = "check the return value for errors"]
+    pub fn transition(&self, from: u32, to: u32, wake: u32) -> Result<(), TransitionError> {
+        unsafe { (*self.atom).compare_exchange(from, to, SeqCst, SeqCst) }
+            .map_err(|observed| TransitionError { observed })?;
+        let _ = self.wake_n(wake);
+        Ok(())
+    }
+
+    /// Stores `new_value` if the futex word holds `expected`, then wakes waiters
+    /// FUTEX_WAKE_OP always applies its operation before comparing, so it cannot express a
+    /// conditional store; the update is a CAS and the wake only follows a successful one.
+    /// Waiters that went to sleep on `expected` are woken because the word changed under them,
+    /// the CAS followed by the wake leaves no window for a missed wake up.
+    /// # Arguments
+    /// * `expected` - The value the futex word is expected to hold
+    /// * `new_value` - The value to store
+    /// * `wake_count` - The number of waiters to wake if the store happened
+    /// # Returns
+    /// Ok(true) if the value was swapped and the waiters woken, Ok(false) if the word did not
+    /// hold `expected`, or the error of the wake syscall
+    #[must_use = "check the return value for errors"]
+    pub fn compare_and_wake(
+        &self,
+        expected: u32,
+        new_value: u32,
+        wake_count: u32,
+    ) -> Result<bool, FutexError> {
+        if self.compare_exchange(expected, new_value).is_err() {
+            return Ok(false);
+        }
+        self.wake_n(wake_count)?;
+        Ok(true)
+    }
+
+    /// Blocks until the futex word holds `state`
+    /// # Arguments
+    /// * `state` - The state to wait for
+    /// * `timeout` - The maximum time to wait, None waits forever
+    /// # Returns
+    /// true if the state was reached, false if the timeout expired first
+    #[must_use = "if false the timeout expired"]
+    pub fn await_state(&self, state: u32, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map_or(Deadline::never(), Deadline::after);
+        loop {
+            let current = self.get_futex_value();
+            if current == state {
+                return true;
+            }
+            if !deadline.wait(self, current) {
+                return false;
+            }
+        }
+    }
+
+    /// Lock the futex
+    /// Memory ordering follows Drepper's "Futexes are Tricky" mutex 2: the CAS that takes the
+    /// lock is Acquire and pairs with the Release in unlock(), which is all a mutex needs. Every
+    /// access of the protocol is to the one word, whose modification order alone rules out a
+    /// lost wake up. The FUTEX_WAIT/FUTEX_WAKE syscalls carry their own full barriers in the
+    /// kernel, so the sleep/wake handshake does not rely on SeqCst user space operations.
+    /// tests/loom.rs checks the protocol under these orderings.
+    pub fn lock(&self) {
+        self.lock_traced();
+    }
+
+    /// Lock the futex and report how the lock was acquired
+    /// The uncontended path is the same single CAS as [`SharedFutex::lock`], the accounting
+    /// only happens once the lock is found held and the clock is only read before sleeping.
+    /// # Returns
+    /// Whether the lock was free, taken after retrying or taken after sleeping in the kernel
+    pub fn lock_traced(&self) -> LockOutcome {
+        match self.lock_before(Deadline::never()) {
+            Ok(outcome) => outcome,
+            Err(_) => unreachable!("lock without a deadline cannot time out"),
+        }
+    }
+
+    /// Lock the futex from async code
+    /// A free lock is taken in the first poll. Otherwise a helper thread sleeps in lock() on
+    /// behalf of the task, see [`crate::async_lock`]; dropping the future before it completes
+    /// gives the lock up without stranding the other waiters. That drop blocks the dropping
+    /// thread, usually a runtime worker, until the helper lets go of the word: one
+    /// futex_waitv(2) wake up, or up to 10ms on kernels older than 5.16.
+    /// # Safety
+    /// The helper uses the futex word until the future completes or is dropped. A pending
+    /// future that is leaked, with `mem::forget` or a reference cycle, is never dropped, and
+    /// its helper may sleep on the word and take the lock at any later time. The word must
+    /// stay mapped for as long as that may happen. A SharedFutex owning its mapping, from
+    /// [`SharedFutex::new_anonymous`], [`SharedFutex::create_memfd`], [`SharedFutex::from_fd`]
+    /// or [`SharedFutex::open_path`], meets this on its own: the helper holds a clone of it.
+    /// # Returns
+    /// A future resolving to a guard unlocking the futex when dropped
+    #[cfg(all(feature = "async", not(loom)))]
+    pub unsafe fn lock_async(&self) -> LockFuture<'_, B>
+    where
+        B: Clone + Send + Sync + 'static,
+    {
+        LockFuture::new(self)
+    }
+
+    /// The lock() protocol, `sleep` sleeps while the word holds the value it is given
+    /// `sleep` returns whether a FUTEX_WAKE ended the sleep, or an error to give up with.
+    fn lock_with<E>(
+        &self,
+        mut sleep: impl FnMut(u32) -> Result<bool, E>,
+    ) -> Result<LockOutcome, E> {
+        let mut ret = Self::cmpxchg(self.atom, UNLOCKED, LOCKED_NO_WAITERS);
+
+        // If the lock was previously unlocked, there's nothing else for us to do.
+        // Otherwise, we'll probably have to wait.
+        if ret == UNLOCKED {
+            #[cfg(feature = "metrics")]
+            self.metrics.acquired();
+            if let Some(stats) = self.shared_stats_block() {
+                stats.acquired(None);
+            }
+            return Ok(LockOutcome::Uncontended);
+        }
+
+        trace_event!("lock {:p} contended, observed state {}", self.futex, ret);
+        let stats_since = self.stats.map(|_| Instant::now());
+        #[cfg(feature = "metrics")]
+        let contended_at = Instant::now();
+        let mut iterations = 0;
+        let mut sleeps = 0;
+        let mut slept_at = None;
+        let mut woken = false;
+        loop {
+            iterations += 1;
+            if ret == HANDED_OFF {
+                // unlock_fair() passed the lock on, it belongs to a thread that was asleep
+                // when it happened. The others sleep until it is unlocked again.
+                if woken {
+                    ret = Self::cmpxchg(self.atom, HANDED_OFF, LOCKED_WAITERS);
+                    if ret == HANDED_OFF {
+                        break;
+                    }
+                    woken = false;
+                    continue;
+                }
+                slept_at.get_or_insert_with(Instant::now);
+                sleeps += 1;
+                woken = sleep(HANDED_OFF)?;
+            }
+            // If the mutex is locked, we signal that we're waiting by setting the
+            // atom to 2. A shortcut checks is it's LOCKED_WAITERS already and avoids the atomic
+            // operation in this case.
+            else if (ret == LOCKED_WAITERS)
+                || (Self::cmpxchg(self.atom, LOCKED_NO_WAITERS, LOCKED_WAITERS) != UNLOCKED)
+            {
+                // Here we have to actually sleep, because the mutex is actually
+                // locked. Note that it's not necessary to loop around this syscall;
+                // a spurious wakeup will do no harm since we only exit the do...while
+                // loop when atom_ is indeed 0.
+                slept_at.get_or_insert_with(Instant::now);
+                sleeps += 1;
+                woken = sleep(LOCKED_WAITERS)?;
+            }
+            // We're here when either:
+            // (a) the mutex was in fact unlocked (by an intervening thread).
+            // (b) we slept waiting for the atom and were awoken.
+            //
+            // So we try to lock the atom again. We set teh state to 2 because we
+            // can't be certain there's no other thread at this exact point. So we
+            // prefer to err on the safe side.
+            ret = Self::cmpxchg(self.atom, UNLOCKED, LOCKED_WAITERS);
+            if ret == 0 {
+                break;
+            }
+        }
+
+        trace_event!("lock {:p} acquired after contention", self.futex);
+        #[cfg(feature = "metrics")]
+        self.metrics.contended(contended_at.elapsed());
+        if let Some(stats) = self.shared_stats_block() {
+            stats.acquired(stats_since.map(|since| since.elapsed()));
+        }
+        Ok(match slept_at {
+            None => LockOutcome::Spun { iterations },
+            Some(slept_at) => LockOutcome::Slept {
+                times: sleeps,
+                waited: slept_at.elapsed(),
+            },
+        })
+    }
+
+    /// Lock the futex, spinning on plain loads before sleeping
+    /// Test-and-test-and-set: while the word is held the spin only reads it, so the cache line
+    /// stays shared between the spinning cores, and the CAS is attempted once it reads UNLOCKED.
+    /// When the spins run out the call sleeps like [`SharedFutex::lock`]. See
+    /// [`crate::spin_wait::SpinWaitFutex`] for a spin with backoff and yields.
+    /// # Arguments
+    /// * `spin_count` - The number of reads of the word before sleeping
+    pub fn lock_ttas(&self, spin_count: u32) {
+        for _ in 0..spin_count {
+            if self.get_futex_value_with_ordering(Relaxed) == UNLOCKED && self.try_lock() {
+                return;
+            }
+            std::hint::spin_loop();
+        }
+        self.lock();
+    }
+
+    /// Try to lock the futex without blocking
+    /// # Returns
+    /// true if the lock was acquired
+    #[must_use = "if false the lock was not acquired"]
+    pub fn try_lock(&self) -> bool {
+        let acquired = Self::cmpxchg(self.atom, UNLOCKED, LOCKED_NO_WAITERS) == UNLOCKED;
+        #[cfg(feature = "metrics")]
+        if acquired {
+            self.metrics.acquired();
+        }
+        if let (true, Some(stats)) = (acquired, self.shared_stats_block()) {
+            stats.acquired(None);
+        }
+        acquired
+    }
+
+    /// Try to lock the futex with a weak compare and exchange
+    /// The CAS may fail spuriously even though the futex is unlocked. On load-linked/
+    /// store-conditional machines such as ARM it compiles to a single LDREX/STREX (LDXR/STXR)
+    /// attempt instead of the retry loop of [`SharedFutex::try_lock`]. Prefer it when the
+    /// caller polls in a loop anyway, where a spurious failure only costs one more iteration;
+    /// use try_lock when a single attempt has to be conclusive.
+    /// # Returns
+    /// true if the lock was acquired, false if it is held or the CAS failed spuriously
+    #[must_use = "if false the lock was not acquired"]
+    pub fn try_lock_weak(&self) -> bool {
+        let acquired = self
+            .as_atomic()
+            .compare_exchange_weak(UNLOCKED, LOCKED_NO_WAITERS, Acquire, Relaxed)
+            .is_ok();
+        #[cfg(feature = "metrics")]
+        if acquired {
+            self.metrics.acquired();
+        }
+        if let (true, Some(stats)) = (acquired, self.shared_stats_block()) {
+            stats.acquired(None);
+        }
+        acquired
+    }
+
+    /// Tries to lock the futex, spinning for a bounded number of attempts
+    /// Each attempt is a weak compare and exchange followed by a spin loop hint. Unlike
+    /// [`SharedFutex::lock_ttas`] it never sleeps, it gives up once the attempts run out.
+    /// # Arguments
+    /// * `max_spins` - The number of attempts, 0 makes a single one
+    /// # Returns
+    /// Ok once locked, WouldBlock if the futex stayed held
+    pub fn try_lock_or_spin(&self, max_spins: u32) -> Result<(), FutexError> {
+        for _ in 0..max_spins.max(1) {
+            if self.try_lock_weak() {
+                return Ok(());
+            }
+            std::hint::spin_loop();
+        }
+        Err(FutexError::WouldBlock)
+    }
+
     /// Unlock the futex
-    /// If there are waiters, we wake them up
+    /// If there are waiters, one of them is woken up to take the lock
     /// If there are no waiters, we set the atom to UNLOCKED
+    pub fn unlock(&self) {
+        self.release(1);
+    }
+
+    /// Unlock the futex and wake up to `how_may_waiters` waiters
+    /// Only one of them gets the lock, the others go back to sleep. At least one waiter is
+    /// woken, a count of 0 would leave them asleep on an unlocked futex.
     /// # Arguments
     /// * `how_may_waiters` - The number of waiters to wake up
-    pub fn unlock(&mut self, how_may_waiters: u32) {
-        //let val = self.atom;
+    /// # Returns
+    /// The number of waiters woken, 0 when nobody waited
+    pub fn unlock_many(&self, how_may_waiters: u32) -> usize {
+        self.release(how_may_waiters.max(1))
+    }
+
+    /// Unlock the futex and wake one waiter, like [`SharedFutex::unlock`]
+    /// # Returns
+    /// The number of waiters woken, 0 when nobody waited
+    pub fn unlock_one(&self) -> usize {
+        self.release(1)
+    }
+
+    /// Unlock the futex and wake every waiter
+    /// # Returns
+    /// The number of waiters woken, 0 when nobody waited
+    pub fn unlock_all(&self) -> usize {
+        self.release(i32::MAX as u32)
+    }
+
+    fn release(&self, how_may_waiters: u32) -> usize {
+        // Both the decrement and the swap can hand the lock to the next owner, so both are
+        // Release to publish the critical section to its Acquire CAS in lock().
+        if let Some(stats) = self.shared_stats_block() {
+            stats.releasing();
+        }
         let ret: u32;
         unsafe {
-            ret = (*self.atom).fetch_sub(1, SeqCst);
+            ret = (*self.atom).fetch_sub(1, Release);
         }
 
         if ret != LOCKED_NO_WAITERS {
+            // A swap rather than a store: a locker may move the word from 1 to 2 between the
+            // fetch_sub and here, and as a read-modify-write the swap is ordered after that
+            // CAS. loom does not order a plain store after a concurrent CAS and would report
+            // the waiter as stranded. It only runs before the FUTEX_WAKE, so it costs nothing.
             unsafe {
-                (*self.atom).store(UNLOCKED, SeqCst);
-                self.post(how_may_waiters);
+                (*self.atom).swap(UNLOCKED, Release);
             }
+            let woken = self.post(how_may_waiters).unwrap_or(0);
+            trace_event!(
+                "unlock of {:p} woke {} of {} requested waiters",
+                self.futex,
+                woken,
+                how_may_waiters
+            );
+            #[cfg(feature = "metrics")]
+            self.metrics.woken(woken);
+            woken
+        } else {
+            0
         }
     }
+
+    /// Panics in debug builds if the futex is unlocked
+    /// For the functions that expect their caller to hold the lock. Only the word is checked,
+    /// not who holds the lock. Release builds compile it away.
+    #[track_caller]
+    pub fn debug_assert_locked(&self) {
+        debug_assert!(
+            self.get_futex_value_with_ordering(Relaxed) != UNLOCKED,
+            "futex {:p} is not locked",
+            self.futex
+        );
+    }
+
+    /// Panics in debug builds if the futex is locked
+    /// Release builds compile it away.
+    #[track_caller]
+    pub fn debug_assert_unlocked(&self) {
+        debug_assert!(
+            self.get_futex_value_with_ordering(Relaxed) == UNLOCKED,
+            "futex {:p} is locked",
+            self.futex
+        );
+    }
+
+    /// Runs `f` with the lock held
+    /// The lock is released when `f` returns or panics, so it cannot be left locked by mistake.
+    /// # Arguments
+    /// * `f` - The critical section
+    /// # Returns
+    /// The value returned by `f`
+    pub fn with_lock<R>(&self, f: impl FnOnce() -> R) -> Result<R, FutexError> {
+        self.lock();
+        let _unlock = SharedFutexGuard::new(self);
+        Ok(f())
+    }
+}
+
+/// Unlocks the futex when dropped, see [`SharedFutex::lock_until`]
+#[must_use = "the futex is unlocked as soon as the guard is dropped"]
+pub struct SharedFutexGuard<'a, B: FutexBackend = SyscallBackend>(&'a SharedFutex<B>);
+
+impl<'a, B: FutexBackend> SharedFutexGuard<'a, B> {
+    /// Guards a futex locked by the caller
+    pub(crate) fn new(futex: &'a SharedFutex<B>) -> Self {
+        Self(futex)
+    }
+
+    /// Keeps the futex locked without the guard, the caller unlocks it
+    pub fn leak(self) {
+        mem::forget(self);
+    }
+}
+
+impl<B: FutexBackend> Drop for SharedFutexGuard<'_, B> {
+    fn drop(&mut self) {
+        self.0.unlock();
+    }
+}
+
+/// Checks that a pointer can be used as a futex word
+/// FUTEX_WAIT and friends require the word to be aligned like an AtomicU32; a misaligned word is
+/// rejected by the kernel with an EINVAL that is hard to trace back to its cause.
+/// # Arguments
+/// * `ptr` - The pointer to check
+/// # Returns
+/// Ok if the pointer is suitably aligned, FutexError::Misaligned otherwise
+pub fn validate_alignment(ptr: *mut c_void) -> Result<(), FutexError> {
+    if (ptr as usize).is_multiple_of(mem::align_of::<AtomicU32>()) {
+        Ok(())
+    } else {
+        Err(FutexError::Misaligned)
+    }
+}
+
+/// Orderings a load can use
+fn is_load_ordering(order: Ordering) -> bool {
+    !matches!(order, Ordering::Release | Ordering::AcqRel)
+}
+
+/// Orderings a store can use
+fn is_store_ordering(order: Ordering) -> bool {
+    !matches!(order, Ordering::Acquire | Ordering::AcqRel)
+}
+
+/// Converts a relative Duration into a timespec suitable for FUTEX_WAIT
+/// Seconds beyond what time_t holds saturate instead of wrapping into a negative timeout.
+pub(crate) fn duration_to_timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: libc::time_t::try_from(duration.as_secs()).unwrap_or(libc::time_t::MAX),
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
 }
 
 #[cfg(test)]
@@ -243,6 +1563,7 @@ mod tests {
     //use std::intrinsics::atomic_cxchg_acqrel_acquire;
 
     use super::*;
+    use crate::test_support::ShmSegment;
     use rushm::posixaccessor::POSIXShm;
     use std::mem;
     use std::sync::atomic;
@@ -250,6 +1571,7 @@ mod tests {
     use std::sync::mpsc;
     use std::{thread, time};
     #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
     fn test_atomic_in_shared_memory() {
         let mut shm = POSIXShm::<i32>::new("futex".to_string(), mem::size_of::<u32>());
         unsafe {
@@ -277,12 +1599,13 @@ mod tests {
     fn test_cmpxchg() {
         let mut atomic_val: AtomicU32 = AtomicU32::new(UNLOCKED);
         let before = atomic_val.load(atomic::Ordering::SeqCst);
-        let ret = SharedFutex::cmpxchg(&mut atomic_val, UNLOCKED, LOCKED_NO_WAITERS);
+        let ret = <SharedFutex>::cmpxchg(&mut atomic_val, UNLOCKED, LOCKED_NO_WAITERS);
         assert_eq!(before, UNLOCKED);
         assert_eq!(ret, before);
     }
 
     #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
     fn test_cmpxchg_shm() {
         unsafe {
             let mut shm =
@@ -297,7 +1620,7 @@ mod tests {
             (*atom_val).store(0xFF, atomic::Ordering::SeqCst);
 
             let before = (*atom_val).load(atomic::Ordering::SeqCst);
-            let ret = SharedFutex::cmpxchg(atom_val, UNLOCKED, LOCKED_NO_WAITERS);
+            let ret = <SharedFutex>::cmpxchg(atom_val, UNLOCKED, LOCKED_NO_WAITERS);
             assert_eq!(before, 0xFF);
             assert_eq!(ret, before);
 
@@ -357,6 +1680,7 @@ mod tests {
     }*/
 
     #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
     fn test_futex_lock_in_shared_memory() {
         let (tx, rx) = mpsc::channel();
         let mut shm = POSIXShm::<i32>::new(
@@ -377,7 +1701,7 @@ mod tests {
             assert_eq!(val, LOCKED_NO_WAITERS);
         }
 
-        let mut shared_futex = SharedFutex::new(ptr_shm);
+        let shared_futex = SharedFutex::new(ptr_shm);
 
         let handle = thread::spawn(move || {
             let mut shm = POSIXShm::<i32>::new(
@@ -389,7 +1713,7 @@ mod tests {
                 assert!(ret.is_ok());
             }
             let ptr_shm = shm.get_cptr_mut();
-            let mut shared_futex = SharedFutex::new(ptr_shm);
+            let shared_futex = SharedFutex::new(ptr_shm);
             tx.send(true).unwrap();
             shared_futex.lock();
         });
@@ -398,7 +1722,7 @@ mod tests {
 
         // wait a few ms to make sure the other thread is in the lock function
         thread::sleep(time::Duration::from_millis(500));
-        shared_futex.unlock(1);
+        shared_futex.unlock();
 
         handle.join().unwrap();
         unsafe {
@@ -408,6 +1732,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
     fn test_shared_lock_unlock() {
         let mut shm = POSIXShm::<i32>::new("test_shared_lock_unlock".to_string(), 8);
         unsafe {
@@ -415,12 +1740,12 @@ mod tests {
             assert!(ret.is_ok());
         }
         let ptr_shm = shm.get_cptr_mut();
-        let mut shared_futex = SharedFutex::new(ptr_shm);
+        let shared_futex = SharedFutex::new(ptr_shm);
 
         shared_futex.lock();
-        shared_futex.unlock(1);
+        shared_futex.unlock();
         shared_futex.lock();
-        shared_futex.unlock(1);
+        shared_futex.unlock();
 
         // Cleanup
         unsafe {
@@ -430,6 +1755,110 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_lock_traced() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        assert_eq!(futex.lock_traced(), LockOutcome::Uncontended);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let outcome = futex.lock_traced();
+                futex.unlock();
+                outcome
+            });
+            thread::sleep(time::Duration::from_millis(50));
+            futex.unlock();
+
+            match waiter.join().unwrap() {
+                LockOutcome::Slept { times, waited } => {
+                    assert!(times >= 1);
+                    assert!(waited > Duration::ZERO);
+                }
+                outcome => panic!("waiter did not sleep: {:?}", outcome),
+            }
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_lock_ttas_counter() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let counter = unsafe { (futex.futex as *mut u32).add(1) } as usize;
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        futex.lock_ttas(100);
+                        unsafe {
+                            let counter = counter as *mut u32;
+                            counter.write_volatile(counter.read_volatile() + 1);
+                        }
+                        futex.unlock();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(unsafe { (counter as *const u32).read_volatile() }, 4000);
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+        // No spin at all falls back to lock() at once
+        futex.lock_ttas(0);
+        assert_eq!(futex.get_futex_value(), LOCKED_NO_WAITERS);
+        futex.unlock();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_park_on_value() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(1);
+        assert_eq!(futex.park_on_value(0), Err(FutexError::WouldBlock));
+        futex.set_futex_value(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                while futex.get_futex_value() == 0 {
+                    let _ = futex.park_on_value(0);
+                }
+            });
+            thread::sleep(time::Duration::from_millis(50));
+            let woken = futex.unpark_by_value(1, 1).unwrap();
+            assert!(woken <= 1);
+        });
+        assert_eq!(futex.get_futex_value(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_atomic_add_and_wake() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+
+        thread::scope(|s| {
+            let consumer = s.spawn(|| {
+                let mut consumed = 0;
+                while consumed < 100 {
+                    let available = futex.get_futex_value();
+                    if available == 0 {
+                        // A lost wake up would leave the consumer asleep here
+                        let _ = futex.wait(0);
+                    } else if futex.compare_exchange(available, available - 1).is_ok() {
+                        consumed += 1;
+                    }
+                }
+                consumed
+            });
+            for _ in 0..100 {
+                assert!(futex.atomic_add_and_wake(1, 1).is_ok());
+            }
+            assert_eq!(consumer.join().unwrap(), 100);
+        });
+
+        assert_eq!(futex.get_futex_value(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
     fn test_shared_lock_timeout() {
         let mut shm = POSIXShm::<i32>::new("test_shared_lock_timeout".to_string(), 8);
         unsafe {
@@ -437,7 +1866,7 @@ mod tests {
             assert!(ret.is_ok());
         }
         let ptr_shm = shm.get_cptr_mut();
-        let mut shared_futex = SharedFutex::new(ptr_shm);
+        let shared_futex = SharedFutex::new(ptr_shm);
         let wait_time = libc::timespec {
             tv_sec: 0,
             tv_nsec: 500 * 1000 * 1000,
@@ -445,7 +1874,12 @@ mod tests {
 
         shared_futex.set_futex_value(1);
 
-        shared_futex.wait_with_timeout(1, wait_time);
+        let start = Instant::now();
+        assert_eq!(
+            shared_futex.wait_with_timeout(1, wait_time),
+            Ok(FutexWakeReason::TimedOut)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(500));
 
         // Cleanup
         unsafe {
@@ -453,4 +1887,1215 @@ mod tests {
             assert!(ret.is_ok());
         }
     }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_syscall_wait_timeout_slot() {
+        let segment = ShmSegment::create("test_syscall_wait_timeout_slot", 8);
+        let shared_futex = SharedFutex::new(segment.as_ptr());
+        shared_futex.set_futex_value(1);
+        let wait_time = duration_to_timespec(Duration::from_millis(50));
+
+        // The timeout is the 4th syscall argument, a wrong slot would block forever
+        let start = Instant::now();
+        let ret = unsafe { shared_futex.syscall_futex3_wait(libc::FUTEX_WAIT, 1, &wait_time, 0) };
+        assert_eq!(ret, -1);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_transition_reports_observed_state() {
+        let segment = ShmSegment::create("test_transition_observed", 8);
+        let shared_futex = SharedFutex::new(segment.as_ptr());
+        shared_futex.set_futex_value(3);
+
+        assert_eq!(
+            shared_futex.transition(0, 1, 1),
+            Err(TransitionError { observed: 3 })
+        );
+        assert!(shared_futex.transition(3, 4, 1).is_ok());
+        assert_eq!(shared_futex.get_futex_value(), 4);
+        assert!(!shared_futex.await_state(5, Some(time::Duration::from_millis(50))));
+        assert!(shared_futex.await_state(4, Some(time::Duration::from_millis(50))));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_transition_without_wake_count_wakes_nobody() {
+        let segment = &ShmSegment::create("test_transition_wakes_nobody", 8);
+        let futex = SharedFutex::new(segment.as_ptr());
+        futex.set_futex_value(0);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(move || {
+                SharedFutex::new(segment.as_ptr()).wait_for(0, Duration::from_millis(300))
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(futex.transition(0, 1, 0).is_ok());
+            // FUTEX_WAKE with a count of 0 would still have woken the waiter
+            assert_eq!(waiter.join().unwrap(), Ok(FutexWakeReason::TimedOut));
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_transition_state_machine() {
+        const IDLE: u32 = 0;
+        const REQUEST: u32 = 1;
+        const PROCESSING: u32 = 2;
+        const DONE: u32 = 3;
+        const ROUNDS: u32 = 5000;
+
+        let segment = ShmSegment::create("test_transition_state_machine", 8);
+        let client = SharedFutex::new(segment.as_ptr());
+        client.set_futex_value(IDLE);
+
+        let mapping = segment.map_again();
+        let handle = thread::spawn(move || {
+            let server = SharedFutex::new(mapping.as_ptr());
+            for _ in 0..ROUNDS {
+                assert!(server.await_state(REQUEST, None));
+                server.transition(REQUEST, PROCESSING, 1).unwrap();
+                server.transition(PROCESSING, DONE, 1).unwrap();
+            }
+        });
+
+        for _ in 0..ROUNDS {
+            client.transition(IDLE, REQUEST, 1).unwrap();
+            assert!(client.await_state(DONE, None));
+            client.transition(DONE, IDLE, 1).unwrap();
+        }
+
+        handle.join().unwrap();
+        assert_eq!(client.get_futex_value(), IDLE);
+    }
+
+    #[test]
+    fn test_compare_exchange_matches_std() {
+        let mut word = AtomicU32::new(5);
+        let reference = AtomicU32::new(5);
+        let futex = SharedFutex::new(&mut word as *mut AtomicU32 as *mut c_void);
+
+        let std_result =
+            reference.compare_exchange(5, 6, atomic::Ordering::SeqCst, atomic::Ordering::SeqCst);
+        assert_eq!(futex.compare_exchange(5, 6), std_result);
+        assert_eq!(futex.compare_exchange(5, 7), Err(6));
+        assert_eq!(
+            reference.compare_exchange(5, 7, atomic::Ordering::SeqCst, atomic::Ordering::SeqCst),
+            Err(6)
+        );
+
+        assert_eq!(
+            futex.fetch_add(u32::MAX),
+            reference.fetch_add(u32::MAX, atomic::Ordering::SeqCst)
+        );
+        assert_eq!(
+            futex.fetch_sub(10),
+            reference.fetch_sub(10, atomic::Ordering::SeqCst)
+        );
+        assert_eq!(
+            futex.fetch_or(0b1010),
+            reference.fetch_or(0b1010, atomic::Ordering::SeqCst)
+        );
+        assert_eq!(
+            word.load(atomic::Ordering::SeqCst),
+            reference.load(atomic::Ordering::SeqCst)
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_compare_exchange_shm() {
+        let segment = ShmSegment::create("test_compare_exchange_shm", 8);
+        let ptr_shm = segment.as_ptr();
+        let first = SharedFutex::new(ptr_shm);
+        let second = SharedFutex::new(ptr_shm);
+        first.set_futex_value(UNLOCKED);
+
+        assert_eq!(
+            second.compare_exchange(UNLOCKED, LOCKED_NO_WAITERS),
+            Ok(UNLOCKED)
+        );
+        assert_eq!(first.get_futex_value(), LOCKED_NO_WAITERS);
+        assert_eq!(
+            second.compare_exchange(UNLOCKED, LOCKED_WAITERS),
+            Err(LOCKED_NO_WAITERS)
+        );
+        assert_eq!(second.fetch_add(1), LOCKED_NO_WAITERS);
+        assert_eq!(first.get_futex_value(), LOCKED_WAITERS);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_lock_protects_plain_data() {
+        const THREADS: u64 = 4;
+        const ITERATIONS: u64 = 10000;
+        let size = 2 * mem::size_of::<u64>();
+        let segment = ShmSegment::create("test_lock_protects_plain_data", size);
+        SharedFutex::new(segment.as_ptr()).set_futex_value(UNLOCKED);
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let mapping = segment.map_again();
+                thread::spawn(move || {
+                    let ptr_shm = mapping.as_ptr();
+                    let shared_futex = SharedFutex::new(ptr_shm);
+                    // The counter is not atomic: only the lock orders the accesses
+                    let counter = unsafe { (ptr_shm as *mut u64).add(1) };
+                    for _ in 0..ITERATIONS {
+                        shared_futex.lock();
+                        unsafe {
+                            counter.write_volatile(counter.read_volatile() + 1);
+                        }
+                        shared_futex.unlock();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let counter = unsafe { (segment.as_ptr() as *mut u64).add(1) };
+        assert_eq!(unsafe { counter.read_volatile() }, THREADS * ITERATIONS);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_try_lock() {
+        let segment = ShmSegment::create("test_try_lock", 8);
+        let shared_futex = SharedFutex::new(segment.as_ptr());
+        shared_futex.set_futex_value(UNLOCKED);
+
+        assert!(shared_futex.try_lock());
+        assert!(!shared_futex.try_lock());
+        shared_futex.unlock();
+        assert!(shared_futex.try_lock());
+        shared_futex.unlock();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_relaxed_raw_accessors() {
+        let segment = ShmSegment::create("test_relaxed_raw_accessors", 8);
+        let shared_futex = SharedFutex::new(segment.as_ptr());
+
+        shared_futex.set_futex_value_with_ordering(10, atomic::Ordering::Relaxed);
+        assert_eq!(
+            shared_futex.get_futex_value_with_ordering(atomic::Ordering::Relaxed),
+            10
+        );
+        assert_eq!(
+            shared_futex.fetch_add_with_ordering(5, atomic::Ordering::Relaxed),
+            10
+        );
+        assert_eq!(
+            shared_futex.fetch_sub_with_ordering(1, atomic::Ordering::Release),
+            15
+        );
+        assert_eq!(
+            shared_futex.fetch_or_with_ordering(0x100, atomic::Ordering::AcqRel),
+            14
+        );
+        assert_eq!(
+            shared_futex.compare_exchange_with_ordering(
+                0x10E,
+                1,
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed
+            ),
+            Ok(0x10E)
+        );
+        assert_eq!(
+            shared_futex.compare_exchange_with_ordering(
+                0x10E,
+                2,
+                atomic::Ordering::Relaxed,
+                atomic::Ordering::Relaxed
+            ),
+            Err(1)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_release_load_is_rejected() {
+        let mut word = AtomicU32::new(0);
+        let shared_futex = SharedFutex::new(&mut word as *mut AtomicU32 as *mut c_void);
+        shared_futex.get_futex_value_with_ordering(atomic::Ordering::Release);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_acquire_store_is_rejected() {
+        let mut word = AtomicU32::new(0);
+        let shared_futex = SharedFutex::new(&mut word as *mut AtomicU32 as *mut c_void);
+        shared_futex.set_futex_value_with_ordering(1, atomic::Ordering::Acquire);
+    }
+
+    #[test]
+    fn test_validate_alignment() {
+        let mut words = [0u32; 2];
+        let aligned = words.as_mut_ptr() as *mut c_void;
+        let misaligned = unsafe { (aligned as *mut u8).add(1) } as *mut c_void;
+
+        assert_eq!(validate_alignment(aligned), Ok(()));
+        assert_eq!(validate_alignment(misaligned), Err(FutexError::Misaligned));
+        assert!(SharedFutex::try_new(aligned).is_ok());
+        assert!(matches!(
+            SharedFutex::try_new(misaligned),
+            Err(FutexError::Misaligned)
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_errno_decoding() {
+        let segment = ShmSegment::create("test_errno_decoding", 8);
+        let shared_futex = SharedFutex::new(segment.as_ptr());
+        shared_futex.set_futex_value(1);
+
+        let mismatch = shared_futex.futex(FutexOp::Wait {
+            expected: 0,
+            timeout: None,
+        });
+        assert_eq!(mismatch, Err(FutexError::WouldBlock));
+
+        let timed_out = shared_futex.futex(FutexOp::Wait {
+            expected: 1,
+            timeout: Some(duration_to_timespec(Duration::from_micros(1))),
+        });
+        assert_eq!(timed_out, Err(FutexError::TimedOut));
+
+        let invalid_op = 0x7f;
+        let invalid = unsafe {
+            shared_futex.syscall_futex_checked(invalid_op, 0, 0, std::ptr::null_mut(), 0)
+        };
+        assert!(matches!(
+            invalid,
+            Err(FutexError::NoSys) | Err(FutexError::Invalid)
+        ));
+        assert_eq!(unsafe { shared_futex.syscall_futex(invalid_op, 0, 0) }, -1);
+    }
+
+    #[test]
+    fn test_from_atomic_lock_unlock() {
+        struct Header {
+            lock: AtomicU32,
+            len: u32,
+        }
+        let header = Header {
+            lock: AtomicU32::new(UNLOCKED),
+            len: 3,
+        };
+
+        let futex = SharedFutex::from_atomic(&header.lock);
+        futex.lock();
+        assert_eq!(
+            header.lock.load(atomic::Ordering::SeqCst),
+            LOCKED_NO_WAITERS
+        );
+        assert!(!futex.try_lock());
+        futex.unlock();
+        assert_eq!(header.lock.load(atomic::Ordering::SeqCst), UNLOCKED);
+        assert_eq!(header.len, 3);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_from_non_null_lock_unlock() {
+        let segment = ShmSegment::create("test_from_non_null", 8);
+        let word = NonNull::new(segment.as_ptr() as *mut AtomicU32).unwrap();
+        let futex = SharedFutex::from_non_null(word);
+        futex.set_futex_value(UNLOCKED);
+
+        futex.lock();
+        assert_eq!(futex.get_futex_value(), LOCKED_NO_WAITERS);
+        futex.unlock();
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_relaxed_publish_with_barriers() {
+        let segment = ShmSegment::create("test_relaxed_barriers", 8);
+        let base = segment.as_ptr() as usize;
+        let futex = SharedFutex::new(base as *mut c_void);
+        futex.set_futex_value_with_ordering(0, Relaxed);
+
+        let handle = thread::spawn(move || {
+            let futex = SharedFutex::new(base as *mut c_void);
+            while futex.get_futex_value_with_ordering(Relaxed) == 0 {
+                std::hint::spin_loop();
+            }
+            SharedFutex::memory_barrier();
+            unsafe { std::ptr::read_volatile((base + 4) as *const u32) }
+        });
+
+        unsafe { std::ptr::write_volatile((base + 4) as *mut u32, 1234) };
+        SharedFutex::memory_barrier();
+        SharedFutex::compiler_barrier();
+        futex.set_futex_value_with_ordering(1, Relaxed);
+
+        assert_eq!(handle.join().unwrap(), 1234);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_release_acquire_publish() {
+        let segment = ShmSegment::create("test_release_acquire", 8);
+        let base = segment.as_ptr() as usize;
+        let futex = SharedFutex::new(base as *mut c_void);
+        futex.store_release(0);
+
+        let handle = thread::spawn(move || {
+            let futex = SharedFutex::new(base as *mut c_void);
+            while futex.load_acquire() == 0 {
+                let _ = futex.wait(0);
+            }
+            unsafe { std::ptr::read_volatile((base + 4) as *const u32) }
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        unsafe { std::ptr::write_volatile((base + 4) as *mut u32, 1234) };
+        futex.store_release(1);
+        let _ = futex.post(1);
+
+        assert_eq!(handle.join().unwrap(), 1234);
+        assert_eq!(futex.load_acquire(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_compare_and_wake() {
+        let segment = ShmSegment::create("test_compare_and_wake", 8);
+        let base = segment.as_ptr() as usize;
+        let futex = SharedFutex::new(base as *mut c_void);
+        futex.set_futex_value(0);
+
+        assert_eq!(futex.compare_and_wake(5, 1, 1), Ok(false));
+        assert_eq!(futex.get_futex_value(), 0);
+
+        let (tx, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let futex = SharedFutex::new(base as *mut c_void);
+            tx.send(()).unwrap();
+            while futex.get_futex_value() == 0 {
+                let _ = futex.wait(0);
+            }
+            futex.get_futex_value()
+        });
+
+        rx.recv().unwrap();
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(futex.compare_and_wake(0, 9, 1), Ok(true));
+        assert_eq!(handle.join().unwrap(), 9);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_compare_and_wake_without_wake_count_wakes_nobody() {
+        let segment = &ShmSegment::create("test_compare_and_wake_nobody", 8);
+        let futex = SharedFutex::new(segment.as_ptr());
+        futex.set_futex_value(0);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(move || {
+                SharedFutex::new(segment.as_ptr()).wait_for(0, Duration::from_millis(300))
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(futex.compare_and_wake(0, 1, 0), Ok(true));
+            assert_eq!(waiter.join().unwrap(), Ok(FutexWakeReason::TimedOut));
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_share_one_instance_across_threads() {
+        let segment = ShmSegment::create("test_share_one_instance", 8);
+        let base = segment.as_ptr();
+        let futex = std::sync::Arc::new(SharedFutex::new(base));
+        futex.set_futex_value(UNLOCKED);
+        let counter = unsafe { (base as *mut u8).add(4) } as usize;
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let futex = futex.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        futex.lock();
+                        unsafe {
+                            let value = std::ptr::read_volatile(counter as *const u32);
+                            std::ptr::write_volatile(counter as *mut u32, value + 1);
+                        }
+                        futex.unlock();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(unsafe { *(counter as *const u32) }, 4000);
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_cloned_handles_contend() {
+        let segment = ShmSegment::create("test_cloned_handles", 8);
+        let futex = SharedFutex::new(segment.as_ptr());
+        futex.set_futex_value(UNLOCKED);
+        let counter = unsafe { (futex.futex as *mut u8).add(4) } as usize;
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let futex = futex.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        futex.lock();
+                        unsafe {
+                            let value = std::ptr::read_volatile(counter as *const u32);
+                            std::ptr::write_volatile(counter as *mut u32, value + 1);
+                        }
+                        futex.unlock();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(unsafe { *(counter as *const u32) }, 4000);
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_memfd_two_mappings() {
+        let (futex, fd) = SharedFutex::create_memfd(4096).unwrap();
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+        let dup = fd.try_clone().unwrap();
+        let other = SharedFutex::from_fd(dup.as_fd(), 0).unwrap();
+        drop(dup);
+        assert_ne!(futex.futex, other.futex);
+
+        futex.lock();
+        assert!(!other.try_lock());
+        futex.unlock();
+        assert!(other.try_lock());
+        other.unlock();
+
+        let counter = unsafe { (futex.futex as *mut u32).add(1) } as usize;
+        let other_counter = unsafe { (other.futex as *mut u32).add(1) } as usize;
+        thread::scope(|s| {
+            for (lock, counter) in [(&futex, counter), (&other, other_counter)] {
+                s.spawn(move || {
+                    let counter = counter as *mut u32;
+                    for _ in 0..1000 {
+                        lock.lock();
+                        unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                        lock.unlock();
+                    }
+                });
+            }
+        });
+        assert_eq!(unsafe { *(counter as *const u32) }, 2000);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_from_fd_checks_offset() {
+        let (_futex, fd) = SharedFutex::create_memfd(64).unwrap();
+        let err = SharedFutex::from_fd(fd.as_fd(), 2).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = SharedFutex::from_fd(fd.as_fd(), 64).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let futex = SharedFutex::from_fd(fd.as_fd(), 60).unwrap();
+        futex.set_futex_value(5);
+        assert_eq!(futex.get_futex_value(), 5);
+        assert!(SharedFutex::create_memfd(2).is_err());
+    }
+
+    #[test]
+    fn test_load_and_wait_if() {
+        let word = AtomicU32::new(3);
+        let futex = SharedFutex::from_atomic(&word);
+        // The condition does not hold, no wait
+        assert_eq!(futex.load_and_wait_if(|value| value == 0), 3);
+
+        word.store(0, atomic::Ordering::SeqCst);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let futex = SharedFutex::from_atomic(&word);
+                let mut value = futex.load_and_wait_if(|value| value == 0);
+                while value == 0 {
+                    value = futex.load_and_wait_if(|value| value == 0);
+                }
+                assert_eq!(value, 1);
+            });
+            thread::sleep(time::Duration::from_millis(50));
+            word.store(1, atomic::Ordering::SeqCst);
+            let _ = futex.post(1);
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_open_path_contention() {
+        let path = std::env::temp_dir().join(format!("rufutex_open_path_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // The second word of the second page, to exercise the page offset
+        let offset = 4096 + 8;
+        let first = SharedFutex::open_path(&path, offset).unwrap();
+        let second = SharedFutex::open_path(&path, offset).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), offset as u64 + 4);
+        assert_ne!(first.futex, second.futex);
+        assert_eq!(first.get_futex_value(), UNLOCKED);
+
+        let counter = AtomicU32::new(0);
+        thread::scope(|s| {
+            for lock in [&first, &second] {
+                let counter = &counter;
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        lock.lock();
+                        let value = counter.load(atomic::Ordering::Relaxed);
+                        counter.store(value + 1, atomic::Ordering::Relaxed);
+                        lock.unlock();
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(atomic::Ordering::SeqCst), 2000);
+        assert_eq!(second.get_futex_value(), UNLOCKED);
+
+        drop(first);
+        drop(second);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_open_path_offset_beyond_end() {
+        let path =
+            std::env::temp_dir().join(format!("rufutex_open_path_end_{}", std::process::id()));
+        std::fs::write(&path, [0u8; 16]).unwrap();
+
+        let err = SharedFutex::open_path(&path, 16).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = SharedFutex::open_path(&path, 6).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // The end of the word would overflow
+        let err = SharedFutex::open_path(&path, usize::MAX - 3).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let futex = SharedFutex::open_path(&path, 12).unwrap();
+        futex.set_futex_value(9);
+        assert_eq!(std::fs::read(&path).unwrap()[12..16], 9u32.to_ne_bytes());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_with_lock() {
+        let word = AtomicU32::new(UNLOCKED);
+        let futex = SharedFutex::from_atomic(&word);
+        let ret = futex.with_lock(|| {
+            assert_eq!(word.load(atomic::Ordering::SeqCst), LOCKED_NO_WAITERS);
+            7
+        });
+        assert_eq!(ret, Ok(7));
+        assert_eq!(word.load(atomic::Ordering::SeqCst), UNLOCKED);
+
+        let ret = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            futex.with_lock(|| panic!("critical section failed"))
+        }));
+        assert!(ret.is_err());
+        assert_eq!(word.load(atomic::Ordering::SeqCst), UNLOCKED);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "forks a child process")]
+    fn test_new_anonymous_fork() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let counter = unsafe { (futex.futex as *mut u32).add(1) };
+        futex.lock();
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Child: only async-signal-safe work, the lock is released by the parent
+            futex.lock();
+            unsafe { counter.write_volatile(counter.read_volatile() + 41) };
+            futex.unlock();
+            unsafe { libc::_exit(0) };
+        }
+
+        unsafe { counter.write_volatile(1) };
+        futex.unlock();
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+        futex.lock();
+        assert_eq!(unsafe { counter.read_volatile() }, 42);
+        futex.unlock();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_futex_fd() {
+        let word = AtomicU32::new(0);
+        let futex = SharedFutex::from_atomic(&word);
+        match futex.futex_fd(0) {
+            // Kernels older than 2.6.26
+            Ok(fd) => assert!(fd.as_raw_fd() >= 0),
+            Err(err) => assert_eq!(err, FutexError::NotSupported),
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "forks a child process")]
+    fn test_lock_unlock_contention_across_processes() {
+        use crate::test_support::ForkedChild;
+        use std::os::fd::AsFd;
+
+        const ITERATIONS: u32 = 2000;
+        let (futex, fd) = SharedFutex::create_memfd(4096).unwrap();
+        let timeout = time::Duration::from_secs(30);
+
+        let children: Vec<_> = (0..2)
+            .map(|_| {
+                ForkedChild::spawn_attached(fd.as_fd(), 0, |futex| {
+                    let counter = unsafe { (futex.futex as *mut u32).add(1) };
+                    for _ in 0..ITERATIONS {
+                        futex.lock();
+                        unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                        futex.unlock();
+                    }
+                })
+                .unwrap()
+            })
+            .collect();
+
+        let counter = unsafe { (futex.futex as *mut u32).add(1) };
+        for _ in 0..ITERATIONS {
+            futex.lock();
+            unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+            futex.unlock();
+        }
+        for child in children {
+            assert_eq!(child.wait(timeout), Ok(()));
+        }
+
+        assert_eq!(unsafe { counter.read_volatile() }, 3 * ITERATIONS);
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    fn test_swap_and_bitwise_match_std() {
+        let word = AtomicU32::new(0b1100);
+        let reference = AtomicU32::new(0b1100);
+        let futex = SharedFutex::from_atomic(&word);
+        let seq = atomic::Ordering::SeqCst;
+
+        assert_eq!(futex.swap(0b1010), reference.swap(0b1010, seq));
+        assert_eq!(futex.fetch_and(0b0110), reference.fetch_and(0b0110, seq));
+        assert_eq!(futex.fetch_xor(0b0011), reference.fetch_xor(0b0011, seq));
+        assert_eq!(futex.fetch_or(0b1000), reference.fetch_or(0b1000, seq));
+        assert_eq!(futex.fetch_add(5), reference.fetch_add(5, seq));
+        assert_eq!(futex.fetch_sub(2), reference.fetch_sub(2, seq));
+        assert_eq!(
+            futex.swap_with_ordering(1, Relaxed),
+            reference.swap(1, Relaxed)
+        );
+        assert_eq!(word.load(seq), reference.load(seq));
+    }
+
+    #[test]
+    fn test_mock_spurious_wake_during_lock() {
+        use crate::test_support::{MockBackend, MockCall, MockWait};
+
+        let word = AtomicU32::new(LOCKED_NO_WAITERS);
+        let mock = MockBackend::new();
+        let futex = SharedFutex::with_backend(word.as_ptr() as *mut c_void, mock.clone());
+        mock.push_wait(MockWait::Spurious);
+        // The owner unlocks while we sleep the second time
+        mock.push_wait(MockWait::Run(Box::new(|word| {
+            word.store(UNLOCKED, atomic::Ordering::SeqCst)
+        })));
+
+        futex.lock();
+        // Taken through the slow path, the word says there may be waiters
+        assert_eq!(futex.get_futex_value(), LOCKED_WAITERS);
+        futex.unlock();
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+
+        let wait = MockCall::Wait {
+            expected: LOCKED_WAITERS,
+            timeout: None,
+        };
+        assert_eq!(mock.calls(), [wait, wait, MockCall::Wake { count: 1 }]);
+    }
+
+    #[test]
+    fn test_mock_eintr_during_timed_wait() {
+        use crate::test_support::{MockBackend, MockCall, MockWait};
+
+        let word = AtomicU32::new(0);
+        let mock = MockBackend::new();
+        let futex = SharedFutex::with_backend(word.as_ptr() as *mut c_void, mock.clone());
+        mock.push_wait(MockWait::Interrupted);
+        mock.push_wait(MockWait::Run(Box::new(|word| {
+            word.store(5, atomic::Ordering::SeqCst)
+        })));
+
+        assert!(futex.await_state(5, Some(Duration::from_secs(60))));
+        let calls = mock.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|call| matches!(
+            call,
+            MockCall::Wait {
+                expected: 0,
+                timeout: Some(_)
+            }
+        )));
+    }
+
+    #[test]
+    fn test_mock_transition_wakes() {
+        use crate::test_support::{MockBackend, MockCall};
+
+        let word = AtomicU32::new(3);
+        let mock = MockBackend::new();
+        let futex = SharedFutex::with_backend(word.as_ptr() as *mut c_void, mock.clone());
+
+        assert_eq!(
+            futex.transition(0, 1, 1),
+            Err(TransitionError { observed: 3 })
+        );
+        assert!(mock.calls().is_empty());
+        assert!(futex.transition(3, 4, 2).is_ok());
+        assert_eq!(futex.get_futex_value(), 4);
+        assert_eq!(mock.calls(), [MockCall::Wake { count: 2 }]);
+
+        // The state is already reached, nothing to wait for
+        assert!(futex.await_state(4, Some(Duration::from_millis(50))));
+        // No scripted outcome, the timed wait times out at once
+        assert!(!futex.await_state(5, Some(Duration::ZERO)));
+        assert_eq!(mock.calls().len(), 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_metrics_under_contention() {
+        const WAITERS: u64 = 4;
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let start = Instant::now();
+        futex.lock();
+
+        thread::scope(|scope| {
+            for _ in 0..WAITERS {
+                scope.spawn(|| {
+                    futex.lock();
+                    futex.unlock();
+                });
+            }
+            // Every waiter went to sleep on the held lock
+            while futex.metrics().wait_calls < WAITERS {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            thread::sleep(time::Duration::from_millis(20));
+            futex.unlock();
+        });
+        let wall = start.elapsed().as_nanos() as u64;
+
+        let metrics = futex.metrics();
+        assert_eq!(metrics.acquisitions, WAITERS + 1);
+        assert_eq!(metrics.contended_acquisitions, WAITERS);
+        assert!(metrics.wait_calls >= WAITERS);
+        assert!(metrics.wake_calls >= 1);
+        assert!(metrics.woken_waiters >= 1);
+        assert!(metrics.max_wait_ns >= 20_000_000);
+        assert!(metrics.max_wait_ns <= wall);
+        assert!(metrics.total_wait_ns >= metrics.max_wait_ns);
+        assert!(metrics.total_wait_ns <= wall * WAITERS);
+
+        futex.reset_metrics();
+        assert_eq!(futex.metrics(), FutexMetrics::default());
+        assert!(futex.try_lock());
+        futex.unlock();
+        assert_eq!(futex.metrics().acquisitions, 1);
+        assert_eq!(futex.metrics().contended_acquisitions, 0);
+    }
+
+    #[test]
+    fn test_futex_pair() {
+        let mut words = [0u32; 3];
+        let (ready, free) = SharedFutex::futex_pair(words.as_mut_ptr() as *mut c_void);
+        assert_eq!(free.futex as usize - ready.futex as usize, 4);
+        let misaligned = (words.as_mut_ptr() as *mut u8).wrapping_add(2) as *mut c_void;
+        assert!(std::panic::catch_unwind(|| SharedFutex::futex_pair(misaligned)).is_err());
+
+        // Ping-pong: the producer fills the slot once free, the consumer empties it once ready
+        free.set_futex_value(1);
+        let slot = AtomicU32::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for value in 1..=100 {
+                    while free.swap(0) == 0 {
+                        let _ = free.wait(0);
+                    }
+                    slot.store(value, SeqCst);
+                    ready.set_futex_value(1);
+                    let _ = ready.post(1);
+                }
+            });
+            for value in 1..=100 {
+                while ready.swap(0) == 0 {
+                    let _ = ready.wait(0);
+                }
+                assert_eq!(slot.load(SeqCst), value);
+                free.set_futex_value(1);
+                let _ = free.post(1);
+            }
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_wake_reason() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let no_wait = duration_to_timespec(Duration::from_millis(10));
+        assert_eq!(futex.wait(1), Ok(FutexWakeReason::ValueMismatch));
+        assert_eq!(
+            futex.wait_with_timeout(0, no_wait),
+            Ok(FutexWakeReason::TimedOut)
+        );
+
+        for (value, expected) in [(1, FutexWakeReason::Woken), (0, FutexWakeReason::Spurious)] {
+            futex.set_futex_value(0);
+            thread::scope(|s| {
+                let waiter = s.spawn(|| futex.wait(0));
+                thread::sleep(Duration::from_millis(50));
+                futex.set_futex_value(value);
+                assert_eq!(futex.post(1).unwrap(), 1);
+                assert_eq!(waiter.join().unwrap(), Ok(expected));
+            });
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_wake_one_and_all() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(0);
+        assert_eq!(futex.wake_all().unwrap(), 0);
+        let released = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    let _ = futex.wait(0);
+                    released.fetch_add(1, SeqCst);
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(futex.wake_one().unwrap(), 1);
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(released.load(SeqCst), 1);
+            // A count of u32::MAX would reach the kernel as -1
+            assert_eq!(futex.wake_all().unwrap(), 2);
+        });
+        assert_eq!(released.load(SeqCst), 3);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_wake_n_counts_the_woken() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(0);
+        assert_eq!(futex.wake_n(5), Ok(0));
+        let released = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while futex.get_futex_value() == 0 {
+                        let _ = futex.wait(0);
+                    }
+                    released.fetch_add(1, SeqCst);
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+            // Only the waiters present count, not the requested number
+            futex.set_futex_value(1);
+            assert_eq!(futex.wake_n(2), Ok(2));
+            assert_eq!(futex.wake_n(u32::MAX), Ok(1));
+        });
+        assert_eq!(released.load(SeqCst), 3);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_unlock_one_and_all() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(UNLOCKED);
+        futex.lock();
+        assert_eq!(futex.unlock_one(), 0);
+
+        for (lockers, unlock) in [
+            (1, SharedFutex::unlock_one as fn(&SharedFutex) -> usize),
+            (3, SharedFutex::unlock_all),
+        ] {
+            futex.lock();
+            thread::scope(|s| {
+                for _ in 0..lockers {
+                    s.spawn(|| {
+                        futex.lock();
+                        futex.unlock();
+                    });
+                }
+                thread::sleep(Duration::from_millis(100));
+                assert_eq!(unlock(&futex), lockers);
+            });
+        }
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_unlock_cannot_strand_waiters() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        for unlock in [
+            |futex: &SharedFutex| {
+                futex.unlock();
+            },
+            |futex: &SharedFutex| {
+                // A count of 0 used to leave the waiter asleep on an unlocked futex
+                assert_eq!(futex.unlock_many(0), 1);
+            },
+        ] {
+            futex.lock();
+            thread::scope(|s| {
+                let waiter = s.spawn(|| {
+                    futex.lock();
+                    futex.unlock();
+                });
+                thread::sleep(Duration::from_millis(100));
+                unlock(&futex);
+                waiter.join().unwrap();
+            });
+            assert_eq!(futex.get_futex_value(), UNLOCKED);
+        }
+    }
+
+    #[test]
+    fn test_cancel_waiters() {
+        let word = AtomicU32::new(0);
+        let futex = SharedFutex::from_atomic(&word);
+        let cancelled = atomic::AtomicBool::new(false);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let mut reasons = Vec::new();
+                while !cancelled.load(SeqCst) {
+                    reasons.push(futex.wait(0));
+                }
+                reasons
+            });
+            thread::sleep(Duration::from_millis(50));
+            // The canceller is neither the waiter nor the owner of the word
+            s.spawn(|| {
+                cancelled.store(true, SeqCst);
+                assert_eq!(futex.cancel_waiters(1), Ok(1));
+            })
+            .join()
+            .unwrap();
+            assert_eq!(waiter.join().unwrap(), [Ok(FutexWakeReason::Spurious)]);
+        });
+        assert_eq!(word.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn test_prefetch_leaves_the_word_alone() {
+        let word = AtomicU32::new(7);
+        let futex = SharedFutex::from_atomic(&word);
+        futex.prefetch_for_read();
+        futex.prefetch_for_write();
+        assert_eq!(futex.get_futex_value(), 7);
+        word.store(UNLOCKED, SeqCst);
+        futex.prefetch_for_write();
+        futex.lock();
+        futex.unlock();
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    fn test_wake_zero_wakes_nobody() {
+        let word = AtomicU32::new(0);
+        let futex = SharedFutex::from_atomic(&word);
+        let woken = AtomicU32::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                while word.load(SeqCst) == 0 {
+                    let _ = futex.wait(0);
+                    woken.fetch_add(1, SeqCst);
+                }
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(futex.wake_n(0), Ok(0));
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(woken.load(SeqCst), 0);
+            // The raw FUTEX_WAKE with 0 is what wake_n() avoids, it wakes the sleeper
+            #[cfg(not(miri))]
+            {
+                assert_eq!(futex.futex(FutexOp::Wake { count: 0 }), Ok(1));
+                while woken.load(SeqCst) == 0 {
+                    thread::yield_now();
+                }
+            }
+            word.store(1, SeqCst);
+            let _ = futex.wake_all();
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_requeue_pi() {
+        let word = AtomicU32::new(0);
+        let futex = SharedFutex::from_atomic(&word);
+        let pi_futex = PiFutex::new();
+        assert_eq!(futex.requeue_pi(2, 0, &pi_futex), Err(FutexError::Invalid));
+        // Nobody waits
+        assert_eq!(futex.requeue_pi(1, 0, &pi_futex), Ok(0));
+
+        thread::scope(|s| {
+            let waiters: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        let target = pi_futex.as_futex();
+                        let ret = futex.futex(FutexOp::WaitRequeuePi {
+                            expected: 0,
+                            target: &target,
+                            deadline: None,
+                        });
+                        // Returned owning the PI futex
+                        assert_eq!(pi_futex.owner(), Some(crate::sys::gettid()));
+                        pi_futex.unlock().unwrap();
+                        ret
+                    })
+                })
+                .collect();
+            thread::sleep(Duration::from_millis(100));
+            // One is granted the free PI futex, the other waits on it
+            assert_eq!(futex.requeue_pi(1, 1, &pi_futex), Ok(2));
+            for waiter in waiters {
+                assert_eq!(waiter.join().unwrap(), Ok(0));
+            }
+        });
+        assert_eq!(pi_futex.owner(), None);
+    }
+
+    #[test]
+    fn test_try_lock_or_spin() {
+        let word = AtomicU32::new(UNLOCKED);
+        let futex = SharedFutex::from_atomic(&word);
+        assert_eq!(futex.try_lock_or_spin(0), Ok(()));
+        assert_eq!(futex.try_lock_or_spin(1000), Err(FutexError::WouldBlock));
+        assert_eq!(futex.get_futex_value(), LOCKED_NO_WAITERS);
+
+        // Released by another thread while spinning
+        let released = atomic::AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                released.store(true, SeqCst);
+                futex.unlock();
+            });
+            while futex.try_lock_or_spin(1_000).is_err() {}
+            assert!(released.load(SeqCst));
+        });
+        futex.unlock();
+    }
+
+    #[test]
+    fn test_try_lock_weak() {
+        let word = AtomicU32::new(UNLOCKED);
+        let futex = SharedFutex::from_atomic(&word);
+        while !futex.try_lock_weak() {
+            std::hint::spin_loop();
+        }
+        assert_eq!(futex.get_futex_value(), LOCKED_NO_WAITERS);
+        for _ in 0..100 {
+            assert!(!futex.try_lock_weak());
+        }
+        futex.unlock();
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_post_reports_woken() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(0);
+        assert_eq!(futex.post(1).unwrap(), 0);
+
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let _ = futex.wait(0);
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(futex.post_with_value(1, 2).unwrap(), 2);
+        });
+        assert_eq!(futex.get_futex_value(), 1);
+    }
+
+    #[test]
+    fn test_duration_to_timespec_saturates() {
+        let timespec = duration_to_timespec(Duration::new(1, 5));
+        assert_eq!((timespec.tv_sec, timespec.tv_nsec), (1, 5));
+        let timespec = duration_to_timespec(Duration::MAX);
+        assert_eq!(timespec.tv_sec, libc::time_t::MAX);
+        assert_eq!(timespec.tv_nsec, 999_999_999);
+    }
+
+    #[test]
+    fn test_debug_asserts() {
+        let word = AtomicU32::new(UNLOCKED);
+        let futex = SharedFutex::from_atomic(&word);
+        futex.debug_assert_unlocked();
+        futex.lock();
+        futex.debug_assert_locked();
+        if cfg!(debug_assertions) {
+            let result = std::panic::catch_unwind(|| futex.debug_assert_unlocked());
+            assert!(result.is_err());
+        }
+        futex.unlock();
+        if cfg!(debug_assertions) {
+            let result = std::panic::catch_unwind(|| futex.debug_assert_locked());
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_futex_address() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        assert_eq!(futex.futex_address(), futex.futex as usize);
+        assert_eq!(futex.futex_page_offset(), 0);
+
+        // Two mappings of the same word, like two processes would have
+        let (_, fd) = SharedFutex::create_memfd(4096).unwrap();
+        let first = SharedFutex::from_fd(fd.as_fd(), 8).unwrap();
+        let second = SharedFutex::from_fd(fd.as_fd(), 8).unwrap();
+        assert_ne!(first.futex_address(), second.futex_address());
+        assert_eq!(first.futex_page_offset(), 8);
+        assert_eq!(second.futex_page_offset(), 8);
+    }
 }
@@ -0,0 +1,91 @@
+//! Waits and locks of SharedFutex ended by a [`CancelToken`]
+
+use super::{duration_to_timespec, FutexWakeReason, SharedFutex};
+use crate::backend::FutexBackend;
+use crate::cancel::CancelToken;
+use crate::error::{FutexError, RufutexError};
+use crate::futex_op::FutexOp;
+use crate::sync::AtomicU32;
+use crate::trace::trace_event;
+use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
+
+/// How long wait_either() sleeps between checks of its second word without futex_waitv(2)
+const WAIT_EITHER_SLICE: Duration = Duration::from_millis(10);
+
+impl<B: FutexBackend> SharedFutex<B> {
+    /// Wait on a futex until woken, or until `token` is cancelled
+    /// # Arguments
+    /// * `wait_value` - The value to wait on
+    /// * `token` - The token ending the wait when cancelled
+    /// # Returns
+    /// Why the wait returned, Cancelled if the token was or got cancelled, or the error of a
+    /// failed call
+    #[must_use = "check the return value for errors"]
+    pub fn wait_cancellable(
+        &self,
+        wait_value: u32,
+        token: &CancelToken,
+    ) -> Result<FutexWakeReason, RufutexError> {
+        token.check()?;
+        let ret = self.wait_either(wait_value, token.as_atomic(), 0);
+        token.check()?;
+        Ok(self.wake_reason(wait_value, ret)?)
+    }
+
+    /// Sleeps while the word holds `expected` and `other` holds `other_expected`
+    /// One futex_waitv(2) watches both words. Without it the sleep is cut in slices of
+    /// WAIT_EITHER_SLICE, rechecking `other` between them, and `other` is only noticed at
+    /// the end of a slice.
+    /// # Returns
+    /// 0 when woken on the word, 1 when `other` changed, or the error of FUTEX_WAIT
+    pub(crate) fn wait_either(
+        &self,
+        expected: u32,
+        other: &AtomicU32,
+        other_expected: u32,
+    ) -> Result<i64, FutexError> {
+        let words = [
+            (self.as_atomic(), expected, self.private),
+            (other, other_expected, false),
+        ];
+        match self.backend.wait_any(&words) {
+            Err(FutexError::NoSys) => {}
+            ret => {
+                #[cfg(feature = "metrics")]
+                self.metrics.wait_call();
+                return ret;
+            }
+        }
+        loop {
+            let ret = self.futex(FutexOp::Wait {
+                expected,
+                timeout: Some(duration_to_timespec(WAIT_EITHER_SLICE)),
+            });
+            if ret != Err(FutexError::TimedOut) {
+                return ret;
+            }
+            if other.load(SeqCst) != other_expected {
+                return Ok(1);
+            }
+        }
+    }
+
+    /// Lock the futex, giving up if `token` is cancelled first
+    /// A cancelled token fails the call at once, even if the lock is free.
+    /// # Arguments
+    /// * `token` - The token ending the wait when cancelled
+    /// # Returns
+    /// Ok once the lock is held, Cancelled if the token was or got cancelled first
+    #[must_use = "check the return value for errors"]
+    pub fn lock_cancellable(&self, token: &CancelToken) -> Result<(), RufutexError> {
+        token.check()?;
+        self.lock_with(|expected| {
+            token.check()?;
+            let woke = self.wait_either(expected, token.as_atomic(), 0);
+            trace_event!("cancellable wait on {:p} returned {:?}", self.futex, woke);
+            Ok::<_, RufutexError>(woke == Ok(0))
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! Fair unlock of SharedFutex, handing the lock to a sleeping waiter
+
+use super::SharedFutex;
+use crate::backend::FutexBackend;
+use crate::{HANDED_OFF, LOCKED_NO_WAITERS, UNLOCKED};
+use std::sync::atomic::Ordering::{Relaxed, Release};
+
+impl<B: FutexBackend> SharedFutex<B> {
+    /// Unlock the futex and hand it directly to a sleeping waiter
+    /// A plain unlock frees the word before waking, so a thread that never slept can take the
+    /// lock before the woken one retries, again and again. Here the word goes to HANDED_OFF
+    /// instead and only a thread woken from its sleep in lock() may take it, without racing.
+    /// Every locker of the word has to go through [`SharedFutex::lock`] for this to work.
+    /// # Returns
+    /// The number of waiters woken, 0 when nobody waited
+    pub fn unlock_fair(&self) -> usize {
+        if let Some(stats) = self.shared_stats_block() {
+            stats.releasing();
+        }
+        if self.compare_exchange_with_ordering(LOCKED_NO_WAITERS, UNLOCKED, Release, Relaxed)
+            == Ok(LOCKED_NO_WAITERS)
+        {
+            return 0;
+        }
+        // The woken waiter takes the lock with an Acquire CAS of HANDED_OFF. If nobody was
+        // woken, the CAS back to UNLOCKED continues the release sequence of this store.
+        unsafe {
+            (*self.atom).store(HANDED_OFF, Release);
+        }
+        let mut woken = self.post(1).unwrap_or(0);
+        if woken == 0 && Self::cmpxchg(self.atom, HANDED_OFF, UNLOCKED) == HANDED_OFF {
+            // Nobody was asleep to take the lock, it is free again. A thread that went to
+            // sleep on HANDED_OFF in the meantime must not miss it.
+            woken = self.post(1).unwrap_or(0);
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.woken(woken);
+        woken
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_unlock_fair_bounds_waiting() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(UNLOCKED);
+        let rounds = AtomicU32::new(0);
+        let stop = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            // A holder relocking as soon as it unlocks
+            s.spawn(|| {
+                while !stop.load(SeqCst) {
+                    futex.lock();
+                    rounds.fetch_add(1, SeqCst);
+                    for _ in 0..1000 {
+                        std::hint::spin_loop();
+                    }
+                    futex.unlock_fair();
+                }
+            });
+            while rounds.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            for _ in 0..200 {
+                let before = rounds.load(SeqCst);
+                futex.lock();
+                let waited = rounds.load(SeqCst) - before;
+                futex.unlock_fair();
+                assert!(waited <= 4, "waited {} rounds of the holder", waited);
+            }
+            stop.store(true, SeqCst);
+        });
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+}
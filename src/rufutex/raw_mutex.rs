@@ -0,0 +1,61 @@
+//! `lock_api::RawMutex` for SharedFutex, behind the `lock_api` feature
+
+use super::SharedFutex;
+
+unsafe impl lock_api::RawMutex for SharedFutex {
+    /// `lock_api` needs a constant initializer but the futex word of a SharedFutex only
+    /// exists at run time, and a static word would make every INIT mutex one global lock.
+    /// INIT fails to compile where it is used, as through `lock_api::Mutex::new` or
+    /// `const_new`. Code generic over `R: RawMutex` that builds its mutexes that way, in this
+    /// crate's users or their dependencies, fails too once instantiated with SharedFutex, with
+    /// the error pointing at this constant rather than at the instantiation; see the note at
+    /// the [crate root](crate). Mutexes are built with `lock_api::Mutex::from_raw(SharedFutex::new(ptr), data)`; for a mutex
+    /// that owns its word use [`FutexWord`](crate::futex_word::FutexWord), which has an INIT.
+    const INIT: Self = panic!("SharedFutex has no INIT, build the mutex with from_raw");
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        SharedFutex::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        SharedFutex::try_lock(self)
+    }
+
+    unsafe fn unlock(&self) {
+        SharedFutex::unlock(self);
+    }
+
+    fn is_locked(&self) -> bool {
+        SharedFutex::get_futex_value(self) != crate::UNLOCKED
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use crate::UNLOCKED;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_lock_api_raw_mutex() {
+        let segment = ShmSegment::create("test_lock_api_raw_mutex", 8);
+        let ptr_shm = segment.as_ptr();
+        SharedFutex::new(ptr_shm).set_futex_value(UNLOCKED);
+        let mutex = lock_api::Mutex::<SharedFutex, u32>::from_raw(SharedFutex::new(ptr_shm), 1);
+
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.is_locked());
+            assert!(mutex.try_lock().is_none());
+            // A second mutex over the same word sees the lock taken
+            let other = lock_api::Mutex::<SharedFutex, ()>::from_raw(SharedFutex::new(ptr_shm), ());
+            assert!(other.try_lock().is_none());
+        }
+        assert!(!mutex.is_locked());
+        assert_eq!(*mutex.lock(), 2);
+    }
+}
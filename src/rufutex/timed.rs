@@ -0,0 +1,479 @@
+//! Timed waits and timed locks of SharedFutex
+//!
+//! Relative timeouts of the waits go to FUTEX_WAIT as they are. The locks turn theirs into an
+//! absolute CLOCK_MONOTONIC deadline once, see [`Deadline`], so retries never extend them.
+
+use super::{duration_to_timespec, FutexWakeReason, LockOutcome, SharedFutex, SharedFutexGuard};
+use crate::backend::FutexBackend;
+use crate::deadline::Deadline;
+use crate::error::FutexError;
+use crate::futex_op::FutexOp;
+use crate::sys;
+use crate::trace::trace_event;
+use std::sync::atomic::Ordering::Acquire;
+use std::time::{Duration, Instant};
+
+impl<B: FutexBackend> SharedFutex<B> {
+    /// Wait on a futex, sleeping at most `timeout`
+    /// A zero timeout does not sleep, it reports TimedOut if the word holds `wait_value`.
+    /// Duration::MAX, or any timeout beyond what a timespec holds, waits without timeout.
+    /// # Arguments
+    /// * `wait_value` - The value to wait on
+    /// * `timeout` - The maximum time to sleep, relative to now
+    /// # Returns
+    /// Why the wait returned, or the FutexError of a failed call
+    #[must_use = "check the return value for errors"]
+    pub fn wait_for(
+        &self,
+        wait_value: u32,
+        timeout: Duration,
+    ) -> Result<FutexWakeReason, FutexError> {
+        if timeout.as_secs() > libc::time_t::MAX as u64 {
+            return self.wait(wait_value);
+        }
+        self.wait_with_timeout(wait_value, duration_to_timespec(timeout))
+    }
+
+    /// Sleeps for up to `duration` if the word holds `expected`
+    /// The canonical futex wait: the word is compared in user space first, so a mismatch costs
+    /// no system call, and the kernel compares it again atomically with going to sleep, so a
+    /// change in between is not missed either.
+    /// # Arguments
+    /// * `expected` - The value the word must hold for the call to sleep
+    /// * `duration` - The maximum time to sleep, see [`SharedFutex::wait_for`]
+    /// # Returns
+    /// ValueMismatch at once if the word differs, otherwise why the sleep ended, or the
+    /// FutexError of a failed call
+    #[must_use = "check the return value for errors"]
+    pub fn compare_and_sleep(
+        &self,
+        expected: u32,
+        duration: Duration,
+    ) -> Result<FutexWakeReason, FutexError> {
+        if self.get_futex_value_with_ordering(Acquire) != expected {
+            return Ok(FutexWakeReason::ValueMismatch);
+        }
+        self.wait_for(expected, duration)
+    }
+
+    /// Wait on a futex with a raw timespec timeout
+    /// The low level path, for callers that already hold a timespec; the timespec must be
+    /// normalized, tv_nsec below one second. Prefer [`SharedFutex::wait_for`].
+    /// # Arguments
+    /// * `wait_value` - The value to wait on
+    /// * `timeout` - The maximum time to sleep, relative to now
+    /// # Returns
+    /// Why the wait returned, or the FutexError of a failed call
+    #[must_use = "check the return value for errors"]
+    pub fn wait_with_timeout(
+        &self,
+        wait_value: u32,
+        timeout: libc::timespec,
+    ) -> Result<FutexWakeReason, FutexError> {
+        let ret = self.futex(FutexOp::Wait {
+            expected: wait_value,
+            timeout: Some(timeout),
+        });
+        if ret == Err(FutexError::TimedOut) {
+            trace_event!(
+                "wait on {:p} timed out after {}.{:09}s",
+                self.futex,
+                timeout.tv_sec,
+                timeout.tv_nsec
+            );
+        }
+        self.wake_reason(wait_value, ret)
+    }
+
+    /// Wait on a futex until an absolute CLOCK_REALTIME deadline, the clock of the POSIX timed
+    /// functions. The sleep follows changes of the wall clock: setting it forward ends the wait
+    /// early, setting it back extends it.
+    /// An interrupted wait returns Interrupted without retrying. The deadline is absolute, so
+    /// calling again with the same one retries without extending the wait.
+    /// # Arguments
+    /// * `wait_value` - The value to wait on
+    /// * `deadline` - The absolute CLOCK_REALTIME time to sleep until, tv_nsec below one second
+    /// # Returns
+    /// Why the wait returned, TimedOut once the deadline passed, ValueMismatch if the word did
+    /// not hold `wait_value`, Invalid for a denormalized deadline, or the FutexError of a
+    /// failed call
+    #[must_use = "check the return value for errors"]
+    pub fn timed_wait_absolute(
+        &self,
+        wait_value: u32,
+        deadline: libc::timespec,
+    ) -> Result<FutexWakeReason, FutexError> {
+        let ret = self.futex(FutexOp::WaitRealtime {
+            expected: wait_value,
+            deadline,
+        });
+        self.wake_reason(wait_value, ret)
+    }
+
+    /// Wait on a futex for the conditions of `mask`, sleeping at most `timeout`
+    /// Only the wakes of [`SharedFutex::wake_masked`] sharing a bit with `mask`, and plain
+    /// wakes, release the waiter. [`FUTEX_BITSET_MATCH_ANY`](super::FUTEX_BITSET_MATCH_ANY) makes it a plain wait. The
+    /// timeout is turned into an absolute deadline up front.
+    /// # Arguments
+    /// * `wait_value` - The value to wait on
+    /// * `mask` - The conditions waited for, the kernel rejects 0
+    /// * `timeout` - The maximum time to sleep, None sleeps until woken
+    /// # Returns
+    /// Why the wait returned, Woken also for a spurious wake up since a masked wake often
+    /// leaves the word as it was, Invalid for an empty mask, or the FutexError of a failed call
+    #[must_use = "check the return value for errors"]
+    pub fn wait_masked(
+        &self,
+        wait_value: u32,
+        mask: u32,
+        timeout: Option<Duration>,
+    ) -> Result<FutexWakeReason, FutexError> {
+        if mask == 0 {
+            return Err(FutexError::Invalid);
+        }
+        let ret = self.futex(FutexOp::WaitBitset {
+            expected: wait_value,
+            mask,
+            deadline: timeout.and_then(|timeout| Deadline::after(timeout).monotonic_timespec()),
+        });
+        match ret {
+            Ok(_) => Ok(FutexWakeReason::Woken),
+            ret => self.wake_reason(wait_value, ret),
+        }
+    }
+
+    /// Lock the futex, giving up after `timeout`
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for the lock
+    /// # Returns
+    /// Ok once the lock is held, TimedOut if the timeout expired first
+    #[must_use = "check the return value for errors"]
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<(), FutexError> {
+        self.lock_by(Deadline::after(timeout))
+            .map(SharedFutexGuard::leak)
+    }
+
+    /// Lock the futex, giving up at `deadline`
+    /// The sleeps use FUTEX_WAIT_BITSET with an absolute CLOCK_MONOTONIC timeout, the clock
+    /// behind Instant, so waking up and sleeping again does not move the deadline. A deadline
+    /// already passed behaves exactly like [`SharedFutex::try_lock`].
+    /// # Arguments
+    /// * `deadline` - The time at which to give up
+    /// # Returns
+    /// A guard unlocking the futex when dropped, or TimedOut if the deadline passed first
+    pub fn lock_until(&self, deadline: Instant) -> Result<SharedFutexGuard<'_, B>, FutexError> {
+        self.lock_by(Deadline::at(deadline))
+    }
+
+    /// lock_until() and lock_timeout() past the conversion of their deadline
+    fn lock_by(&self, deadline: Deadline) -> Result<SharedFutexGuard<'_, B>, FutexError> {
+        if deadline.has_passed() {
+            if !self.try_lock() {
+                return Err(FutexError::TimedOut);
+            }
+            return Ok(SharedFutexGuard::new(self));
+        }
+        self.lock_before(deadline)?;
+        Ok(SharedFutexGuard::new(self))
+    }
+
+    /// Locks the futex, runs `f` and unlocks, giving up if the lock is not acquired within
+    /// `timeout`
+    /// The lock is released when `f` returns or panics, like [`SharedFutex::with_lock`].
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for the lock
+    /// * `f` - The critical section
+    /// # Returns
+    /// The value returned by `f`, or TimedOut without running `f`
+    pub fn scope_lock<R>(&self, timeout: Duration, f: impl FnOnce() -> R) -> Result<R, FutexError> {
+        self.lock_timeout(timeout)?;
+        let _unlock = SharedFutexGuard::new(self);
+        Ok(f())
+    }
+
+    /// The lock() protocol with a deadline, Deadline::never() sleeps as long as needed
+    pub(super) fn lock_before(&self, deadline: Deadline) -> Result<LockOutcome, FutexError> {
+        let mut wake_at = None;
+        self.lock_with(|expected| {
+            self.check_deadline(deadline)?;
+            // Every sleep ends at the same absolute time, retries cannot push the deadline back
+            let wake_at = *wake_at.get_or_insert_with(|| deadline.monotonic_timespec());
+            let woke = self.futex(lock_wait(expected, wake_at));
+            trace_event!("FUTEX_WAIT on {:p} returned {:?}", self.futex, woke);
+            Ok(woke.is_ok())
+        })
+    }
+
+    /// Returns TimedOut once `deadline` has passed
+    fn check_deadline(&self, deadline: Deadline) -> Result<(), FutexError> {
+        if deadline.has_passed() {
+            trace_event!("lock {:p} timed out", self.futex);
+            return Err(FutexError::TimedOut);
+        }
+        Ok(())
+    }
+}
+
+/// The FUTEX_WAIT of lock(), with an absolute deadline through FUTEX_WAIT_BITSET
+fn lock_wait(expected: u32, deadline: Option<libc::timespec>) -> FutexOp<'static> {
+    match deadline {
+        None => FutexOp::Wait {
+            expected,
+            timeout: None,
+        },
+        Some(deadline) => FutexOp::WaitBitset {
+            expected,
+            mask: sys::FUTEX_BITSET_MATCH_ANY as u32,
+            deadline: Some(deadline),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rufutex::FUTEX_BITSET_MATCH_ANY;
+    use crate::{LOCKED_NO_WAITERS, UNLOCKED};
+    use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_compare_and_sleep() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(7);
+        let start = Instant::now();
+        assert_eq!(
+            futex.compare_and_sleep(6, Duration::from_secs(10)),
+            Ok(FutexWakeReason::ValueMismatch)
+        );
+        assert_eq!(
+            futex.compare_and_sleep(7, Duration::from_millis(30)),
+            Ok(FutexWakeReason::TimedOut)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        thread::scope(|s| {
+            let sleeper = s.spawn(|| futex.compare_and_sleep(7, Duration::from_secs(10)));
+            thread::sleep(Duration::from_millis(50));
+            futex.set_futex_value(8);
+            let _ = futex.wake_all();
+            assert_eq!(sleeper.join().unwrap(), Ok(FutexWakeReason::Woken));
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_wait_for_boundaries() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        futex.set_futex_value(0);
+        for timeout in [Duration::ZERO, Duration::from_nanos(1)] {
+            assert_eq!(futex.wait_for(0, timeout), Ok(FutexWakeReason::TimedOut));
+        }
+        assert_eq!(
+            futex.wait_for(1, Duration::ZERO),
+            Ok(FutexWakeReason::ValueMismatch)
+        );
+
+        let start = Instant::now();
+        assert_eq!(
+            futex.wait_for(0, Duration::from_millis(50)),
+            Ok(FutexWakeReason::TimedOut)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Long timeouts, down to 1s, sleep until woken
+        for timeout in [
+            Duration::from_secs(1),
+            Duration::from_secs(u64::MAX),
+            Duration::MAX,
+        ] {
+            futex.set_futex_value(0);
+            thread::scope(|s| {
+                let waiter = s.spawn(|| futex.wait_for(0, timeout));
+                thread::sleep(Duration::from_millis(50));
+                futex.set_futex_value(1);
+                let _ = futex.wake_all();
+                assert_eq!(waiter.join().unwrap(), Ok(FutexWakeReason::Woken));
+            });
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_masked_wakes_release_their_waiters() {
+        let word = AtomicU32::new(0);
+        let futex = SharedFutex::from_atomic(&word);
+        assert_eq!(futex.wait_masked(0, 0, None), Err(FutexError::Invalid));
+        assert_eq!(futex.wake_masked(1, 0), Err(FutexError::Invalid));
+        assert_eq!(
+            futex.wait_masked(1, 0b01, None),
+            Ok(FutexWakeReason::ValueMismatch)
+        );
+        assert_eq!(
+            futex.wait_masked(0, 0b01, Some(Duration::from_millis(20))),
+            Ok(FutexWakeReason::TimedOut)
+        );
+
+        let woken = [AtomicU32::new(0), AtomicU32::new(0)];
+        thread::scope(|s| {
+            for (waiter, mask) in [0b01, 0b10].into_iter().enumerate() {
+                let (futex, woken) = (&futex, &woken);
+                s.spawn(move || {
+                    while futex.wait_masked(0, mask, None) != Ok(FutexWakeReason::Woken) {}
+                    woken[waiter].store(1, SeqCst);
+                });
+            }
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(futex.wake_masked(2, 0b10), Ok(1));
+            while woken[1].load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(woken[0].load(SeqCst), 0);
+            assert_eq!(futex.wake_masked(2, 0b01), Ok(1));
+        });
+
+        thread::scope(|s| {
+            for mask in [0b01, 0b10] {
+                let futex = &futex;
+                s.spawn(
+                    move || {
+                        while futex.wait_masked(0, mask, None) != Ok(FutexWakeReason::Woken) {}
+                    },
+                );
+            }
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(futex.wake_masked(2, FUTEX_BITSET_MATCH_ANY), Ok(2));
+        });
+    }
+
+    /// Returns the CLOCK_REALTIME time `after` from now
+    fn realtime_after(after: Duration) -> libc::timespec {
+        let mut now = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+        let nsec = now.tv_nsec + after.subsec_nanos() as libc::c_long;
+        libc::timespec {
+            tv_sec: now.tv_sec + after.as_secs() as libc::time_t + nsec / 1_000_000_000,
+            tv_nsec: nsec % 1_000_000_000,
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_timed_wait_absolute() {
+        let word = AtomicU32::new(0);
+        let futex = SharedFutex::from_atomic(&word);
+        assert_eq!(
+            futex.timed_wait_absolute(1, realtime_after(Duration::from_secs(10))),
+            Ok(FutexWakeReason::ValueMismatch)
+        );
+        // Already passed, it does not sleep
+        let past = libc::timespec {
+            tv_sec: 1,
+            tv_nsec: 0,
+        };
+        assert_eq!(
+            futex.timed_wait_absolute(0, past),
+            Ok(FutexWakeReason::TimedOut)
+        );
+        let denormalized = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 1_000_000_000,
+        };
+        assert_eq!(
+            futex.timed_wait_absolute(0, denormalized),
+            Err(FutexError::Invalid)
+        );
+
+        // Read as relative, the deadline would be decades away
+        let start = Instant::now();
+        let deadline = realtime_after(Duration::from_millis(100));
+        while futex.timed_wait_absolute(0, deadline) != Ok(FutexWakeReason::TimedOut) {}
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(90));
+        assert!(elapsed < Duration::from_secs(5));
+
+        thread::scope(|s| {
+            let waiter =
+                s.spawn(|| futex.timed_wait_absolute(0, realtime_after(Duration::from_secs(10))));
+            thread::sleep(Duration::from_millis(100));
+            futex.set_futex_value(1);
+            futex.wake_all().unwrap();
+            assert_eq!(waiter.join().unwrap(), Ok(FutexWakeReason::Woken));
+        });
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "maps shared memory")]
+    fn test_lock_timeout_and_scope_lock() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        assert_eq!(futex.lock_timeout(Duration::from_millis(10)), Ok(()));
+        thread::scope(|s| {
+            s.spawn(|| {
+                let start = Instant::now();
+                assert_eq!(
+                    futex.lock_timeout(Duration::from_millis(50)),
+                    Err(FutexError::TimedOut)
+                );
+                assert!(start.elapsed() >= Duration::from_millis(50));
+                assert_eq!(
+                    futex.scope_lock(Duration::from_millis(10), || unreachable!()),
+                    Err(FutexError::TimedOut)
+                );
+            });
+        });
+        futex.unlock();
+
+        assert_eq!(futex.scope_lock(Duration::from_secs(1), || 42), Ok(42));
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+        // A panicking critical section still unlocks
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            futex.scope_lock(Duration::from_secs(1), || panic!("in the critical section"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_lock_until() {
+        let futex = SharedFutex::new_anonymous().unwrap();
+        let past = Instant::now() - Duration::from_millis(10);
+        let guard = futex.lock_until(past).unwrap();
+        assert_eq!(futex.get_futex_value(), LOCKED_NO_WAITERS);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                // Held: a past deadline fails like try_lock, without marking waiters
+                assert_eq!(futex.lock_until(past).err(), Some(FutexError::TimedOut));
+                assert_eq!(futex.get_futex_value(), LOCKED_NO_WAITERS);
+
+                let deadline = Instant::now() + Duration::from_millis(50);
+                assert_eq!(futex.lock_until(deadline).err(), Some(FutexError::TimedOut));
+                let now = Instant::now();
+                assert!(now >= deadline);
+                assert!(now - deadline < Duration::from_millis(50));
+            });
+        });
+        drop(guard);
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+
+        futex.lock();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                let guard = futex.lock_until(Instant::now() + Duration::from_secs(10));
+                assert!(guard.is_ok());
+                Instant::now()
+            });
+            thread::sleep(Duration::from_millis(50));
+            let unlocked = Instant::now();
+            futex.unlock();
+            assert!(waiter.join().unwrap() - unlocked < Duration::from_secs(1));
+        });
+        assert_eq!(futex.get_futex_value(), UNLOCKED);
+    }
+}
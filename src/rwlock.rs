@@ -0,0 +1,744 @@
+//! Reader-writer lock shared between processes
+//!
+//! The lock is two futex words: the number of active readers and a Drepper mutex serializing the
+//! writers. A writer takes the mutex, which stops new readers, then sleeps on the reader count
+//! until the readers inside drain. Readers that find the mutex held mark it contended and sleep
+//! on it, so the writer wakes them all when it unlocks. Writers are therefore preferred: a steady
+//! flow of readers cannot starve a writer.
+//!
+//! [`SharedRwLock`] puts the data behind the lock in the same allocation, after the two words.
+//!
+//! [`SharedBiasedRwLock`] lets the caller pick the policy instead. Its state word holds a writer
+//! bit, the count of waiting writers and the reader count, and everybody who sleeps sleeps on
+//! it. Reader biased, readers come in whenever no writer holds the lock,
+//! so writers may starve under a steady flow of readers; writer biased, they stay out while a
+//! writer waits.
+
+use crate::futex_word::FutexWord;
+use crate::sleepers;
+use crate::{LOCKED_NO_WAITERS, LOCKED_WAITERS, UNLOCKED};
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{fence, AtomicU32, Ordering::SeqCst};
+
+/// Cross-process reader-writer lock without data
+/// Zeroed memory is a valid, unlocked RawSharedRwLock. Like the mutex, it has no owner tracking:
+/// a process dying with the lock held leaves it held.
+#[repr(C)]
+pub struct RawSharedRwLock {
+    readers: FutexWord,
+    writer: FutexWord,
+}
+
+const _: () = assert!(mem::size_of::<RawSharedRwLock>() == 8);
+
+impl RawSharedRwLock {
+    /// Create a new RawSharedRwLock
+    /// # Returns
+    /// An unlocked RawSharedRwLock
+    pub const fn new() -> Self {
+        Self {
+            readers: FutexWord::new(0),
+            writer: FutexWord::unlocked(),
+        }
+    }
+
+    /// Locks for reading, sleeping while a writer holds or waits for the lock
+    pub fn read_lock(&self) {
+        loop {
+            if self.try_read_lock() {
+                return;
+            }
+            // Mark the writer mutex contended so that write_unlock() wakes us
+            let state = self.writer.load(SeqCst);
+            if state == LOCKED_WAITERS
+                || (state == LOCKED_NO_WAITERS
+                    && self
+                        .writer
+                        .as_atomic()
+                        .compare_exchange(LOCKED_NO_WAITERS, LOCKED_WAITERS, SeqCst, SeqCst)
+                        .is_ok())
+            {
+                let _ = self.writer.as_futex().wait(LOCKED_WAITERS);
+            }
+        }
+    }
+
+    /// Tries to lock for reading without blocking
+    /// # Returns
+    /// true if the read lock was acquired
+    #[must_use = "if false the read lock was not acquired"]
+    pub fn try_read_lock(&self) -> bool {
+        if self.writer.load(SeqCst) != UNLOCKED {
+            return false;
+        }
+        self.readers.as_atomic().fetch_add(1, SeqCst);
+        // A writer may have come in between, it either sees our count or we see its mutex
+        if self.writer.load(SeqCst) == UNLOCKED {
+            return true;
+        }
+        self.read_unlock();
+        false
+    }
+
+    /// Unlocks a read lock
+    /// The last reader out wakes the writer waiting for the readers to drain.
+    pub fn read_unlock(&self) {
+        let readers = self.readers.as_atomic().fetch_sub(1, SeqCst);
+        debug_assert!(readers > 0, "read_unlock() without a read lock");
+        if readers == 1 && self.writer.load(SeqCst) != UNLOCKED {
+            let _ = self.readers.as_futex().post(1);
+        }
+    }
+
+    /// Locks for writing, sleeping until the other writers and the readers are gone
+    pub fn write_lock(&self) {
+        self.writer.lock();
+        self.wait_for_readers();
+    }
+
+    /// Tries to lock for writing without blocking
+    /// # Returns
+    /// true if the write lock was acquired
+    #[must_use = "if false the write lock was not acquired"]
+    pub fn try_write_lock(&self) -> bool {
+        if !self.writer.try_lock() {
+            return false;
+        }
+        fence(SeqCst);
+        if self.readers.load(SeqCst) == 0 {
+            return true;
+        }
+        self.write_unlock();
+        false
+    }
+
+    /// Unlocks a write lock and wakes the waiting readers and writers
+    pub fn write_unlock(&self) {
+        self.writer.as_futex().unlock_all();
+    }
+
+    /// Sleeps until the active readers are gone, with the writer mutex held
+    fn wait_for_readers(&self) {
+        // Pairs with the SeqCst increment in try_read_lock()
+        fence(SeqCst);
+        loop {
+            let readers = self.readers.load(SeqCst);
+            if readers == 0 {
+                return;
+            }
+            let _ = self.readers.as_futex().wait(readers);
+        }
+    }
+}
+
+impl Default for RawSharedRwLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cross-process reader-writer lock holding its data
+/// The layout is the reader count, the writer mutex and then `T`, aligned for `T`, so one
+/// mapping of [`SharedRwLock::required_size`] bytes holds the whole lock. Only the process
+/// laying it out writes it, the others cast the mapping to a `&SharedRwLock<T>`. `T` is visible
+/// from every process mapping the region, it should not hold pointers or handles local to one
+/// of them.
+#[repr(C)]
+pub struct SharedRwLock<T> {
+    raw: RawSharedRwLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SharedRwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for SharedRwLock<T> {}
+
+impl<T> SharedRwLock<T> {
+    /// Create a new SharedRwLock
+    /// # Arguments
+    /// * `value` - The data behind the lock
+    /// # Returns
+    /// An unlocked SharedRwLock
+    pub const fn new(value: T) -> Self {
+        Self {
+            raw: RawSharedRwLock::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns the number of bytes the lock and its data take in shared memory
+    pub const fn required_size() -> usize {
+        mem::size_of::<Self>()
+    }
+
+    /// Locks for reading, sleeping while a writer holds or waits for the lock
+    /// # Returns
+    /// A guard giving shared access to the data and unlocking when dropped
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        self.raw.read_lock();
+        ReadGuard { lock: self }
+    }
+
+    /// Tries to lock for reading without blocking
+    /// # Returns
+    /// A read guard, or None if a writer holds or waits for the lock
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        // A guard built eagerly would unlock when dropped on failure
+        self.raw.try_read_lock().then(|| ReadGuard { lock: self })
+    }
+
+    /// Locks for writing, sleeping until the other writers and the readers are gone
+    /// # Returns
+    /// A guard giving exclusive access to the data and unlocking when dropped
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        self.raw.write_lock();
+        WriteGuard { lock: self }
+    }
+
+    /// Tries to lock for writing without blocking
+    /// # Returns
+    /// A write guard, or None if the lock is held
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        self.raw.try_write_lock().then(|| WriteGuard { lock: self })
+    }
+
+    /// Returns the data, no lock is needed with a unique reference
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consumes the lock and returns the data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Default> Default for SharedRwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SharedRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("SharedRwLock");
+        match self.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+/// Shared access to the data of a [`SharedRwLock`], released when dropped
+/// Readers only get a shared reference: other readers may hold the lock at the same time.
+#[must_use = "if unused the SharedRwLock will immediately unlock"]
+pub struct ReadGuard<'a, T> {
+    lock: &'a SharedRwLock<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.read_unlock();
+    }
+}
+
+/// Exclusive access to the data of a [`SharedRwLock`], released when dropped
+#[must_use = "if unused the SharedRwLock will immediately unlock"]
+pub struct WriteGuard<'a, T> {
+    lock: &'a SharedRwLock<T>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.write_unlock();
+    }
+}
+
+/// Which side a [`SharedBiasedRwLock`] lets in first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum RwBias {
+    /// New readers come in while a writer waits, writers may starve
+    ReaderBiased = 0,
+    /// New readers stay out while a writer waits, readers may starve
+    WriterBiased = 1,
+}
+
+/// A writer holds the lock
+const BIASED_WRITER: u32 = 1 << 31;
+/// One writer waits for the lock, up to 2^15 - 1 of them, the others sleep until one gets in
+const BIASED_WAITING_ONE: u32 = 1 << 16;
+const BIASED_WAITING_MASK: u32 = 0x7fff << 16;
+/// The readers inside, up to 2^16 - 1 of them
+const BIASED_READERS_MASK: u32 = 0xffff;
+
+/// Cross-process reader-writer lock without data, with a chosen priority policy
+/// Zeroed memory is a valid, unlocked, reader biased lock. Like [`RawSharedRwLock`], it has no
+/// owner tracking. The state word packs the writer bit, the waiting writers and the readers,
+/// so every change a sleeper waits for changes the word it sleeps on.
+#[repr(C)]
+pub struct SharedBiasedRwLock {
+    state: FutexWord,
+    /// The number of threads sleeping on the state word
+    sleepers: AtomicU32,
+    bias: u32,
+}
+
+const _: () = assert!(mem::size_of::<SharedBiasedRwLock>() == 12);
+
+impl SharedBiasedRwLock {
+    /// Create a new SharedBiasedRwLock
+    /// # Arguments
+    /// * `bias` - The side let in first
+    /// # Returns
+    /// An unlocked SharedBiasedRwLock
+    pub const fn new(bias: RwBias) -> Self {
+        Self {
+            state: FutexWord::new(0),
+            sleepers: AtomicU32::new(0),
+            bias: bias as u32,
+        }
+    }
+
+    /// Returns the policy the lock was created with
+    pub fn bias(&self) -> RwBias {
+        match self.bias {
+            0 => RwBias::ReaderBiased,
+            _ => RwBias::WriterBiased,
+        }
+    }
+
+    /// Returns true if a reader may not come in at `state`
+    fn keeps_readers_out(&self, state: u32) -> bool {
+        state & BIASED_WRITER != 0
+            || state & BIASED_READERS_MASK == BIASED_READERS_MASK
+            || (state & BIASED_WAITING_MASK != 0 && self.bias() == RwBias::WriterBiased)
+    }
+
+    /// Locks for reading, sleeping while a writer holds the lock, or waits for it when writer
+    /// biased
+    pub fn read_lock(&self) {
+        loop {
+            if self.try_read_lock() {
+                return;
+            }
+            let state = self.state.load(SeqCst);
+            if self.keeps_readers_out(state) {
+                self.sleep(state);
+            }
+        }
+    }
+
+    /// Tries to lock for reading without blocking
+    /// # Returns
+    /// true if the read lock was acquired
+    #[must_use = "if false the read lock was not acquired"]
+    pub fn try_read_lock(&self) -> bool {
+        self.state
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |state| {
+                (!self.keeps_readers_out(state)).then(|| state + 1)
+            })
+            .is_ok()
+    }
+
+    /// Unlocks a read lock
+    /// The last reader out wakes the sleepers, the writers among them wait for it.
+    pub fn read_unlock(&self) {
+        let state = self.state.as_atomic().fetch_sub(1, SeqCst);
+        debug_assert!(
+            state & BIASED_READERS_MASK > 0,
+            "read_unlock() without a read lock"
+        );
+        if state & BIASED_READERS_MASK == 1 {
+            self.wake_sleepers();
+        }
+    }
+
+    /// Locks for writing, sleeping until the other writers and the readers are gone
+    pub fn write_lock(&self) {
+        let atom = self.state.as_atomic();
+        let mut state = loop {
+            let queued = atom.fetch_update(SeqCst, SeqCst, |state| {
+                (state & BIASED_WAITING_MASK != BIASED_WAITING_MASK)
+                    .then(|| state + BIASED_WAITING_ONE)
+            });
+            match queued {
+                Ok(state) => break state + BIASED_WAITING_ONE,
+                // A full count would carry into the writer bit, wait for a writer to get in
+                Err(state) => self.sleep(state),
+            }
+        };
+        loop {
+            if state & (BIASED_WRITER | BIASED_READERS_MASK) != 0 {
+                self.sleep(state);
+                state = atom.load(SeqCst);
+                continue;
+            }
+            let locked = state - BIASED_WAITING_ONE + BIASED_WRITER;
+            match atom.compare_exchange(state, locked, SeqCst, SeqCst) {
+                Ok(_) => return,
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    /// Tries to lock for writing without blocking
+    /// # Returns
+    /// true if the write lock was acquired
+    #[must_use = "if false the write lock was not acquired"]
+    pub fn try_write_lock(&self) -> bool {
+        self.state
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |state| {
+                (state & (BIASED_WRITER | BIASED_READERS_MASK) == 0)
+                    .then_some(state | BIASED_WRITER)
+            })
+            .is_ok()
+    }
+
+    /// Unlocks a write lock and wakes the waiting readers and writers
+    pub fn write_unlock(&self) {
+        let state = self.state.as_atomic().fetch_and(!BIASED_WRITER, SeqCst);
+        debug_assert!(
+            state & BIASED_WRITER != 0,
+            "write_unlock() without the write lock"
+        );
+        self.wake_sleepers();
+    }
+
+    /// Sleeps on the state word while it holds `state`
+    fn sleep(&self, state: u32) {
+        // Returns at once when the state changed in between
+        let _ = sleepers::sleep(&self.sleepers, || self.state.as_futex().wait(state));
+    }
+
+    /// Wakes every sleeper after an unlock
+    fn wake_sleepers(&self) {
+        if sleepers::any(&self.sleepers) {
+            let _ = self.state.as_futex().wake_all();
+        }
+    }
+}
+
+impl fmt::Debug for SharedBiasedRwLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.load(SeqCst);
+        f.debug_struct("SharedBiasedRwLock")
+            .field("bias", &self.bias())
+            .field("writer", &(state & BIASED_WRITER != 0))
+            .field("readers", &(state & BIASED_READERS_MASK))
+            .field("writers_waiting", &((state & BIASED_WAITING_MASK) >> 16))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::AtomicBool;
+    use std::{thread, time};
+
+    #[test]
+    fn test_readers_share_writers_exclude() {
+        let lock = RawSharedRwLock::new();
+        lock.read_lock();
+        assert!(lock.try_read_lock());
+        assert!(!lock.try_write_lock());
+        lock.read_unlock();
+        lock.read_unlock();
+
+        assert!(lock.try_write_lock());
+        assert!(!lock.try_read_lock());
+        assert!(!lock.try_write_lock());
+        lock.write_unlock();
+        assert!(lock.try_read_lock());
+        lock.read_unlock();
+    }
+
+    #[test]
+    fn test_writer_waits_for_readers() {
+        let lock = RawSharedRwLock::new();
+        let written = AtomicU32::new(0);
+        lock.read_lock();
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.write_lock();
+                written.store(1, SeqCst);
+                lock.write_unlock();
+            });
+            // The waiting writer holds the mutex, new readers are turned away
+            while lock.try_read_lock() {
+                lock.read_unlock();
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            thread::sleep(time::Duration::from_millis(50));
+            assert_eq!(written.load(SeqCst), 0);
+            lock.read_unlock();
+        });
+
+        assert_eq!(written.load(SeqCst), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_rwlock_in_shared_memory() {
+        let len = mem::size_of::<RawSharedRwLock>() + mem::size_of::<u64>() * 2;
+        let segment = &ShmSegment::create("test_raw_shared_rwlock", len);
+        let base = segment.as_ptr() as usize;
+        unsafe { (base as *mut RawSharedRwLock).write(RawSharedRwLock::new()) };
+
+        thread::scope(|s| {
+            for writer in 0..4 {
+                s.spawn(move || {
+                    let mapping = segment.map_again();
+                    let lock = unsafe { mapping.get::<RawSharedRwLock>() };
+                    let pair = unsafe {
+                        (mapping.as_ptr() as *mut u8).add(mem::size_of::<RawSharedRwLock>())
+                            as *mut u64
+                    };
+                    for _ in 0..500 {
+                        if writer % 2 == 0 {
+                            lock.write_lock();
+                            unsafe {
+                                let value = pair.read_volatile() + 1;
+                                pair.write_volatile(value);
+                                pair.add(1).write_volatile(value);
+                            }
+                            lock.write_unlock();
+                        } else {
+                            lock.read_lock();
+                            // Writers update both halves under the lock, readers never see them differ
+                            unsafe {
+                                assert_eq!(pair.read_volatile(), pair.add(1).read_volatile());
+                            }
+                            lock.read_unlock();
+                        }
+                    }
+                });
+            }
+        });
+
+        let pair = (base + mem::size_of::<RawSharedRwLock>()) as *const u64;
+        assert_eq!(unsafe { pair.read_volatile() }, 1000);
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(SharedRwLock::<u8>::required_size(), 12);
+        assert_eq!(SharedRwLock::<u64>::required_size(), 16);
+        assert_eq!(mem::offset_of!(SharedRwLock<u64>, data), 8);
+        assert_eq!(
+            mem::offset_of!(SharedRwLock<u128>, data),
+            mem::align_of::<u128>()
+        );
+        assert_eq!(mem::offset_of!(SharedRwLock<[u8; 3]>, data), 8);
+    }
+
+    #[test]
+    fn test_guards() {
+        let lock = SharedRwLock::new(vec![1]);
+        {
+            let first = lock.read();
+            let second = lock.try_read().unwrap();
+            assert_eq!(*first, *second);
+            assert!(lock.try_write().is_none());
+        }
+        lock.write().push(2);
+        let guard = lock.try_write().unwrap();
+        assert!(lock.try_read().is_none());
+        assert_eq!(format!("{lock:?}"), "SharedRwLock { data: <locked> }");
+        drop(guard);
+        assert_eq!(lock.into_inner(), [1, 2]);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_data_in_shared_memory() {
+        let len = SharedRwLock::<[u64; 2]>::required_size();
+        let segment = &ShmSegment::create("test_shared_rwlock", len);
+        unsafe {
+            (segment.as_ptr() as *mut SharedRwLock<[u64; 2]>).write(SharedRwLock::new([0; 2]));
+        }
+
+        thread::scope(|s| {
+            for writer in 0..4 {
+                s.spawn(move || {
+                    let mapping = segment.map_again();
+                    let lock = unsafe { mapping.get::<SharedRwLock<[u64; 2]>>() };
+                    for _ in 0..500 {
+                        if writer % 2 == 0 {
+                            let mut pair = lock.write();
+                            pair[0] += 1;
+                            pair[1] = pair[0];
+                        } else {
+                            let pair = lock.read();
+                            assert_eq!(pair[0], pair[1]);
+                        }
+                    }
+                });
+            }
+        });
+
+        let lock = unsafe { segment.get::<SharedRwLock<[u64; 2]>>() };
+        assert_eq!(*lock.read(), [1000; 2]);
+    }
+
+    /// Holds a read lock while a writer queues, then checks whether a new reader gets in
+    fn new_reader_enters_while_a_writer_waits(bias: RwBias) -> bool {
+        let lock = SharedBiasedRwLock::new(bias);
+        lock.read_lock();
+        thread::scope(|s| {
+            s.spawn(|| {
+                lock.write_lock();
+                lock.write_unlock();
+            });
+            while lock.state.load(SeqCst) & BIASED_WAITING_MASK == 0 {
+                thread::yield_now();
+            }
+            let entered = lock.try_read_lock();
+            if entered {
+                lock.read_unlock();
+            }
+            lock.read_unlock();
+            entered
+        })
+    }
+
+    #[test]
+    fn test_biased_rwlock_policies() {
+        assert!(new_reader_enters_while_a_writer_waits(RwBias::ReaderBiased));
+        assert!(!new_reader_enters_while_a_writer_waits(
+            RwBias::WriterBiased
+        ));
+
+        let zeroed: [u32; 3] = [0; 3];
+        let lock = unsafe { &*(zeroed.as_ptr() as *const SharedBiasedRwLock) };
+        assert_eq!(lock.bias(), RwBias::ReaderBiased);
+        assert!(lock.try_write_lock());
+        assert!(!lock.try_read_lock());
+        lock.write_unlock();
+    }
+
+    #[test]
+    fn test_biased_rwlock_full_writer_queue() {
+        let lock = SharedBiasedRwLock::new(RwBias::WriterBiased);
+        let atom = lock.state.as_atomic();
+        // A reader inside and as many writers waiting as the word counts
+        atom.store(BIASED_WAITING_MASK | 1, SeqCst);
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                lock.write_lock();
+                lock.write_unlock();
+            });
+            while lock.sleepers.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            assert_eq!(atom.load(SeqCst), BIASED_WAITING_MASK | 1);
+            // One of the waiting writers gives up, then the reader leaves
+            atom.fetch_sub(BIASED_WAITING_ONE, SeqCst);
+            lock.read_unlock();
+            writer.join().unwrap();
+        });
+        assert_eq!(atom.load(SeqCst), BIASED_WAITING_MASK - BIASED_WAITING_ONE);
+    }
+
+    #[test]
+    fn test_writer_biased_rwlock_lets_writers_through_readers() {
+        let lock = SharedBiasedRwLock::new(RwBias::WriterBiased);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            // Overlapping readers, the lock never runs out of them without the bias
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(SeqCst) {
+                        lock.read_lock();
+                        assert_eq!(lock.state.load(SeqCst) & BIASED_WRITER, 0);
+                        thread::yield_now();
+                        lock.read_unlock();
+                    }
+                });
+            }
+            for _ in 0..200 {
+                lock.write_lock();
+                assert_eq!(lock.state.load(SeqCst) & BIASED_READERS_MASK, 0);
+                lock.write_unlock();
+            }
+            done.store(true, SeqCst);
+        });
+        assert_eq!(lock.state.load(SeqCst), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_biased_rwlock_in_shared_memory() {
+        // The pair starts at the first offset after the lock aligned for u64
+        let offset = mem::size_of::<SharedBiasedRwLock>().next_multiple_of(mem::align_of::<u64>());
+        let len = offset + mem::size_of::<u64>() * 2;
+        for bias in [RwBias::ReaderBiased, RwBias::WriterBiased] {
+            let segment = &ShmSegment::create("test_biased_rwlock", len);
+            unsafe {
+                (segment.as_ptr() as *mut SharedBiasedRwLock).write(SharedBiasedRwLock::new(bias));
+            }
+
+            thread::scope(|s| {
+                for writer in 0..4 {
+                    s.spawn(move || {
+                        let mapping = segment.map_again();
+                        let lock = unsafe { mapping.get::<SharedBiasedRwLock>() };
+                        assert_eq!(lock.bias(), bias);
+                        let pair = unsafe { (mapping.as_ptr() as *mut u8).add(offset) as *mut u64 };
+                        for _ in 0..500 {
+                            if writer % 2 == 0 {
+                                lock.write_lock();
+                                unsafe {
+                                    let value = pair.read_volatile() + 1;
+                                    pair.write_volatile(value);
+                                    pair.add(1).write_volatile(value);
+                                }
+                                lock.write_unlock();
+                            } else {
+                                lock.read_lock();
+                                unsafe {
+                                    assert_eq!(pair.read_volatile(), pair.add(1).read_volatile());
+                                }
+                                lock.read_unlock();
+                            }
+                        }
+                    });
+                }
+            });
+
+            let pair = unsafe { (segment.as_ptr() as *const u8).add(offset) as *const u64 };
+            assert_eq!(unsafe { pair.read_volatile() }, 1000);
+        }
+    }
+}
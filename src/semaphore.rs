@@ -0,0 +1,254 @@
+//! Counting semaphore on a futex word
+//!
+//! The word holds the available permits, the one after it the count of sleeping acquirers, so
+//! a release without them makes no system call. An acquirer sleeps on the word while it holds
+//! 0, and a release wakes one of them after adding its permit.
+//!
+//! [`Semaphore`] is the interface [`SharedSemaphore`] shares with the POSIX named semaphores of
+//! `crate::posix_sem`, so code written against it takes either.
+
+use crate::deadline::Deadline;
+use crate::error::RufutexError;
+use crate::futex_word::FutexWord;
+use crate::sleepers;
+
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::time::Duration;
+
+/// The most permits a semaphore holds, SEM_VALUE_MAX on Linux
+pub const MAX_PERMITS: u32 = i32::MAX as u32;
+
+/// A counting semaphore whose permits are handed out as guards
+pub trait Semaphore {
+    /// Takes a permit, sleeping until one is available or until `timeout` expires
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait, None waits until a permit is available
+    /// # Returns
+    /// Ok once the permit is taken, TimedOut if the timeout expired, or the error of the
+    /// system call
+    fn acquire_permit(&self, timeout: Option<Duration>) -> Result<(), RufutexError>;
+
+    /// Takes a permit if one is available
+    /// # Returns
+    /// Ok once the permit is taken, WouldBlock if there is none, or the error of the system call
+    fn try_acquire_permit(&self) -> Result<(), RufutexError>;
+
+    /// Adds a permit, waking an acquirer
+    /// # Returns
+    /// Ok, or a Syscall error with EOVERFLOW if the semaphore already holds [`MAX_PERMITS`]
+    fn release(&self) -> Result<(), RufutexError>;
+
+    /// Takes a permit, sleeping until one is available
+    /// # Returns
+    /// The guard releasing the permit on drop, or the error of the system call
+    fn acquire(&self) -> Result<SemaphoreGuard<'_, Self>, RufutexError> {
+        self.acquire_permit(None)?;
+        Ok(SemaphoreGuard(self))
+    }
+
+    /// Takes a permit if one is available
+    /// # Returns
+    /// The guard releasing the permit on drop, WouldBlock if there is none, or the error of
+    /// the system call
+    fn try_acquire(&self) -> Result<SemaphoreGuard<'_, Self>, RufutexError> {
+        self.try_acquire_permit()?;
+        Ok(SemaphoreGuard(self))
+    }
+
+    /// Takes a permit, sleeping at most `timeout` until one is available
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait
+    /// # Returns
+    /// The guard releasing the permit on drop, TimedOut if the timeout expired, or the error of
+    /// the system call
+    fn acquire_timeout(&self, timeout: Duration) -> Result<SemaphoreGuard<'_, Self>, RufutexError> {
+        self.acquire_permit(Some(timeout))?;
+        Ok(SemaphoreGuard(self))
+    }
+}
+
+/// Holds a permit of a [`Semaphore`], releasing it on drop
+pub struct SemaphoreGuard<'a, S: Semaphore + ?Sized>(&'a S);
+
+impl<S: Semaphore + ?Sized> SemaphoreGuard<'_, S> {
+    /// Keeps the permit without the guard, the caller releases it or hands it over
+    pub fn leak(self) {
+        mem::forget(self);
+    }
+}
+
+impl<S: Semaphore + ?Sized> Drop for SemaphoreGuard<'_, S> {
+    fn drop(&mut self) {
+        // The permit was taken from the semaphore, giving it back cannot overflow
+        let _ = self.0.release();
+    }
+}
+
+impl<S: Semaphore + ?Sized> fmt::Debug for SemaphoreGuard<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphoreGuard").finish_non_exhaustive()
+    }
+}
+
+/// Cross-process counting semaphore
+/// Zeroed memory is a valid SharedSemaphore without permits.
+#[repr(C)]
+pub struct SharedSemaphore {
+    permits: FutexWord,
+    sleepers: AtomicU32,
+}
+
+const _: () = assert!(mem::size_of::<SharedSemaphore>() == 8);
+
+impl SharedSemaphore {
+    /// Create a new SharedSemaphore
+    /// # Arguments
+    /// * `permits` - The permits available at first, at most [`MAX_PERMITS`]
+    /// # Returns
+    /// A SharedSemaphore holding `permits`
+    pub const fn new(permits: u32) -> Self {
+        assert!(permits <= MAX_PERMITS, "too many permits for a semaphore");
+        Self {
+            permits: FutexWord::new(permits),
+            sleepers: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns the permits available now
+    pub fn permits(&self) -> u32 {
+        self.permits.load(SeqCst)
+    }
+
+    fn acquire_until(&self, deadline: Deadline) -> Result<(), RufutexError> {
+        loop {
+            if self.try_acquire_permit().is_ok() {
+                return Ok(());
+            }
+            if deadline.has_passed() {
+                return Err(RufutexError::TimedOut);
+            }
+            // Returns at once when a release landed in between
+            let slept = sleepers::sleep(&self.sleepers, || {
+                deadline.wait(&self.permits.as_futex(), 0)
+            });
+            if !slept {
+                return Err(RufutexError::TimedOut);
+            }
+        }
+    }
+}
+
+impl Semaphore for SharedSemaphore {
+    fn acquire_permit(&self, timeout: Option<Duration>) -> Result<(), RufutexError> {
+        self.acquire_until(timeout.map_or(Deadline::never(), Deadline::after))
+    }
+
+    fn try_acquire_permit(&self) -> Result<(), RufutexError> {
+        self.permits
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |permits| permits.checked_sub(1))
+            .map(|_| ())
+            .map_err(|_| RufutexError::WouldBlock)
+    }
+
+    fn release(&self) -> Result<(), RufutexError> {
+        self.permits
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |permits| {
+                (permits < MAX_PERMITS).then_some(permits + 1)
+            })
+            // EOVERFLOW like sem_post(3), for the substitutes to fail alike
+            .map_err(|_| RufutexError::Syscall {
+                operation: "release",
+                error: io::Error::from_raw_os_error(libc::EOVERFLOW),
+            })?;
+        if sleepers::any(&self.sleepers) {
+            let _ = self.permits.as_futex().post(1);
+        }
+        Ok(())
+    }
+}
+
+impl Default for SharedSemaphore {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl fmt::Debug for SharedSemaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedSemaphore")
+            .field("permits", &self.permits())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_permits_and_overflow() {
+        let sem = SharedSemaphore::new(2);
+        let first = sem.try_acquire().unwrap();
+        let second = sem.acquire().unwrap();
+        assert!(matches!(sem.try_acquire(), Err(RufutexError::WouldBlock)));
+        drop(first);
+        assert_eq!(sem.permits(), 1);
+        second.leak();
+        assert_eq!(sem.permits(), 1);
+
+        let start = Instant::now();
+        let _held = sem.acquire().unwrap();
+        assert!(matches!(
+            sem.acquire_timeout(Duration::from_millis(50)),
+            Err(RufutexError::TimedOut)
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let full = SharedSemaphore::new(MAX_PERMITS);
+        let err = full.release().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EOVERFLOW));
+        assert_eq!(full.permits(), MAX_PERMITS);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_release_wakes_an_acquirer_of_another_mapping() {
+        let segment =
+            ShmSegment::create("test_shared_semaphore", mem::size_of::<SharedSemaphore>());
+        let sem = unsafe {
+            (segment.as_ptr() as *mut SharedSemaphore).write(SharedSemaphore::new(0));
+            segment.get::<SharedSemaphore>()
+        };
+
+        thread::scope(|s| {
+            let acquirers: Vec<_> = (0..3)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mapping = segment.map_again();
+                        let sem = unsafe { mapping.get::<SharedSemaphore>() };
+                        sem.acquire_timeout(Duration::from_secs(10)).unwrap().leak();
+                    })
+                })
+                .collect();
+            while sem.sleepers.load(SeqCst) < 3 {
+                thread::yield_now();
+            }
+            for _ in 0..3 {
+                sem.release().unwrap();
+            }
+            for acquirer in acquirers {
+                acquirer.join().unwrap();
+            }
+        });
+
+        assert_eq!(sem.permits(), 0);
+    }
+}
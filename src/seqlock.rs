@@ -0,0 +1,314 @@
+//! Sequence lock for read-mostly data in shared memory
+//!
+//! Readers never write to the lock: they read the sequence, copy the data and read the sequence
+//! again, and retry when it was odd, a write in progress, or when it changed under them. A
+//! writer makes the sequence odd, stores the data and makes it even again, so a copy that
+//! raced with a write is always detected and thrown away.
+//!
+//! The orderings follow Boehm, "Can seqlocks get along with programming language memory
+//! models?" (MSPC 2012), and the `smp_rmb`/`smp_wmb` pairs of Linux's seqlock.h:
+//!
+//! * The reader's first load is Acquire, so the copy cannot move before it.
+//! * An Acquire fence after the copy keeps the copy before the second load of the sequence,
+//!   which can then be Relaxed.
+//! * The writer's odd store is followed by a Release fence, so the data stores cannot move
+//!   before it, and the even store is Release, so they cannot move after it.
+//!
+//! The copy itself races with the writer by design. As in most Rust seqlocks it is a volatile
+//! read, into a `MaybeUninit<T>` that only becomes a `T` once the sequence proved that no write
+//! overlapped. `T: Copy` keeps the value from owning anything.
+
+use crate::futex_word::FutexWord;
+use crate::sleepers;
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::hint;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{
+    fence, AtomicU32,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+/// Cross-process sequence lock holding its data
+/// The layout is the sequence, the count of blocked readers, the writer mutex and then `T`,
+/// aligned for `T`, so one mapping of [`SharedSeqLock::required_size`] bytes holds the whole
+/// lock. Writers exclude each other with the mutex, readers never block them.
+#[repr(C)]
+pub struct SharedSeqLock<T: Copy> {
+    sequence: FutexWord,
+    waiters: AtomicU32,
+    writer: FutexWord,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for SharedSeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SharedSeqLock<T> {}
+
+/// Ends a write started at the even `sequence`, also when the update panics
+/// A panicking update never stored its copy, so the data is still the old one and the next
+/// even sequence only makes the readers copy it again.
+struct EndWrite<'a, T: Copy>(&'a SharedSeqLock<T>, u32);
+
+impl<T: Copy> Drop for EndWrite<'_, T> {
+    fn drop(&mut self) {
+        let EndWrite(lock, sequence) = *self;
+        lock.sequence
+            .as_atomic()
+            .store(sequence.wrapping_add(2), Release);
+        // The even store is only Release, the fence orders it before the sleepers like a SeqCst
+        // store would
+        fence(SeqCst);
+        if sleepers::any(&lock.waiters) {
+            let _ = lock.sequence.as_futex().wake_all();
+        }
+        lock.writer.unlock();
+    }
+}
+
+impl<T: Copy> SharedSeqLock<T> {
+    /// Create a new SharedSeqLock
+    /// # Arguments
+    /// * `value` - The initial data
+    /// # Returns
+    /// A SharedSeqLock without a write in progress
+    pub const fn new(value: T) -> Self {
+        Self {
+            sequence: FutexWord::new(0),
+            waiters: AtomicU32::new(0),
+            writer: FutexWord::unlocked(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns the number of bytes the lock and its data take in shared memory
+    pub const fn required_size() -> usize {
+        mem::size_of::<Self>()
+    }
+
+    /// Returns a consistent copy of the data, spinning while a write is in progress
+    pub fn read(&self) -> T {
+        loop {
+            let sequence = self.sequence.load(Acquire);
+            if sequence & 1 == 0 {
+                if let Some(value) = self.try_read_at(sequence) {
+                    return value;
+                }
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Returns a consistent copy of the data, sleeping while a write is in progress
+    /// Meant for data whose writers may be descheduled mid update, where spinning would burn
+    /// the reader's time slice.
+    pub fn read_blocking(&self) -> T {
+        loop {
+            let sequence = self.sequence.load(Acquire);
+            if sequence & 1 == 0 {
+                if let Some(value) = self.try_read_at(sequence) {
+                    return value;
+                }
+                continue;
+            }
+            // Returns at once when the write completed in between
+            let _ = sleepers::sleep(&self.waiters, || self.sequence.as_futex().wait(sequence));
+        }
+    }
+
+    /// Copies the data, read with the even `sequence`
+    /// # Returns
+    /// The copy, or None when a write overlapped it
+    fn try_read_at(&self, sequence: u32) -> Option<T> {
+        // A torn copy may not be a valid T, a bool or an enum, so it stays uninitialized
+        let value = unsafe { ptr::read_volatile(self.data.get().cast::<MaybeUninit<T>>()) };
+        // Keeps the copy before the second load, smp_rmb() in seqlock.h
+        fence(Acquire);
+        (self.sequence.load(Relaxed) == sequence).then(|| unsafe { value.assume_init() })
+    }
+
+    /// Updates the data, waking the readers blocked on the write
+    /// `update` runs on a copy of the data, which is then stored as a whole. If it panics the
+    /// data is left as it was and the lock is released for the next writer.
+    /// # Arguments
+    /// * `update` - Changes the data
+    pub fn write(&self, update: impl FnOnce(&mut T)) {
+        self.writer.lock();
+        let sequence = self.sequence.load(Relaxed);
+        self.sequence
+            .as_atomic()
+            .store(sequence.wrapping_add(1), Relaxed);
+        // Keeps the data stores after the odd sequence, smp_wmb() in seqlock.h
+        fence(Release);
+        let _end = EndWrite(self, sequence);
+        // The writer mutex is held, nobody else stores the data
+        let mut value = unsafe { ptr::read(self.data.get()) };
+        update(&mut value);
+        unsafe { ptr::write_volatile(self.data.get(), value) };
+    }
+
+    /// Returns the data, the exclusive borrow proves no write is in progress
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consumes the lock and returns its data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Copy + Default> Default for SharedSeqLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SharedSeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedSeqLock")
+            .field("data", &self.read())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[repr(C)]
+    struct Telemetry {
+        first: u64,
+        second: u64,
+        third: u64,
+        sum: u64,
+    }
+
+    impl Telemetry {
+        fn at(round: u64) -> Self {
+            Self {
+                first: round,
+                second: round * 2,
+                third: round * 3,
+                sum: round * 6,
+            }
+        }
+
+        fn is_consistent(&self) -> bool {
+            self.second == self.first * 2
+                && self.third == self.first * 3
+                && self.sum == self.first + self.second + self.third
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "races on the data by design, like every seqlock")]
+    fn test_readers_never_see_a_torn_value() {
+        let len = SharedSeqLock::<Telemetry>::required_size();
+        let segment = &ShmSegment::create("test_shared_seqlock", len);
+        unsafe {
+            (segment.as_ptr() as *mut SharedSeqLock<Telemetry>)
+                .write(SharedSeqLock::new(Telemetry::default()));
+        }
+        let lock = unsafe { segment.get::<SharedSeqLock<Telemetry>>() };
+        let done = AtomicBool::new(false);
+        let started = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            let readers: Vec<_> = (0..4)
+                .map(|reader| {
+                    let (done, started) = (&done, &started);
+                    s.spawn(move || {
+                        let mapping = segment.map_again();
+                        let lock = unsafe { mapping.get::<SharedSeqLock<Telemetry>>() };
+                        started.fetch_add(1, SeqCst);
+                        let mut last = 0;
+                        let mut reads = 0u64;
+                        while !done.load(SeqCst) {
+                            let value = if reader % 2 == 0 {
+                                lock.read()
+                            } else {
+                                lock.read_blocking()
+                            };
+                            assert!(value.is_consistent(), "torn read {value:?}");
+                            assert!(value.first >= last, "went back in time");
+                            last = value.first;
+                            reads += 1;
+                        }
+                        reads
+                    })
+                })
+                .collect();
+            while started.load(SeqCst) != 4 {
+                thread::yield_now();
+            }
+            for round in 1..=20_000 {
+                lock.write(|value| *value = Telemetry::at(round));
+            }
+            done.store(true, SeqCst);
+            for reader in readers {
+                assert!(reader.join().unwrap() > 0);
+            }
+        });
+
+        assert_eq!(lock.read(), Telemetry::at(20_000));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "races on the data by design, like every seqlock")]
+    fn test_read_blocking_sleeps_through_a_write() {
+        let lock = SharedSeqLock::new(Telemetry::at(1));
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                lock.write(|value| {
+                    // Descheduled mid update
+                    thread::sleep(Duration::from_millis(100));
+                    *value = Telemetry::at(2);
+                });
+            });
+            while lock.sequence.load(SeqCst) & 1 == 0 {
+                thread::yield_now();
+            }
+            let reader = s.spawn(|| lock.read_blocking());
+            while lock.waiters.load(SeqCst) == 0 {
+                thread::yield_now();
+            }
+            assert_eq!(reader.join().unwrap(), Telemetry::at(2));
+            writer.join().unwrap();
+        });
+        assert_eq!(lock.waiters.load(SeqCst), 0);
+        assert_eq!(lock.into_inner(), Telemetry::at(2));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "races on the data by design, like every seqlock")]
+    fn test_panicking_update_ends_the_write() {
+        let lock = SharedSeqLock::new(Telemetry::at(1));
+        thread::scope(|s| {
+            let reader = s.spawn(|| {
+                while lock.sequence.load(SeqCst) & 1 == 0 {
+                    thread::yield_now();
+                }
+                lock.read_blocking()
+            });
+            let writer = s.spawn(|| {
+                lock.write(|value| {
+                    value.first = 7;
+                    thread::sleep(Duration::from_millis(50));
+                    panic!("update failed");
+                })
+            });
+            assert!(writer.join().is_err());
+            assert_eq!(reader.join().unwrap(), Telemetry::at(1));
+        });
+        assert_eq!(lock.sequence.load(SeqCst), 2);
+        lock.write(|value| *value = Telemetry::at(2));
+        assert_eq!(lock.read(), Telemetry::at(2));
+    }
+}
@@ -0,0 +1,200 @@
+//! Lock statistics kept in the shared segment
+//!
+//! A futex built with [`crate::builder::SharedFutexBuilder::shared_stats`] has a layout header
+//! after its word, followed by a block of counters that every attached process updates with
+//! Relaxed atomic adds. A monitoring process samples them with [`read_shared_stats`] without
+//! taking part in the locking.
+//!
+//! Segment layout, 8 bytes aligned:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | futex word                                    |
+//! | 4      | 4    | layout header: magic, version, flags          |
+//! | 8      | 4    | current holder TID, 0 when unlocked           |
+//! | 12     | 4    | padding                                       |
+//! | 16     | 8    | acquisitions                                  |
+//! | 24     | 8    | contended acquisitions                        |
+//! | 32     | 8    | cumulative wait of the contended acquisitions |
+//!
+//! Without shared stats the segment stops after the header.
+
+use crate::sys;
+use libc::c_void;
+
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{
+    AtomicU32, AtomicU64,
+    Ordering::{Acquire, Relaxed},
+};
+use std::time::Duration;
+
+/// "RF" in the upper half of the header
+const LAYOUT_MAGIC: u32 = 0x5246_0000;
+const LAYOUT_MAGIC_MASK: u32 = 0xffff_0000;
+/// Bumped on every change of the segment layout
+pub(crate) const LAYOUT_VERSION: u32 = 1;
+const LAYOUT_VERSION_SHIFT: u32 = 8;
+const LAYOUT_VERSION_MASK: u32 = 0x0000_ff00;
+/// The segment carries a StatsBlock
+const LAYOUT_SHARED_STATS: u32 = 1;
+
+/// Offset of the layout header in the segment
+pub(crate) const HEADER_OFFSET: usize = 4;
+/// Offset of the StatsBlock in the segment
+pub(crate) const STATS_OFFSET: usize = 8;
+
+/// The counters shared by every process attached to the segment
+#[repr(C)]
+pub(crate) struct StatsBlock {
+    holder_tid: AtomicU32,
+    _pad: u32,
+    acquisitions: AtomicU64,
+    contentions: AtomicU64,
+    wait_ns: AtomicU64,
+}
+
+const _: () = assert!(mem::size_of::<StatsBlock>() == 32);
+
+impl StatsBlock {
+    /// Records an acquisition by the calling thread
+    /// # Arguments
+    /// * `waited` - The time spent in lock() if the lock was contended
+    pub(crate) fn acquired(&self, waited: Option<Duration>) {
+        self.acquisitions.fetch_add(1, Relaxed);
+        if let Some(waited) = waited {
+            let waited = u64::try_from(waited.as_nanos()).unwrap_or(u64::MAX);
+            self.contentions.fetch_add(1, Relaxed);
+            self.wait_ns.fetch_add(waited, Relaxed);
+        }
+        self.holder_tid.store(sys::gettid(), Relaxed);
+    }
+
+    /// Records that the lock is about to be released
+    pub(crate) fn releasing(&self) {
+        self.holder_tid.store(0, Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        self.holder_tid.store(0, Relaxed);
+        self.acquisitions.store(0, Relaxed);
+        self.contentions.store(0, Relaxed);
+        self.wait_ns.store(0, Relaxed);
+    }
+
+    fn snapshot(&self) -> SharedStats {
+        SharedStats {
+            acquisitions: self.acquisitions.load(Relaxed),
+            contentions: self.contentions.load(Relaxed),
+            wait_ns: self.wait_ns.load(Relaxed),
+            holder_tid: match self.holder_tid.load(Relaxed) {
+                0 => None,
+                tid => Some(tid),
+            },
+        }
+    }
+}
+
+/// A sample of the shared counters, see [`read_shared_stats`]
+/// Each counter only grows, but they are read one by one so a sample taken while other
+/// processes lock may mix counts from slightly different moments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SharedStats {
+    /// Locks taken by every process
+    pub acquisitions: u64,
+    /// Locks taken by lock() after finding the lock held
+    pub contentions: u64,
+    /// Nanoseconds spent in lock() by the contended acquisitions
+    pub wait_ns: u64,
+    /// Kernel thread id of the holder, None when unlocked
+    pub holder_tid: Option<u32>,
+}
+
+/// Error returned when a segment header does not match the expected layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The segment has no layout header, it was not initialized by a SharedFutexBuilder
+    NotInitialized,
+    /// The segment was laid out by another version of the crate
+    Version(u32),
+    /// The segment does not agree on the shared stats block
+    SharedStats { expected: bool, found: bool },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::NotInitialized => write!(f, "futex segment has no layout header"),
+            LayoutError::Version(version) => write!(
+                f,
+                "futex segment layout version {} is not {}",
+                version, LAYOUT_VERSION
+            ),
+            LayoutError::SharedStats { expected, found } => write!(
+                f,
+                "futex segment shared stats are {} but expected {}",
+                enabled(*found),
+                enabled(*expected)
+            ),
+        }
+    }
+}
+
+impl Error for LayoutError {}
+
+fn enabled(value: bool) -> &'static str {
+    if value {
+        "enabled"
+    } else {
+        "disabled"
+    }
+}
+
+/// Encodes the layout header of a segment
+pub(crate) fn layout_header(shared_stats: bool) -> u32 {
+    let flags = if shared_stats { LAYOUT_SHARED_STATS } else { 0 };
+    LAYOUT_MAGIC | (LAYOUT_VERSION << LAYOUT_VERSION_SHIFT) | flags
+}
+
+/// Decodes a layout header
+/// # Returns
+/// Whether the segment carries shared stats, or the LayoutError
+pub(crate) fn check_layout_header(header: u32) -> Result<bool, LayoutError> {
+    if header & LAYOUT_MAGIC_MASK != LAYOUT_MAGIC {
+        return Err(LayoutError::NotInitialized);
+    }
+    let version = (header & LAYOUT_VERSION_MASK) >> LAYOUT_VERSION_SHIFT;
+    if version != LAYOUT_VERSION {
+        return Err(LayoutError::Version(version));
+    }
+    Ok(header & LAYOUT_SHARED_STATS != 0)
+}
+
+/// Returns the layout header word of a segment
+pub(crate) fn header_word(segment: *const c_void) -> *const AtomicU32 {
+    unsafe { (segment as *const u8).add(HEADER_OFFSET) as *const AtomicU32 }
+}
+
+/// Returns the StatsBlock of a segment
+pub(crate) fn stats_block(segment: *const c_void) -> *const StatsBlock {
+    unsafe { (segment as *const u8).add(STATS_OFFSET) as *const StatsBlock }
+}
+
+/// Samples the shared counters of a futex segment
+/// The monitor only reads the segment, it never touches the futex word.
+/// # Arguments
+/// * `segment` - A pointer to the futex word of a segment built with shared stats
+/// # Returns
+/// The counters, or a LayoutError if the segment has no shared stats
+pub fn read_shared_stats(segment: *const c_void) -> Result<SharedStats, LayoutError> {
+    let found = check_layout_header(unsafe { (*header_word(segment)).load(Acquire) })?;
+    if !found {
+        return Err(LayoutError::SharedStats {
+            expected: true,
+            found,
+        });
+    }
+    Ok(unsafe { (*stats_block(segment)).snapshot() })
+}
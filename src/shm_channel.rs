@@ -0,0 +1,502 @@
+//! Bounded multi-producer multi-consumer channel in shared memory
+//!
+//! The region holds a header followed by a ring of slots. Each slot carries a sequence number,
+//! as in Dmitry Vyukov's bounded MPMC queue: a sender claims the enqueue position with a CAS,
+//! writes the value and publishes it by bumping the slot sequence; a receiver does the same on
+//! the dequeue position. Senders and receivers never take a lock.
+//!
+//! Blocking uses two futex words, one signalled when a value is sent and one when a slot is
+//! freed. Each is an event count: the lowest bit tells that someone sleeps on the word, the
+//! upper bits change on every signal so a sleeper never misses one. A signal with nobody asleep
+//! costs a single load.
+
+use crate::deadline::Deadline;
+use crate::futex_word::FutexWord;
+use libc::c_void;
+
+use std::cell::UnsafeCell;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{
+    fence, AtomicU64,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+use std::time::Duration;
+
+/// Bit 0 of an event word: a thread sleeps on the word
+const WAITERS: u32 = 1;
+
+#[repr(C)]
+struct Header {
+    capacity: u64,
+    enqueue: AtomicU64,
+    dequeue: AtomicU64,
+    not_empty: FutexWord,
+    not_full: FutexWord,
+}
+
+#[repr(C)]
+struct Slot<T> {
+    sequence: AtomicU64,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Error returned when the channel has no free slot, it gives the value back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelFullError<T>(pub T);
+
+impl<T> fmt::Display for ChannelFullError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "shared channel is full")
+    }
+}
+
+impl<T: fmt::Debug> Error for ChannelFullError<T> {}
+
+/// The state shared by the senders and receivers of a process
+struct Channel<T> {
+    channel: *mut c_void,
+    header: *const Header,
+    slots: *const Slot<T>,
+    _value: PhantomData<T>,
+}
+
+/// The sending side of a [`shm_channel`]
+/// It can be cloned, and any number of processes may send on the same region.
+pub struct Sender<T: Copy> {
+    channel: Channel<T>,
+}
+
+/// The receiving side of a [`shm_channel`]
+/// It can be cloned, and any number of processes may receive from the same region.
+pub struct Receiver<T: Copy> {
+    channel: Channel<T>,
+}
+
+unsafe impl<T: Copy + Send> Send for Sender<T> {}
+unsafe impl<T: Copy + Send> Sync for Sender<T> {}
+unsafe impl<T: Copy + Send> Send for Receiver<T> {}
+unsafe impl<T: Copy + Send> Sync for Receiver<T> {}
+
+/// Returns the number of bytes needed for a channel of `capacity` values of type T
+pub fn memory_requirements<T: Copy>(capacity: usize) -> usize {
+    Channel::<T>::slots_offset() + capacity * mem::size_of::<Slot<T>>()
+}
+
+/// Lays out an empty channel in the memory
+/// Only one process creates the channel, the others attach to it with [`Sender::attach`] and
+/// [`Receiver::attach`]. Values are plain `Copy` data since they cross process boundaries and
+/// are never dropped.
+/// # Arguments
+/// * `channel` - A pointer to memory_requirements(capacity) bytes, 8 bytes aligned and aligned
+///   for T
+/// * `capacity` - The number of values the channel holds, at least 1
+/// # Returns
+/// A Sender and a Receiver on the channel
+pub fn shm_channel<T: Copy>(channel: *mut c_void, capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "shared channel capacity is 0");
+    let shared = Channel::<T>::from_ptr(channel);
+    unsafe {
+        (shared.header as *mut Header).write(Header {
+            capacity: capacity as u64,
+            enqueue: AtomicU64::new(0),
+            dequeue: AtomicU64::new(0),
+            not_empty: FutexWord::new(0),
+            not_full: FutexWord::new(0),
+        });
+        for index in 0..capacity {
+            let slot = shared.slots.add(index) as *mut Slot<T>;
+            ptr::addr_of_mut!((*slot).sequence).write(AtomicU64::new(index as u64));
+        }
+    }
+    (Sender::attach(channel), Receiver::attach(channel))
+}
+
+impl<T: Copy> Channel<T> {
+    fn from_ptr(channel: *mut c_void) -> Self {
+        assert!(
+            (channel as usize)
+                .is_multiple_of(mem::align_of::<Header>().max(mem::align_of::<Slot<T>>())),
+            "channel memory is not aligned for its header and slots"
+        );
+        Self {
+            channel,
+            header: channel as *const Header,
+            slots: unsafe { (channel as *const u8).add(Self::slots_offset()) } as *const Slot<T>,
+            _value: PhantomData,
+        }
+    }
+
+    /// The slots follow the header, aligned for a slot
+    fn slots_offset() -> usize {
+        mem::size_of::<Header>().next_multiple_of(mem::align_of::<Slot<T>>())
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    fn capacity(&self) -> u64 {
+        self.header().capacity
+    }
+
+    fn slot(&self, position: u64) -> &Slot<T> {
+        unsafe { &*self.slots.add((position % self.capacity()) as usize) }
+    }
+
+    fn try_send(&self, value: T) -> Result<(), ChannelFullError<T>> {
+        let header = self.header();
+        let mut position = header.enqueue.load(Relaxed);
+        loop {
+            let slot = self.slot(position);
+            let sequence = slot.sequence.load(Acquire);
+            match sequence.wrapping_sub(position) as i64 {
+                0 => match header.enqueue.compare_exchange_weak(
+                    position,
+                    position + 1,
+                    Relaxed,
+                    Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(position + 1, Release);
+                        signal(&header.not_empty);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                },
+                // The slot still holds the value sent one lap ago
+                distance if distance < 0 => return Err(ChannelFullError(value)),
+                _ => position = header.enqueue.load(Relaxed),
+            }
+        }
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        let header = self.header();
+        let mut position = header.dequeue.load(Relaxed);
+        loop {
+            let slot = self.slot(position);
+            let sequence = slot.sequence.load(Acquire);
+            match sequence.wrapping_sub(position + 1) as i64 {
+                0 => match header.dequeue.compare_exchange_weak(
+                    position,
+                    position + 1,
+                    Relaxed,
+                    Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init() };
+                        slot.sequence.store(position + self.capacity(), Release);
+                        signal(&header.not_full);
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                },
+                // Nothing was sent in the slot yet
+                distance if distance < 0 => return None,
+                _ => position = header.dequeue.load(Relaxed),
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        let header = self.header();
+        let dequeue = header.dequeue.load(SeqCst);
+        let enqueue = header.enqueue.load(SeqCst);
+        enqueue.saturating_sub(dequeue).min(self.capacity()) as usize
+    }
+}
+
+impl<T: Copy> Clone for Channel<T> {
+    fn clone(&self) -> Self {
+        Self::from_ptr(self.channel)
+    }
+}
+
+impl<T: Copy> Sender<T> {
+    /// Sends on a channel created by [`shm_channel`], possibly in another process
+    /// # Arguments
+    /// * `channel` - A pointer to the start of the region
+    /// # Returns
+    /// A Sender on the channel
+    pub fn attach(channel: *mut c_void) -> Self {
+        Self {
+            channel: Channel::from_ptr(channel),
+        }
+    }
+
+    /// Sends a value, sleeping while the channel is full
+    /// # Arguments
+    /// * `value` - The value to send
+    pub fn send(&self, value: T) {
+        let sent = self.send_until(value, Deadline::never());
+        debug_assert!(sent.is_ok());
+    }
+
+    /// Sends a value if a slot is free
+    /// # Arguments
+    /// * `value` - The value to send
+    /// # Returns
+    /// Ok, or ChannelFullError with the value if the channel is full
+    pub fn try_send(&self, value: T) -> Result<(), ChannelFullError<T>> {
+        self.channel.try_send(value)
+    }
+
+    /// Sends a value, sleeping at most `timeout` while the channel is full
+    /// # Arguments
+    /// * `value` - The value to send
+    /// * `timeout` - The maximum time to wait for a free slot
+    /// # Returns
+    /// Ok, or ChannelFullError with the value if the timeout expired
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), ChannelFullError<T>> {
+        self.send_until(value, Deadline::after(timeout))
+    }
+
+    /// Returns the number of values in the channel
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    /// Returns true if the channel holds no value
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of values the channel holds
+    pub fn capacity(&self) -> usize {
+        self.channel.capacity() as usize
+    }
+
+    fn send_until(&self, value: T, deadline: Deadline) -> Result<(), ChannelFullError<T>> {
+        let mut pending = Some(value);
+        let sent = block_on(&self.channel.header().not_full, deadline, || {
+            match self.channel.try_send(pending.take()?) {
+                Ok(()) => Some(()),
+                Err(ChannelFullError(value)) => {
+                    pending = Some(value);
+                    None
+                }
+            }
+        });
+        match (sent, pending) {
+            (Some(()), _) => Ok(()),
+            (None, Some(value)) => Err(ChannelFullError(value)),
+            (None, None) => unreachable!("the value was neither sent nor kept"),
+        }
+    }
+}
+
+impl<T: Copy> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T: Copy> Receiver<T> {
+    /// Receives from a channel created by [`shm_channel`], possibly in another process
+    /// # Arguments
+    /// * `channel` - A pointer to the start of the region
+    /// # Returns
+    /// A Receiver on the channel
+    pub fn attach(channel: *mut c_void) -> Self {
+        Self {
+            channel: Channel::from_ptr(channel),
+        }
+    }
+
+    /// Receives a value, sleeping while the channel is empty
+    /// # Returns
+    /// The oldest value of the channel
+    pub fn recv(&self) -> T {
+        block_on(&self.channel.header().not_empty, Deadline::never(), || {
+            self.channel.try_recv()
+        })
+        .expect("recv without deadline returned nothing")
+    }
+
+    /// Receives a value if there is one
+    /// # Returns
+    /// The oldest value of the channel, or None if it is empty
+    pub fn try_recv(&self) -> Option<T> {
+        self.channel.try_recv()
+    }
+
+    /// Receives a value, sleeping at most `timeout` while the channel is empty
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait for a value
+    /// # Returns
+    /// The oldest value of the channel, or None if the timeout expired
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+        block_on(
+            &self.channel.header().not_empty,
+            Deadline::after(timeout),
+            || self.channel.try_recv(),
+        )
+    }
+
+    /// Returns the number of values in the channel
+    pub fn len(&self) -> usize {
+        self.channel.len()
+    }
+
+    /// Returns true if the channel holds no value
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of values the channel holds
+    pub fn capacity(&self) -> usize {
+        self.channel.capacity() as usize
+    }
+}
+
+impl<T: Copy> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+/// Retries `attempt` until it succeeds, sleeping on `event` in between
+/// # Returns
+/// The result of the attempt, or None if the deadline passed
+fn block_on<R>(
+    event: &FutexWord,
+    deadline: Deadline,
+    mut attempt: impl FnMut() -> Option<R>,
+) -> Option<R> {
+    loop {
+        if let Some(done) = attempt() {
+            return Some(done);
+        }
+        // Announce the sleep before the last attempt, a signal after it changes the word
+        let key = event.as_atomic().fetch_or(WAITERS, SeqCst) | WAITERS;
+        fence(SeqCst);
+        if let Some(done) = attempt() {
+            return Some(done);
+        }
+        if !deadline.wait(&event.as_futex(), key) {
+            return None;
+        }
+    }
+}
+
+/// Starts a new generation of `event` and wakes its sleepers, if there are any
+fn signal(event: &FutexWord) {
+    fence(SeqCst);
+    if event.load(Relaxed) & WAITERS == 0 {
+        return;
+    }
+    // Adding 1 to the waiters bit carries into the generation and clears the bit
+    let woke = event
+        .as_atomic()
+        .fetch_update(SeqCst, Relaxed, |value| {
+            (value & WAITERS != 0).then(|| value.wrapping_add(1))
+        })
+        .is_ok();
+    if woke {
+        // Every sleeper rechecks, the bit is gone so a single wake could strand the others
+        let _ = event.as_futex().post(i32::MAX as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn test_try_send_recv() {
+        let mut memory = vec![0u64; memory_requirements::<u32>(2).div_ceil(8)];
+        let (tx, rx) = shm_channel::<u32>(memory.as_mut_ptr() as *mut c_void, 2);
+        assert_eq!(tx.capacity(), 2);
+        assert!(rx.is_empty());
+        assert_eq!(rx.try_recv(), None);
+
+        assert_eq!(tx.try_send(1), Ok(()));
+        assert_eq!(tx.try_send(2), Ok(()));
+        assert_eq!(tx.try_send(3), Err(ChannelFullError(3)));
+        assert_eq!(
+            tx.send_timeout(3, Duration::from_millis(50)),
+            Err(ChannelFullError(3))
+        );
+        assert_eq!(rx.len(), 2);
+
+        assert_eq!(rx.recv(), 1);
+        assert_eq!(tx.try_send(3), Ok(()));
+        assert_eq!(rx.try_recv(), Some(2));
+        assert_eq!(rx.recv_timeout(Duration::from_millis(50)), Some(3));
+        assert_eq!(rx.recv_timeout(Duration::from_millis(50)), None);
+    }
+
+    #[test]
+    fn test_stress_mpmc() {
+        const PRODUCERS: u64 = 4;
+        const CONSUMERS: usize = 4;
+        const ITEMS: u64 = 250_000;
+        let mut memory = vec![0u64; memory_requirements::<u64>(64).div_ceil(8)];
+        let (tx, rx) = shm_channel::<u64>(memory.as_mut_ptr() as *mut c_void, 64);
+        let received = Mutex::new(Vec::new());
+
+        thread::scope(|s| {
+            for producer in 0..PRODUCERS {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for item in 0..ITEMS {
+                        tx.send((producer << 32) | item);
+                    }
+                });
+            }
+            for _ in 0..CONSUMERS {
+                let rx = rx.clone();
+                let received = &received;
+                s.spawn(move || {
+                    let share = (PRODUCERS * ITEMS) as usize / CONSUMERS;
+                    let values: Vec<u64> = (0..share).map(|_| rx.recv()).collect();
+                    received.lock().unwrap().extend(values);
+                });
+            }
+        });
+
+        let mut received = received.into_inner().unwrap();
+        received.sort_unstable();
+        let sent: Vec<u64> = (0..PRODUCERS)
+            .flat_map(|producer| (0..ITEMS).map(move |item| (producer << 32) | item))
+            .collect();
+        assert_eq!(received, sent);
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_across_mappings() {
+        const ITEMS: u32 = 10_000;
+        let len = memory_requirements::<u32>(8);
+        let segment = &ShmSegment::create("test_shm_channel", len);
+        let (_tx, rx) = shm_channel::<u32>(segment.as_ptr(), 8);
+
+        thread::scope(|s| {
+            for producer in 0..2 {
+                s.spawn(move || {
+                    let mapping = segment.map_again();
+                    let tx = Sender::<u32>::attach(mapping.as_ptr());
+                    for item in 0..ITEMS {
+                        tx.send(producer * ITEMS + item);
+                    }
+                });
+            }
+            let mut received: Vec<u32> = (0..2 * ITEMS).map(|_| rx.recv()).collect();
+            received.sort_unstable();
+            assert!(received.into_iter().eq(0..2 * ITEMS));
+        });
+    }
+}
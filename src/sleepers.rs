@@ -0,0 +1,43 @@
+//! Counting sleepers so a wake up without them makes no system call
+//!
+//! A sleeper increments the counter, then sleeps in FUTEX_WAIT on the value it last saw, then
+//! decrements the counter. A waker changes the futex word first and wakes only if the counter
+//! is not 0. Every access is SeqCst, so the two orders are total: either the waker loads the
+//! counter after the increment and wakes, or the sleeper registered after the change and its
+//! FUTEX_WAIT, or its load of the word, finds the new value and does not sleep.
+
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+/// Runs `sleep` counted among the sleepers of `sleepers`
+/// # Arguments
+/// * `sleepers` - The counter of the sleepers
+/// * `sleep` - Loads the futex word if needed and sleeps on it
+/// # Returns
+/// The result of `sleep`
+pub(crate) fn sleep<R>(sleepers: &AtomicU32, sleep: impl FnOnce() -> R) -> R {
+    register(sleepers);
+    let ret = sleep();
+    unregister(sleepers);
+    ret
+}
+
+/// Counts a sleeper whose sleep outlives a call, as a future does, until [`unregister`]
+/// # Arguments
+/// * `sleepers` - The counter of the sleepers
+pub(crate) fn register(sleepers: &AtomicU32) {
+    sleepers.fetch_add(1, SeqCst);
+}
+
+/// Stops counting a sleeper counted by [`register`]
+/// # Arguments
+/// * `sleepers` - The counter of the sleepers
+pub(crate) fn unregister(sleepers: &AtomicU32) {
+    sleepers.fetch_sub(1, SeqCst);
+}
+
+/// Returns true if a waker that just changed the futex word has to wake someone
+/// # Arguments
+/// * `sleepers` - The counter of the sleepers
+pub(crate) fn any(sleepers: &AtomicU32) -> bool {
+    sleepers.load(SeqCst) != 0
+}
@@ -0,0 +1,187 @@
+//! Mutex spinning with exponential backoff, then yielding, before sleeping in the kernel
+//!
+//! Short critical sections are often released within a few hundred cycles, much less than a
+//! FUTEX_WAIT round trip. SpinWaitFutex follows the SpinWait pattern of .NET: spin with
+//! exponentially growing pauses, then yield the CPU a few times, and only then fall back to the
+//! futex protocol of [`SharedFutex::lock`].
+
+use crate::rufutex::SharedFutex;
+use crate::UNLOCKED;
+
+use std::hint;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
+
+/// Longest spin round is 2^MAX_BACKOFF_SHIFT pauses
+const MAX_BACKOFF_SHIFT: u32 = 10;
+
+/// How long a SpinWaitFutex tries before sleeping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinWaitConfig {
+    /// Number of spin rounds, round `i` pauses 2^i times
+    pub spin_count: u32,
+    /// Number of `thread::yield_now` calls after spinning
+    pub yield_count: u32,
+}
+
+impl Default for SpinWaitConfig {
+    fn default() -> Self {
+        Self {
+            spin_count: 10,
+            yield_count: 5,
+        }
+    }
+}
+
+/// A SharedFutex mutex that spins and yields before sleeping
+/// The word protocol is the one of SharedFutex, so both can be used on the same word.
+pub struct SpinWaitFutex {
+    futex: SharedFutex,
+    config: SpinWaitConfig,
+}
+
+impl SpinWaitFutex {
+    /// Create a new SpinWaitFutex with the default configuration
+    /// # Arguments
+    /// * `futex` - The SharedFutex to lock
+    /// # Returns
+    /// A new SpinWaitFutex
+    pub fn new(futex: SharedFutex) -> Self {
+        Self::with_config(futex, SpinWaitConfig::default())
+    }
+
+    /// Create a new SpinWaitFutex
+    /// # Arguments
+    /// * `futex` - The SharedFutex to lock
+    /// * `config` - The spin and yield counts
+    /// # Returns
+    /// A new SpinWaitFutex
+    pub fn with_config(futex: SharedFutex, config: SpinWaitConfig) -> Self {
+        Self { futex, config }
+    }
+
+    /// Returns the spin and yield counts
+    pub fn config(&self) -> SpinWaitConfig {
+        self.config
+    }
+
+    /// Returns the wrapped SharedFutex
+    pub fn futex(&self) -> &SharedFutex {
+        &self.futex
+    }
+
+    /// Lock the futex
+    /// The word is only read while spinning and yielding, the CAS is attempted once it looks
+    /// unlocked, which keeps the cache line shared between the contending cores.
+    pub fn lock(&self) {
+        if self.futex.try_lock() {
+            return;
+        }
+        for round in 0..self.config.spin_count {
+            for _ in 0..1u32 << round.min(MAX_BACKOFF_SHIFT) {
+                hint::spin_loop();
+            }
+            if self.try_lock_unlocked() {
+                return;
+            }
+        }
+        for _ in 0..self.config.yield_count {
+            thread::yield_now();
+            if self.try_lock_unlocked() {
+                return;
+            }
+        }
+        self.futex.lock();
+    }
+
+    /// Try to lock the futex without blocking
+    /// # Returns
+    /// true if the lock was acquired
+    #[must_use = "if false the lock was not acquired"]
+    pub fn try_lock(&self) -> bool {
+        self.futex.try_lock()
+    }
+
+    /// Unlock the futex, waking one waiter
+    pub fn unlock(&self) {
+        self.futex.unlock();
+    }
+
+    /// Unlock the futex and wake up to `how_may_waiters` waiters
+    /// # Arguments
+    /// * `how_may_waiters` - The number of waiters to wake up
+    /// # Returns
+    /// The number of waiters woken
+    pub fn unlock_many(&self, how_may_waiters: u32) -> usize {
+        self.futex.unlock_many(how_may_waiters)
+    }
+
+    fn try_lock_unlocked(&self) -> bool {
+        self.futex.get_futex_value_with_ordering(Relaxed) == UNLOCKED && self.futex.try_lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+
+    fn contend(name: &str, config: SpinWaitConfig) {
+        let segment = ShmSegment::create(name, 8);
+        let futex = SharedFutex::new(segment.as_ptr());
+        futex.set_futex_value(UNLOCKED);
+        let counter = unsafe { (futex.futex as *mut u32).add(1) } as usize;
+        let lock = SpinWaitFutex::with_config(futex, config);
+        assert_eq!(lock.config(), config);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let counter = counter as *mut u32;
+                    for _ in 0..1000 {
+                        lock.lock();
+                        unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                        lock.unlock();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(unsafe { *(counter as *const u32) }, 4000);
+        assert_eq!(lock.futex().get_futex_value(), UNLOCKED);
+    }
+
+    #[test]
+    fn test_spin_wait_default_config() {
+        assert_eq!(
+            SpinWaitConfig::default(),
+            SpinWaitConfig {
+                spin_count: 10,
+                yield_count: 5
+            }
+        );
+        contend("test_spin_wait_default", SpinWaitConfig::default());
+    }
+
+    #[test]
+    fn test_spin_wait_no_spinning() {
+        contend(
+            "test_spin_wait_no_spin",
+            SpinWaitConfig {
+                spin_count: 0,
+                yield_count: 0,
+            },
+        );
+    }
+
+    #[test]
+    fn test_spin_wait_try_lock() {
+        let mut word = 0u32;
+        let lock = SpinWaitFutex::new(SharedFutex::new(&mut word as *mut u32 as *mut _));
+        assert!(lock.try_lock());
+        assert!(!lock.try_lock());
+        lock.unlock();
+        assert!(lock.try_lock());
+        lock.unlock();
+    }
+}
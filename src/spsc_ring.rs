@@ -0,0 +1,332 @@
+//! Single-producer single-consumer byte ring in shared memory
+//!
+//! The header holds the write position, the read position and a word of waiting flags, followed
+//! by `capacity` bytes of data. Positions run freely and wrap at 2^32, so their difference is
+//! the number of buffered bytes whether the ring is empty or full; the capacity is a power of
+//! two, at most 2^31, so a position maps to a byte offset with a mask.
+//!
+//! A reader finding the ring empty sets its waiting flag and sleeps on the write position, a
+//! writer finding it full does the same on the read position. The peer only calls FUTEX_WAKE
+//! when it sees the flag, so a transfer without blocking makes no system call.
+
+use crate::futex_word::FutexWord;
+use libc::c_void;
+
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{
+    fence,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+/// The reader sleeps on the write position
+const READER_WAITING: u32 = 1;
+/// The writer sleeps on the read position
+const WRITER_WAITING: u32 = 2;
+
+#[repr(C)]
+struct Header {
+    head: FutexWord,
+    tail: FutexWord,
+    waiting: FutexWord,
+    capacity: u32,
+}
+
+const _: () = assert!(mem::size_of::<Header>() == 16);
+
+/// A byte ring between one writer and one reader
+/// Each process, or thread, maps the ring on its own. At any time at most one of them writes and
+/// at most one reads; the ring does not check it.
+pub struct SpscRing {
+    pub ring: *mut c_void,
+    header: *const Header,
+    data: *mut u8,
+}
+
+unsafe impl Send for SpscRing {}
+unsafe impl Sync for SpscRing {}
+
+impl SpscRing {
+    /// Returns the number of bytes needed for a ring of `capacity` bytes
+    pub fn memory_requirements(capacity: usize) -> usize {
+        mem::size_of::<Header>() + capacity
+    }
+
+    /// Lays out an empty ring in the memory
+    /// Only one process creates the ring, the other [`SpscRing::attach`]es to it.
+    /// # Arguments
+    /// * `ring` - A pointer to memory_requirements(capacity) bytes, 4 bytes aligned
+    /// * `capacity` - The size of the ring in bytes, a power of two up to 2^31
+    /// # Returns
+    /// A new SpscRing
+    pub fn create(ring: *mut c_void, capacity: usize) -> Self {
+        assert!(
+            capacity.is_power_of_two() && capacity <= 1 << 31,
+            "ring capacity is not a power of two up to 2^31"
+        );
+        unsafe {
+            (ring as *mut Header).write(Header {
+                head: FutexWord::new(0),
+                tail: FutexWord::new(0),
+                waiting: FutexWord::new(0),
+                capacity: capacity as u32,
+            });
+        }
+        Self::attach(ring)
+    }
+
+    /// Uses a ring created by [`SpscRing::create`], possibly in another process
+    /// # Arguments
+    /// * `ring` - A pointer to the start of the ring
+    /// # Returns
+    /// A SpscRing over the existing data
+    pub fn attach(ring: *mut c_void) -> Self {
+        assert!(
+            (ring as usize).is_multiple_of(mem::align_of::<Header>()),
+            "ring memory is not 4 bytes aligned"
+        );
+        Self {
+            ring,
+            header: ring as *const Header,
+            data: unsafe { (ring as *mut u8).add(mem::size_of::<Header>()) },
+        }
+    }
+
+    /// Returns the size of the ring in bytes
+    pub fn capacity(&self) -> usize {
+        self.header().capacity as usize
+    }
+
+    /// Returns the number of buffered bytes
+    pub fn len(&self) -> usize {
+        let header = self.header();
+        let tail = header.tail.load(Acquire);
+        header.head.load(Acquire).wrapping_sub(tail) as usize
+    }
+
+    /// Returns true if no byte is buffered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies as many bytes of `data` as fit in the ring, without blocking
+    /// Only the writer calls it.
+    /// # Arguments
+    /// * `data` - The bytes to write
+    /// # Returns
+    /// The number of bytes written, 0 if the ring is full
+    pub fn write(&self, data: &[u8]) -> usize {
+        let header = self.header();
+        let head = header.head.load(Relaxed);
+        // Acquire: the reader is done with the bytes it freed
+        let free = self.capacity() - head.wrapping_sub(header.tail.load(Acquire)) as usize;
+        let len = data.len().min(free);
+        if len == 0 {
+            return 0;
+        }
+        let offset = self.offset(head);
+        let first = len.min(self.capacity() - offset);
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.data.add(offset), first);
+            ptr::copy_nonoverlapping(data.as_ptr().add(first), self.data, len - first);
+        }
+        header
+            .head
+            .as_atomic()
+            .store(head.wrapping_add(len as u32), Release);
+        self.wake(READER_WAITING, &header.head);
+        len
+    }
+
+    /// Copies as many buffered bytes as fit in `buf`, without blocking
+    /// Only the reader calls it.
+    /// # Arguments
+    /// * `buf` - The buffer to fill
+    /// # Returns
+    /// The number of bytes read, 0 if the ring is empty
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        let header = self.header();
+        let tail = header.tail.load(Relaxed);
+        // Acquire: the bytes the writer published are visible
+        let used = header.head.load(Acquire).wrapping_sub(tail) as usize;
+        let len = buf.len().min(used);
+        if len == 0 {
+            return 0;
+        }
+        let offset = self.offset(tail);
+        let first = len.min(self.capacity() - offset);
+        unsafe {
+            ptr::copy_nonoverlapping(self.data.add(offset), buf.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(self.data, buf.as_mut_ptr().add(first), len - first);
+        }
+        header
+            .tail
+            .as_atomic()
+            .store(tail.wrapping_add(len as u32), Release);
+        self.wake(WRITER_WAITING, &header.tail);
+        len
+    }
+
+    /// Writes every byte of `data`, sleeping while the ring is full
+    /// Only the writer calls it.
+    /// # Arguments
+    /// * `data` - The bytes to write
+    pub fn write_all(&self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let written = self.write(data);
+            data = &data[written..];
+            if written == 0 {
+                let tail = self.header().tail.load(Relaxed);
+                self.sleep(WRITER_WAITING, &self.header().tail, tail, || {
+                    self.len() == self.capacity()
+                });
+            }
+        }
+    }
+
+    /// Fills `buf`, sleeping while the ring is empty
+    /// Only the reader calls it.
+    /// # Arguments
+    /// * `buf` - The buffer to fill
+    pub fn read_exact(&self, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            let read = self.read(buf);
+            buf = &mut buf[read..];
+            if read == 0 {
+                let head = self.header().head.load(Relaxed);
+                self.sleep(READER_WAITING, &self.header().head, head, || {
+                    self.is_empty()
+                });
+            }
+        }
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { &*self.header }
+    }
+
+    fn offset(&self, position: u32) -> usize {
+        position as usize & (self.capacity() - 1)
+    }
+
+    /// Sleeps on `word` while it holds `value` and the ring is still `blocked`
+    fn sleep(&self, flag: u32, word: &FutexWord, value: u32, blocked: impl Fn() -> bool) {
+        self.header().waiting.as_atomic().fetch_or(flag, SeqCst);
+        // The peer moving its position now either sees the flag or is seen by the check
+        fence(SeqCst);
+        if blocked() {
+            let _ = word.as_futex().wait(value);
+        }
+    }
+
+    /// Wakes the peer if it announced that it sleeps on `word`
+    fn wake(&self, flag: u32, word: &FutexWord) {
+        fence(SeqCst);
+        let waiting = self.header().waiting.as_atomic();
+        if waiting.load(Relaxed) & flag != 0 && waiting.fetch_and(!flag, SeqCst) & flag != 0 {
+            let _ = word.as_futex().post(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    use std::sync::atomic::{AtomicBool, AtomicU64};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// xorshift64, enough for a reproducible stream
+    fn stream(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn checksum(bytes: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(bytes);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let mut memory = vec![0u32; SpscRing::memory_requirements(8).div_ceil(4)];
+        let ring = SpscRing::create(memory.as_mut_ptr() as *mut c_void, 8);
+        let mut buf = [0u8; 8];
+        assert!(ring.is_empty());
+        assert_eq!(ring.read(&mut buf), 0);
+
+        assert_eq!(ring.write(b"abcdef"), 6);
+        assert_eq!(ring.read(&mut buf[..4]), 4);
+        assert_eq!(&buf[..4], b"abcd");
+        // Crosses the end of the data
+        assert_eq!(ring.write(b"ghijklmn"), 6);
+        assert_eq!(ring.len(), 8);
+        assert_eq!(ring.write(b"x"), 0);
+        assert_eq!(ring.read(&mut buf), 8);
+        assert_eq!(&buf, b"efghijkl");
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_stream_through_small_ring() {
+        const LEN: usize = 1 << 22;
+        let len = SpscRing::memory_requirements(64);
+        let segment = ShmSegment::create("test_spsc_ring", len);
+        let ring = SpscRing::create(segment.as_ptr(), 64);
+        let sent = stream(0x9e37_79b9_7f4a_7c15, LEN);
+        let received_checksum = AtomicU64::new(0);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mapping = segment.map_again();
+                let ring = SpscRing::attach(mapping.as_ptr());
+                let mut received = vec![0u8; LEN];
+                for chunk in received.chunks_mut(37) {
+                    ring.read_exact(chunk);
+                }
+                received_checksum.store(checksum(&received), SeqCst);
+            });
+            for chunk in sent.chunks(101) {
+                ring.write_all(chunk);
+            }
+        });
+
+        assert_eq!(received_checksum.load(SeqCst), checksum(&sent));
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn test_blocked_reader_wakes() {
+        let mut memory = vec![0u32; SpscRing::memory_requirements(16).div_ceil(4)];
+        let ring = SpscRing::create(memory.as_mut_ptr() as *mut c_void, 16);
+        let done = AtomicBool::new(false);
+
+        thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let mut buf = [0u8; 4];
+                ring.read_exact(&mut buf);
+                done.store(true, SeqCst);
+                (buf, Instant::now())
+            });
+            thread::sleep(Duration::from_millis(100));
+            assert!(!done.load(SeqCst));
+            let written = Instant::now();
+            ring.write_all(b"ping");
+            let (buf, woke) = reader.join().unwrap();
+            assert_eq!(&buf, b"ping");
+            assert!(woke.duration_since(written) < Duration::from_millis(100));
+        });
+    }
+}
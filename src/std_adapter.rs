@@ -0,0 +1,185 @@
+//! Adapter exposing a SharedFutex through a `std::sync::Mutex<()>` shaped API
+//!
+//! Projects moving from `std::sync::Mutex<()>` to a SharedFutex can swap the type and keep the
+//! `lock()`/`try_lock()` call sites. With the `lock_api` feature the adapter also implements
+//! `lock_api::RawMutex`, so it plugs into `lock_api::Mutex<R, T>` and generic parking_lot style
+//! code.
+
+use crate::rufutex::SharedFutex;
+
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::{TryLockError, TryLockResult};
+
+/// A SharedFutex locked through the API of `std::sync::Mutex<()>`
+/// It only owns the SharedFutex, the lock lives in the futex word, so adapters in different
+/// processes over the same word exclude each other. Like the mutex it has no owner tracking:
+/// a process dying with the lock held leaves it held, and the lock is never poisoned.
+pub struct StdMutexAdapter {
+    futex: SharedFutex,
+}
+
+/// RAII guard returned by [`StdMutexAdapter::lock`], unlocks the futex when dropped
+pub struct MutexGuard<'a> {
+    adapter: &'a StdMutexAdapter,
+    // Like std's MutexGuard, the guard must not be sent to another thread
+    _not_send: PhantomData<*const ()>,
+}
+
+impl StdMutexAdapter {
+    /// Create a new StdMutexAdapter
+    /// # Arguments
+    /// * `futex` - The SharedFutex to adapt
+    /// # Returns
+    /// A new StdMutexAdapter
+    pub const fn new(futex: SharedFutex) -> Self {
+        Self { futex }
+    }
+
+    /// Returns the adapted SharedFutex
+    pub fn into_inner(self) -> SharedFutex {
+        self.futex
+    }
+
+    /// Lock the futex, blocking until it is available
+    /// # Returns
+    /// A guard that unlocks the futex when dropped
+    pub fn lock(&self) -> MutexGuard<'_> {
+        self.raw().lock();
+        MutexGuard {
+            adapter: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Try to lock the futex without blocking
+    /// # Returns
+    /// A guard, or TryLockError::WouldBlock if the futex is locked. The futex is never poisoned.
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_>> {
+        if self.raw().try_lock() {
+            Ok(MutexGuard {
+                adapter: self,
+                _not_send: PhantomData,
+            })
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    fn raw(&self) -> &SharedFutex {
+        &self.futex
+    }
+}
+
+impl Deref for StdMutexAdapter {
+    type Target = SharedFutex;
+
+    fn deref(&self) -> &SharedFutex {
+        &self.futex
+    }
+}
+
+impl Deref for MutexGuard<'_> {
+    type Target = ();
+
+    fn deref(&self) -> &() {
+        &()
+    }
+}
+
+impl Drop for MutexGuard<'_> {
+    fn drop(&mut self) {
+        self.adapter.raw().unlock();
+    }
+}
+
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawMutex for StdMutexAdapter {
+    /// Fails to compile where it is used, generic code calling `lock_api::Mutex::new`
+    /// included, like [`SharedFutex::INIT`](lock_api::RawMutex::INIT).
+    /// Use `lock_api::Mutex::from_raw` with an adapter over shared memory, or
+    /// [`FutexWord`](crate::futex_word::FutexWord) for a mutex built with `lock_api::Mutex::new`.
+    const INIT: Self = panic!("StdMutexAdapter has no INIT, build the mutex with from_raw");
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        lock_api::RawMutex::lock(&self.futex);
+    }
+
+    fn try_lock(&self) -> bool {
+        lock_api::RawMutex::try_lock(&self.futex)
+    }
+
+    unsafe fn unlock(&self) {
+        lock_api::RawMutex::unlock(&self.futex);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_lock_and_try_lock() {
+        let segment = ShmSegment::create("test_std_adapter_lock", 8);
+        let adapter = StdMutexAdapter::new(SharedFutex::new(segment.as_ptr()));
+        {
+            let _guard = adapter.lock();
+            assert!(matches!(adapter.try_lock(), Err(TryLockError::WouldBlock)));
+        }
+        let guard = adapter.try_lock();
+        assert!(guard.is_ok());
+        drop(guard);
+        assert_eq!(adapter.futex.futex, segment.as_ptr());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_shared_between_threads() {
+        const ITERATIONS: u64 = 5000;
+        let segment = ShmSegment::create("test_std_adapter_threads", 16);
+        let ptr_shm = segment.as_ptr();
+        let adapter = StdMutexAdapter::new(SharedFutex::new(ptr_shm));
+        let counter = unsafe { (ptr_shm as *mut u64).add(1) } as usize;
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..ITERATIONS {
+                        let _guard = adapter.lock();
+                        let counter = counter as *mut u64;
+                        unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                    }
+                });
+            }
+        });
+        assert_eq!(
+            unsafe { (counter as *mut u64).read_volatile() },
+            4 * ITERATIONS
+        );
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_lock_api_mutex() {
+        let segment = ShmSegment::create("test_std_adapter_lock_api", 8);
+        let adapter = StdMutexAdapter::new(SharedFutex::new(segment.as_ptr()));
+        let mutex = lock_api::Mutex::<StdMutexAdapter, u32>::from_raw(adapter, 0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *mutex.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*mutex.lock(), 4000);
+        assert!(mutex.try_lock().is_some());
+    }
+}
@@ -0,0 +1,9 @@
+//! Atomics of the lock protocol
+//!
+//! Under `--cfg loom` they are loom's, so the model checker sees every access of
+//! [`SharedFutex`](crate::rufutex::SharedFutex) to its word. Normal builds use std's.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{fence, AtomicU32};
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{fence, AtomicU32};
@@ -0,0 +1,223 @@
+//! The single place where futex(2) and futex_waitv(2) are invoked
+//!
+//! Other targets have no futex(2): there the futex operations fail with NotSupported and
+//! futex_waitv with NoSys, so the crate still builds and a dependent crate can pick another
+//! lock at run time. The lock operations never sleep without futex(2), they keep retrying.
+
+use crate::error::FutexError;
+use libc::c_void;
+
+use std::ffi::CStr;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+
+#[cfg(all(target_os = "linux", not(miri)))]
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+#[cfg(target_os = "linux")]
+pub(crate) use libc::{
+    FUTEX_BITSET_MATCH_ANY, FUTEX_CLOCK_REALTIME, FUTEX_CMP_REQUEUE, FUTEX_CMP_REQUEUE_PI,
+    FUTEX_LOCK_PI, FUTEX_PRIVATE_FLAG, FUTEX_REQUEUE, FUTEX_UNLOCK_PI, FUTEX_WAIT,
+    FUTEX_WAIT_BITSET, FUTEX_WAIT_REQUEUE_PI, FUTEX_WAKE, FUTEX_WAKE_BITSET,
+};
+
+/// The operations of linux/futex.h, which libc only defines for Linux
+#[cfg(not(target_os = "linux"))]
+mod futex_ops {
+    pub(crate) const FUTEX_WAIT: i32 = 0;
+    pub(crate) const FUTEX_WAKE: i32 = 1;
+    pub(crate) const FUTEX_REQUEUE: i32 = 3;
+    pub(crate) const FUTEX_CMP_REQUEUE: i32 = 4;
+    pub(crate) const FUTEX_LOCK_PI: i32 = 6;
+    pub(crate) const FUTEX_UNLOCK_PI: i32 = 7;
+    pub(crate) const FUTEX_WAIT_BITSET: i32 = 9;
+    pub(crate) const FUTEX_WAKE_BITSET: i32 = 10;
+    pub(crate) const FUTEX_WAIT_REQUEUE_PI: i32 = 11;
+    pub(crate) const FUTEX_CMP_REQUEUE_PI: i32 = 12;
+    pub(crate) const FUTEX_PRIVATE_FLAG: i32 = 128;
+    pub(crate) const FUTEX_CLOCK_REALTIME: i32 = 256;
+    pub(crate) const FUTEX_BITSET_MATCH_ANY: i32 = -1;
+}
+#[cfg(not(target_os = "linux"))]
+pub(crate) use futex_ops::*;
+
+/// Returns the kernel thread id of the calling thread, gettid(2)
+#[cfg(target_os = "linux")]
+pub(crate) fn gettid() -> u32 {
+    unsafe { libc::gettid() as u32 }
+}
+
+/// Returns an identifier of the calling thread, unique within the process only
+/// Other targets have no gettid(2), the identifiers are handed out from 1 as threads ask.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn gettid() -> u32 {
+    use std::sync::atomic::{AtomicU32, Ordering::Relaxed};
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+    thread_local! {
+        static ID: u32 = NEXT_ID.fetch_add(1, Relaxed);
+    }
+    ID.with(|id| *id)
+}
+
+/// Creates an anonymous file with memfd_create(2), closed on exec
+/// # Arguments
+/// * `name` - The name shown in /proc/self/fd
+/// # Returns
+/// The descriptor or the memfd_create error
+#[cfg(target_os = "linux")]
+pub(crate) fn memfd_create(name: &CStr) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Other targets have no memfd_create(2)
+/// # Returns
+/// Unsupported
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn memfd_create(_name: &CStr) -> io::Result<OwnedFd> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Invokes futex(2) and decodes failures
+/// errno is captured immediately after the syscall, before anything else can overwrite it.
+/// # Arguments
+/// * `uaddr` - The futex word
+/// * `futex_op` - The operation, including flags
+/// * `val` - The operation's val argument
+/// * `timeout_or_val2` - The timeout pointer or the val2 number, depending on the operation
+/// * `uaddr2` - The second futex word for requeue operations
+/// * `val3` - The operation's val3 argument
+/// # Returns
+/// The non negative result of the syscall or the decoded errno
+/// # Safety
+/// The pointers must be valid for the requested operation
+#[cfg(all(target_os = "linux", not(miri)))]
+pub(crate) unsafe fn futex(
+    uaddr: *mut c_void,
+    futex_op: i32,
+    val: u32,
+    timeout_or_val2: usize,
+    uaddr2: *mut c_void,
+    val3: u32,
+) -> Result<i64, FutexError> {
+    let ret = libc::syscall(
+        libc::SYS_futex,
+        uaddr,
+        futex_op,
+        val,
+        timeout_or_val2,
+        uaddr2,
+        val3,
+    );
+    if ret == -1 {
+        Err(FutexError::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Miri cannot call futex(2), the operations are emulated by a CondvarBackend
+/// # Safety
+/// The pointers must be valid for the requested operation
+#[cfg(miri)]
+pub(crate) unsafe fn futex(
+    uaddr: *mut c_void,
+    futex_op: i32,
+    val: u32,
+    timeout_or_val2: usize,
+    uaddr2: *mut c_void,
+    val3: u32,
+) -> Result<i64, FutexError> {
+    use crate::backend::{CondvarBackend, FutexBackend};
+    CondvarBackend.futex(uaddr, futex_op, val, timeout_or_val2, uaddr2, val3)
+}
+
+/// Other targets have no futex(2)
+/// # Safety
+/// Nothing is dereferenced, the signature matches the Linux one
+#[cfg(all(not(target_os = "linux"), not(miri)))]
+pub(crate) unsafe fn futex(
+    _uaddr: *mut c_void,
+    _futex_op: i32,
+    _val: u32,
+    _timeout_or_val2: usize,
+    _uaddr2: *mut c_void,
+    _val3: u32,
+) -> Result<i64, FutexError> {
+    Err(FutexError::NotSupported)
+}
+
+/// struct futex_waitv of linux/futex.h
+#[cfg(all(target_os = "linux", not(miri)))]
+#[repr(C)]
+struct FutexWaitv {
+    val: u64,
+    uaddr: u64,
+    flags: u32,
+    reserved: u32,
+}
+
+/// FUTEX2_SIZE_U32, the words are 32 bits wide
+#[cfg(all(target_os = "linux", not(miri)))]
+const FUTEX2_SIZE_U32: u32 = 0x02;
+/// FUTEX2_PRIVATE, the futex2 spelling of FUTEX_PRIVATE_FLAG
+#[cfg(all(target_os = "linux", not(miri)))]
+const FUTEX2_PRIVATE: u32 = 128;
+
+/// Set once the kernel answered ENOSYS, the later calls fail without a system call
+#[cfg(all(target_os = "linux", not(miri)))]
+static WAITV_MISSING: AtomicBool = AtomicBool::new(false);
+
+/// Invokes futex_waitv(2) without timeout and decodes failures
+/// futex_waitv arrived in Linux 5.16, older kernels fail with NoSys.
+/// # Arguments
+/// * `words` - The futex words with the value to sleep on and whether they are private
+/// # Returns
+/// The index of the woken word or the decoded errno
+/// # Safety
+/// The pointers must be valid futex words
+#[cfg(all(target_os = "linux", not(miri)))]
+pub(crate) unsafe fn futex_waitv(words: &[(*mut c_void, u32, bool)]) -> Result<i64, FutexError> {
+    if WAITV_MISSING.load(Relaxed) {
+        return Err(FutexError::NoSys);
+    }
+    let waiters: Vec<FutexWaitv> = words
+        .iter()
+        .map(|&(uaddr, val, private)| FutexWaitv {
+            val: val as u64,
+            uaddr: uaddr as usize as u64,
+            flags: FUTEX2_SIZE_U32 | if private { FUTEX2_PRIVATE } else { 0 },
+            reserved: 0,
+        })
+        .collect();
+    let ret = libc::syscall(
+        libc::SYS_futex_waitv,
+        waiters.as_ptr(),
+        waiters.len() as u32,
+        0u32,
+        std::ptr::null::<libc::timespec>(),
+        libc::CLOCK_MONOTONIC,
+    );
+    if ret == -1 {
+        let err = FutexError::last_os_error();
+        if err == FutexError::NoSys {
+            WAITV_MISSING.store(true, Relaxed);
+        }
+        Err(err)
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Miri and other targets cannot call futex_waitv(2), callers fall back to FUTEX_WAIT
+/// # Safety
+/// The pointers must be valid futex words
+#[cfg(any(not(target_os = "linux"), miri))]
+pub(crate) unsafe fn futex_waitv(_words: &[(*mut c_void, u32, bool)]) -> Result<i64, FutexError> {
+    Err(FutexError::NoSys)
+}
@@ -0,0 +1,415 @@
+//! Fork based harness for multi-process tests, enabled by the `test-support` feature
+//!
+//! Threads share one address space, so tests written with threads cannot catch a protocol that
+//! relies on process-private state or on the address of the mapping. The helpers here run a
+//! closure in a forked child, optionally against a fresh mapping of a memfd, and report how the
+//! child ended.
+//!
+//! Only the forking thread exists in the child. A lock held by another test thread at the time
+//! of the fork (the allocator, stdout) stays locked forever in the child, so closures should
+//! keep to atomics, futex operations and plain memory accesses. The timeout turns such a
+//! deadlock, or a deadlock of the code under test, into a failed test instead of a hung run.
+//!
+//! [`ShmSegment`] is the lighter variant: the sides of a protocol run as threads, each with its
+//! own mapping of a POSIX shared memory segment.
+//!
+//! [`MockBackend`] replaces the futex syscalls of a SharedFutex with scripted outcomes, to test
+//! the tricky paths of a protocol deterministically and count its syscalls.
+
+use crate::backend::FutexBackend;
+use crate::error::FutexError;
+use crate::rufutex::SharedFutex;
+use libc::c_void;
+use rushm::posixaccessor::POSIXShm;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::os::fd::BorrowedFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit code of a child whose closure panicked, the one of a failed Rust test
+const PANIC_EXIT_CODE: i32 = 101;
+/// Time between two checks of the child status
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How a forked child failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkError {
+    /// fork() failed with this errno
+    Fork(i32),
+    /// The closure panicked
+    Panicked,
+    /// The child exited with a non zero code
+    Exited(i32),
+    /// The child was killed by a signal
+    Signaled(i32),
+    /// The child did not finish in time and has been killed
+    TimedOut,
+}
+
+impl fmt::Display for ForkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForkError::Fork(errno) => write!(f, "fork failed with errno {}", errno),
+            ForkError::Panicked => write!(f, "child process panicked"),
+            ForkError::Exited(code) => write!(f, "child process exited with code {}", code),
+            ForkError::Signaled(signal) => write!(f, "child process killed by signal {}", signal),
+            ForkError::TimedOut => write!(f, "child process timed out"),
+        }
+    }
+}
+
+impl std::error::Error for ForkError {}
+
+/// A running forked child
+pub struct ForkedChild {
+    pid: libc::pid_t,
+}
+
+impl ForkedChild {
+    /// Forks and runs `f` in the child, which exits right after it
+    /// # Arguments
+    /// * `f` - The closure run by the child
+    /// # Returns
+    /// The child, or ForkError::Fork
+    pub fn spawn(f: impl FnOnce()) -> Result<Self, ForkError> {
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(ForkError::Fork(
+                std::io::Error::last_os_error().raw_os_error().unwrap_or(0),
+            ));
+        }
+        if pid == 0 {
+            let code = match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(()) => 0,
+                Err(_) => PANIC_EXIT_CODE,
+            };
+            // Skip the atexit handlers and the test harness of the parent
+            unsafe { libc::_exit(code) };
+        }
+        Ok(Self { pid })
+    }
+
+    /// Forks and runs `f` in the child with a SharedFutex over a new mapping of `fd`
+    /// The mapping is made in the child, so the word lives at another address than in the
+    /// parent, like in an unrelated process.
+    /// # Arguments
+    /// * `fd` - The descriptor holding the futex word, inherited by the child
+    /// * `offset` - The offset of the futex word
+    /// * `f` - The closure run by the child
+    /// # Returns
+    /// The child, or ForkError::Fork
+    pub fn spawn_attached(
+        fd: BorrowedFd<'_>,
+        offset: usize,
+        f: impl FnOnce(SharedFutex),
+    ) -> Result<Self, ForkError> {
+        Self::spawn(|| f(SharedFutex::from_fd(fd, offset).expect("cannot map the descriptor")))
+    }
+
+    /// Returns the process id of the child
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Waits for the child to finish, killing it after `timeout`
+    /// # Arguments
+    /// * `timeout` - The longest time to wait
+    /// # Returns
+    /// Ok if the child exited with code 0
+    pub fn wait(self, timeout: Duration) -> Result<(), ForkError> {
+        let deadline = Instant::now() + timeout;
+        let mut status = 0;
+        loop {
+            let ret = unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) };
+            if ret == self.pid {
+                break;
+            }
+            if Instant::now() >= deadline {
+                unsafe {
+                    libc::kill(self.pid, libc::SIGKILL);
+                    libc::waitpid(self.pid, &mut status, 0);
+                }
+                return Err(ForkError::TimedOut);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        if libc::WIFSIGNALED(status) {
+            return Err(ForkError::Signaled(libc::WTERMSIG(status)));
+        }
+        match libc::WEXITSTATUS(status) {
+            0 => Ok(()),
+            PANIC_EXIT_CODE => Err(ForkError::Panicked),
+            code => Err(ForkError::Exited(code)),
+        }
+    }
+}
+
+/// Runs `f` in a forked child and waits for it
+/// # Arguments
+/// * `timeout` - The longest time the child may run
+/// * `f` - The closure run by the child
+/// # Returns
+/// Ok if the closure returned, the way the child failed otherwise
+pub fn run_forked(timeout: Duration, f: impl FnOnce()) -> Result<(), ForkError> {
+    ForkedChild::spawn(f)?.wait(timeout)
+}
+
+/// A POSIX shared memory segment mapped by a test
+/// Each thread standing for a process maps the segment with [`ShmSegment::map_again`] on its
+/// own, like separate processes would, so the memory lives at another address in every one of
+/// them. Dropping the mapping of [`ShmSegment::create`] unmaps and unlinks the segment.
+pub struct ShmSegment {
+    shm: POSIXShm<i32>,
+    name: String,
+    len: usize,
+    ptr: *mut c_void,
+    owner: bool,
+}
+
+// The memory is shared by design, the mapping only goes away on drop
+unsafe impl Send for ShmSegment {}
+unsafe impl Sync for ShmSegment {}
+
+impl ShmSegment {
+    /// Creates and maps the segment `name`
+    /// # Arguments
+    /// * `name` - The name of the segment, unique to the test
+    /// * `len` - The size of the segment in bytes
+    /// # Returns
+    /// The mapping, zeroed when the segment did not exist
+    pub fn create(name: &str, len: usize) -> Self {
+        Self::map(name, len, true)
+    }
+
+    /// Maps the segment again, at another address
+    /// # Returns
+    /// A mapping that is unmapped on drop but leaves the segment in place
+    pub fn map_again(&self) -> Self {
+        Self::map(&self.name, self.len, false)
+    }
+
+    fn map(name: &str, len: usize, owner: bool) -> Self {
+        let mut shm = POSIXShm::<i32>::new(name.to_string(), len);
+        unsafe {
+            let ret = shm.open();
+            assert!(ret.is_ok(), "cannot map the shared memory segment {}", name);
+        }
+        let ptr = shm.get_cptr_mut();
+        Self {
+            shm,
+            name: name.to_string(),
+            len,
+            ptr,
+            owner,
+        }
+    }
+
+    /// Returns the start of the mapping
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.ptr
+    }
+
+    /// Returns the start of the mapping as a `T`
+    /// # Safety
+    /// The segment must hold a valid `T` at its start, initialized through any mapping.
+    pub unsafe fn get<T>(&self) -> &T {
+        &*(self.ptr as *const T)
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        // Every mapping unmaps itself, only the creator removes the name
+        unsafe {
+            let _ = self.shm.close(self.owner);
+        }
+    }
+}
+
+/// A futex operation seen by a [`MockBackend`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockCall {
+    /// FUTEX_WAIT on `expected`
+    Wait {
+        expected: u32,
+        timeout: Option<Duration>,
+    },
+    /// FUTEX_WAKE of up to `count` waiters
+    Wake { count: u32 },
+    /// Any other operation
+    Raw { futex_op: i32 },
+}
+
+/// Scripted outcome of a wait that would sleep
+pub enum MockWait {
+    /// Returns 0 with nothing changed, like a spurious wake up
+    Spurious,
+    /// Fails with FutexError::Interrupted, like a signal
+    Interrupted,
+    /// Fails with FutexError::TimedOut
+    TimedOut,
+    /// Runs the closure on the futex word, like another thread would while this one sleeps,
+    /// then returns 0
+    Run(Box<dyn FnOnce(&AtomicU32) + Send>),
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<MockCall>,
+    waits: VecDeque<MockWait>,
+}
+
+/// FutexBackend recording every operation and answering waits from a script
+/// Like the kernel, a wait on a word that does not hold the expected value fails with
+/// FutexError::WouldBlock without using the script. A wait that would sleep takes the next
+/// scripted outcome; with an empty script it times out if it has a timeout and panics
+/// otherwise, since it would sleep forever. Wakes wake nobody. Clones share the record and the
+/// script.
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    /// Create a new MockBackend with an empty script
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the outcome of a future wait that would sleep
+    /// # Arguments
+    /// * `outcome` - The outcome
+    pub fn push_wait(&self, outcome: MockWait) {
+        self.state.lock().unwrap().waits.push_back(outcome);
+    }
+
+    /// Returns the operations seen so far
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    fn record(&self, call: MockCall) {
+        self.state.lock().unwrap().calls.push(call);
+    }
+}
+
+impl FutexBackend for MockBackend {
+    unsafe fn futex(
+        &self,
+        _uaddr: *mut c_void,
+        futex_op: i32,
+        _val: u32,
+        _timeout_or_val2: usize,
+        _uaddr2: *mut c_void,
+        _val3: u32,
+    ) -> Result<i64, FutexError> {
+        self.record(MockCall::Raw { futex_op });
+        Ok(0)
+    }
+
+    fn wait(
+        &self,
+        word: &AtomicU32,
+        expected: u32,
+        timeout: Option<&libc::timespec>,
+        _private: bool,
+    ) -> Result<i64, FutexError> {
+        let timeout =
+            timeout.map(|timeout| Duration::new(timeout.tv_sec as u64, timeout.tv_nsec as u32));
+        self.record(MockCall::Wait { expected, timeout });
+        if word.load(SeqCst) != expected {
+            return Err(FutexError::WouldBlock);
+        }
+        let outcome = self.state.lock().unwrap().waits.pop_front();
+        match outcome {
+            Some(MockWait::Spurious) => Ok(0),
+            Some(MockWait::Interrupted) => Err(FutexError::Interrupted),
+            Some(MockWait::TimedOut) => Err(FutexError::TimedOut),
+            Some(MockWait::Run(f)) => {
+                f(word);
+                Ok(0)
+            }
+            None if timeout.is_some() => Err(FutexError::TimedOut),
+            None => panic!(
+                "wait on {} would sleep forever, no outcome scripted",
+                expected
+            ),
+        }
+    }
+
+    fn wake(&self, _word: &AtomicU32, count: u32, _private: bool) -> Result<i64, FutexError> {
+        self.record(MockCall::Wake { count });
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "forks a child process")]
+    fn test_child_outcomes() {
+        let timeout = Duration::from_secs(10);
+        assert_eq!(run_forked(timeout, || {}), Ok(()));
+        assert_eq!(
+            run_forked(timeout, || unsafe { libc::_exit(3) }),
+            Err(ForkError::Exited(3))
+        );
+        assert_eq!(
+            run_forked(timeout, || unsafe {
+                libc::raise(libc::SIGTERM);
+            }),
+            Err(ForkError::Signaled(libc::SIGTERM))
+        );
+        // Silence the panic message of the child
+        assert_eq!(
+            run_forked(timeout, || {
+                panic::set_hook(Box::new(|_| {}));
+                panic!("child failure");
+            }),
+            Err(ForkError::Panicked)
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_segment_mapped_again() {
+        let segment = ShmSegment::create("test_support_segment", 8);
+        let word = unsafe { segment.get::<AtomicU32>() };
+        let mappings = || {
+            std::fs::read_to_string("/proc/self/maps")
+                .unwrap()
+                .matches("/test_support_segment")
+                .count()
+        };
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mapping = segment.map_again();
+                assert_ne!(mapping.as_ptr(), segment.as_ptr());
+                assert_eq!(mappings(), 2);
+                unsafe { mapping.get::<AtomicU32>() }.store(7, SeqCst);
+            });
+        });
+        assert_eq!(word.load(SeqCst), 7);
+        assert_eq!(mappings(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "forks a child process")]
+    fn test_deadlocked_child_times_out() {
+        let (futex, _fd) = SharedFutex::create_memfd(4096).unwrap();
+        futex.lock();
+        let start = Instant::now();
+        let ret = run_forked(Duration::from_millis(200), || futex.lock());
+        assert_eq!(ret, Err(ForkError::TimedOut));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        futex.unlock();
+    }
+}
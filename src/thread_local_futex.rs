@@ -0,0 +1,181 @@
+//! Queue based (MCS) lock with thread-local queue nodes
+//!
+//! Every waiter spins, or rather sleeps, on its own futex word instead of the shared lock word,
+//! so an unlock wakes exactly the next thread in line. The queue nodes live in thread-local
+//! storage, which avoids allocating a node per acquisition.
+
+use crate::rufutex::SharedFutex;
+use libc::c_void;
+
+use std::cell::Cell;
+use std::hint;
+use std::ptr;
+use std::sync::atomic::{
+    AtomicPtr, AtomicU32,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+
+/// The node owner is queued and must keep sleeping
+const WAITING: u32 = 1;
+/// The lock has been handed over to the node owner
+const GRANTED: u32 = 0;
+
+struct McsNode {
+    locked: AtomicU32,
+    next: AtomicPtr<McsNode>,
+    in_use: Cell<bool>,
+}
+
+thread_local! {
+    static TL_NODE: McsNode = const {
+        McsNode {
+            locked: AtomicU32::new(GRANTED),
+            next: AtomicPtr::new(ptr::null_mut()),
+            in_use: Cell::new(false),
+        }
+    };
+}
+
+/// MCS lock whose tail pointer is stored at the given address and whose queue nodes are
+/// thread-local.
+/// The nodes are process-private, so every thread contending on a ThreadLocalFutex must belong
+/// to the same process even if the tail word itself is placed in shared memory.
+/// A thread can hold only one ThreadLocalFutex at a time since it owns a single node.
+pub struct ThreadLocalFutex {
+    pub futex: *mut c_void,
+    tail: *mut AtomicPtr<McsNode>,
+}
+
+impl ThreadLocalFutex {
+    /// Create a new ThreadLocalFutex
+    /// # Arguments
+    /// * `futex` - A pointer to a pointer sized, pointer aligned and zero initialized word
+    /// # Returns
+    /// A new ThreadLocalFutex
+    pub fn new(futex: *mut c_void) -> Self {
+        let tail = futex as *mut AtomicPtr<McsNode>;
+        Self { futex, tail }
+    }
+
+    /// Returns the number of bytes needed to hold the tail pointer
+    pub fn memory_requirements() -> usize {
+        std::mem::size_of::<AtomicPtr<McsNode>>()
+    }
+
+    /// Lock the futex
+    /// The calling thread appends its node to the queue and sleeps until its predecessor hands
+    /// the lock over.
+    pub fn lock(&mut self) {
+        TL_NODE.with(|node| {
+            assert!(
+                !node.in_use.replace(true),
+                "a thread can hold only one ThreadLocalFutex at a time"
+            );
+            let node_ptr = node as *const McsNode as *mut McsNode;
+            node.next.store(ptr::null_mut(), Relaxed);
+            node.locked.store(WAITING, Relaxed);
+
+            let pred = unsafe { (*self.tail).swap(node_ptr, AcqRel) };
+            if pred.is_null() {
+                return;
+            }
+            unsafe {
+                (*pred).next.store(node_ptr, Release);
+            }
+
+            let futex = SharedFutex::new(&node.locked as *const AtomicU32 as *mut c_void);
+            while node.locked.load(Acquire) == WAITING {
+                let _ = futex.wait(WAITING);
+            }
+        });
+    }
+
+    /// Unlock the futex
+    /// If a thread is queued behind us the lock is handed over to it and it is woken up.
+    pub fn unlock(&mut self) {
+        TL_NODE.with(|node| {
+            let node_ptr = node as *const McsNode as *mut McsNode;
+            let mut next = node.next.load(Acquire);
+            if next.is_null() {
+                let released = unsafe {
+                    (*self.tail).compare_exchange(node_ptr, ptr::null_mut(), AcqRel, Acquire)
+                };
+                if released.is_ok() {
+                    node.in_use.set(false);
+                    return;
+                }
+                // A successor swapped the tail but has not linked itself yet
+                loop {
+                    next = node.next.load(Acquire);
+                    if !next.is_null() {
+                        break;
+                    }
+                    hint::spin_loop();
+                }
+            }
+            node.in_use.set(false);
+
+            // The successor may wake up before the FUTEX_WAKE and even exit its thread; a wake
+            // on a stale address is at worst a spurious wakeup for someone else.
+            let locked = unsafe { &(*next).locked };
+            locked.store(GRANTED, Release);
+            let _ = SharedFutex::new(locked as *const AtomicU32 as *mut c_void).post(1);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::thread;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_lock_unlock() {
+        let segment = ShmSegment::create("test_tl_futex_lock_unlock", 8);
+        let mut futex = ThreadLocalFutex::new(segment.as_ptr());
+        futex.lock();
+        futex.unlock();
+        futex.lock();
+        futex.unlock();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_contended_counter() {
+        const THREADS: u64 = 4;
+        const ITERATIONS: u64 = 5000;
+        let size = ThreadLocalFutex::memory_requirements() + std::mem::size_of::<u64>();
+        let segment = ShmSegment::create("test_tl_futex_counter", size);
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let mapping = segment.map_again();
+                thread::spawn(move || {
+                    let ptr_shm = mapping.as_ptr();
+                    let mut futex = ThreadLocalFutex::new(ptr_shm);
+                    let counter =
+                        unsafe { ptr_shm.add(ThreadLocalFutex::memory_requirements()) as *mut u64 };
+                    for _ in 0..ITERATIONS {
+                        futex.lock();
+                        unsafe {
+                            counter.write_volatile(counter.read_volatile() + 1);
+                        }
+                        futex.unlock();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let counter = unsafe {
+            segment
+                .as_ptr()
+                .add(ThreadLocalFutex::memory_requirements()) as *mut u64
+        };
+        assert_eq!(unsafe { counter.read_volatile() }, THREADS * ITERATIONS);
+    }
+}
@@ -0,0 +1,433 @@
+//! Async mutex and condition variable living in shared memory
+//!
+//! [`AsyncSharedMutex`] and [`AsyncSharedCondvar`] have the layout and protocol of their
+//! blocking counterparts, so async tasks and blocking threads of any process mapping the region
+//! can share them. The kernel waits run on the helper threads of
+//! [`SharedFutex::lock_async`], never on the runtime's workers, which keeps the types usable
+//! from tokio or any other executor without depending on one.
+//!
+//! Both futures are cancel safe: a dropped [`AsyncSharedMutex::lock`] future never leaves the
+//! mutex locked, and a dropped [`Notified`] stops its helper and consumes no notification.
+//! Dropping either pending future waits for its helper to let go of the word, which blocks the
+//! dropping thread for one futex_waitv(2) wake up, or up to 10ms on kernels older than 5.16, so
+//! the mutex or condvar may be freed right after.
+//!
+//! The types live in memory the caller maps and only borrow it, so a helper cannot keep it
+//! alive, and a leaked future is never dropped. `lock`, `notified` and `wait` are unsafe for
+//! that reason, like [`SharedFutex::lock_async`]: their pending futures must not be leaked
+//! while the memory may still go away.
+
+use crate::async_lock::run_on_helper;
+use crate::futex_word::FutexWord;
+use crate::rufutex::SharedFutex;
+use crate::sleepers;
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Cross-process mutex holding its data, locked from async code
+/// The layout is the futex word and then `T`, aligned for `T`, so one mapping of
+/// [`AsyncSharedMutex::required_size`] bytes holds the whole lock. `T` is visible from every
+/// process mapping the region, it should not hold pointers or handles local to one of them.
+#[repr(C)]
+pub struct AsyncSharedMutex<T> {
+    lock: FutexWord,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for AsyncSharedMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncSharedMutex<T> {}
+
+impl<T> AsyncSharedMutex<T> {
+    /// Create a new AsyncSharedMutex
+    /// # Arguments
+    /// * `value` - The data behind the lock
+    /// # Returns
+    /// An unlocked AsyncSharedMutex
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: FutexWord::unlocked(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns the number of bytes the mutex and its data take in shared memory
+    pub const fn required_size() -> usize {
+        mem::size_of::<Self>()
+    }
+
+    /// Locks the mutex, waiting on a helper thread while it is contended
+    /// Dropping the future before it completes leaves the mutex as it found it, after blocking
+    /// the dropping thread until the helper lets go of the mutex, see [`crate::tokio_sync`].
+    /// # Safety
+    /// A pending future leaked with `mem::forget` or a reference cycle leaves its helper
+    /// sleeping on the mutex, and taking it later. The mutex must then stay mapped for as long
+    /// as that may happen.
+    /// # Returns
+    /// The guard, unlocking on drop
+    pub async unsafe fn lock(&self) -> AsyncSharedMutexGuard<'_, T> {
+        let futex = self.lock.as_futex();
+        // The guard of the word becomes the guard of the data
+        mem::forget(futex.lock_async().await);
+        AsyncSharedMutexGuard {
+            mutex: self,
+            _data: PhantomData,
+        }
+    }
+
+    /// Locks the mutex, blocking the calling thread
+    /// Meant for threads outside the runtime, like `tokio::sync::Mutex::blocking_lock`.
+    /// # Returns
+    /// The guard, unlocking on drop
+    pub fn blocking_lock(&self) -> AsyncSharedMutexGuard<'_, T> {
+        self.lock.lock();
+        AsyncSharedMutexGuard {
+            mutex: self,
+            _data: PhantomData,
+        }
+    }
+
+    /// Tries to lock the mutex without waiting
+    /// # Returns
+    /// The guard, or None if the mutex is locked
+    pub fn try_lock(&self) -> Option<AsyncSharedMutexGuard<'_, T>> {
+        // then() and not then_some(): a guard built eagerly would unlock on drop
+        self.lock.try_lock().then(|| AsyncSharedMutexGuard {
+            mutex: self,
+            _data: PhantomData,
+        })
+    }
+
+    /// Returns the data, the exclusive borrow proves nobody holds the lock
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Consumes the mutex and returns its data
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Default> Default for AsyncSharedMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for AsyncSharedMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncSharedMutex")
+            .field("state", &self.lock.load(SeqCst))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Access to the data of a locked [`AsyncSharedMutex`], unlocking on drop
+#[must_use = "the mutex is unlocked as soon as the guard is dropped"]
+pub struct AsyncSharedMutexGuard<'a, T> {
+    mutex: &'a AsyncSharedMutex<T>,
+    // Hands out &T like a &mut T would, so sharing the guard needs T: Sync
+    _data: PhantomData<&'a mut T>,
+}
+
+impl<T> Deref for AsyncSharedMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for AsyncSharedMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for AsyncSharedMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.lock.unlock();
+    }
+}
+
+/// Cross-process condition variable awaited from async code
+/// Zeroed memory is a valid AsyncSharedCondvar. As with a blocking condvar a notification only
+/// reaches the futures created before it, and futures may complete spuriously, so callers
+/// check their condition in a loop.
+#[repr(C)]
+pub struct AsyncSharedCondvar {
+    sequence: FutexWord,
+    waiters: AtomicU32,
+}
+
+const _: () = assert!(mem::size_of::<AsyncSharedCondvar>() == 8);
+
+impl AsyncSharedCondvar {
+    /// Create a new AsyncSharedCondvar
+    /// # Returns
+    /// An AsyncSharedCondvar without waiters
+    pub const fn new() -> Self {
+        Self {
+            sequence: FutexWord::new(0),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// Returns a future completing at the next notification
+    /// The notification is watched for from this call on, not from the first poll, so the
+    /// future can be created with the mutex held and awaited after unlocking it. Dropping the
+    /// pending future blocks the dropping thread until its helper lets go of the condvar, see
+    /// [`crate::tokio_sync`].
+    /// # Safety
+    /// A pending future leaked with `mem::forget` or a reference cycle leaves its helper
+    /// reading the condvar until the next notification. The condvar must then stay mapped
+    /// until that notification.
+    pub unsafe fn notified(&self) -> Notified<'_> {
+        Notified {
+            condvar: self,
+            sequence: self.sequence.load(SeqCst),
+            request: None,
+        }
+    }
+
+    /// Unlocks the mutex of `guard`, waits for a notification and locks the mutex again
+    /// Dropping the future before it completes leaves the mutex unlocked.
+    /// # Safety
+    /// The future must not be leaked while pending, see [`AsyncSharedCondvar::notified`] and
+    /// [`AsyncSharedMutex::lock`].
+    /// # Arguments
+    /// * `guard` - The guard of the mutex protecting the condition
+    /// # Returns
+    /// The guard of the locked mutex
+    pub async unsafe fn wait<'a, T>(
+        &self,
+        guard: AsyncSharedMutexGuard<'a, T>,
+    ) -> AsyncSharedMutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let notified = self.notified();
+        drop(guard);
+        notified.await;
+        mutex.lock().await
+    }
+
+    /// Wakes one waiter, if any
+    pub fn notify_one(&self) {
+        self.notify(1);
+    }
+
+    /// Wakes every waiter
+    pub fn notify_all(&self) {
+        self.notify(i32::MAX as u32);
+    }
+
+    fn notify(&self, count: u32) {
+        self.sequence.as_atomic().fetch_add(1, SeqCst);
+        if sleepers::any(&self.waiters) {
+            let _ = self.sequence.as_futex().post(count);
+        }
+    }
+}
+
+impl Default for AsyncSharedCondvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The sleep of a helper on behalf of a [`Notified`]
+struct NotifyRequest {
+    /// Set to 1 by a dropped future, the helper sleeps on it with the sequence
+    cancel: AtomicU32,
+    /// Whether the helper is done with the word, and the waker of the task
+    state: Mutex<(bool, Option<Waker>)>,
+    finished: Condvar,
+}
+
+/// Future of [`AsyncSharedCondvar::notified`]
+#[must_use = "futures do nothing unless polled"]
+pub struct Notified<'a> {
+    condvar: &'a AsyncSharedCondvar,
+    sequence: u32,
+    request: Option<Arc<NotifyRequest>>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.condvar.sequence.load(SeqCst) != self.sequence {
+            return Poll::Ready(());
+        }
+        let request = match &self.request {
+            Some(request) => request.clone(),
+            None => {
+                let request = Arc::new(NotifyRequest {
+                    cancel: AtomicU32::new(0),
+                    state: Mutex::new((false, Some(cx.waker().clone()))),
+                    finished: Condvar::new(),
+                });
+                let helper = request.clone();
+                let condvar = self.condvar as *const AsyncSharedCondvar as usize;
+                let sequence = self.sequence;
+                // Only a sleeping helper is counted, it stops counting itself once it is done
+                sleepers::register(&self.condvar.waiters);
+                run_on_helper(move || {
+                    // A dropped future waits for the helper to finish, and a leaked one leaves
+                    // the condvar mapped, see notified(), so the condvar is still mapped
+                    let condvar = unsafe { &*(condvar as *const AsyncSharedCondvar) };
+                    let futex = condvar.sequence.as_futex();
+                    while helper.cancel.load(SeqCst) == 0 && futex.get_futex_value() == sequence {
+                        let _ = futex.wait_either(sequence, &helper.cancel, 0);
+                    }
+                    sleepers::unregister(&condvar.waiters);
+                    let mut state = helper.state.lock().unwrap();
+                    state.0 = true;
+                    helper.finished.notify_all();
+                    if let Some(waker) = state.1.take() {
+                        drop(state);
+                        waker.wake();
+                    }
+                });
+                self.request = Some(request);
+                return Poll::Pending;
+            }
+        };
+        let mut state = request.state.lock().unwrap();
+        if state.0 {
+            return Poll::Ready(());
+        }
+        if !state
+            .1
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            state.1 = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(request) = &self.request {
+            if request.cancel.swap(1, SeqCst) == 0 {
+                let _ = SharedFutex::from_atomic(&request.cancel).post(1);
+            }
+            let mut state = request.state.lock().unwrap();
+            while !state.0 {
+                state = request.finished.wait(state).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    /// Implemented twice for Sync types, naming `check` through it only compiles for the others
+    trait NotSync<A> {
+        fn check() {}
+    }
+    impl<T: ?Sized> NotSync<()> for T {}
+    impl<T: ?Sized + Sync> NotSync<u8> for T {}
+
+    #[test]
+    fn test_guard_is_sync_only_for_sync_data() {
+        fn is_sync<T: Sync>() {}
+        is_sync::<AsyncSharedMutexGuard<'static, u64>>();
+        // Shared guards would race on the Cell through Deref
+        <AsyncSharedMutexGuard<'static, Cell<u32>> as NotSync<_>>::check();
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(AsyncSharedMutex::<u64>::required_size(), 16);
+        let zeroed: [u32; 2] = [0; 2];
+        let condvar = unsafe { &*(zeroed.as_ptr() as *const AsyncSharedCondvar) };
+        assert_eq!(condvar.waiters.load(SeqCst), 0);
+    }
+
+    #[test]
+    fn test_notification_before_the_first_poll() {
+        let condvar = AsyncSharedCondvar::new();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut notified = unsafe { condvar.notified() };
+        condvar.notify_one();
+        assert_eq!(Pin::new(&mut notified).poll(&mut cx), Poll::Ready(()));
+        assert!(notified.request.is_none());
+        // Futures created afterwards wait for the next notification
+        let mut later = unsafe { condvar.notified() };
+        assert_eq!(condvar.waiters.load(SeqCst), 0);
+        assert!(Pin::new(&mut later).poll(&mut cx).is_pending());
+        assert_eq!(condvar.waiters.load(SeqCst), 1);
+        drop(notified);
+        drop(later);
+        assert_eq!(condvar.waiters.load(SeqCst), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_dropped_future_stops_its_helper() {
+        let condvar = AsyncSharedCondvar::new();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut notified = unsafe { condvar.notified() };
+        assert!(Pin::new(&mut notified).poll(&mut cx).is_pending());
+        let request = notified.request.clone().unwrap();
+        let start = Instant::now();
+        drop(notified);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(request.state.lock().unwrap().0);
+        assert_eq!(condvar.waiters.load(SeqCst), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_leaked_future_stops_counting_at_the_notification() {
+        let condvar = AsyncSharedCondvar::new();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut notified = unsafe { condvar.notified() };
+        assert!(Pin::new(&mut notified).poll(&mut cx).is_pending());
+        let request = notified.request.clone().unwrap();
+        // The condvar outlives the helper, as the contract of notified() asks
+        mem::forget(notified);
+        assert_eq!(condvar.waiters.load(SeqCst), 1);
+        condvar.notify_one();
+        let start = Instant::now();
+        while !request.state.lock().unwrap().0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+        assert_eq!(condvar.waiters.load(SeqCst), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_dropped_lock_future_before_freeing_the_mutex() {
+        let mutex = Box::new(AsyncSharedMutex::new(7u64));
+        let mut cx = Context::from_waker(Waker::noop());
+        let guard = mutex.blocking_lock();
+        let mut lock = Box::pin(unsafe { mutex.lock() });
+        assert!(lock.as_mut().poll(&mut cx).is_pending());
+        // Lets the helper fall asleep on the word
+        std::thread::sleep(Duration::from_millis(100));
+        drop(lock);
+        // The helper is gone: nobody sleeps on the word, nobody locks it after the unlock
+        assert_eq!(mutex.lock.as_futex().wake_n(i32::MAX as u32), Ok(0));
+        drop(guard);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(mutex.try_lock().is_some());
+        drop(mutex);
+    }
+}
@@ -0,0 +1,25 @@
+//! Slow path events
+//!
+//! With the `log` feature the contended paths emit `debug` records under the `rufutex` target:
+//! contended lock entry, FUTEX_WAIT returns, wakes issued by unlock and wait timeouts. The
+//! uncontended fast path never logs. Without the feature the events compile to nothing.
+
+/// Emits a slow path event, the arguments are those of `format!`
+#[cfg(feature = "log")]
+macro_rules! trace_event {
+    ($($arg:tt)+) => {
+        log::debug!(target: "rufutex", $($arg)+)
+    };
+}
+
+/// Emits a slow path event, the arguments are type checked but never evaluated
+#[cfg(not(feature = "log"))]
+macro_rules! trace_event {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    };
+}
+
+pub(crate) use trace_event;
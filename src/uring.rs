@@ -0,0 +1,747 @@
+//! Futex waits and wakes submitted through io_uring
+//!
+//! Linux 6.7 added IORING_OP_FUTEX_WAIT and IORING_OP_FUTEX_WAKE, so a single thread can keep
+//! many futex waits in flight next to its other io_uring requests. [`UringFutexReactor`] owns a
+//! ring: waits and wakes are submitted with a callback, or as a [`UringWait`] future, and
+//! [`UringFutexReactor::dispatch`] reaps the completions and routes each one to its request.
+//!
+//! [`UringBackend`] is a [`FutexBackend`] issuing FUTEX_WAIT and FUTEX_WAKE, with or without a
+//! bitset, through a ring owned by the calling thread. Timed waits and the other operations go
+//! to futex(2).
+//!
+//! Support is probed once per process: on kernels without io_uring, or without its futex
+//! operations, [`UringFutexReactor::new`] fails with Unsupported and [`UringBackend`] calls
+//! futex(2) like [`SyscallBackend`].
+
+use crate::backend::{FutexBackend, SyscallBackend};
+use crate::error::FutexError;
+use crate::mapping::Mapping;
+use crate::sys;
+use libc::c_void;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{
+    AtomicU32,
+    Ordering::{Acquire, Relaxed, Release},
+};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_FUTEX_WAIT: u8 = 51;
+const IORING_OP_FUTEX_WAKE: u8 = 52;
+
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_REGISTER_PROBE: u32 = 8;
+const IO_URING_OP_SUPPORTED: u16 = 1;
+
+const IORING_OFF_SQ_RING: usize = 0;
+const IORING_OFF_CQ_RING: usize = 0x800_0000;
+const IORING_OFF_SQES: usize = 0x1000_0000;
+
+/// FUTEX2_SIZE_U32, the words are 32 bits wide
+const FUTEX2_SIZE_U32: u32 = 0x02;
+/// FUTEX2_PRIVATE, the futex2 spelling of FUTEX_PRIVATE_FLAG
+const FUTEX2_PRIVATE: u32 = 128;
+
+/// Ring size of the per thread ring of UringBackend, it holds one wait and its cancellation
+const BACKEND_ENTRIES: u32 = 2;
+
+/// struct io_sqring_offsets of linux/io_uring.h
+#[repr(C)]
+#[derive(Default)]
+struct SqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// struct io_cqring_offsets of linux/io_uring.h
+#[repr(C)]
+#[derive(Default)]
+struct CqOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+/// struct io_uring_params of linux/io_uring.h
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqOffsets,
+    cq_off: CqOffsets,
+}
+
+/// struct io_uring_sqe of linux/io_uring.h, with the fields the futex operations use
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    addr2: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    file_index: u32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// struct io_uring_cqe of linux/io_uring.h
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// struct io_uring_probe_op of linux/io_uring.h
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ProbeOp {
+    op: u8,
+    resv: u8,
+    flags: u16,
+    resv2: u32,
+}
+
+/// struct io_uring_probe of linux/io_uring.h, with room for every opcode
+#[repr(C)]
+struct Probe {
+    last_op: u8,
+    ops_len: u8,
+    resv: u16,
+    resv2: [u32; 3],
+    ops: [ProbeOp; 256],
+}
+
+const _: () = assert!(mem::size_of::<Params>() == 120);
+const _: () = assert!(mem::size_of::<Sqe>() == 64);
+const _: () = assert!(mem::size_of::<Cqe>() == 16);
+
+/// An io_uring instance with its submission and completion queues mapped
+struct Ring {
+    fd: OwnedFd,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    params: Params,
+}
+
+impl Ring {
+    /// Sets up a ring with room for `entries` submissions
+    /// # Arguments
+    /// * `entries` - The submission queue size, rounded up to a power of two by the kernel
+    /// # Returns
+    /// The ring or the io_uring_setup or mmap error
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+        Ok(Self {
+            sq: Mapping::map_fd_at(fd.as_fd(), IORING_OFF_SQ_RING, sq_len)?,
+            cq: Mapping::map_fd_at(fd.as_fd(), IORING_OFF_CQ_RING, cq_len)?,
+            sqes: Mapping::map_fd_at(fd.as_fd(), IORING_OFF_SQES, sqes_len)?,
+            fd,
+            params,
+        })
+    }
+
+    /// Returns true when the kernel implements every operation in `ops`
+    fn supports(&self, ops: &[u8]) -> bool {
+        let mut probe = Probe {
+            last_op: 0,
+            ops_len: 0,
+            resv: 0,
+            resv2: [0; 3],
+            ops: [ProbeOp::default(); 256],
+        };
+        let len = probe.ops.len() as u32;
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_register,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_PROBE,
+                &mut probe,
+                len,
+            )
+        };
+        ret == 0
+            && ops.iter().all(|&op| {
+                op <= probe.last_op && probe.ops[op as usize].flags & IO_URING_OP_SUPPORTED != 0
+            })
+    }
+
+    fn word(mapping: &Mapping, offset: u32) -> &AtomicU32 {
+        unsafe { &*(mapping.as_ptr().cast::<u8>().add(offset as usize) as *const AtomicU32) }
+    }
+
+    /// Queues a submission, the kernel sees it on the next enter()
+    /// # Returns
+    /// false when the submission queue is full
+    fn push(&mut self, sqe: Sqe) -> bool {
+        let off = &self.params.sq_off;
+        let head = Self::word(&self.sq, off.head).load(Acquire);
+        let tail = Self::word(&self.sq, off.tail).load(Relaxed);
+        if tail.wrapping_sub(head) >= self.params.sq_entries {
+            return false;
+        }
+        let index = tail & Self::word(&self.sq, off.ring_mask).load(Relaxed);
+        unsafe {
+            (self.sqes.as_ptr() as *mut Sqe)
+                .add(index as usize)
+                .write(sqe);
+            (self.sq.as_ptr().cast::<u8>().add(off.array as usize) as *mut u32)
+                .add(index as usize)
+                .write(index);
+        }
+        Self::word(&self.sq, off.tail).store(tail.wrapping_add(1), Release);
+        true
+    }
+
+    /// Returns the number of queued submissions the kernel has not consumed yet
+    fn unsubmitted(&self) -> u32 {
+        let off = &self.params.sq_off;
+        let tail = Self::word(&self.sq, off.tail).load(Relaxed);
+        tail.wrapping_sub(Self::word(&self.sq, off.head).load(Acquire))
+    }
+
+    /// Submits the queued requests and waits for `min_complete` completions
+    /// # Returns
+    /// Nothing, or the io_uring_enter error, EINTR included
+    fn enter(&self, min_complete: u32) -> io::Result<()> {
+        let flags = if min_complete > 0 {
+            IORING_ENTER_GETEVENTS
+        } else {
+            0
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd.as_raw_fd(),
+                self.unsubmitted(),
+                min_complete,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0usize,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Pops every available completion as (user_data, res)
+    /// # Returns
+    /// The number of completions
+    fn reap(&mut self, mut complete: impl FnMut(u64, i32)) -> usize {
+        let off = &self.params.cq_off;
+        let head_word = Self::word(&self.cq, off.head);
+        let mut head = head_word.load(Relaxed);
+        let tail = Self::word(&self.cq, off.tail).load(Acquire);
+        let mask = Self::word(&self.cq, off.ring_mask).load(Relaxed);
+        let mut reaped = 0;
+        while head != tail {
+            let cqe = unsafe {
+                &*(self.cq.as_ptr().cast::<u8>().add(off.cqes as usize) as *const Cqe)
+                    .add((head & mask) as usize)
+            };
+            let (user_data, res) = (cqe.user_data, cqe.res);
+            head = head.wrapping_add(1);
+            head_word.store(head, Release);
+            complete(user_data, res);
+            reaped += 1;
+        }
+        reaped
+    }
+}
+
+/// Returns true when the kernel supports the io_uring futex operations
+/// The probe sets up a ring once per process.
+pub fn is_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| {
+        !cfg!(miri)
+            && Ring::new(BACKEND_ENTRIES)
+                .is_ok_and(|ring| ring.supports(&[IORING_OP_FUTEX_WAIT, IORING_OP_FUTEX_WAKE]))
+    })
+}
+
+fn futex2_flags(private: bool) -> i32 {
+    (FUTEX2_SIZE_U32 | if private { FUTEX2_PRIVATE } else { 0 }) as i32
+}
+
+fn futex_sqe(opcode: u8, word: *const AtomicU32, val: u32, mask: u32, private: bool) -> Sqe {
+    Sqe {
+        opcode,
+        fd: futex2_flags(private),
+        addr: word as usize as u64,
+        addr2: val as u64,
+        addr3: mask as u64,
+        ..Sqe::default()
+    }
+}
+
+fn decode(res: i32) -> Result<i64, FutexError> {
+    if res < 0 {
+        Err(FutexError::from_errno(-res))
+    } else {
+        Ok(res as i64)
+    }
+}
+
+type Callback = Box<dyn FnOnce(Result<i64, FutexError>) + Send>;
+
+/// A ring multiplexing futex waits and wakes
+/// Requests are submitted with a callback and complete when [`dispatch`](Self::dispatch) reaps
+/// them, on the thread calling it. A wait completes with 0 once woken, or WouldBlock when the
+/// word did not hold the expected value; a wake completes with the number of woken waiters.
+/// Dropping the reactor cancels the waits still in flight without running their callbacks.
+pub struct UringFutexReactor {
+    ring: Ring,
+    next_token: u64,
+    pending: HashMap<u64, Callback>,
+}
+
+impl UringFutexReactor {
+    /// Create a new UringFutexReactor
+    /// # Arguments
+    /// * `entries` - The submission queue size, the number of requests submitted at once
+    /// # Returns
+    /// The reactor, Unsupported when the kernel lacks the io_uring futex operations
+    pub fn new(entries: u32) -> io::Result<Self> {
+        if !is_supported() {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        Ok(Self {
+            ring: Ring::new(entries)?,
+            next_token: 0,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Queues a FUTEX_WAIT on `word`
+    /// # Arguments
+    /// * `word` - The futex word
+    /// * `expected` - The value to sleep on
+    /// * `private` - Whether the word is private to the process
+    /// * `callback` - Called by dispatch() with the result of the wait
+    /// # Returns
+    /// The token of the request, WouldBlock when the submission queue is full
+    /// # Safety
+    /// The word must stay mapped until the wait completed or the reactor is dropped
+    pub unsafe fn submit_wait(
+        &mut self,
+        word: &AtomicU32,
+        expected: u32,
+        private: bool,
+        callback: impl FnOnce(Result<i64, FutexError>) + Send + 'static,
+    ) -> io::Result<u64> {
+        let sqe = futex_sqe(
+            IORING_OP_FUTEX_WAIT,
+            word,
+            expected,
+            libc::FUTEX_BITSET_MATCH_ANY as u32,
+            private,
+        );
+        self.submit(sqe, Box::new(callback))
+    }
+
+    /// Queues a FUTEX_WAKE on `word`
+    /// # Arguments
+    /// * `word` - The futex word
+    /// * `count` - The maximum number of waiters to wake
+    /// * `private` - Whether the word is private to the process
+    /// * `callback` - Called by dispatch() with the number of woken waiters
+    /// # Returns
+    /// The token of the request, WouldBlock when the submission queue is full
+    /// # Safety
+    /// The word must stay mapped until the wake completed or the reactor is dropped
+    pub unsafe fn submit_wake(
+        &mut self,
+        word: &AtomicU32,
+        count: u32,
+        private: bool,
+        callback: impl FnOnce(Result<i64, FutexError>) + Send + 'static,
+    ) -> io::Result<u64> {
+        let sqe = futex_sqe(
+            IORING_OP_FUTEX_WAKE,
+            word,
+            count,
+            libc::FUTEX_BITSET_MATCH_ANY as u32,
+            private,
+        );
+        self.submit(sqe, Box::new(callback))
+    }
+
+    /// Queues a FUTEX_WAIT on `word` completing a future
+    /// The future is ready once dispatch() reaped the wait, some thread must keep dispatching.
+    /// # Arguments
+    /// * `word` - The futex word
+    /// * `expected` - The value to sleep on
+    /// * `private` - Whether the word is private to the process
+    /// # Returns
+    /// The future, WouldBlock when the submission queue is full
+    /// # Safety
+    /// The word must stay mapped until the wait completed or the reactor is dropped
+    pub unsafe fn wait(
+        &mut self,
+        word: &AtomicU32,
+        expected: u32,
+        private: bool,
+    ) -> io::Result<UringWait> {
+        let state = Arc::new(Mutex::new(WaitState::default()));
+        let completed = state.clone();
+        self.submit_wait(word, expected, private, move |result| {
+            let mut state = completed.lock().unwrap();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                drop(state);
+                waker.wake();
+            }
+        })?;
+        Ok(UringWait { state })
+    }
+
+    fn submit(&mut self, mut sqe: Sqe, callback: Callback) -> io::Result<u64> {
+        let token = self.next_token;
+        sqe.user_data = token;
+        if !self.ring.push(sqe) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.next_token += 1;
+        self.pending.insert(token, callback);
+        Ok(token)
+    }
+
+    /// Submits the queued requests and runs the callbacks of the completed ones
+    /// # Arguments
+    /// * `block` - Whether to sleep until at least one request completes
+    /// # Returns
+    /// The number of completed requests, or the io_uring_enter error, EINTR included
+    pub fn dispatch(&mut self, block: bool) -> io::Result<usize> {
+        let min_complete = if block && !self.pending.is_empty() {
+            1
+        } else {
+            0
+        };
+        self.ring.enter(min_complete)?;
+        let mut completed = Vec::new();
+        self.ring.reap(|token, res| completed.push((token, res)));
+        for &(token, res) in &completed {
+            if let Some(callback) = self.pending.remove(&token) {
+                callback(decode(res));
+            }
+        }
+        Ok(completed.len())
+    }
+
+    /// Returns the number of requests whose callback has not run yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl AsFd for UringFutexReactor {
+    /// The ring polls readable while completions are waiting for dispatch()
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.ring.fd.as_fd()
+    }
+}
+
+impl AsRawFd for UringFutexReactor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ring.fd.as_raw_fd()
+    }
+}
+
+#[derive(Default)]
+struct WaitState {
+    result: Option<Result<i64, FutexError>>,
+    waker: Option<Waker>,
+}
+
+/// Future of [`UringFutexReactor::wait`]
+#[must_use = "futures do nothing unless polled"]
+pub struct UringWait {
+    state: Arc<Mutex<WaitState>>,
+}
+
+impl Future for UringWait {
+    type Output = Result<i64, FutexError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// The ring of UringBackend on this thread, None when it could not be set up
+    static BACKEND_RING: RefCell<Option<Ring>> = RefCell::new(
+        is_supported().then(|| Ring::new(BACKEND_ENTRIES).ok()).flatten()
+    );
+}
+
+/// The request waited for by UringBackend
+const BACKEND_REQUEST: u64 = 1;
+/// Its cancellation after a signal
+const BACKEND_CANCEL: u64 = 2;
+
+/// Issues FUTEX_WAIT and FUTEX_WAKE through io_uring
+/// Every thread submits through a ring of its own, set up on its first call. A signal
+/// interrupting a wait cancels the request in flight, the wait then fails with Interrupted
+/// like futex(2) would. Timed waits, the other operations and kernels without the io_uring futex
+/// operations go to futex(2), and so does a thread whose ring failed to enter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UringBackend;
+
+impl UringBackend {
+    /// Runs one request through the thread's ring
+    /// # Returns
+    /// The result of the request, None when the thread has no ring
+    fn submit(sqe: Sqe) -> Option<Result<i64, FutexError>> {
+        BACKEND_RING.with(|ring| {
+            let mut slot = ring.try_borrow_mut().ok()?;
+            let ring = slot.as_mut()?;
+            let mut sqe = sqe;
+            sqe.user_data = BACKEND_REQUEST;
+            if !ring.push(sqe) {
+                return None;
+            }
+            let mut result = None;
+            let mut cancelled = false;
+            loop {
+                match ring.enter(1) {
+                    Ok(()) => {}
+                    Err(err) if err.raw_os_error() == Some(libc::EINTR) => {
+                        if !cancelled && ring.unsubmitted() == 0 {
+                            cancelled = ring.push(Sqe {
+                                opcode: IORING_OP_ASYNC_CANCEL,
+                                addr: BACKEND_REQUEST,
+                                user_data: BACKEND_CANCEL,
+                                ..Sqe::default()
+                            });
+                        }
+                    }
+                    Err(err) => {
+                        // The request may still be in flight and the next one would reap its
+                        // completion. Closing the ring cancels it, the later requests go to futex(2)
+                        *slot = None;
+                        return Some(Err(FutexError::from_errno(err.raw_os_error().unwrap_or(0))));
+                    }
+                }
+                let mut cancel_done = !cancelled;
+                ring.reap(|user_data, res| match user_data {
+                    BACKEND_REQUEST => result = Some(res),
+                    _ => cancel_done = true,
+                });
+                // The cancellation completes too, reap it before the next request
+                if let (Some(res), true) = (result, cancel_done) {
+                    return Some(if res == -libc::ECANCELED {
+                        Err(FutexError::Interrupted)
+                    } else {
+                        decode(res)
+                    });
+                }
+            }
+        })
+    }
+}
+
+impl FutexBackend for UringBackend {
+    unsafe fn futex(
+        &self,
+        uaddr: *mut c_void,
+        futex_op: i32,
+        val: u32,
+        timeout_or_val2: usize,
+        uaddr2: *mut c_void,
+        val3: u32,
+    ) -> Result<i64, FutexError> {
+        let private = futex_op & libc::FUTEX_PRIVATE_FLAG != 0;
+        let word = uaddr as *const AtomicU32;
+        let sqe = match futex_op & !(libc::FUTEX_PRIVATE_FLAG | libc::FUTEX_CLOCK_REALTIME) {
+            libc::FUTEX_WAIT if timeout_or_val2 == 0 => Some(futex_sqe(
+                IORING_OP_FUTEX_WAIT,
+                word,
+                val,
+                libc::FUTEX_BITSET_MATCH_ANY as u32,
+                private,
+            )),
+            libc::FUTEX_WAIT_BITSET if timeout_or_val2 == 0 => {
+                Some(futex_sqe(IORING_OP_FUTEX_WAIT, word, val, val3, private))
+            }
+            libc::FUTEX_WAKE => Some(futex_sqe(
+                IORING_OP_FUTEX_WAKE,
+                word,
+                val,
+                libc::FUTEX_BITSET_MATCH_ANY as u32,
+                private,
+            )),
+            libc::FUTEX_WAKE_BITSET => {
+                Some(futex_sqe(IORING_OP_FUTEX_WAKE, word, val, val3, private))
+            }
+            _ => None,
+        };
+        match sqe.and_then(Self::submit) {
+            Some(result) => result,
+            None => sys::futex(uaddr, futex_op, val, timeout_or_val2, uaddr2, val3),
+        }
+    }
+
+    fn wait_any(&self, words: &[(&AtomicU32, u32, bool)]) -> Result<i64, FutexError> {
+        SyscallBackend.wait_any(words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rufutex::SharedFutex;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_routes_completions_to_their_waits() {
+        // Kernels without the io_uring futex operations have nothing to test
+        let Ok(mut reactor) = UringFutexReactor::new(8) else {
+            return;
+        };
+        let words: Arc<[AtomicU32; 3]> = Arc::new(Default::default());
+        let woken = Arc::new(Mutex::new(Vec::new()));
+        for index in 0..2 {
+            let woken = woken.clone();
+            unsafe {
+                reactor
+                    .submit_wait(&words[index], 0, true, move |result| {
+                        woken.lock().unwrap().push((index, result));
+                    })
+                    .unwrap();
+            }
+        }
+        let mut last = unsafe { reactor.wait(&words[2], 0, true).unwrap() };
+        // A stale value completes at once
+        let stale = woken.clone();
+        unsafe {
+            reactor
+                .submit_wait(&words[0], 1, true, move |result| {
+                    stale.lock().unwrap().push((3, result));
+                })
+                .unwrap();
+        }
+        assert_eq!(reactor.dispatch(true).unwrap(), 1);
+        assert_eq!(
+            woken.lock().unwrap().pop(),
+            Some((3, Err(FutexError::WouldBlock)))
+        );
+        assert_eq!(reactor.pending(), 3);
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut last).poll(&mut cx).is_pending());
+        for index in [1, 2, 0] {
+            // Woken from another thread with futex(2)
+            let waker = words.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                waker[index].store(1, Release);
+                SyscallBackend.wake(&waker[index], 1, true).unwrap();
+            });
+            let before = reactor.pending();
+            while reactor.pending() == before {
+                let _ = reactor.dispatch(true);
+            }
+            assert_eq!(reactor.pending(), before - 1);
+            if index == 2 {
+                assert_eq!(Pin::new(&mut last).poll(&mut cx), Poll::Ready(Ok(0)));
+            } else {
+                assert_eq!(woken.lock().unwrap().pop(), Some((index, Ok(0))));
+            }
+        }
+        assert!(woken.lock().unwrap().is_empty());
+
+        // Wakes are routed as well, with the number of woken waiters
+        let count = Arc::new(Mutex::new(None));
+        let result = count.clone();
+        unsafe {
+            reactor
+                .submit_wake(&words[0], 1, true, move |woken| {
+                    *result.lock().unwrap() = Some(woken);
+                })
+                .unwrap();
+        }
+        while reactor.pending() > 0 {
+            reactor.dispatch(true).unwrap();
+        }
+        assert_eq!(*count.lock().unwrap(), Some(Ok(0)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs a futex operation Miri does not emulate")]
+    fn test_backend_mutual_exclusion() {
+        let mut word = 0u32;
+        let futex = SharedFutex::with_backend(&mut word as *mut u32 as *mut c_void, UringBackend);
+        let counter = AtomicU32::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        futex.lock();
+                        let value = counter.load(Relaxed);
+                        counter.store(value + 1, Relaxed);
+                        futex.unlock();
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(Relaxed), 4000);
+    }
+}
@@ -0,0 +1,226 @@
+//! Go style wait group on a futex word
+//!
+//! The word holds the number of pending tasks in its lower 31 bits and a waiters flag in the
+//! top bit. [`SharedWaitGroup::done`] bringing the count to zero clears the flag and wakes every
+//! waiter; without waiters it makes no system call. Zeroed memory is an idle wait group.
+//!
+//! As in Go, an add() that starts a new round must happen after every wait() of the previous
+//! round returned. A waiter that finds the count already raised again when it wakes reports
+//! [`WaitGroupError::Reused`]; other interleavings cannot be told apart from a legitimate
+//! new round and are not detected.
+
+use crate::deadline::Deadline;
+use crate::rufutex::SharedFutex;
+use libc::c_void;
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::Ordering::{Acquire, SeqCst};
+use std::time::Duration;
+
+/// A waiter sleeps on the word
+const WAITERS: u32 = 1 << 31;
+const COUNT_MASK: u32 = WAITERS - 1;
+
+/// Error returned by the misuses of a wait group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitGroupError {
+    /// done() was called more times than add() accounted for
+    Negative,
+    /// add() would take the count past 2^31 - 1
+    Overflow,
+    /// The count was raised again before a wait() of the previous round returned
+    Reused,
+}
+
+impl fmt::Display for WaitGroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitGroupError::Negative => write!(f, "wait group count would go below zero"),
+            WaitGroupError::Overflow => write!(f, "wait group count overflows"),
+            WaitGroupError::Reused => {
+                write!(f, "wait group reused before the previous wait returned")
+            }
+        }
+    }
+}
+
+impl Error for WaitGroupError {}
+
+/// A wait group shared between processes
+pub struct SharedWaitGroup {
+    futex: SharedFutex,
+}
+
+impl SharedWaitGroup {
+    /// Create a new SharedWaitGroup
+    /// # Arguments
+    /// * `wait_group` - A pointer to a 4 bytes aligned word, zeroed before the first use
+    /// # Returns
+    /// A new SharedWaitGroup
+    pub fn new(wait_group: *mut c_void) -> Self {
+        Self {
+            futex: SharedFutex::new(wait_group),
+        }
+    }
+
+    /// Returns the number of pending tasks
+    pub fn count(&self) -> u32 {
+        self.futex.get_futex_value_with_ordering(Acquire) & COUNT_MASK
+    }
+
+    /// Adds `n` pending tasks
+    /// # Arguments
+    /// * `n` - The number of tasks
+    /// # Returns
+    /// Ok, or Overflow if the count would not fit
+    pub fn add(&self, n: u32) -> Result<(), WaitGroupError> {
+        self.futex
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |value| {
+                let count = (value & COUNT_MASK).checked_add(n)?;
+                (count <= COUNT_MASK).then_some((value & WAITERS) | count)
+            })
+            .map(|_| ())
+            .map_err(|_| WaitGroupError::Overflow)
+    }
+
+    /// Marks one task as finished, waking the waiters if it was the last one
+    /// # Returns
+    /// Ok, or Negative if no task was pending
+    pub fn done(&self) -> Result<(), WaitGroupError> {
+        let previous = self
+            .futex
+            .as_atomic()
+            .fetch_update(SeqCst, SeqCst, |value| match value & COUNT_MASK {
+                0 => None,
+                // The last task clears the waiters flag along with the count
+                1 => Some(0),
+                _ => Some(value - 1),
+            })
+            .map_err(|_| WaitGroupError::Negative)?;
+        if previous == WAITERS | 1 {
+            let _ = self.futex.post(i32::MAX as u32);
+        }
+        Ok(())
+    }
+
+    /// Sleeps until the count drops to zero
+    /// # Returns
+    /// Ok, or Reused if the count was raised again before this wait returned
+    pub fn wait(&self) -> Result<(), WaitGroupError> {
+        self.wait_until(Deadline::never()).map(|_| ())
+    }
+
+    /// Sleeps until the count drops to zero or `timeout` expires
+    /// # Arguments
+    /// * `timeout` - The maximum time to wait
+    /// # Returns
+    /// Ok(true) once the count is zero, Ok(false) if the timeout expired, or Reused if the
+    /// count was raised again before this wait returned
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool, WaitGroupError> {
+        self.wait_until(Deadline::after(timeout))
+    }
+
+    fn wait_until(&self, deadline: Deadline) -> Result<bool, WaitGroupError> {
+        let atom = self.futex.as_atomic();
+        let mut value = atom.load(SeqCst);
+        let mut slept = false;
+        loop {
+            if value & COUNT_MASK == 0 {
+                return Ok(true);
+            }
+            if value & WAITERS == 0 {
+                // Only a count reaching zero clears the flag this waiter set
+                if slept {
+                    return Err(WaitGroupError::Reused);
+                }
+                if let Err(current) = atom.compare_exchange(value, value | WAITERS, SeqCst, SeqCst)
+                {
+                    value = current;
+                    continue;
+                }
+                value |= WAITERS;
+            }
+            if !deadline.wait(&self.futex, value) {
+                return Ok(false);
+            }
+            slept = true;
+            value = atom.load(SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::AtomicU32;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_misuse() {
+        let mut word = 0u32;
+        let wait_group = SharedWaitGroup::new(&mut word as *mut u32 as *mut c_void);
+        assert_eq!(wait_group.done(), Err(WaitGroupError::Negative));
+        assert_eq!(wait_group.wait(), Ok(()));
+        assert_eq!(wait_group.add(COUNT_MASK), Ok(()));
+        assert_eq!(wait_group.add(1), Err(WaitGroupError::Overflow));
+        assert_eq!(wait_group.count(), COUNT_MASK);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_released_after_last_done() {
+        const WORKERS: u32 = 4;
+        let segment = &ShmSegment::create("test_wait_group", 4);
+        let wait_group = SharedWaitGroup::new(segment.as_ptr());
+        wait_group.add(WORKERS).unwrap();
+        let finished = AtomicU32::new(0);
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                wait_group.wait().unwrap();
+                finished.load(SeqCst)
+            });
+            for worker in 0..WORKERS {
+                let finished = &finished;
+                s.spawn(move || {
+                    let mapping = segment.map_again();
+                    thread::sleep(Duration::from_millis(20 * (worker as u64 + 1)));
+                    finished.fetch_add(1, SeqCst);
+                    SharedWaitGroup::new(mapping.as_ptr()).done().unwrap();
+                });
+            }
+            assert_eq!(waiter.join().unwrap(), WORKERS);
+        });
+
+        assert_eq!(wait_group.count(), 0);
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let mut word = 0u32;
+        let wait_group = SharedWaitGroup::new(&mut word as *mut u32 as *mut c_void);
+        wait_group.add(2).unwrap();
+        wait_group.done().unwrap();
+
+        let start = Instant::now();
+        assert_eq!(
+            wait_group.wait_timeout(Duration::from_millis(100)),
+            Ok(false)
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(wait_group.count(), 1);
+
+        // The straggler finishes while a waiter sleeps
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                wait_group.done().unwrap();
+            });
+            assert_eq!(wait_group.wait_timeout(Duration::from_secs(10)), Ok(true));
+        });
+    }
+}
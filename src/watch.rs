@@ -0,0 +1,175 @@
+//! Change notification on a sequence counter
+//!
+//! The first word is the sequence, bumped by every [`SharedWatch::publish`], the second the
+//! count of sleeping subscribers, so a publish without them makes no system call. Only the
+//! change is signalled, the value lives elsewhere, in a [`crate::seqlock::SharedSeqLock`] for
+//! instance. A subscriber compares the sequence with the last one it saw: any number of bumps
+//! in between reads as one change, so slow subscribers coalesce missed updates.
+//!
+//! The sequence wraps around. Subscribers only compare for inequality, which stays right unless
+//! exactly a multiple of 2^32 publishes happen between two looks at the counter.
+
+use crate::deadline::Deadline;
+use crate::rufutex::SharedFutex;
+use crate::sleepers;
+use libc::c_void;
+
+use std::sync::atomic::Ordering::{Acquire, SeqCst};
+use std::time::Duration;
+
+/// A publisher of changes and their subscribers, shared between processes
+pub struct SharedWatch {
+    sequence: SharedFutex,
+    sleepers: SharedFutex,
+}
+
+impl SharedWatch {
+    /// Create a new SharedWatch
+    /// # Arguments
+    /// * `watch` - A pointer to two 4 bytes aligned words, zeroed before the first use
+    /// # Returns
+    /// A new SharedWatch
+    pub fn new(watch: *mut c_void) -> Self {
+        Self {
+            sequence: SharedFutex::new(watch),
+            sleepers: SharedFutex::new(watch.cast::<u32>().wrapping_add(1).cast()),
+        }
+    }
+
+    /// Returns the current sequence, the `since` of the next checks
+    pub fn sequence(&self) -> u32 {
+        self.sequence.get_futex_value_with_ordering(Acquire)
+    }
+
+    /// Signals a change to every subscriber
+    /// # Returns
+    /// The new sequence
+    pub fn publish(&self) -> u32 {
+        let sequence = self
+            .sequence
+            .as_atomic()
+            .fetch_add(1, SeqCst)
+            .wrapping_add(1);
+        if sleepers::any(self.sleepers.as_atomic()) {
+            let _ = self.sequence.wake_all();
+        }
+        sequence
+    }
+
+    /// Returns true if a change was published after `since`
+    /// # Arguments
+    /// * `since` - The last sequence the subscriber saw
+    pub fn has_changed(&self, since: u32) -> bool {
+        self.sequence() != since
+    }
+
+    /// Sleeps until a change is published after `since`
+    /// # Arguments
+    /// * `since` - The last sequence the subscriber saw
+    /// # Returns
+    /// The new sequence
+    pub fn wait_for_change(&self, since: u32) -> u32 {
+        // Without a deadline it only returns changed
+        self.wait_until(since, Deadline::never()).unwrap()
+    }
+
+    /// Sleeps until a change is published after `since` or `timeout` expires
+    /// # Arguments
+    /// * `since` - The last sequence the subscriber saw
+    /// * `timeout` - The maximum time to wait
+    /// # Returns
+    /// The new sequence, or None if the timeout expired
+    pub fn wait_for_change_timeout(&self, since: u32, timeout: Duration) -> Option<u32> {
+        self.wait_until(since, Deadline::after(timeout))
+    }
+
+    fn wait_until(&self, since: u32, deadline: Deadline) -> Option<u32> {
+        loop {
+            let sequence = self.sequence();
+            if sequence != since {
+                return Some(sequence);
+            }
+            if deadline.has_passed() {
+                return None;
+            }
+            // Returns at once when a change was published in between
+            let slept = sleepers::sleep(self.sleepers.as_atomic(), || {
+                deadline.wait(&self.sequence, since)
+            });
+            if !slept {
+                return None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ShmSegment;
+    use std::sync::atomic::AtomicU32;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wraparound() {
+        let mut words = [u32::MAX, 0];
+        let watch = SharedWatch::new(words.as_mut_ptr() as *mut c_void);
+        let since = watch.sequence();
+        assert!(!watch.has_changed(since));
+        assert_eq!(watch.publish(), 0);
+        assert!(watch.has_changed(since));
+        assert_eq!(watch.wait_for_change(since), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "needs POSIX shared memory")]
+    fn test_slow_subscriber_sees_one_change() {
+        let segment = ShmSegment::create("test_shared_watch", 8);
+        let watch = SharedWatch::new(segment.as_ptr());
+        let since = watch.sequence();
+        let busy = AtomicU32::new(1);
+
+        thread::scope(|s| {
+            let subscriber = s.spawn(|| {
+                let mapping = segment.map_again();
+                let watch = SharedWatch::new(mapping.as_ptr());
+                // Busy elsewhere while the publisher bumps
+                while busy.load(SeqCst) != 0 {
+                    thread::yield_now();
+                }
+                let sequence = watch.wait_for_change(since);
+                (sequence, watch.has_changed(sequence))
+            });
+            for _ in 0..5 {
+                watch.publish();
+            }
+            busy.store(0, SeqCst);
+            assert_eq!(subscriber.join().unwrap(), (since.wrapping_add(5), false));
+        });
+    }
+
+    #[test]
+    fn test_wait_for_change_blocks() {
+        let mut words = [0u32; 2];
+        let watch = SharedWatch::new(words.as_mut_ptr() as *mut c_void);
+        let since = watch.publish();
+
+        let start = Instant::now();
+        assert_eq!(
+            watch.wait_for_change_timeout(since, Duration::from_millis(100)),
+            None
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        thread::scope(|s| {
+            let subscriber = s.spawn(|| watch.wait_for_change(since));
+            while watch.sleepers.get_futex_value() == 0 {
+                thread::yield_now();
+            }
+            assert_eq!(watch.publish(), since + 1);
+            assert_eq!(subscriber.join().unwrap(), since + 1);
+        });
+        assert_eq!(watch.sleepers.get_futex_value(), 0);
+    }
+}
@@ -0,0 +1,93 @@
+//! lock_async under a tokio runtime: the contended waits happen on helper threads, so a runtime
+//! with fewer workers than waiting tasks keeps making progress.
+
+#![cfg(not(miri))]
+
+use rufutex::rufutex::SharedFutex;
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_time()
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn test_many_tasks_protect_a_counter() {
+    const TASKS: u64 = 32;
+    const ROUNDS: u64 = 200;
+    let futex = Arc::new(SharedFutex::new_anonymous().unwrap());
+    // Updated with a plain load and store, only the lock keeps increments from being lost
+    let counter = Arc::new(AtomicU64::new(0));
+
+    runtime().block_on(async {
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let futex = futex.clone();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    for round in 0..ROUNDS {
+                        // The futex owns its mapping, the helper's clone keeps it alive
+                        let _guard = unsafe { futex.lock_async() }.await;
+                        let value = counter.load(SeqCst);
+                        if round % 16 == 0 {
+                            // Holds the lock across a suspension point
+                            tokio::time::sleep(Duration::from_millis(1)).await;
+                        }
+                        counter.store(value + 1, SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+
+    assert_eq!(counter.load(SeqCst), TASKS * ROUNDS);
+    assert!(futex.try_lock());
+    futex.unlock();
+}
+
+#[test]
+fn test_dropped_pending_future_does_not_block_others() {
+    let futex = Arc::new(SharedFutex::new_anonymous().unwrap());
+    futex.lock();
+
+    runtime().block_on(async {
+        // Polled once, so a helper waits for the lock on its behalf, then dropped
+        {
+            let mut abandoned = pin!(unsafe { futex.lock_async() });
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(matches!(abandoned.as_mut().poll(&mut cx), Poll::Pending));
+        }
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let futex = futex.clone();
+                tokio::spawn(async move {
+                    let _guard = unsafe { futex.lock_async() }.await;
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        futex.unlock();
+        for waiter in waiters {
+            tokio::time::timeout(Duration::from_secs(10), waiter)
+                .await
+                .expect("a waiter was stranded by the dropped future")
+                .unwrap();
+        }
+    });
+
+    assert!(futex.try_lock());
+    futex.unlock();
+}
@@ -0,0 +1,67 @@
+/*
+ * Reference C implementation of Drepper's mutex 2 ("Futexes are Tricky"), as given in
+ * https://eli.thegreenplace.net/2018/basics-of-futexes/
+ * It never links against rufutex, see tests/c_compat.rs.
+ */
+#include <fcntl.h>
+#include <linux/futex.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/mman.h>
+#include <sys/syscall.h>
+#include <unistd.h>
+
+static uint32_t cmpxchg(uint32_t *atom, uint32_t expected, uint32_t desired)
+{
+    __atomic_compare_exchange_n(atom, &expected, desired, 0, __ATOMIC_SEQ_CST, __ATOMIC_SEQ_CST);
+    return expected;
+}
+
+static void mutex_lock(uint32_t *atom)
+{
+    uint32_t c = cmpxchg(atom, 0, 1);
+    if (c != 0) {
+        do {
+            if (c == 2 || cmpxchg(atom, 1, 2) != 0) {
+                syscall(SYS_futex, atom, FUTEX_WAIT, 2, NULL, NULL, 0);
+            }
+        } while ((c = cmpxchg(atom, 0, 2)) != 0);
+    }
+}
+
+static void mutex_unlock(uint32_t *atom)
+{
+    if (__atomic_fetch_sub(atom, 1, __ATOMIC_SEQ_CST) != 1) {
+        __atomic_store_n(atom, 0, __ATOMIC_SEQ_CST);
+        syscall(SYS_futex, atom, FUTEX_WAKE, 1, NULL, NULL, 0);
+    }
+}
+
+int main(int argc, char **argv)
+{
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s <shm name> <iterations>\n", argv[0]);
+        return 2;
+    }
+    int fd = shm_open(argv[1], O_RDWR, 0600);
+    if (fd < 0) {
+        perror("shm_open");
+        return 1;
+    }
+    uint32_t *base = mmap(NULL, 4096, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    if (base == MAP_FAILED) {
+        perror("mmap");
+        return 1;
+    }
+    volatile uint32_t *counter = base + 1;
+    long iterations = atol(argv[2]);
+    for (long i = 0; i < iterations; i++) {
+        mutex_lock(base);
+        *counter = *counter + 1;
+        mutex_unlock(base);
+    }
+    munmap(base, 4096);
+    close(fd);
+    return 0;
+}
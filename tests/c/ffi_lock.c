@@ -0,0 +1,43 @@
+/* Increments the counter next to the futex word under the lock, see tests/ffi.rs */
+#include <fcntl.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/mman.h>
+#include <unistd.h>
+
+#include "rufutex.h"
+
+int main(int argc, char **argv)
+{
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s <shm name> <iterations>\n", argv[0]);
+        return 2;
+    }
+    int fd = shm_open(argv[1], O_RDWR, 0600);
+    if (fd < 0) {
+        perror("shm_open");
+        return 1;
+    }
+    uint32_t *base = mmap(NULL, 4096, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    if (base == MAP_FAILED) {
+        perror("mmap");
+        return 1;
+    }
+    rufutex_t *futex = rufutex_new(base);
+    if (futex == NULL) {
+        return 1;
+    }
+    volatile uint32_t *counter = base + 1;
+    long iterations = atol(argv[2]);
+    for (long i = 0; i < iterations; i++) {
+        if (rufutex_lock(futex) != RUFUTEX_OK) {
+            return 1;
+        }
+        *counter = *counter + 1;
+        rufutex_unlock(futex);
+    }
+    rufutex_free(futex);
+    munmap(base, 4096);
+    close(fd);
+    return 0;
+}
@@ -0,0 +1,71 @@
+/*
+ * A legacy C service sharing a robust PTHREAD_PROCESS_SHARED mutex, see tests/pthread_interop.rs.
+ * The mutex sits at offset 0 of the segment and the counter it protects at offset 64.
+ *
+ *   init <shm name>                  initializes the mutex and zeroes the counter
+ *   contend <shm name> <iterations>  increments the counter under the mutex
+ *   die <shm name>                   exits holding the mutex
+ */
+#include <fcntl.h>
+#include <pthread.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/mman.h>
+#include <unistd.h>
+
+#define COUNTER_OFFSET 64
+
+int main(int argc, char **argv)
+{
+    if (argc < 3) {
+        fprintf(stderr, "usage: %s init|contend|die <shm name> [iterations]\n", argv[0]);
+        return 2;
+    }
+    int fd = shm_open(argv[2], O_RDWR, 0600);
+    if (fd < 0) {
+        perror("shm_open");
+        return 1;
+    }
+    char *base = mmap(NULL, 4096, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    if (base == MAP_FAILED) {
+        perror("mmap");
+        return 1;
+    }
+    pthread_mutex_t *mutex = (pthread_mutex_t *)base;
+    volatile uint32_t *counter = (volatile uint32_t *)(base + COUNTER_OFFSET);
+
+    if (strcmp(argv[1], "init") == 0) {
+        pthread_mutexattr_t attr;
+        pthread_mutexattr_init(&attr);
+        pthread_mutexattr_setpshared(&attr, PTHREAD_PROCESS_SHARED);
+        pthread_mutexattr_setrobust(&attr, PTHREAD_MUTEX_ROBUST);
+        if (pthread_mutex_init(mutex, &attr) != 0) {
+            return 1;
+        }
+        pthread_mutexattr_destroy(&attr);
+        *counter = 0;
+    } else if (strcmp(argv[1], "contend") == 0 && argc == 4) {
+        long iterations = atol(argv[3]);
+        for (long i = 0; i < iterations; i++) {
+            if (pthread_mutex_lock(mutex) != 0) {
+                return 1;
+            }
+            *counter = *counter + 1;
+            pthread_mutex_unlock(mutex);
+        }
+    } else if (strcmp(argv[1], "die") == 0) {
+        if (pthread_mutex_lock(mutex) != 0) {
+            return 1;
+        }
+        *counter = *counter + 1;
+        /* The kernel walks the robust list of the process and marks the mutex owner dead */
+        _exit(0);
+    } else {
+        return 2;
+    }
+    munmap(base, 4096);
+    close(fd);
+    return 0;
+}
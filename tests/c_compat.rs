@@ -0,0 +1,90 @@
+//! Protocol compatibility test: the reference C implementation of Drepper's mutex, which the
+//! crate is based on, contends with rufutex on the same word from another process.
+//! A change to the meaning of the 0/1/2 word states breaks this test.
+
+#![cfg(not(miri))]
+
+use rufutex::rufutex::SharedFutex;
+
+use std::ffi::CString;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+
+const SHM_NAME: &str = "/rufutex_c_compat_test";
+const ITERATIONS: u32 = 20000;
+
+fn compile_c_program(out: &Path) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(root.join("tests/c/drepper_mutex.c"))
+        .arg("-o")
+        .arg(out)
+        .status()
+        .expect("a C compiler is needed to run this test");
+    assert!(status.success());
+}
+
+#[test]
+fn test_c_reference_mutex_interop() {
+    let exe = std::env::current_exe().unwrap();
+    let program = exe.parent().unwrap().join("rufutex_drepper_mutex");
+    compile_c_program(&program);
+
+    let name = CString::new(SHM_NAME).unwrap();
+    let base = unsafe {
+        let fd = libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o600);
+        assert!(fd >= 0);
+        assert_eq!(libc::ftruncate(fd, 4096), 0);
+        let base = libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        assert_ne!(base, libc::MAP_FAILED);
+        libc::close(fd);
+        base
+    };
+    let futex = SharedFutex::new(base);
+    futex.set_futex_value(0);
+    let counter = unsafe { (base as *mut u32).add(1) };
+    unsafe { counter.write_volatile(0) };
+
+    let children: Vec<_> = (0..2)
+        .map(|_| {
+            Command::new(&program)
+                .arg(SHM_NAME)
+                .arg(ITERATIONS.to_string())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let counter_addr = counter as usize;
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                let counter = counter_addr as *mut u32;
+                for _ in 0..ITERATIONS {
+                    futex.lock();
+                    unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                    futex.unlock();
+                }
+            });
+        }
+    });
+
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    assert_eq!(unsafe { counter.read_volatile() }, 4 * ITERATIONS);
+    assert_eq!(futex.get_futex_value(), 0);
+
+    unsafe {
+        libc::munmap(base, 4096);
+        libc::shm_unlink(name.as_ptr());
+    }
+}
@@ -0,0 +1,113 @@
+//! C interop test: a C program built against include/rufutex.h and the cdylib contends on the
+//! same lock as Rust threads of this process.
+
+#![cfg(not(miri))]
+
+use rufutex::rufutex::SharedFutex;
+
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+
+const SHM_NAME: &str = "/rufutex_ffi_test";
+const ITERATIONS: u32 = 20000;
+
+/// Directory of this test's build products, the parent of the deps directory of its binary
+fn target_dir() -> PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+/// Builds librufutex.so with the ffi feature and returns the directory holding it
+/// The cdylib of the running `cargo test` may not be built, or be built without the feature,
+/// so the test builds its own. It goes to a target directory of its own, the one of the
+/// running build is locked.
+fn build_cdylib() -> PathBuf {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let cdylib_target = target_dir().join("ffi-cdylib");
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--features", "ffi", "--manifest-path"])
+        .arg(root.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&cdylib_target)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    cdylib_target.join("debug")
+}
+
+fn compile_c_program(out: &Path, lib_dir: &Path) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(root.join("tests/c/ffi_lock.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg("-L")
+        .arg(lib_dir)
+        .arg("-lrufutex")
+        .arg("-o")
+        .arg(out)
+        .status()
+        .expect("a C compiler is needed to run this test");
+    assert!(status.success());
+}
+
+#[test]
+fn test_c_program_shares_lock() {
+    let lib_dir = build_cdylib();
+    let program = target_dir().join("rufutex_ffi_lock");
+    compile_c_program(&program, &lib_dir);
+
+    let name = CString::new(SHM_NAME).unwrap();
+    let base = unsafe {
+        let fd = libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o600);
+        assert!(fd >= 0);
+        assert_eq!(libc::ftruncate(fd, 4096), 0);
+        let base = libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        assert_ne!(base, libc::MAP_FAILED);
+        libc::close(fd);
+        base
+    };
+    let futex = SharedFutex::new(base);
+    futex.set_futex_value(0);
+    let counter = unsafe { (base as *mut u32).add(1) };
+    unsafe { counter.write_volatile(0) };
+
+    let mut child = Command::new(&program)
+        .arg(SHM_NAME)
+        .arg(ITERATIONS.to_string())
+        .env("LD_LIBRARY_PATH", &lib_dir)
+        .spawn()
+        .unwrap();
+
+    let counter_addr = counter as usize;
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                let counter = counter_addr as *mut u32;
+                for _ in 0..ITERATIONS {
+                    futex.lock();
+                    unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                    futex.unlock();
+                }
+            });
+        }
+    });
+
+    assert!(child.wait().unwrap().success());
+    assert_eq!(unsafe { counter.read_volatile() }, 3 * ITERATIONS);
+    assert_eq!(futex.get_futex_value(), 0);
+
+    unsafe {
+        libc::munmap(base, 4096);
+        libc::shm_unlink(name.as_ptr());
+    }
+}
@@ -0,0 +1,65 @@
+//! Cross-process test of futex handle passing: the test binary re-executes itself as the child.
+
+#![cfg(not(miri))]
+
+use rufutex::ipc::{map_futex_fd, recv_futex_handle, send_futex_handle};
+
+use std::env;
+use std::ffi::CString;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::Command;
+use std::{thread, time};
+
+const SOCKET_ENV: &str = "RUFUTEX_IPC_TEST_SOCKET";
+const COUNTER_OFFSET: usize = 8;
+
+/// Child side, only does something when spawned by `test_lock_across_processes`
+#[test]
+fn ipc_child_process() {
+    let path = match env::var(SOCKET_ENV) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let sock = UnixStream::connect(path).unwrap();
+    let (futex, _fd) = recv_futex_handle(&sock).unwrap();
+    // Blocks until the parent releases the lock
+    futex.lock();
+    let counter = unsafe { (futex.futex as *mut u8).add(COUNTER_OFFSET) as *mut u64 };
+    unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+    futex.unlock();
+}
+
+#[test]
+fn test_lock_across_processes() {
+    let name = CString::new("rufutex_ipc_test").unwrap();
+    let fd = unsafe {
+        let fd = libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC);
+        assert!(fd >= 0);
+        assert_eq!(libc::ftruncate(fd, 4096), 0);
+        OwnedFd::from_raw_fd(fd)
+    };
+    let futex = map_futex_fd(fd.as_fd()).unwrap();
+    let counter = unsafe { (futex.futex as *mut u8).add(COUNTER_OFFSET) as *mut u64 };
+    futex.lock();
+
+    let path = env::temp_dir().join(format!("rufutex-ipc-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "ipc_child_process", "--nocapture"])
+        .env(SOCKET_ENV, &path)
+        .spawn()
+        .unwrap();
+    let (sock, _) = listener.accept().unwrap();
+    send_futex_handle(&sock, fd.as_raw_fd(), "ipc_test").unwrap();
+
+    // The child is stuck in lock() while we hold it
+    thread::sleep(time::Duration::from_millis(300));
+    assert_eq!(unsafe { counter.read_volatile() }, 0);
+    futex.unlock();
+
+    assert!(child.wait().unwrap().success());
+    assert_eq!(unsafe { counter.read_volatile() }, 1);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,122 @@
+//! Slow path events of the `log` feature, captured with an in-memory logger
+
+#![cfg(not(miri))]
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rufutex::rufutex::{FutexWakeReason, SharedFutex};
+
+use std::sync::{Mutex, MutexGuard, Once};
+use std::{thread, time};
+
+static LOGGER: CapturingLogger = CapturingLogger {
+    records: Mutex::new(Vec::new()),
+};
+static INIT: Once = Once::new();
+/// The logger is global, the tests take turns so each sees only its own records
+static SERIAL: Mutex<()> = Mutex::new(());
+
+struct CapturingLogger {
+    records: Mutex<Vec<String>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target() == "rufutex" && metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns the records that mention `futex` and clears the capture
+fn take_records(futex: &SharedFutex) -> Vec<String> {
+    let address = format!("{:p}", futex.futex);
+    let mut records = LOGGER.records.lock().unwrap();
+    let ours = records
+        .iter()
+        .filter(|record| record.contains(&address))
+        .cloned()
+        .collect();
+    records.clear();
+    ours
+}
+
+/// Installs the logger and returns the guard serializing the tests
+fn init() -> MutexGuard<'static, ()> {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Debug);
+    });
+    let guard = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+    LOGGER.records.lock().unwrap().clear();
+    guard
+}
+
+#[test]
+fn test_uncontended_lock_is_silent() {
+    let _serial = init();
+    let futex = SharedFutex::new_anonymous().unwrap();
+    futex.lock();
+    futex.unlock();
+    assert!(futex.try_lock());
+    futex.unlock();
+    assert!(take_records(&futex).is_empty());
+}
+
+#[test]
+fn test_contended_lock_events() {
+    let _serial = init();
+    let futex = SharedFutex::new_anonymous().unwrap();
+    futex.lock();
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            futex.lock();
+            futex.unlock();
+        });
+        // The waiter marks the word LOCKED_WAITERS before going to sleep
+        while futex.get_futex_value() != 2 {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        thread::sleep(time::Duration::from_millis(50));
+        futex.unlock();
+    });
+
+    let records = take_records(&futex);
+    let position = |needle: &str| {
+        records
+            .iter()
+            .position(|record| record.contains(needle))
+            .unwrap_or_else(|| panic!("no `{}` event in {:?}", needle, records))
+    };
+    let contended = position("contended, observed state 1");
+    let returned = position("FUTEX_WAIT");
+    let acquired = position("acquired after contention");
+    // The wake is logged by the other thread, its record can land on either side of these
+    position("woke 1 of 1 requested waiters");
+    assert!(contended < returned);
+    assert!(returned < acquired);
+}
+
+#[test]
+fn test_timeout_event() {
+    let _serial = init();
+    let futex = SharedFutex::new_anonymous().unwrap();
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 10_000_000,
+    };
+    assert_eq!(
+        futex.wait_with_timeout(0, timeout),
+        Ok(FutexWakeReason::TimedOut)
+    );
+
+    let records = take_records(&futex);
+    assert_eq!(records.len(), 1);
+    assert!(records[0].contains("timed out after 0.010000000s"));
+}
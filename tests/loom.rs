@@ -0,0 +1,169 @@
+//! Model checking of the lock protocol with loom
+//!
+//! Run with `RUSTFLAGS="--cfg loom" cargo test --test loom --release`. loom explores the
+//! interleavings of lock() and unlock(), including the "set to 2 to be safe" path and the
+//! fetch_sub/swap pair of unlock, and fails on a data race on the counter (mutual exclusion)
+//! or on a state where every thread sleeps (lost wake up). loom_two_sleepers_wakeup has two
+//! threads asleep on the word at once.
+#![cfg(loom)]
+
+use libc::c_void;
+use loom::cell::UnsafeCell;
+use loom::sync::atomic::AtomicU32;
+use loom::sync::Arc;
+use loom::thread;
+use rufutex::backend::LoomBackend;
+use rufutex::rufutex::SharedFutex;
+
+use std::sync::atomic::Ordering::SeqCst;
+
+struct Shared {
+    word: AtomicU32,
+    counter: UnsafeCell<u32>,
+    backend: LoomBackend,
+}
+
+impl Shared {
+    fn new() -> Arc<Self> {
+        Arc::new(Shared {
+            word: AtomicU32::new(0),
+            counter: UnsafeCell::new(0),
+            backend: LoomBackend::new(),
+        })
+    }
+
+    fn futex(&self) -> SharedFutex<LoomBackend> {
+        SharedFutex::with_backend(
+            &self.word as *const AtomicU32 as *mut c_void,
+            self.backend.clone(),
+        )
+    }
+
+    fn increment(&self) {
+        self.counter.with_mut(|counter| unsafe { *counter += 1 });
+    }
+
+    fn counter(&self) -> u32 {
+        self.counter.with(|counter| unsafe { *counter })
+    }
+}
+
+fn contend(threads: u32) {
+    let shared = Shared::new();
+
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let futex = shared.futex();
+                futex.lock();
+                shared.increment();
+                futex.unlock();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(shared.counter(), threads);
+    assert_eq!(shared.word.load(SeqCst), 0);
+}
+
+#[test]
+fn loom_lock_two_threads() {
+    loom::model(|| contend(2));
+}
+
+#[test]
+fn loom_lock_three_threads() {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(2);
+    builder.check(|| contend(3));
+}
+
+#[test]
+fn loom_try_lock() {
+    loom::model(|| {
+        let shared = Shared::new();
+        let other = shared.clone();
+        let handle = thread::spawn(move || {
+            let futex = other.futex();
+            if futex.try_lock() {
+                other.increment();
+                futex.unlock();
+                true
+            } else {
+                false
+            }
+        });
+
+        let futex = shared.futex();
+        futex.lock();
+        shared.increment();
+        futex.unlock();
+
+        let other_locked = handle.join().unwrap();
+        assert_eq!(shared.counter(), 1 + other_locked as u32);
+        assert_eq!(shared.word.load(SeqCst), 0);
+    });
+}
+
+#[test]
+fn loom_waiters_wakeup() {
+    loom::model(|| {
+        let shared = Shared::new();
+        let futex = shared.futex();
+        // Held before the other thread starts, its lock() takes the LOCKED_WAITERS path
+        futex.lock();
+
+        let other = shared.clone();
+        let handle = thread::spawn(move || {
+            let futex = other.futex();
+            futex.lock();
+            other.increment();
+            futex.unlock();
+        });
+
+        shared.increment();
+        futex.unlock();
+        handle.join().unwrap();
+
+        assert_eq!(shared.counter(), 2);
+        assert_eq!(shared.word.load(SeqCst), 0);
+    });
+}
+
+#[test]
+fn loom_two_sleepers_wakeup() {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(3);
+    builder.check(|| {
+        let shared = Shared::new();
+        let futex = shared.futex();
+        // Held before the others start, both can be asleep on LOCKED_WAITERS at once. The one
+        // woken first retakes the lock with 2, not knowing whether the other still sleeps.
+        futex.lock();
+
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let other = shared.clone();
+                thread::spawn(move || {
+                    let futex = other.futex();
+                    futex.lock();
+                    other.increment();
+                    futex.unlock();
+                })
+            })
+            .collect();
+
+        shared.increment();
+        futex.unlock();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(shared.counter(), 3);
+        assert_eq!(shared.word.load(SeqCst), 0);
+    });
+}
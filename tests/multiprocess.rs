@@ -0,0 +1,96 @@
+//! Cross-process test of a SharedFutex in POSIX shared memory: the test binary re-executes
+//! itself as the child, which finds the segment by the name passed in its environment.
+
+#![cfg(not(miri))]
+
+use rufutex::rufutex::SharedFutex;
+use rushm::posixaccessor::POSIXShm;
+
+use std::env;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+const SHM_ENV: &str = "RUFUTEX_MULTIPROCESS_TEST_SHM";
+const LEN: usize = 16;
+
+/// The words of the segment: the lock, a flag raised once the child holds it and the number of
+/// the critical sections run
+struct Segment {
+    lock: SharedFutex,
+    held: SharedFutex,
+    entries: SharedFutex,
+}
+
+fn segment(shm: &mut POSIXShm<i32>) -> Segment {
+    let base = shm.get_cptr_mut() as *mut u32;
+    Segment {
+        lock: SharedFutex::new(base.cast()),
+        held: SharedFutex::new(base.wrapping_add(1).cast()),
+        entries: SharedFutex::new(base.wrapping_add(2).cast()),
+    }
+}
+
+/// Child side, only does something when spawned by `test_lock_handed_to_the_parent`
+#[test]
+fn multiprocess_child_process() {
+    let name = match env::var(SHM_ENV) {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    let mut shm = POSIXShm::<i32>::new(name, LEN);
+    unsafe {
+        let ret = shm.open();
+        assert!(ret.is_ok());
+    }
+    let segment = segment(&mut shm);
+    segment.lock.lock();
+    segment.held.set_futex_value(1);
+    let _ = segment.held.wake_all();
+    // The parent blocks in lock() meanwhile
+    thread::sleep(Duration::from_millis(300));
+    segment
+        .entries
+        .set_futex_value(segment.entries.get_futex_value() + 1);
+    segment.lock.unlock();
+    unsafe {
+        let ret = shm.close(false);
+        assert!(ret.is_ok());
+    }
+}
+
+#[test]
+fn test_lock_handed_to_the_parent() {
+    let name = format!("rufutex_multiprocess_{}", std::process::id());
+    let mut shm = POSIXShm::<i32>::new(name.clone(), LEN);
+    unsafe {
+        let ret = shm.open();
+        assert!(ret.is_ok());
+        (shm.get_cptr_mut() as *mut u8).write_bytes(0, LEN);
+    }
+    let segment = segment(&mut shm);
+
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "multiprocess_child_process", "--nocapture"])
+        .env(SHM_ENV, &name)
+        .spawn()
+        .unwrap();
+
+    // Woken from the child's address space once it holds the lock
+    while segment.held.get_futex_value() == 0 {
+        let _ = segment.held.wait(0);
+    }
+    assert!(!segment.lock.try_lock());
+    segment.lock.lock();
+    // Only acquired after the child left its critical section
+    assert_eq!(segment.entries.get_futex_value(), 1);
+    segment.entries.set_futex_value(2);
+    segment.lock.unlock();
+
+    assert!(child.wait().unwrap().success());
+    assert_eq!(segment.entries.get_futex_value(), 2);
+    unsafe {
+        let ret = shm.close(true);
+        assert!(ret.is_ok());
+    }
+}
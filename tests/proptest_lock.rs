@@ -0,0 +1,43 @@
+//! Property test of the lock protocol: any number of threads doing any number of critical
+//! sections must leave the counter at exactly threads * iterations and the lock unlocked.
+//! The word and the counter live in a shared memory segment, and every other thread goes
+//! through a second mapping of it, like another process would.
+
+use proptest::prelude::*;
+use rufutex::rufutex::SharedFutex;
+use rufutex::test_support::ShmSegment;
+
+use std::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use std::thread;
+
+fn contend(threads: usize, iterations: u32) -> (u32, u32) {
+    let segment = ShmSegment::create("proptest_lock", 8);
+    let other = segment.map_again();
+
+    thread::scope(|s| {
+        for thread in 0..threads {
+            let mapping = if thread % 2 == 0 { &segment } else { &other };
+            s.spawn(move || {
+                let futex = SharedFutex::new(mapping.as_ptr());
+                let counter = unsafe { (mapping.as_ptr() as *mut u32).add(1) };
+                for _ in 0..iterations {
+                    futex.lock();
+                    unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                    futex.unlock();
+                }
+            });
+        }
+    });
+    let word = unsafe { segment.get::<AtomicU32>() }.load(SeqCst);
+    let counter = unsafe { (segment.as_ptr() as *const u32).add(1).read_volatile() };
+    (counter, word)
+}
+
+proptest! {
+    #[test]
+    fn lock_unlock_counts_every_critical_section(threads in 2usize..=16, iterations in 1u32..=100) {
+        let (counter, word) = contend(threads, iterations);
+        prop_assert_eq!(counter, threads as u32 * iterations);
+        prop_assert_eq!(word, 0);
+    }
+}
@@ -0,0 +1,123 @@
+//! Interop test with the pthread mutexes of C programs: a C service initializes a robust
+//! PTHREAD_PROCESS_SHARED mutex in POSIX shared memory, C processes and Rust threads contend on
+//! it, then a C process dies holding it and Rust recovers the mutex.
+//! The C shim is compiled with the system compiler, like tests/c_compat.rs, no build script.
+
+#![cfg(not(miri))]
+
+use rufutex::error::RufutexError;
+use rufutex::pthread_interop::PthreadShmMutex;
+
+use std::ffi::CString;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+const SHM_NAME: &str = "/rufutex_pthread_interop_test";
+const COUNTER_OFFSET: usize = 64;
+const ITERATIONS: u32 = 20000;
+
+fn compile_c_program(out: &Path) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(root.join("tests/c/pthread_shared_mutex.c"))
+        .arg("-pthread")
+        .arg("-o")
+        .arg(out)
+        .status()
+        .expect("a C compiler is needed to run this test");
+    assert!(status.success());
+}
+
+fn run(program: &Path, mode: &str, args: &[&str]) {
+    let status = Command::new(program)
+        .arg(mode)
+        .arg(SHM_NAME)
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_c_pthread_mutex_interop() {
+    let exe = std::env::current_exe().unwrap();
+    let program = exe.parent().unwrap().join("rufutex_pthread_shared_mutex");
+    compile_c_program(&program);
+
+    let name = CString::new(SHM_NAME).unwrap();
+    let base = unsafe {
+        let fd = libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o600);
+        assert!(fd >= 0);
+        assert_eq!(libc::ftruncate(fd, 4096), 0);
+        let base = libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        assert_ne!(base, libc::MAP_FAILED);
+        libc::close(fd);
+        base
+    };
+    run(&program, "init", &[]);
+    let mutex = unsafe { PthreadShmMutex::attach(base) };
+    let counter = unsafe { base.cast::<u8>().add(COUNTER_OFFSET).cast::<u32>() };
+
+    let children: Vec<_> = (0..2)
+        .map(|_| {
+            Command::new(&program)
+                .arg("contend")
+                .arg(SHM_NAME)
+                .arg(ITERATIONS.to_string())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let counter_addr = counter as usize;
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                let counter = counter_addr as *mut u32;
+                for _ in 0..ITERATIONS {
+                    let guard = mutex.lock().unwrap();
+                    assert!(!guard.owner_died());
+                    unsafe { counter.write_volatile(counter.read_volatile() + 1) };
+                }
+            });
+        }
+    });
+    for mut child in children {
+        assert!(child.wait().unwrap().success());
+    }
+    assert_eq!(unsafe { counter.read_volatile() }, 4 * ITERATIONS);
+
+    // The C process exits with the mutex held
+    run(&program, "die", &[]);
+    assert_eq!(unsafe { counter.read_volatile() }, 4 * ITERATIONS + 1);
+    {
+        let mut guard = mutex.lock_timeout(Duration::from_secs(10)).unwrap();
+        assert!(guard.owner_died());
+        guard.mark_consistent().unwrap();
+        assert!(!guard.owner_died());
+        assert!(matches!(
+            guard.mark_consistent(),
+            Err(RufutexError::Syscall { .. })
+        ));
+    }
+    // Repaired, the C processes use it again
+    run(&program, "contend", &["1"]);
+    let guard = mutex.try_lock().unwrap();
+    assert!(!guard.owner_died());
+    assert_eq!(unsafe { counter.read_volatile() }, 4 * ITERATIONS + 2);
+    drop(guard);
+
+    unsafe {
+        libc::munmap(base, 4096);
+        libc::shm_unlink(name.as_ptr());
+    }
+}
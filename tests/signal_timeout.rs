@@ -0,0 +1,102 @@
+//! Timed waits bombarded with signals: every EINTR sends the waiter back to sleep, the total
+//! time must still follow the requested timeout instead of restarting it on each wake up.
+
+#![cfg(not(miri))]
+
+use rufutex::park::Parker;
+use rufutex::rufutex::SharedFutex;
+use rufutex::wait_group::SharedWaitGroup;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::SeqCst};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_millis(200);
+const TOLERANCE: Duration = Duration::from_millis(150);
+
+static HANDLER: Once = Once::new();
+
+extern "C" fn ignore_signal(_: libc::c_int) {}
+
+/// Installs a SIGUSR1 handler without SA_RESTART, so that FUTEX_WAIT fails with EINTR
+fn install_handler() {
+    HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = ignore_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        assert_eq!(
+            libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()),
+            0
+        );
+    });
+}
+
+/// Runs `wait` on a thread signalled every millisecond and returns how long it took
+fn under_signals(wait: impl FnOnce() + Send) -> Duration {
+    install_handler();
+    let done = AtomicBool::new(false);
+    let target = AtomicU64::new(0);
+    thread::scope(|s| {
+        let waiter = s.spawn(|| {
+            target.store(unsafe { libc::pthread_self() } as u64, SeqCst);
+            let start = Instant::now();
+            wait();
+            let elapsed = start.elapsed();
+            done.store(true, SeqCst);
+            elapsed
+        });
+        while target.load(SeqCst) == 0 {
+            thread::yield_now();
+        }
+        let thread = target.load(SeqCst) as libc::pthread_t;
+        let mut signals = 0;
+        while !done.load(SeqCst) {
+            unsafe { libc::pthread_kill(thread, libc::SIGUSR1) };
+            signals += 1;
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(signals > 10);
+        waiter.join().unwrap()
+    })
+}
+
+fn assert_within_tolerance(elapsed: Duration) {
+    assert!(elapsed >= TIMEOUT, "returned early after {elapsed:?}");
+    assert!(
+        elapsed < TIMEOUT + TOLERANCE,
+        "overslept, returned after {elapsed:?}"
+    );
+}
+
+#[test]
+fn test_lock_timeout_under_signals() {
+    let futex = SharedFutex::new_anonymous().unwrap();
+    futex.lock();
+    let elapsed = under_signals(|| assert!(futex.lock_timeout(TIMEOUT).is_err()));
+    assert_within_tolerance(elapsed);
+    futex.unlock();
+}
+
+#[test]
+fn test_await_state_under_signals() {
+    let futex = SharedFutex::new_anonymous().unwrap();
+    let elapsed = under_signals(|| assert!(!futex.await_state(1, Some(TIMEOUT))));
+    assert_within_tolerance(elapsed);
+}
+
+#[test]
+fn test_wait_group_under_signals() {
+    let futex = SharedFutex::new_anonymous().unwrap();
+    let wait_group = SharedWaitGroup::new(futex.futex);
+    wait_group.add(1).unwrap();
+    let elapsed = under_signals(|| assert_eq!(wait_group.wait_timeout(TIMEOUT), Ok(false)));
+    assert_within_tolerance(elapsed);
+}
+
+#[test]
+fn test_park_timeout_under_signals() {
+    let parker = Parker::new(SharedFutex::new_anonymous().unwrap());
+    let elapsed = under_signals(|| assert!(!parker.park_timeout(TIMEOUT)));
+    assert_within_tolerance(elapsed);
+}
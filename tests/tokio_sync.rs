@@ -0,0 +1,115 @@
+//! AsyncSharedMutex and AsyncSharedCondvar in POSIX shared memory under a tokio runtime with
+//! fewer workers than waiting tasks.
+
+#![cfg(not(miri))]
+
+use rufutex::tokio_sync::{AsyncSharedCondvar, AsyncSharedMutex};
+use rushm::posixaccessor::POSIXShm;
+
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_time()
+        .build()
+        .unwrap()
+}
+
+#[repr(C)]
+struct Segment {
+    mutex: AsyncSharedMutex<u64>,
+    condvar: AsyncSharedCondvar,
+}
+
+/// Maps the segment `name`, laying it out when `create` is set
+fn map(name: &str, create: bool) -> (POSIXShm<i32>, &'static Segment) {
+    let mut shm = POSIXShm::<i32>::new(name.to_string(), mem::size_of::<Segment>());
+    unsafe {
+        let ret = shm.open();
+        assert!(ret.is_ok());
+        if create {
+            (shm.get_cptr_mut() as *mut Segment).write(Segment {
+                mutex: AsyncSharedMutex::new(0),
+                condvar: AsyncSharedCondvar::new(),
+            });
+        }
+    }
+    // The segment stays mapped until the test closes it, after the runtime and every future
+    // of the test are gone
+    let segment = unsafe { &*(shm.get_cptr_mut() as *const Segment) };
+    (shm, segment)
+}
+
+#[test]
+fn test_tasks_queue_behind_a_lock_held_across_await() {
+    const TASKS: u64 = 16;
+    let (mut shm, segment) = map("test_tokio_sync_mutex", true);
+    let holding = Arc::new(AtomicBool::new(false));
+
+    runtime().block_on(async {
+        let guard = unsafe { segment.mutex.lock() }.await;
+        holding.store(true, SeqCst);
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| {
+                let holding = holding.clone();
+                tokio::spawn(async move {
+                    let mut guard = unsafe { segment.mutex.lock() }.await;
+                    assert!(!holding.load(SeqCst), "entered while the lock was held");
+                    let value = *guard;
+                    // Suspends with the lock held, the others keep waiting
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    *guard = value + 1;
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(segment.mutex.try_lock().is_none());
+        holding.store(false, SeqCst);
+        drop(guard);
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+
+    assert_eq!(*segment.mutex.try_lock().unwrap(), TASKS);
+    unsafe {
+        let ret = shm.close(true);
+        assert!(ret.is_ok());
+    }
+}
+
+#[test]
+fn test_notification_from_another_mapping() {
+    let (mut shm, segment) = map("test_tokio_sync_condvar", true);
+
+    let notifier = thread::spawn(|| {
+        // Maps the segment on its own, like a separate process would
+        let (_shm, segment) = map("test_tokio_sync_condvar", false);
+        thread::sleep(Duration::from_millis(50));
+        let mut guard = segment.mutex.blocking_lock();
+        *guard = 1;
+        segment.condvar.notify_one();
+    });
+
+    runtime().block_on(async {
+        let mut guard = unsafe { segment.mutex.lock() }.await;
+        while *guard == 0 {
+            guard = tokio::time::timeout(Duration::from_secs(10), unsafe {
+                segment.condvar.wait(guard)
+            })
+            .await
+            .expect("the notification was lost");
+        }
+    });
+
+    notifier.join().unwrap();
+    unsafe {
+        let ret = shm.close(true);
+        assert!(ret.is_ok());
+    }
+}